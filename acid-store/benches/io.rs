@@ -22,6 +22,8 @@ use rand::rngs::SmallRng;
 use rand::{Rng, RngCore, SeedableRng};
 use tempfile::tempdir;
 
+#[cfg(feature = "compression")]
+use acid_store::repo::Compression;
 use acid_store::repo::{ObjectRepository, OpenRepo, RepositoryConfig};
 use acid_store::store::{DirectoryStore, OpenOption, OpenStore};
 
@@ -33,15 +35,47 @@ pub fn random_bytes(size: usize) -> Vec<u8> {
     buffer
 }
 
+/// Return a buffer containing `size` bytes of compressible data for testing purposes.
+///
+/// The data is made up of words chosen at random from a small vocabulary which is the same for
+/// every call, so that a dictionary trained on this data can be used to compress later data.
+pub fn compressible_bytes(size: usize) -> Vec<u8> {
+    let mut vocabulary_rng = SmallRng::seed_from_u64(0);
+    let vocabulary = (0..256)
+        .map(|_| {
+            let mut word = vec![0u8; vocabulary_rng.gen_range(2, 12)];
+            vocabulary_rng.fill_bytes(&mut word);
+            word
+        })
+        .collect::<Vec<_>>();
+
+    let mut rng = SmallRng::from_entropy();
+    let mut buffer = Vec::with_capacity(size);
+    while buffer.len() < size {
+        buffer.extend_from_slice(&vocabulary[rng.gen_range(0, vocabulary.len())]);
+        buffer.push(b' ');
+    }
+    buffer.truncate(size);
+    buffer
+}
+
 /// Return a new repository in the given `directory` for benchmarking.
 pub fn new_repo(directory: &Path) -> ObjectRepository<String, DirectoryStore> {
+    new_repo_with_config(directory, RepositoryConfig::default())
+}
+
+/// Return a new repository in the given `directory` with the given `config` for benchmarking.
+pub fn new_repo_with_config(
+    directory: &Path,
+    config: RepositoryConfig,
+) -> ObjectRepository<String, DirectoryStore> {
     ObjectRepository::new_repo(
         DirectoryStore::open(
             directory.join("store"),
             OpenOption::CREATE | OpenOption::TRUNCATE,
         )
         .unwrap(),
-        RepositoryConfig::default(),
+        config,
         None,
     )
     .unwrap()
@@ -178,6 +212,104 @@ pub fn write_read_object(criterion: &mut Criterion) {
     );
 }
 
+/// The compression methods to compare.
+#[cfg(feature = "compression")]
+const COMPRESSION_METHODS: [(&str, Compression); 5] = [
+    ("None", Compression::None),
+    ("DEFLATE", Compression::Deflate { level: 6 }),
+    ("LZMA", Compression::Lzma { level: 6 }),
+    ("LZ4", Compression::Lz4 { level: 6 }),
+    ("Zstandard", Compression::Zstd { level: 3 }),
+];
+
+/// Return a new repository for each compression method, including one which uses a trained
+/// Zstandard dictionary.
+#[cfg(feature = "compression")]
+fn compression_repos(directory: &Path) -> Vec<(String, ObjectRepository<String, DirectoryStore>)> {
+    let mut repos = COMPRESSION_METHODS
+        .iter()
+        .map(|(name, compression)| {
+            let mut config = RepositoryConfig::default();
+            config.compression = *compression;
+            let repo = new_repo_with_config(&directory.join(name), config);
+            (String::from(*name), repo)
+        })
+        .collect::<Vec<_>>();
+
+    let mut config = RepositoryConfig::default();
+    config.compression = Compression::Zstd { level: 3 };
+    let mut repo = new_repo_with_config(&directory.join("Zstandard with dictionary"), config);
+    let samples = (0..1_000)
+        .map(|_| compressible_bytes(bytesize::kib(4u64) as usize))
+        .collect::<Vec<_>>();
+    repo.train_dictionary(&samples, bytesize::kib(100u64) as usize)
+        .unwrap();
+    repos.push((String::from("Zstandard with dictionary"), repo));
+
+    repos
+}
+
+#[cfg(feature = "compression")]
+pub fn write_compressed_object(criterion: &mut Criterion) {
+    let tmp_dir = tempdir().unwrap();
+
+    let mut group = criterion.benchmark_group("Write to an object with compression");
+
+    let object_size = bytesize::kib(100u64);
+
+    group.throughput(Throughput::Bytes(object_size));
+
+    for (name, mut repo) in compression_repos(tmp_dir.path()) {
+        group.bench_function(name, |bencher| {
+            bencher.iter_batched(
+                || compressible_bytes(object_size as usize),
+                |data| {
+                    let mut object = repo.insert(String::from("Test"));
+                    object.write_all(data.as_slice()).unwrap();
+                    object.flush().unwrap();
+                },
+                BatchSize::SmallInput,
+            );
+        });
+    }
+}
+
+#[cfg(feature = "compression")]
+pub fn read_compressed_object(criterion: &mut Criterion) {
+    let tmp_dir = tempdir().unwrap();
+
+    let mut group = criterion.benchmark_group("Read from an object with compression");
+
+    let object_size = bytesize::kib(100u64);
+
+    group.throughput(Throughput::Bytes(object_size));
+
+    for (name, mut repo) in compression_repos(tmp_dir.path()) {
+        let mut object = repo.insert(String::from("Test"));
+        object
+            .write_all(compressible_bytes(object_size as usize).as_slice())
+            .unwrap();
+        object.flush().unwrap();
+        drop(object);
+
+        group.bench_function(name, |bencher| {
+            bencher.iter(|| {
+                let mut buffer = Vec::new();
+                let mut object = repo.get("Test").unwrap();
+                object.read_to_end(black_box(&mut buffer)).unwrap();
+            });
+        });
+    }
+}
+
 criterion_group!(throughput, write_object, write_read_object);
 criterion_group!(insert, insert_object, insert_object_and_write);
+
+#[cfg(feature = "compression")]
+criterion_group!(compression, write_compressed_object, read_compressed_object);
+
+#[cfg(feature = "compression")]
+criterion_main!(insert, throughput, compression);
+
+#[cfg(not(feature = "compression"))]
 criterion_main!(insert, throughput);
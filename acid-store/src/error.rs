@@ -49,6 +49,18 @@ pub enum Error {
     #[error("This format is not supported by this version of the library.")]
     UnsupportedFormat,
 
+    /// The repository configuration is invalid.
+    #[error("The repository configuration is invalid.")]
+    InvalidConfig,
+
+    /// There are uncommitted changes which must be committed or rolled back first.
+    #[error("There are uncommitted changes which must be committed or rolled back first.")]
+    Uncommitted,
+
+    /// This operation is not supported on this machine.
+    #[error("This operation is not supported on this machine.")]
+    Unsupported,
+
     /// The provided key type does not match the data in the repository.
     #[error("The provided key type does not match the data in the repository.")]
     KeyType,
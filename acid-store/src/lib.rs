@@ -30,12 +30,14 @@
 //! A repository stores its data in a `DataStore`, which is a small trait that can be implemented to
 //! create new storage backends. The following data stores are provided out of the box:
 //! - `DirectoryStore` stores data in a directory in the local file system.
+//! - `PackStore` stores data in large pack files in a directory in the local file system.
 //! - `SqliteStore` stores data in a SQLite database.
 //! - `RedisStore` stores data on a Redis server.
 //! - `S3Store` stores data in an Amazon S3 bucket.
 //! - `RcloneStore` stores data in a varity of cloud storage backends using
 //! [rclone](https://rclone.org/).
 //! - `MemoryStore` stores data in memory.
+//! - `FaultyStore` wraps another data store and injects faults for testing.
 //!
 //! # Examples
 //! ```
@@ -81,14 +83,17 @@
 //!
 //! Types | Cargo Feature | Default
 //! --- | --- | ---
-//! `Encryption::XChaCha20Poly1305` | `encryption` | No
-//! `Compression::Deflate`, `Compression::Lzma`, `Compression::Lz4` | `compression` | No
+//! `Encryption::XChaCha20Poly1305`, `Encryption::Aes256Gcm`, `Encryption::XChaCha20Poly1305Committing`, `Encryption::Aes256GcmCommitting`, `Encryption::Auto` | `encryption` | No
+//! `Compression::Deflate`, `Compression::Lzma`, `Compression::Lz4`, `Compression::Zstd` | `compression` | No
 //! `CommonMetadata`, `UnixMetadata` | `file-metadata` | No
 //! `DirectoryStore` | `store-directory` | Yes
+//! `PackStore`, `PackConfig` | `store-pack` | Yes
 //! `SqliteStore` | `store-sqlite` | No
 //! `RedisStore` | `store-redis` | No
 //! `S3Store` | `store-s3` | No
 //! `RcloneStore` | `store-rclone` | No
+//! `FaultyStore`, `FaultConfig`, `FaultyStoreError` | `store-faulty` | No
+//! `ErasureStore`, `ErasureError`, `RepairStats` | `store-erasure` | No
 //!
 //! To use a feature which is not enabled by default, you must enable it in your `Cargo.toml`.
 
@@ -16,6 +16,7 @@
 
 use std::collections::HashSet;
 use std::io::{Read, Write};
+use std::time::Duration;
 
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
@@ -25,8 +26,9 @@ use lazy_static::lazy_static;
 use crate::repo::content::hash::HashAlgorithm;
 use crate::repo::version_id::{check_version, write_version};
 use crate::repo::{
-    LockStrategy, ObjectRepository, OpenRepo, ReadOnlyObject, RepositoryConfig, RepositoryInfo,
-    RepositoryStats,
+    EncodingStats, GcStats, KeySlotInfo, LockStrategy, ObjectRepository, OpenRepo, ReadOnlyObject,
+    ReconfigureProgress, RepositoryConfig, RepositoryInfo, RepositoryStats, Savepoint, SlotKey,
+    RAW_KEY_SIZE,
 };
 use crate::store::DataStore;
 
@@ -78,24 +80,32 @@ impl<S: DataStore> OpenRepo<S> for ContentRepository<S> {
         Self: Sized,
     {
         let repository = ObjectRepository::open_repo(store, strategy, password)?;
+        Self::from_repository(repository)
+    }
 
-        // Read the repository version.
-        let object = repository
-            .get(&ContentKey::RepositoryVersion)
-            .ok_or(crate::Error::NotFound)?;
-        check_version(object, *VERSION_ID)?;
-
-        // Read the hash algorithm.
-        let mut object = repository
-            .get(&ContentKey::HashAlgorithm)
-            .ok_or(crate::Error::Corrupt)?;
-        let hash_algorithm = object.deserialize()?;
-        drop(object);
+    fn open_repo_with_key(
+        store: S,
+        strategy: LockStrategy,
+        key: &[u8; RAW_KEY_SIZE],
+    ) -> crate::Result<Self>
+    where
+        Self: Sized,
+    {
+        let repository = ObjectRepository::open_repo_with_key(store, strategy, key)?;
+        Self::from_repository(repository)
+    }
 
-        Ok(Self {
-            repository,
-            hash_algorithm,
-        })
+    fn open_repo_with_slot(
+        store: S,
+        strategy: LockStrategy,
+        slot: &str,
+        key: SlotKey,
+    ) -> crate::Result<Self>
+    where
+        Self: Sized,
+    {
+        let repository = ObjectRepository::open_repo_with_slot(store, strategy, slot, key)?;
+        Self::from_repository(repository)
     }
 
     fn new_repo(store: S, config: RepositoryConfig, password: Option<&[u8]>) -> crate::Result<Self>
@@ -139,6 +149,27 @@ impl<S: DataStore> OpenRepo<S> for ContentRepository<S> {
 }
 
 impl<S: DataStore> ContentRepository<S> {
+    /// Open the repository stored in the given `repository`, which has already been unlocked.
+    fn from_repository(repository: ObjectRepository<ContentKey, S>) -> crate::Result<Self> {
+        // Read the repository version.
+        let object = repository
+            .try_get(&ContentKey::RepositoryVersion)?
+            .ok_or(crate::Error::NotFound)?;
+        check_version(object, *VERSION_ID)?;
+
+        // Read the hash algorithm.
+        let mut object = repository
+            .try_get(&ContentKey::HashAlgorithm)?
+            .ok_or(crate::Error::Corrupt)?;
+        let hash_algorithm = object.deserialize()?;
+        drop(object);
+
+        Ok(Self {
+            repository,
+            hash_algorithm,
+        })
+    }
+
     /// Return whether the repository contains an object with the given `hash`.
     pub fn contains(&self, hash: &[u8]) -> bool {
         self.repository.contains(&ContentKey::Object(hash.to_vec()))
@@ -175,10 +206,10 @@ impl<S: DataStore> ContentRepository<S> {
         // Now that we know the hash, we can associate the object with its hash.
         let hash = digest.result();
         let key = ContentKey::Object(hash.clone());
-        if !self.repository.contains(&key) {
+        if !self.repository.try_contains(&key)? {
             self.repository.copy(&ContentKey::Stage, key)?;
         }
-        self.repository.remove(&ContentKey::Stage);
+        self.repository.try_remove(&ContentKey::Stage)?;
 
         Ok(hash)
     }
@@ -199,9 +230,9 @@ impl<S: DataStore> ContentRepository<S> {
     }
 
     /// Return an iterator of hashes of all the objects in this repository.
-    pub fn list(&self) -> impl Iterator<Item = &[u8]> {
+    pub fn list(&self) -> impl Iterator<Item = Vec<u8>> + '_ {
         self.repository.keys().filter_map(|key| match key {
-            ContentKey::Object(hash) => Some(hash.as_slice()),
+            ContentKey::Object(hash) => Some(hash),
             _ => None,
         })
     }
@@ -233,15 +264,23 @@ impl<S: DataStore> ContentRepository<S> {
         drop(object);
 
         // Re-compute the hashes of the objects in the repository.
-        let old_hashes = self.list().map(|hash| hash.to_vec()).collect::<Vec<_>>();
+        let mut old_hashes = Vec::new();
+        for key in self.repository.try_keys() {
+            if let ContentKey::Object(hash) = key? {
+                old_hashes.push(hash);
+            }
+        }
         for old_hash in old_hashes {
-            let mut object = self.get(&old_hash).unwrap();
+            let mut object = self
+                .repository
+                .try_get(&ContentKey::Object(old_hash.clone()))?
+                .ok_or(crate::Error::Corrupt)?;
             let new_hash = new_algorithm.hash(&mut object)?;
             drop(object);
             let old_key = ContentKey::Object(old_hash);
             self.repository
                 .copy(&old_key, ContentKey::Object(new_hash))?;
-            self.repository.remove(&old_key);
+            self.repository.try_remove(&old_key)?;
         }
 
         Ok(())
@@ -254,6 +293,40 @@ impl<S: DataStore> ContentRepository<S> {
         self.repository.commit()
     }
 
+    /// Read the hash algorithm from its object in the repository.
+    fn read_algorithm(&self) -> crate::Result<HashAlgorithm> {
+        let mut object = self
+            .repository
+            .try_get(&ContentKey::HashAlgorithm)?
+            .ok_or(crate::Error::Corrupt)?;
+        object.deserialize()
+    }
+
+    /// Roll back all changes which have been made since the last commit.
+    ///
+    /// See `ObjectRepository::rollback` for details.
+    pub fn rollback(&mut self) -> crate::Result<()> {
+        self.repository.rollback()?;
+        self.hash_algorithm = self.read_algorithm()?;
+        Ok(())
+    }
+
+    /// Create a savepoint which uncommitted changes can be rolled back to with `rollback_to`.
+    ///
+    /// See `ObjectRepository::savepoint` for details.
+    pub fn savepoint(&mut self) -> Savepoint {
+        self.repository.savepoint()
+    }
+
+    /// Roll back the changes which have been made since `savepoint` was created.
+    ///
+    /// See `ObjectRepository::rollback_to` for details.
+    pub fn rollback_to(&mut self, savepoint: Savepoint) -> crate::Result<()> {
+        self.repository.rollback_to(savepoint)?;
+        self.hash_algorithm = self.read_algorithm()?;
+        Ok(())
+    }
+
     /// Verify the integrity of all the data in the repository.
     ///
     /// This returns the set of hashes of objects which are corrupt.
@@ -262,13 +335,13 @@ impl<S: DataStore> ContentRepository<S> {
     /// - `Error::InvalidData`: Ciphertext verification failed.
     /// - `Error::Store`: An error occurred with the data store.
     /// - `Error::Io`: An I/O error occurred.
-    pub fn verify(&self) -> crate::Result<HashSet<&[u8]>> {
+    pub fn verify(&self) -> crate::Result<HashSet<Vec<u8>>> {
         Ok(self
             .repository
             .verify()?
-            .iter()
+            .into_iter()
             .filter_map(|key| match key {
-                ContentKey::Object(hash) => Some(hash.as_slice()),
+                ContentKey::Object(hash) => Some(hash),
                 _ => None,
             })
             .collect())
@@ -278,10 +351,45 @@ impl<S: DataStore> ContentRepository<S> {
     ///
     /// See `ObjectRepository::change_password` for details.
     #[cfg(feature = "encryption")]
-    pub fn change_password(&mut self, new_password: &[u8]) {
+    pub fn change_password(&mut self, new_password: &[u8]) -> crate::Result<()> {
         self.repository.change_password(new_password)
     }
 
+    /// Add a new key slot named `name` which can be unlocked with `key`.
+    ///
+    /// See `ObjectRepository::add_key_slot` for details.
+    #[cfg(feature = "encryption")]
+    pub fn add_key_slot(&mut self, name: &str, key: SlotKey) -> crate::Result<()> {
+        self.repository.add_key_slot(name, key)
+    }
+
+    /// Remove the key slot named `name`.
+    ///
+    /// See `ObjectRepository::remove_key_slot` for details.
+    #[cfg(feature = "encryption")]
+    pub fn remove_key_slot(&mut self, name: &str) -> bool {
+        self.repository.remove_key_slot(name)
+    }
+
+    /// Return information about each of the key slots in this repository.
+    ///
+    /// See `ObjectRepository::key_slots` for details.
+    pub fn key_slots(&self) -> Vec<KeySlotInfo> {
+        self.repository.key_slots()
+    }
+
+    /// Train a dictionary for Zstandard compression using `samples`.
+    ///
+    /// See `ObjectRepository::train_dictionary` for details.
+    #[cfg(feature = "compression")]
+    pub fn train_dictionary<T: AsRef<[u8]>>(
+        &mut self,
+        samples: &[T],
+        max_size: usize,
+    ) -> crate::Result<()> {
+        self.repository.train_dictionary(samples, max_size)
+    }
+
     /// Return information about the repository.
     pub fn info(&self) -> RepositoryInfo {
         self.repository.info()
@@ -299,6 +407,60 @@ impl<S: DataStore> ContentRepository<S> {
         self.repository.stats()
     }
 
+    /// Return statistics about the time spent encoding and decoding data.
+    ///
+    /// See `ObjectRepository::encoding_stats` for details.
+    pub fn encoding_stats(&self) -> EncodingStats {
+        self.repository.encoding_stats()
+    }
+
+    /// Return the number of threads used to compress and encrypt chunks.
+    ///
+    /// See `ObjectRepository::encoding_threads` for details.
+    pub fn encoding_threads(&self) -> usize {
+        self.repository.encoding_threads()
+    }
+
+    /// Set the number of `threads` used to compress and encrypt chunks.
+    ///
+    /// See `ObjectRepository::set_encoding_threads` for details.
+    pub fn set_encoding_threads(&mut self, threads: usize) -> crate::Result<()> {
+        self.repository.set_encoding_threads(threads)
+    }
+
+    /// Remove blocks which are no longer used by the repository from the data store.
+    ///
+    /// See `ObjectRepository::gc` for details.
+    pub fn gc(&mut self, budget: Option<Duration>) -> crate::Result<GcStats> {
+        self.repository.gc(budget)
+    }
+
+    /// Remove every block in the data store which is not used by the repository.
+    ///
+    /// See `ObjectRepository::gc_full` for details.
+    pub fn gc_full(&mut self) -> crate::Result<GcStats> {
+        self.repository.gc_full()
+    }
+
+    /// Return how much space would be reclaimed by calling `gc` without removing anything.
+    ///
+    /// See `ObjectRepository::gc_dry_run` for details.
+    pub fn gc_dry_run(&self) -> GcStats {
+        self.repository.gc_dry_run()
+    }
+
+    /// Rewrite the data in the repository using a new `config`.
+    ///
+    /// See `ObjectRepository::reconfigure` for details.
+    pub fn reconfigure(
+        &mut self,
+        config: RepositoryConfig,
+        key: Option<SlotKey>,
+        budget: Option<Duration>,
+    ) -> crate::Result<ReconfigureProgress> {
+        self.repository.reconfigure(config, key, budget)
+    }
+
     /// Consume this repository and return the wrapped `DataStore`.
     pub fn into_store(self) -> S {
         self.repository.into_store()
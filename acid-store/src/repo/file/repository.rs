@@ -21,6 +21,7 @@ use std::fs::{create_dir, create_dir_all, metadata, File, OpenOptions};
 use std::io::{self, copy, Write};
 use std::marker::PhantomData;
 use std::path::Path;
+use std::time::Duration;
 
 use relative_path::{RelativePath, RelativePathBuf};
 use uuid::Uuid;
@@ -30,8 +31,9 @@ use lazy_static::lazy_static;
 
 use crate::repo::version_id::{check_version, write_version};
 use crate::repo::{
-    LockStrategy, Object, ObjectRepository, OpenRepo, ReadOnlyObject, RepositoryConfig,
-    RepositoryInfo, RepositoryStats,
+    EncodingStats, GcStats, KeySlotInfo, LockStrategy, Object, ObjectRepository, OpenRepo,
+    ReadOnlyObject, ReconfigureProgress, RepositoryConfig, RepositoryInfo, RepositoryStats,
+    Savepoint, SlotKey, RAW_KEY_SIZE,
 };
 use crate::store::DataStore;
 
@@ -83,17 +85,32 @@ impl<S: DataStore, M: FileMetadata> OpenRepo<S> for FileRepository<S, M> {
         Self: Sized,
     {
         let repository = ObjectRepository::open_repo(store, strategy, password)?;
+        Self::from_repository(repository)
+    }
 
-        // Read the repository version to see if this is a compatible repository.
-        let object = repository
-            .get(&EntryKey::Version)
-            .ok_or(crate::Error::NotFound)?;
-        check_version(object, *VERSION_ID)?;
+    fn open_repo_with_key(
+        store: S,
+        strategy: LockStrategy,
+        key: &[u8; RAW_KEY_SIZE],
+    ) -> crate::Result<Self>
+    where
+        Self: Sized,
+    {
+        let repository = ObjectRepository::open_repo_with_key(store, strategy, key)?;
+        Self::from_repository(repository)
+    }
 
-        Ok(Self {
-            repository,
-            marker: PhantomData,
-        })
+    fn open_repo_with_slot(
+        store: S,
+        strategy: LockStrategy,
+        slot: &str,
+        key: SlotKey,
+    ) -> crate::Result<Self>
+    where
+        Self: Sized,
+    {
+        let repository = ObjectRepository::open_repo_with_slot(store, strategy, slot, key)?;
+        Self::from_repository(repository)
     }
 
     fn new_repo(store: S, config: RepositoryConfig, password: Option<&[u8]>) -> crate::Result<Self>
@@ -132,12 +149,32 @@ impl<S: DataStore, M: FileMetadata> OpenRepo<S> for FileRepository<S, M> {
 }
 
 impl<S: DataStore, M: FileMetadata> FileRepository<S, M> {
+    /// Open the repository stored in the given `repository`, which has already been unlocked.
+    fn from_repository(repository: ObjectRepository<EntryKey, S>) -> crate::Result<Self> {
+        // Read the repository version to see if this is a compatible repository.
+        let object = repository
+            .try_get(&EntryKey::Version)?
+            .ok_or(crate::Error::NotFound)?;
+        check_version(object, *VERSION_ID)?;
+
+        Ok(Self {
+            repository,
+            marker: PhantomData,
+        })
+    }
+
     /// Return whether there is an entry at `path`.
     pub fn exists(&self, path: impl AsRef<RelativePath>) -> bool {
         self.repository
             .contains(&EntryKey::Entry(path.as_ref().to_owned()))
     }
 
+    /// Return whether there is an entry at `path` or an error if the header can't be read.
+    fn try_exists(&self, path: impl AsRef<RelativePath>) -> crate::Result<bool> {
+        self.repository
+            .try_contains(&EntryKey::Entry(path.as_ref().to_owned()))
+    }
+
     /// Add a new empty file or directory entry to the repository at the given `path`.
     ///
     /// # Errors
@@ -153,7 +190,7 @@ impl<S: DataStore, M: FileMetadata> FileRepository<S, M> {
         path: impl AsRef<RelativePath>,
         entry: &Entry<M>,
     ) -> crate::Result<()> {
-        if self.exists(&path) {
+        if self.try_exists(&path)? {
             return Err(crate::Error::AlreadyExists);
         }
 
@@ -177,7 +214,7 @@ impl<S: DataStore, M: FileMetadata> FileRepository<S, M> {
         if entry.is_file() {
             self.repository.insert(data_key);
         } else {
-            self.repository.remove(&data_key);
+            self.repository.try_remove(&data_key)?;
         }
 
         // Write the metadata for the file.
@@ -230,13 +267,13 @@ impl<S: DataStore, M: FileMetadata> FileRepository<S, M> {
     /// - `Error::NotFound`: There is no entry with the given `path`.
     /// - `Error::NotEmpty`: The entry is a directory which is not empty.
     pub fn remove(&mut self, path: impl AsRef<RelativePath>) -> crate::Result<()> {
-        if !self.exists(&path) {
+        if !self.try_exists(&path)? {
             return Err(crate::Error::NotFound);
         }
 
-        match self.list(&path) {
-            Ok(mut children) => {
-                if children.next().is_some() {
+        match self.children(&path) {
+            Ok(children) => {
+                if !children.is_empty() {
                     return Err(crate::Error::NotEmpty);
                 }
             }
@@ -245,9 +282,9 @@ impl<S: DataStore, M: FileMetadata> FileRepository<S, M> {
         }
 
         self.repository
-            .remove(&EntryKey::Data(path.as_ref().to_owned()));
+            .try_remove(&EntryKey::Data(path.as_ref().to_owned()))?;
         self.repository
-            .remove(&EntryKey::Entry(path.as_ref().to_owned()));
+            .try_remove(&EntryKey::Entry(path.as_ref().to_owned()))?;
 
         Ok(())
     }
@@ -260,8 +297,8 @@ impl<S: DataStore, M: FileMetadata> FileRepository<S, M> {
     /// # Errors
     /// - `Error::NotFound`: There is no entry with the given `path`.
     pub fn remove_tree(&mut self, path: impl AsRef<RelativePath>) -> crate::Result<()> {
-        let mut descendants = match self.walk(&path) {
-            Ok(descendants) => descendants.map(|path| path.to_owned()).collect::<Vec<_>>(),
+        let mut descendants = match self.descendants(&path) {
+            Ok(descendants) => descendants,
             Err(crate::Error::NotDirectory) => Vec::new(),
             Err(error) => return Err(error),
         };
@@ -289,7 +326,7 @@ impl<S: DataStore, M: FileMetadata> FileRepository<S, M> {
     pub fn entry(&self, path: impl AsRef<RelativePath>) -> crate::Result<Entry<M>> {
         let mut object = self
             .repository
-            .get(&EntryKey::Entry(path.as_ref().to_owned()))
+            .try_get(&EntryKey::Entry(path.as_ref().to_owned()))?
             .ok_or(crate::Error::NotFound)?;
 
         object.deserialize()
@@ -314,7 +351,7 @@ impl<S: DataStore, M: FileMetadata> FileRepository<S, M> {
 
         let mut object = self
             .repository
-            .get_mut(&EntryKey::Entry(path.as_ref().to_owned()))
+            .try_get_mut(&EntryKey::Entry(path.as_ref().to_owned()))?
             .ok_or(crate::Error::NotFound)?;
 
         object.serialize(&entry)?;
@@ -345,7 +382,7 @@ impl<S: DataStore, M: FileMetadata> FileRepository<S, M> {
 
         let object = self
             .repository
-            .get(&EntryKey::Data(path.as_ref().to_owned()))
+            .try_get(&EntryKey::Data(path.as_ref().to_owned()))?
             .expect("There is no object associated with this file.");
 
         Ok(object)
@@ -374,7 +411,7 @@ impl<S: DataStore, M: FileMetadata> FileRepository<S, M> {
 
         let object = self
             .repository
-            .get_mut(&EntryKey::Data(path.as_ref().to_owned()))
+            .try_get_mut(&EntryKey::Data(path.as_ref().to_owned()))?
             .expect("There is no object associated with this file.");
 
         Ok(object)
@@ -408,7 +445,7 @@ impl<S: DataStore, M: FileMetadata> FileRepository<S, M> {
 
         if source_entry.is_file() {
             let data_key = EntryKey::Data(dest.as_ref().to_owned());
-            self.repository.remove(&data_key);
+            self.repository.try_remove(&data_key)?;
             self.repository
                 .copy(&EntryKey::Data(source.as_ref().to_owned()), data_key)?;
         }
@@ -441,8 +478,8 @@ impl<S: DataStore, M: FileMetadata> FileRepository<S, M> {
         // Copy the root directory.
         self.copy(&source, &dest)?;
 
-        let mut descendants = match self.walk(&source) {
-            Ok(descendants) => descendants.map(|path| path.to_owned()).collect::<Vec<_>>(),
+        let mut descendants = match self.descendants(&source) {
+            Ok(descendants) => descendants,
             Err(crate::Error::NotDirectory) => return Ok(()),
             Err(error) => return Err(error),
         };
@@ -462,7 +499,7 @@ impl<S: DataStore, M: FileMetadata> FileRepository<S, M> {
 
     /// Return an unsorted iterator of paths which are children of `parent`.
     ///
-    /// The returned paths do not include `parent`.
+    /// The returned paths do not include `parent`. See `ObjectRepository::keys`.
     ///
     /// # Errors
     /// - `Error::NotFound`: There is no entry at `parent`.
@@ -474,15 +511,13 @@ impl<S: DataStore, M: FileMetadata> FileRepository<S, M> {
     pub fn list<'a>(
         &'a self,
         parent: impl AsRef<RelativePath> + 'a,
-    ) -> crate::Result<impl Iterator<Item = &'a RelativePath> + 'a> {
+    ) -> crate::Result<impl Iterator<Item = RelativePathBuf> + 'a> {
         if !self.entry(&parent)?.is_directory() {
             return Err(crate::Error::NotDirectory);
         }
 
         let children = self.repository.keys().filter_map(move |entry| match entry {
-            EntryKey::Entry(path) if path.parent() == Some(parent.as_ref()) => {
-                Some(path.as_relative_path())
-            }
+            EntryKey::Entry(path) if path.parent() == Some(parent.as_ref()) => Some(path),
             _ => None,
         });
 
@@ -491,7 +526,7 @@ impl<S: DataStore, M: FileMetadata> FileRepository<S, M> {
 
     /// Return an unsorted iterator of paths which are descendants of `parent`.
     ///
-    /// The returned paths do not include `parent`.
+    /// The returned paths do not include `parent`. See `ObjectRepository::keys`.
     ///
     /// # Errors
     /// - `Error::NotFound`: There is no entry at `parent`.
@@ -503,7 +538,7 @@ impl<S: DataStore, M: FileMetadata> FileRepository<S, M> {
     pub fn walk<'a>(
         &'a self,
         parent: impl AsRef<RelativePath> + 'a,
-    ) -> crate::Result<impl Iterator<Item = &'a RelativePath> + 'a> {
+    ) -> crate::Result<impl Iterator<Item = RelativePathBuf> + 'a> {
         if !self.entry(&parent)?.is_directory() {
             return Err(crate::Error::NotDirectory);
         }
@@ -512,7 +547,7 @@ impl<S: DataStore, M: FileMetadata> FileRepository<S, M> {
             EntryKey::Entry(path)
                 if path.starts_with(parent.as_ref()) && path != parent.as_ref() =>
             {
-                Some(path.as_relative_path())
+                Some(path)
             }
             _ => None,
         });
@@ -520,6 +555,44 @@ impl<S: DataStore, M: FileMetadata> FileRepository<S, M> {
         Ok(descendants)
     }
 
+    /// Return the paths of the children of `parent`, or an error if they can't be read.
+    ///
+    /// This is like `list`, but collects the paths so that errors from the header are returned.
+    fn children(&self, parent: impl AsRef<RelativePath>) -> crate::Result<Vec<RelativePathBuf>> {
+        if !self.entry(&parent)?.is_directory() {
+            return Err(crate::Error::NotDirectory);
+        }
+
+        let mut children = Vec::new();
+        for entry in self.repository.try_keys() {
+            if let EntryKey::Entry(path) = entry? {
+                if path.parent() == Some(parent.as_ref()) {
+                    children.push(path);
+                }
+            }
+        }
+        Ok(children)
+    }
+
+    /// Return the paths of the descendants of `parent`, or an error if they can't be read.
+    ///
+    /// This is like `walk`, but collects the paths so that errors from the header are returned.
+    fn descendants(&self, parent: impl AsRef<RelativePath>) -> crate::Result<Vec<RelativePathBuf>> {
+        if !self.entry(&parent)?.is_directory() {
+            return Err(crate::Error::NotDirectory);
+        }
+
+        let mut descendants = Vec::new();
+        for entry in self.repository.try_keys() {
+            if let EntryKey::Entry(path) = entry? {
+                if path.starts_with(parent.as_ref()) && path != parent.as_ref() {
+                    descendants.push(path);
+                }
+            }
+        }
+        Ok(descendants)
+    }
+
     /// Copy a file from the file system into the repository.
     ///
     /// The `source` file's metadata will be applied to the `dest` entry according to the selected
@@ -539,7 +612,7 @@ impl<S: DataStore, M: FileMetadata> FileRepository<S, M> {
         source: impl AsRef<Path>,
         dest: impl AsRef<RelativePath>,
     ) -> crate::Result<()> {
-        if self.exists(&dest) {
+        if self.try_exists(&dest)? {
             return Err(crate::Error::AlreadyExists);
         }
 
@@ -678,9 +751,10 @@ impl<S: DataStore, M: FileMetadata> FileRepository<S, M> {
         source: impl AsRef<RelativePath>,
         dest: impl AsRef<Path>,
     ) -> crate::Result<()> {
-        let relative_descendants = match self.walk(&source) {
+        let relative_descendants = match self.descendants(&source) {
             Ok(descendants) => {
                 let mut relative_descendants = descendants
+                    .iter()
                     .map(|path| path.strip_prefix(&source).unwrap().to_owned())
                     .collect::<Vec<_>>();
 
@@ -713,6 +787,27 @@ impl<S: DataStore, M: FileMetadata> FileRepository<S, M> {
         self.repository.commit()
     }
 
+    /// Roll back all changes which have been made since the last commit.
+    ///
+    /// See `ObjectRepository::rollback` for details.
+    pub fn rollback(&mut self) -> crate::Result<()> {
+        self.repository.rollback()
+    }
+
+    /// Create a savepoint which uncommitted changes can be rolled back to with `rollback_to`.
+    ///
+    /// See `ObjectRepository::savepoint` for details.
+    pub fn savepoint(&mut self) -> Savepoint {
+        self.repository.savepoint()
+    }
+
+    /// Roll back the changes which have been made since `savepoint` was created.
+    ///
+    /// See `ObjectRepository::rollback_to` for details.
+    pub fn rollback_to(&mut self, savepoint: Savepoint) -> crate::Result<()> {
+        self.repository.rollback_to(savepoint)
+    }
+
     /// Verify the integrity of all the data in the repository.
     ///
     /// This returns the set of paths of files with corrupt data or metadata.
@@ -721,14 +816,14 @@ impl<S: DataStore, M: FileMetadata> FileRepository<S, M> {
     /// - `Error::InvalidData`: Ciphertext verification failed.
     /// - `Error::Store`: An error occurred with the data store.
     /// - `Error::Io`: An I/O error occurred.
-    pub fn verify(&self) -> crate::Result<HashSet<&RelativePath>> {
+    pub fn verify(&self) -> crate::Result<HashSet<RelativePathBuf>> {
         let paths = self
             .repository
             .verify()?
-            .iter()
+            .into_iter()
             .filter_map(|entry| match entry {
-                EntryKey::Data(path) => Some(path.as_relative_path()),
-                EntryKey::Entry(path) => Some(path.as_relative_path()),
+                EntryKey::Data(path) => Some(path),
+                EntryKey::Entry(path) => Some(path),
                 _ => None,
             })
             .collect();
@@ -740,8 +835,43 @@ impl<S: DataStore, M: FileMetadata> FileRepository<S, M> {
     ///
     /// See `ObjectRepository::change_password` for details.
     #[cfg(feature = "encryption")]
-    pub fn change_password(&mut self, new_password: &[u8]) {
-        self.repository.change_password(new_password);
+    pub fn change_password(&mut self, new_password: &[u8]) -> crate::Result<()> {
+        self.repository.change_password(new_password)
+    }
+
+    /// Add a new key slot named `name` which can be unlocked with `key`.
+    ///
+    /// See `ObjectRepository::add_key_slot` for details.
+    #[cfg(feature = "encryption")]
+    pub fn add_key_slot(&mut self, name: &str, key: SlotKey) -> crate::Result<()> {
+        self.repository.add_key_slot(name, key)
+    }
+
+    /// Remove the key slot named `name`.
+    ///
+    /// See `ObjectRepository::remove_key_slot` for details.
+    #[cfg(feature = "encryption")]
+    pub fn remove_key_slot(&mut self, name: &str) -> bool {
+        self.repository.remove_key_slot(name)
+    }
+
+    /// Return information about each of the key slots in this repository.
+    ///
+    /// See `ObjectRepository::key_slots` for details.
+    pub fn key_slots(&self) -> Vec<KeySlotInfo> {
+        self.repository.key_slots()
+    }
+
+    /// Train a dictionary for Zstandard compression using `samples`.
+    ///
+    /// See `ObjectRepository::train_dictionary` for details.
+    #[cfg(feature = "compression")]
+    pub fn train_dictionary<T: AsRef<[u8]>>(
+        &mut self,
+        samples: &[T],
+        max_size: usize,
+    ) -> crate::Result<()> {
+        self.repository.train_dictionary(samples, max_size)
     }
 
     /// Return information about the repository.
@@ -761,6 +891,60 @@ impl<S: DataStore, M: FileMetadata> FileRepository<S, M> {
         self.repository.stats()
     }
 
+    /// Return statistics about the time spent encoding and decoding data.
+    ///
+    /// See `ObjectRepository::encoding_stats` for details.
+    pub fn encoding_stats(&self) -> EncodingStats {
+        self.repository.encoding_stats()
+    }
+
+    /// Return the number of threads used to compress and encrypt chunks.
+    ///
+    /// See `ObjectRepository::encoding_threads` for details.
+    pub fn encoding_threads(&self) -> usize {
+        self.repository.encoding_threads()
+    }
+
+    /// Set the number of `threads` used to compress and encrypt chunks.
+    ///
+    /// See `ObjectRepository::set_encoding_threads` for details.
+    pub fn set_encoding_threads(&mut self, threads: usize) -> crate::Result<()> {
+        self.repository.set_encoding_threads(threads)
+    }
+
+    /// Remove blocks which are no longer used by the repository from the data store.
+    ///
+    /// See `ObjectRepository::gc` for details.
+    pub fn gc(&mut self, budget: Option<Duration>) -> crate::Result<GcStats> {
+        self.repository.gc(budget)
+    }
+
+    /// Remove every block in the data store which is not used by the repository.
+    ///
+    /// See `ObjectRepository::gc_full` for details.
+    pub fn gc_full(&mut self) -> crate::Result<GcStats> {
+        self.repository.gc_full()
+    }
+
+    /// Return how much space would be reclaimed by calling `gc` without removing anything.
+    ///
+    /// See `ObjectRepository::gc_dry_run` for details.
+    pub fn gc_dry_run(&self) -> GcStats {
+        self.repository.gc_dry_run()
+    }
+
+    /// Rewrite the data in the repository using a new `config`.
+    ///
+    /// See `ObjectRepository::reconfigure` for details.
+    pub fn reconfigure(
+        &mut self,
+        config: RepositoryConfig,
+        key: Option<SlotKey>,
+        budget: Option<Duration>,
+    ) -> crate::Result<ReconfigureProgress> {
+        self.repository.reconfigure(config, key, budget)
+    }
+
     /// Consume this repository and return the wrapped `DataStore`.
     pub fn into_store(self) -> S {
         self.repository.into_store()
@@ -28,8 +28,11 @@
 //! The other repository types provided by this module can be found in sub-modules.
 
 pub use object::{
-    Compression, ContentId, Encryption, Key, LockStrategy, Object, ObjectRepository, OpenRepo,
-    ReadOnlyObject, RepositoryConfig, RepositoryInfo, RepositoryStats, ResourceLimit,
+    Chunking, Compression, ContentId, EncodingStats, Encryption, GcStats, HeaderMode, Key,
+    KeySlotInfo, KeySlotKind, LockOwner, LockStrategy, Object, ObjectMetadata, ObjectRepository,
+    OpenRepo, ReadMode, ReadOnlyObject, ReadOnlyObjectRepository, ReconfigureProgress,
+    RepositoryConfig, RepositoryInfo, RepositoryStats, ResourceLimit, Savepoint, SlotKey,
+    DEFAULT_KEY_SLOT, RAW_KEY_SIZE,
 };
 
 pub mod content;
@@ -0,0 +1,564 @@
+/*
+ * Copyright 2019-2020 Wren Powell
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::cmp::max;
+use std::collections::HashMap;
+use std::mem::{replace, take};
+use std::sync::{Arc, Mutex};
+
+use lru::LruCache;
+use rmp_serde::{from_read, to_vec};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// The maximum number of entries in a node before it is split in two.
+const MAX_NODE_ENTRIES: usize = 64;
+
+/// The separator key and ID of the new right sibling of a node which was split.
+type Split = (Vec<u8>, Uuid);
+
+/// A place to read and write the pages of a `BTree`.
+pub trait PageStore {
+    /// Read and decode the page with the given `id`.
+    fn read_page(&self, id: Uuid) -> crate::Result<Vec<u8>>;
+
+    /// Encode and write the given `data` as the page with the given `id`.
+    fn write_page(&self, id: Uuid, data: &[u8]) -> crate::Result<()>;
+
+    /// Encode and write each of the given `pages` with its ID.
+    fn write_pages(&self, pages: &[(Uuid, Vec<u8>)]) -> crate::Result<()> {
+        for (id, data) in pages {
+            self.write_page(*id, data)?;
+        }
+        Ok(())
+    }
+}
+
+impl<T: PageStore + ?Sized> PageStore for &T {
+    fn read_page(&self, id: Uuid) -> crate::Result<Vec<u8>> {
+        (**self).read_page(id)
+    }
+
+    fn write_page(&self, id: Uuid, data: &[u8]) -> crate::Result<()> {
+        (**self).write_page(id, data)
+    }
+
+    fn write_pages(&self, pages: &[(Uuid, Vec<u8>)]) -> crate::Result<()> {
+        (**self).write_pages(pages)
+    }
+}
+
+/// A node in a `BTree`, which is stored as a single page.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+enum Node<V> {
+    /// A node which stores entries in order of their keys.
+    Leaf { keys: Vec<Vec<u8>>, values: Vec<V> },
+
+    /// A node which stores the IDs of its children.
+    ///
+    /// The key at index `i` is the smallest key stored under the child at index `i + 1`.
+    Branch {
+        keys: Vec<Vec<u8>>,
+        children: Vec<Uuid>,
+    },
+}
+
+impl<V> Node<V> {
+    /// The number of entries or children in this node.
+    fn len(&self) -> usize {
+        match self {
+            Node::Leaf { keys, .. } => keys.len(),
+            Node::Branch { children, .. } => children.len(),
+        }
+    }
+}
+
+/// Search the sorted list of `keys` for the given `key`.
+fn search(keys: &[Vec<u8>], key: &[u8]) -> Result<usize, usize> {
+    keys.binary_search_by(|probe| probe.as_slice().cmp(key))
+}
+
+/// Return the index of the child of a branch node which may contain `key`.
+fn child_index(keys: &[Vec<u8>], key: &[u8]) -> usize {
+    match search(keys, key) {
+        Ok(index) => index + 1,
+        Err(index) => index,
+    }
+}
+
+/// A copy-on-write B+tree which maps serialized keys to values and stores its nodes as pages.
+///
+/// Nodes are read lazily and kept in an LRU cache which is shared between clones of the tree. A
+/// node which has been flushed is never modified; changing it writes a copy to a new page. This
+/// means that a tree can be cloned cheaply and the clone modified without affecting the pages of
+/// the original.
+///
+/// Nodes which become empty are removed from the tree, but nodes are never merged, so a tree which
+/// has had many entries removed may contain nodes which are less than half full.
+#[derive(Debug)]
+pub struct BTree<V> {
+    /// The ID of the root node or `None` if the tree is empty.
+    root: Option<Uuid>,
+
+    /// The number of entries in the tree.
+    len: u64,
+
+    /// Nodes which have been created or modified since the tree was last flushed.
+    dirty: HashMap<Uuid, Arc<Node<V>>>,
+
+    /// The IDs of flushed nodes which are no longer part of the tree.
+    freed: Vec<Uuid>,
+
+    /// A cache of nodes which have been flushed.
+    cache: Arc<Mutex<LruCache<Uuid, Arc<Node<V>>>>>,
+}
+
+impl<V> Clone for BTree<V> {
+    fn clone(&self) -> Self {
+        Self {
+            root: self.root,
+            len: self.len,
+            dirty: self.dirty.clone(),
+            freed: self.freed.clone(),
+            cache: Arc::clone(&self.cache),
+        }
+    }
+}
+
+impl<V: Clone + Serialize + DeserializeOwned> BTree<V> {
+    /// Open a tree with the given `root` node and `len` entries.
+    ///
+    /// At most `cache_pages` nodes are kept in the cache.
+    pub fn open(root: Option<Uuid>, len: u64, cache_pages: usize) -> Self {
+        Self {
+            root,
+            len,
+            dirty: HashMap::new(),
+            freed: Vec::new(),
+            cache: Arc::new(Mutex::new(LruCache::new(max(cache_pages, 1)))),
+        }
+    }
+
+    /// The ID of the root node or `None` if the tree is empty.
+    pub fn root(&self) -> Option<Uuid> {
+        self.root
+    }
+
+    /// The number of entries in the tree.
+    pub fn entry_count(&self) -> u64 {
+        self.len
+    }
+
+    /// Return the node with the given `id`, reading it from `pages` if it is not in memory.
+    fn load(&self, pages: &impl PageStore, id: Uuid) -> crate::Result<Arc<Node<V>>> {
+        if let Some(node) = self.dirty.get(&id) {
+            return Ok(Arc::clone(node));
+        }
+        if let Some(node) = self.cache.lock().unwrap().get(&id) {
+            return Ok(Arc::clone(node));
+        }
+
+        let page = pages.read_page(id)?;
+        let node: Node<V> = from_read(page.as_slice()).map_err(|_| crate::Error::Corrupt)?;
+        let node = Arc::new(node);
+        self.cache.lock().unwrap().put(id, Arc::clone(&node));
+        Ok(node)
+    }
+
+    /// Prepare the node with the given `id` to be modified and return the ID it is now stored under.
+    ///
+    /// A node which has already been flushed is copied to a new ID so that its page is unchanged.
+    fn load_mut(&mut self, pages: &impl PageStore, id: Uuid) -> crate::Result<Uuid> {
+        if self.dirty.contains_key(&id) {
+            return Ok(id);
+        }
+        let node = self.load(pages, id)?;
+        let new_id = Uuid::new_v4();
+        self.dirty.insert(new_id, node);
+        self.freed.push(id);
+        Ok(new_id)
+    }
+
+    /// Return a mutable reference to the dirty node with the given `id`.
+    fn node_mut(&mut self, id: Uuid) -> &mut Node<V> {
+        Arc::make_mut(self.dirty.get_mut(&id).expect("The node is not dirty."))
+    }
+
+    /// Remove the node with the given `id` from the tree.
+    fn discard(&mut self, id: Uuid) {
+        if self.dirty.remove(&id).is_none() {
+            self.freed.push(id);
+        }
+    }
+
+    /// Return the value associated with `key` or `None` if there is none.
+    pub fn get(&self, pages: &impl PageStore, key: &[u8]) -> crate::Result<Option<V>> {
+        let mut id = match self.root {
+            Some(id) => id,
+            None => return Ok(None),
+        };
+
+        loop {
+            let node = self.load(pages, id)?;
+            match node.as_ref() {
+                Node::Leaf { keys, values } => {
+                    return Ok(search(keys, key).ok().map(|index| values[index].clone()))
+                }
+                Node::Branch { keys, children } => id = children[child_index(keys, key)],
+            }
+        }
+    }
+
+    /// Insert an entry into the tree and return the old value if there was one.
+    pub fn insert(
+        &mut self,
+        pages: &impl PageStore,
+        key: Vec<u8>,
+        value: V,
+    ) -> crate::Result<Option<V>> {
+        let root = match self.root {
+            Some(root) => root,
+            None => {
+                let root = Uuid::new_v4();
+                let node = Node::Leaf {
+                    keys: vec![key],
+                    values: vec![value],
+                };
+                self.dirty.insert(root, Arc::new(node));
+                self.root = Some(root);
+                self.len = 1;
+                return Ok(None);
+            }
+        };
+
+        let (root, old_value, split) = self.insert_at(pages, root, key, value)?;
+
+        // If the root node was split, the tree grows by one level.
+        let root = match split {
+            Some((separator, right)) => {
+                let new_root = Uuid::new_v4();
+                let node = Node::Branch {
+                    keys: vec![separator],
+                    children: vec![root, right],
+                };
+                self.dirty.insert(new_root, Arc::new(node));
+                new_root
+            }
+            None => root,
+        };
+        self.root = Some(root);
+
+        if old_value.is_none() {
+            self.len += 1;
+        }
+
+        Ok(old_value)
+    }
+
+    /// Insert an entry under the node with the given `id`.
+    ///
+    /// This returns the new ID of the node, the old value if there was one, and the separator key
+    /// and ID of the new sibling if the node was split.
+    fn insert_at(
+        &mut self,
+        pages: &impl PageStore,
+        id: Uuid,
+        key: Vec<u8>,
+        value: V,
+    ) -> crate::Result<(Uuid, Option<V>, Option<Split>)> {
+        let id = self.load_mut(pages, id)?;
+        let child = match self.dirty[&id].as_ref() {
+            Node::Branch { keys, children } => {
+                let index = child_index(keys, &key);
+                Some((index, children[index]))
+            }
+            Node::Leaf { .. } => None,
+        };
+
+        let old_value = match child {
+            Some((index, child)) => {
+                let (new_child, old_value, split) = self.insert_at(pages, child, key, value)?;
+                if let Node::Branch { keys, children } = self.node_mut(id) {
+                    children[index] = new_child;
+                    if let Some((separator, right)) = split {
+                        keys.insert(index, separator);
+                        children.insert(index + 1, right);
+                    }
+                }
+                old_value
+            }
+            None => match self.node_mut(id) {
+                Node::Leaf { keys, values } => match search(keys, &key) {
+                    Ok(index) => Some(replace(&mut values[index], value)),
+                    Err(index) => {
+                        keys.insert(index, key);
+                        values.insert(index, value);
+                        None
+                    }
+                },
+                Node::Branch { .. } => unreachable!(),
+            },
+        };
+
+        Ok((id, old_value, self.split(id)))
+    }
+
+    /// Split the dirty node with the given `id` if it is too large.
+    ///
+    /// This returns the separator key and the ID of the new right sibling.
+    fn split(&mut self, id: Uuid) -> Option<Split> {
+        let node = self.node_mut(id);
+        if node.len() <= MAX_NODE_ENTRIES {
+            return None;
+        }
+
+        let (separator, right) = match node {
+            Node::Leaf { keys, values } => {
+                let middle = keys.len() / 2;
+                let right_keys = keys.split_off(middle);
+                let right_values = values.split_off(middle);
+                let separator = right_keys[0].clone();
+                let right = Node::Leaf {
+                    keys: right_keys,
+                    values: right_values,
+                };
+                (separator, right)
+            }
+            Node::Branch { keys, children } => {
+                let middle = children.len() / 2;
+                let right_children = children.split_off(middle);
+                let right_keys = keys.split_off(middle);
+                let separator = keys.pop().expect("A branch node has no keys.");
+                let right = Node::Branch {
+                    keys: right_keys,
+                    children: right_children,
+                };
+                (separator, right)
+            }
+        };
+
+        let right_id = Uuid::new_v4();
+        self.dirty.insert(right_id, Arc::new(right));
+        Some((separator, right_id))
+    }
+
+    /// Remove the entry with the given `key` from the tree and return its value.
+    pub fn remove(&mut self, pages: &impl PageStore, key: &[u8]) -> crate::Result<Option<V>> {
+        let root = match self.root {
+            Some(root) => root,
+            None => return Ok(None),
+        };
+
+        // Avoid copying nodes on the path to a key which doesn't exist.
+        if self.get(pages, key)?.is_none() {
+            return Ok(None);
+        }
+
+        let (mut root, old_value) = self.remove_at(pages, root, key)?;
+
+        // If the root node has a single child, the tree shrinks by one level.
+        while let Some(id) = root {
+            match self.load(pages, id)?.as_ref() {
+                Node::Branch { children, .. } if children.len() == 1 => {
+                    self.discard(id);
+                    root = Some(children[0]);
+                }
+                _ => break,
+            }
+        }
+        self.root = root;
+        self.len -= 1;
+
+        Ok(Some(old_value))
+    }
+
+    /// Remove the entry with the given `key` from under the node with the given `id`.
+    ///
+    /// The entry must exist. This returns the new ID of the node, or `None` if the node is now
+    /// empty, and the value which was removed.
+    fn remove_at(
+        &mut self,
+        pages: &impl PageStore,
+        id: Uuid,
+        key: &[u8],
+    ) -> crate::Result<(Option<Uuid>, V)> {
+        let id = self.load_mut(pages, id)?;
+        let child = match self.dirty[&id].as_ref() {
+            Node::Branch { keys, children } => {
+                let index = child_index(keys, key);
+                Some((index, children[index]))
+            }
+            Node::Leaf { .. } => None,
+        };
+
+        let old_value = match child {
+            Some((index, child)) => {
+                let (new_child, old_value) = self.remove_at(pages, child, key)?;
+                if let Node::Branch { keys, children } = self.node_mut(id) {
+                    match new_child {
+                        Some(new_child) => children[index] = new_child,
+                        None => {
+                            children.remove(index);
+                            if !keys.is_empty() {
+                                keys.remove(index.saturating_sub(1));
+                            }
+                        }
+                    }
+                }
+                old_value
+            }
+            None => match self.node_mut(id) {
+                Node::Leaf { keys, values } => {
+                    let index = search(keys, key).expect("The key is not in the tree.");
+                    keys.remove(index);
+                    values.remove(index)
+                }
+                Node::Branch { .. } => unreachable!(),
+            },
+        };
+
+        if self.dirty[&id].len() == 0 {
+            self.dirty.remove(&id);
+            return Ok((None, old_value));
+        }
+
+        Ok((Some(id), old_value))
+    }
+
+    /// Call `f` with each entry in the tree in order of their keys.
+    pub fn for_each(
+        &self,
+        pages: &impl PageStore,
+        mut f: impl FnMut(&[u8], &V) -> crate::Result<()>,
+    ) -> crate::Result<()> {
+        let mut stack = self.root.into_iter().collect::<Vec<_>>();
+
+        while let Some(id) = stack.pop() {
+            match self.load(pages, id)?.as_ref() {
+                Node::Leaf { keys, values } => {
+                    for (key, value) in keys.iter().zip(values) {
+                        f(key, value)?;
+                    }
+                }
+                Node::Branch { children, .. } => stack.extend(children.iter().rev()),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Return an iterator over the keys in the tree in order.
+    ///
+    /// Nodes are read from `pages` as the iterator reaches them, so only one leaf is held at a
+    /// time. If a node can't be read, the error is returned and the iterator stops.
+    pub fn keys<P: PageStore>(&self, pages: P) -> Keys<'_, V, P> {
+        Keys {
+            tree: self,
+            pages,
+            stack: self.root.into_iter().collect(),
+            leaf: None,
+        }
+    }
+
+    /// Call `f` with the ID of each page in the tree which has been flushed.
+    pub fn for_each_page(
+        &self,
+        pages: &impl PageStore,
+        mut f: impl FnMut(Uuid),
+    ) -> crate::Result<()> {
+        let mut stack = self.root.into_iter().collect::<Vec<_>>();
+
+        while let Some(id) = stack.pop() {
+            if !self.dirty.contains_key(&id) {
+                f(id);
+            }
+            if let Node::Branch { children, .. } = self.load(pages, id)?.as_ref() {
+                stack.extend(children.iter());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Write all modified nodes to `pages` and return the IDs of pages no longer used by the tree.
+    ///
+    /// The returned pages are still part of the previously flushed version of the tree, so they
+    /// should only be removed once the new version has been committed.
+    pub fn flush(&mut self, pages: &impl PageStore) -> crate::Result<Vec<Uuid>> {
+        let serialized_nodes = self
+            .dirty
+            .iter()
+            .map(|(id, node)| {
+                let serialized_node = to_vec(node.as_ref()).expect("Could not serialize node.");
+                (*id, serialized_node)
+            })
+            .collect::<Vec<_>>();
+        pages.write_pages(&serialized_nodes)?;
+
+        let mut cache = self.cache.lock().unwrap();
+        for (id, node) in self.dirty.drain() {
+            cache.put(id, node);
+        }
+
+        Ok(take(&mut self.freed))
+    }
+}
+
+/// An iterator over the keys of a `BTree`.
+///
+/// This is returned by `BTree::keys`.
+pub struct Keys<'a, V, P> {
+    tree: &'a BTree<V>,
+    pages: P,
+
+    /// The IDs of the nodes which have not been visited yet, with the next one at the end.
+    stack: Vec<Uuid>,
+
+    /// The leaf which is being iterated over and the index of its next key.
+    leaf: Option<(Arc<Node<V>>, usize)>,
+}
+
+impl<'a, V: Clone + Serialize + DeserializeOwned, P: PageStore> Iterator for Keys<'a, V, P> {
+    type Item = crate::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some((node, index)) = &mut self.leaf {
+                if let Node::Leaf { keys, .. } = node.as_ref() {
+                    if let Some(key) = keys.get(*index) {
+                        *index += 1;
+                        return Some(Ok(key.clone()));
+                    }
+                }
+                self.leaf = None;
+            }
+
+            let id = self.stack.pop()?;
+            let node = match self.tree.load(&self.pages, id) {
+                Ok(node) => node,
+                Err(error) => {
+                    self.stack.clear();
+                    return Some(Err(error));
+                }
+            };
+            match node.as_ref() {
+                Node::Branch { children, .. } => self.stack.extend(children.iter().rev()),
+                Node::Leaf { .. } => self.leaf = Some((Arc::clone(&node), 0)),
+            }
+        }
+    }
+}
@@ -14,13 +14,20 @@
  * limitations under the License.
  */
 
+use std::collections::HashSet;
+use std::iter::once;
+
+use rayon::prelude::*;
 use uuid::Uuid;
 
 use crate::repo::Key;
 use crate::store::DataStore;
 
+use super::compression::Dictionaries;
+use super::encryption::EncryptionKey;
+use super::metadata::RepositoryMetadata;
 use super::object::{chunk_hash, Chunk};
-use super::state::RepositoryState;
+use super::state::{CounterSet, EncodingCounters, RepositoryState};
 
 /// Encode and decode chunks of data.
 pub trait ChunkEncoder {
@@ -31,62 +38,210 @@ pub trait ChunkEncoder {
     fn decode_data(&self, data: &[u8]) -> crate::Result<Vec<u8>>;
 }
 
-impl<K: Key, S: DataStore> ChunkEncoder for RepositoryState<K, S> {
-    fn encode_data(&self, data: &[u8]) -> crate::Result<Vec<u8>> {
-        let compressed_data = self.metadata.compression.compress(data)?;
+/// The parts of a repository's state which are needed to encode and decode chunks.
+///
+/// Unlike `RepositoryState`, this can be shared between the threads which encode chunks.
+pub struct ChunkCodec<'a> {
+    metadata: &'a RepositoryMetadata,
+    master_key: &'a EncryptionKey,
+    dictionaries: &'a Dictionaries,
+    counters: CounterSet<'a>,
+}
 
-        Ok(self
+impl<'a> ChunkEncoder for ChunkCodec<'a> {
+    fn encode_data(&self, data: &[u8]) -> crate::Result<Vec<u8>> {
+        // Compress new chunks using the most recently trained dictionary, if there is one.
+        let dictionary = self
             .metadata
-            .encryption
-            .encrypt(compressed_data.as_slice(), &self.master_key))
+            .dictionaries
+            .last()
+            .and_then(|dictionary| self.dictionaries.get(&dictionary.id))
+            .map(Vec::as_slice);
+        let compressed_data = self.counters.time(
+            |counters| &counters.compression_nanos,
+            || self.metadata.compression.compress_with(data, dictionary),
+        )?;
+
+        self.counters.time(
+            |counters| &counters.encryption_nanos,
+            || {
+                self.metadata
+                    .encryption
+                    .encrypt(compressed_data.as_slice(), self.master_key)
+            },
+        )
     }
 
     fn decode_data(&self, data: &[u8]) -> crate::Result<Vec<u8>> {
-        let decrypted_data = self.metadata.encryption.decrypt(data, &self.master_key)?;
+        let decrypted_data = self.counters.time(
+            |counters| &counters.decryption_nanos,
+            || self.metadata.encryption.decrypt(data, self.master_key),
+        )?;
 
-        Ok(self
-            .metadata
-            .compression
-            .decompress(decrypted_data.as_slice())?)
+        Ok(self.counters.time(
+            |counters| &counters.decompression_nanos,
+            || {
+                self.metadata
+                    .compression
+                    .decompress_with(decrypted_data.as_slice(), self.dictionaries)
+            },
+        )?)
+    }
+}
+
+impl<K: Key, S: DataStore> RepositoryState<K, S> {
+    /// Return a `ChunkCodec` which encodes and decodes chunks for this repository.
+    pub fn codec(&self) -> ChunkCodec {
+        self.codec_for(None)
+    }
+
+    /// Return a `ChunkCodec` which also counts its work against the given `object` counters.
+    pub fn codec_for<'a>(&'a self, object: Option<&'a EncodingCounters>) -> ChunkCodec<'a> {
+        ChunkCodec {
+            metadata: &self.metadata,
+            master_key: &self.master_key,
+            dictionaries: &self.dictionaries,
+            counters: self.counters_for(object),
+        }
+    }
+
+    /// Return the counters which work done for the given `object` counters is added to.
+    fn counters_for<'a>(&'a self, object: Option<&'a EncodingCounters>) -> CounterSet<'a> {
+        CounterSet {
+            repository: &self.counters,
+            object,
+        }
+    }
+}
+
+impl<K: Key, S: DataStore> ChunkEncoder for RepositoryState<K, S> {
+    fn encode_data(&self, data: &[u8]) -> crate::Result<Vec<u8>> {
+        self.codec().encode_data(data)
+    }
+
+    fn decode_data(&self, data: &[u8]) -> crate::Result<Vec<u8>> {
+        self.codec().decode_data(data)
     }
 }
 
 /// Read chunks of data.
+///
+/// Each method accepts the `EncodingCounters` of the object handle doing the reading, if there is
+/// one, which the cost of the read is added to along with the repository's counters.
 pub trait ChunkReader {
     /// Return the bytes of the chunk with the given checksum.
-    fn read_chunk(&self, chunk: Chunk) -> crate::Result<Vec<u8>>;
+    fn read_chunk(&self, chunk: Chunk, object: Option<&EncodingCounters>)
+        -> crate::Result<Vec<u8>>;
+
+    /// Return the bytes of each of the given `chunks` in order.
+    ///
+    /// If the repository uses more than one encoding thread, the chunks are decoded in parallel.
+    fn read_chunks(
+        &self,
+        chunks: &[Chunk],
+        object: Option<&EncodingCounters>,
+    ) -> crate::Result<Vec<Vec<u8>>>;
 }
 
-impl<K: Key, S: DataStore> ChunkReader for RepositoryState<K, S> {
-    fn read_chunk(&self, chunk: Chunk) -> crate::Result<Vec<u8>> {
-        let chunk_id = *self
+impl<K: Key, S: DataStore> RepositoryState<K, S> {
+    /// Read the encoded contents of the given `chunk` from the data store.
+    fn read_encoded_chunk(
+        &self,
+        chunk: &Chunk,
+        object: Option<&EncodingCounters>,
+    ) -> crate::Result<Vec<u8>> {
+        let chunk_id = self
             .header
-            .chunks
-            .get(&chunk)
+            .chunk_block(&self.pages(), chunk)?
             .ok_or(crate::Error::InvalidData)?;
-        let chunk = self
+        let encoded_chunk = self
             .store
             .lock()
             .unwrap()
             .read_block(chunk_id)
             .map_err(anyhow::Error::from)?
             .ok_or(crate::Error::InvalidData)?;
+        self.counters_for(object)
+            .add(|counters| &counters.bytes_read, encoded_chunk.len() as u64);
+        Ok(encoded_chunk)
+    }
+}
 
-        self.decode_data(chunk.as_slice())
+impl<K: Key, S: DataStore> ChunkReader for RepositoryState<K, S> {
+    fn read_chunk(
+        &self,
+        chunk: Chunk,
+        object: Option<&EncodingCounters>,
+    ) -> crate::Result<Vec<u8>> {
+        let encoded_chunk = self.read_encoded_chunk(&chunk, object)?;
+        self.codec_for(object).decode_data(encoded_chunk.as_slice())
+    }
+
+    fn read_chunks(
+        &self,
+        chunks: &[Chunk],
+        object: Option<&EncodingCounters>,
+    ) -> crate::Result<Vec<Vec<u8>>> {
+        let pool = match self.pool.get(self.encoding_threads)? {
+            Some(pool) => pool,
+            None => {
+                return chunks
+                    .iter()
+                    .map(|chunk| self.read_chunk(*chunk, object))
+                    .collect()
+            }
+        };
+
+        // Blocks are read from the data store in order on this thread.
+        let encoded_chunks = chunks
+            .iter()
+            .map(|chunk| self.read_encoded_chunk(chunk, object))
+            .collect::<crate::Result<Vec<_>>>()?;
+
+        let codec = self.codec_for(object);
+        pool.install(|| {
+            encoded_chunks
+                .par_iter()
+                .map(|encoded_chunk| codec.decode_data(encoded_chunk.as_slice()))
+                .collect()
+        })
     }
 }
 
 /// Write chunks of data.
+///
+/// Like `ChunkReader`, each method accepts the `EncodingCounters` of the object handle doing the
+/// writing, if there is one.
 pub trait ChunkWriter {
     /// Write the given `data` as a new chunk and returns its checksum.
     ///
     /// If a chunk with the given `data` already exists, its checksum may be returned without
     /// writing any new data.
-    fn write_chunk(&mut self, data: &[u8]) -> crate::Result<Chunk>;
+    fn write_chunk(
+        &mut self,
+        data: &[u8],
+        object: Option<&EncodingCounters>,
+    ) -> crate::Result<Chunk>;
+
+    /// Write each of the given `chunks` of data and return their checksums in order.
+    ///
+    /// This is equivalent to calling `write_chunk` for each chunk, except that if the repository
+    /// uses more than one encoding thread, the chunks are encoded in parallel in batches of
+    /// `encoding_threads`, and each batch is encoded while the previous one is written to the data
+    /// store.
+    fn write_chunks(
+        &mut self,
+        chunks: &[Vec<u8>],
+        object: Option<&EncodingCounters>,
+    ) -> crate::Result<Vec<Chunk>>;
 }
 
 impl<K: Key, S: DataStore> ChunkWriter for RepositoryState<K, S> {
-    fn write_chunk(&mut self, data: &[u8]) -> crate::Result<Chunk> {
+    fn write_chunk(
+        &mut self,
+        data: &[u8],
+        object: Option<&EncodingCounters>,
+    ) -> crate::Result<Chunk> {
         // Get a checksum of the unencoded data.
         let chunk = Chunk {
             hash: chunk_hash(data),
@@ -94,12 +249,12 @@ impl<K: Key, S: DataStore> ChunkWriter for RepositoryState<K, S> {
         };
 
         // Check if the chunk already exists.
-        if self.header.chunks.contains_key(&chunk) {
+        if self.header.chunk_block(&self.pages(), &chunk)?.is_some() {
             return Ok(chunk);
         }
 
         // Encode the data.
-        let encoded_data = self.encode_data(data)?;
+        let encoded_data = self.codec_for(object).encode_data(data)?;
         let block_id = Uuid::new_v4();
 
         // Write the data to the data store.
@@ -108,10 +263,109 @@ impl<K: Key, S: DataStore> ChunkWriter for RepositoryState<K, S> {
             .unwrap()
             .write_block(block_id, &encoded_data)
             .map_err(anyhow::Error::from)?;
+        self.counters_for(object).add(
+            |counters| &counters.bytes_written,
+            encoded_data.len() as u64,
+        );
 
         // Add the chunk to the header.
-        self.header.chunks.insert(chunk, block_id);
+        self.header.insert_chunk(chunk, block_id);
 
         Ok(chunk)
     }
+
+    fn write_chunks(
+        &mut self,
+        chunks: &[Vec<u8>],
+        object: Option<&EncodingCounters>,
+    ) -> crate::Result<Vec<Chunk>> {
+        let pool = match self.pool.get(self.encoding_threads)? {
+            Some(pool) => pool,
+            None => {
+                return chunks
+                    .iter()
+                    .map(|data| self.write_chunk(data, object))
+                    .collect()
+            }
+        };
+
+        // Get a checksum of the unencoded data of each chunk.
+        let checksums = pool.install(|| {
+            chunks
+                .par_iter()
+                .map(|data| Chunk {
+                    hash: chunk_hash(data),
+                    size: data.len(),
+                })
+                .collect::<Vec<_>>()
+        });
+
+        // Find the chunks which don't already exist, including those which are repeated within
+        // this batch.
+        let mut new_checksums = HashSet::new();
+        let mut new_indices = Vec::new();
+        for (index, chunk) in checksums.iter().enumerate() {
+            if !new_checksums.contains(chunk)
+                && self.header.chunk_block(&self.pages(), chunk)?.is_none()
+            {
+                new_checksums.insert(*chunk);
+                new_indices.push(index);
+            }
+        }
+
+        // Encode each batch of new chunks on the pool while the previous batch is written to the
+        // data store on this thread. The extra `None` at the end writes the last batch.
+        let codec = self.codec_for(object);
+        let mut written_blocks = Vec::with_capacity(new_indices.len());
+        let mut encoded_batch: Vec<(Uuid, Vec<u8>)> = Vec::new();
+        let batches = new_indices
+            .chunks(self.encoding_threads)
+            .map(Some)
+            .chain(once(None));
+        for batch in batches {
+            let mut next_batch = None;
+            let write_result = pool.in_place_scope(|scope| {
+                if let Some(batch) = batch {
+                    let next_batch = &mut next_batch;
+                    let codec = &codec;
+                    scope.spawn(move |_| {
+                        *next_batch = Some(
+                            batch
+                                .par_iter()
+                                .map(|&index| codec.encode_data(chunks[index].as_slice()))
+                                .collect::<crate::Result<Vec<_>>>(),
+                        );
+                    });
+                }
+
+                let blocks = encoded_batch
+                    .iter()
+                    .map(|(block_id, encoded_data)| (*block_id, encoded_data.as_slice()))
+                    .collect::<Vec<_>>();
+                self.store.lock().unwrap().write_blocks(&blocks)
+            });
+            write_result.map_err(anyhow::Error::from)?;
+
+            written_blocks.extend(
+                encoded_batch
+                    .drain(..)
+                    .map(|(block_id, encoded_data)| (block_id, encoded_data.len())),
+            );
+            if let Some(next_batch) = next_batch {
+                encoded_batch = next_batch?
+                    .into_iter()
+                    .map(|encoded_data| (Uuid::new_v4(), encoded_data))
+                    .collect();
+            }
+        }
+
+        // Add the written chunks to the header.
+        for (index, (block_id, size)) in new_indices.into_iter().zip(written_blocks) {
+            self.counters_for(object)
+                .add(|counters| &counters.bytes_written, size as u64);
+            self.header.insert_chunk(checksums[index], block_id);
+        }
+
+        Ok(checksums)
+    }
 }
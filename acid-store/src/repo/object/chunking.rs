@@ -14,10 +14,285 @@
  * limitations under the License.
  */
 
-use cdchunking::ChunkerImpl;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
 use std::io::{self, Write};
 use std::mem::replace;
 
+lazy_static! {
+    /// The table of random values used by the FastCDC rolling hash.
+    ///
+    /// This is generated from a fixed seed using SplitMix64. Changing it would change the chunk
+    /// boundaries in every repository which uses FastCDC.
+    static ref GEAR: [u64; 256] = {
+        let mut state = 0x6a09_e667_f3bc_c908u64;
+        let mut table = [0u64; 256];
+        for value in table.iter_mut() {
+            state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+            let mut mixed = state;
+            mixed = (mixed ^ (mixed >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+            mixed = (mixed ^ (mixed >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+            *value = mixed ^ (mixed >> 31);
+        }
+        table
+    };
+}
+
+/// A method for finding chunk boundaries in a stream of data.
+pub trait ChunkerImpl {
+    /// Look for a chunk boundary in the next bytes of the stream.
+    ///
+    /// This returns the number of bytes in `data` which belong to the current chunk if a boundary
+    /// was found or `None` if the whole of `data` belongs to the current chunk.
+    fn find_boundary(&mut self, data: &[u8]) -> Option<usize>;
+
+    /// Reset the state of the chunker at the start of a new chunk.
+    fn reset(&mut self);
+}
+
+/// A method for splitting data into chunks.
+///
+/// Data is deduplicated, read into memory, and written to the data store in chunks. The chunk size
+/// affects deduplication ratios, memory usage, and I/O performance. Some experimentation may be
+/// required to determine the optimal chunking method for a given workload.
+///
+/// The chunking method is stored in the repository so that the same data is always split into
+/// the same chunks.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub enum Chunking {
+    /// Content-defined chunking using the ZPAQ algorithm.
+    ///
+    /// The average size of chunks is 2^`bits` bytes. Chunks have no minimum or maximum size.
+    /// `bits` must be between 1 and 31.
+    Zpaq {
+        /// The number of bits which determine a chunk boundary.
+        bits: u32,
+    },
+
+    /// Content-defined chunking using the FastCDC algorithm.
+    ///
+    /// Chunks are between `min_size` and `max_size` bytes, and their sizes are normalized around
+    /// `avg_size`. FastCDC is faster than ZPAQ and avoids very small and very large chunks.
+    /// `min_size` must be at least 1 and no more than `avg_size`, which must be no more than
+    /// `max_size`.
+    FastCdc {
+        /// The minimum size of a chunk in bytes.
+        min_size: usize,
+
+        /// The target average size of a chunk in bytes.
+        avg_size: usize,
+
+        /// The maximum size of a chunk in bytes.
+        max_size: usize,
+    },
+
+    /// Split data into chunks of `size` bytes.
+    ///
+    /// This is fast and deduplicates well for data which is modified in place, like database
+    /// files and disk images. Inserting or removing bytes changes every chunk after that point.
+    /// `size` must be at least 1.
+    Fixed {
+        /// The size of a chunk in bytes.
+        size: usize,
+    },
+}
+
+impl Chunking {
+    /// Return whether the parameters of this chunking method are valid.
+    pub(super) fn is_valid(&self) -> bool {
+        match *self {
+            Chunking::Zpaq { bits } => (1..=31).contains(&bits),
+            Chunking::FastCdc {
+                min_size,
+                avg_size,
+                max_size,
+            } => min_size >= 1 && min_size <= avg_size && avg_size <= max_size,
+            Chunking::Fixed { size } => size >= 1,
+        }
+    }
+
+    /// Return a new chunker which uses this chunking method.
+    pub(super) fn chunker(&self) -> Chunker {
+        match *self {
+            Chunking::Zpaq { bits } => Chunker::Zpaq(Box::new(Zpaq::new(bits))),
+            Chunking::FastCdc {
+                min_size,
+                avg_size,
+                max_size,
+            } => Chunker::FastCdc(FastCdc::new(min_size, avg_size, max_size)),
+            Chunking::Fixed { size } => Chunker::Fixed(FixedChunker { size, position: 0 }),
+        }
+    }
+}
+
+/// A chunker for any of the methods in `Chunking`.
+pub enum Chunker {
+    Zpaq(Box<Zpaq>),
+    FastCdc(FastCdc),
+    Fixed(FixedChunker),
+}
+
+impl ChunkerImpl for Chunker {
+    fn find_boundary(&mut self, data: &[u8]) -> Option<usize> {
+        match self {
+            Chunker::Zpaq(chunker) => chunker.find_boundary(data),
+            Chunker::FastCdc(chunker) => chunker.find_boundary(data),
+            Chunker::Fixed(chunker) => chunker.find_boundary(data),
+        }
+    }
+
+    fn reset(&mut self) {
+        match self {
+            Chunker::Zpaq(chunker) => chunker.reset(),
+            Chunker::FastCdc(chunker) => chunker.reset(),
+            Chunker::Fixed(chunker) => chunker.reset(),
+        }
+    }
+}
+
+/// A chunker which implements the ZPAQ algorithm.
+///
+/// Boundaries are found using a rolling hash which is predicted from the previous occurrence of
+/// each byte, as in the ZPAQ archiver.
+pub struct Zpaq {
+    max_hash: u32,
+    hash: u32,
+    last_byte: u8,
+    predictions: [u8; 256],
+}
+
+impl Zpaq {
+    /// Return a new instance which finds a boundary every 2^`bits` bytes on average.
+    fn new(bits: u32) -> Self {
+        Self {
+            max_hash: 1 << (32 - bits),
+            hash: 0,
+            last_byte: 0,
+            predictions: [0; 256],
+        }
+    }
+}
+
+impl ChunkerImpl for Zpaq {
+    fn find_boundary(&mut self, data: &[u8]) -> Option<usize> {
+        for (index, &byte) in data.iter().enumerate() {
+            let multiplier = if byte == self.predictions[self.last_byte as usize] {
+                314_159_265
+            } else {
+                271_828_182
+            };
+            self.hash = self
+                .hash
+                .wrapping_add(u32::from(byte) + 1)
+                .wrapping_mul(multiplier);
+            self.predictions[self.last_byte as usize] = byte;
+            self.last_byte = byte;
+
+            if self.hash < self.max_hash {
+                return Some(index + 1);
+            }
+        }
+
+        None
+    }
+
+    fn reset(&mut self) {
+        self.hash = 0;
+        self.last_byte = 0;
+        self.predictions = [0; 256];
+    }
+}
+
+/// A chunker which implements the FastCDC algorithm with normalized chunking.
+///
+/// Boundaries are found using a gear hash. A stricter mask is used before the chunk reaches the
+/// average size and a looser one after, which keeps chunk sizes close to the average.
+pub struct FastCdc {
+    min_size: usize,
+    avg_size: usize,
+    max_size: usize,
+    small_mask: u64,
+    large_mask: u64,
+    position: usize,
+    hash: u64,
+}
+
+impl FastCdc {
+    /// Return a new instance with the given chunk size bounds.
+    fn new(min_size: usize, avg_size: usize, max_size: usize) -> Self {
+        // Use the high bits of the hash, which depend on the most bytes.
+        let bits = 63 - (avg_size as u64).leading_zeros();
+        let high_mask = |bits: u32| match bits {
+            0 => 0,
+            bits => u64::MAX << (64 - bits.min(64)),
+        };
+        Self {
+            min_size,
+            avg_size,
+            max_size,
+            small_mask: high_mask(bits + 1),
+            large_mask: high_mask(bits.saturating_sub(1)),
+            position: 0,
+            hash: 0,
+        }
+    }
+}
+
+impl ChunkerImpl for FastCdc {
+    fn find_boundary(&mut self, data: &[u8]) -> Option<usize> {
+        for (index, byte) in data.iter().enumerate() {
+            self.position += 1;
+            if self.position >= self.max_size {
+                return Some(index + 1);
+            }
+
+            // The hash doesn't need to be computed until the chunk reaches the minimum size.
+            if self.position < self.min_size {
+                continue;
+            }
+
+            self.hash = (self.hash << 1).wrapping_add(GEAR[*byte as usize]);
+            let mask = if self.position < self.avg_size {
+                self.small_mask
+            } else {
+                self.large_mask
+            };
+            if self.hash & mask == 0 {
+                return Some(index + 1);
+            }
+        }
+
+        None
+    }
+
+    fn reset(&mut self) {
+        self.position = 0;
+        self.hash = 0;
+    }
+}
+
+/// A chunker which splits data into chunks of a fixed size.
+pub struct FixedChunker {
+    size: usize,
+    position: usize,
+}
+
+impl ChunkerImpl for FixedChunker {
+    fn find_boundary(&mut self, data: &[u8]) -> Option<usize> {
+        let remaining = self.size - self.position;
+        if data.len() >= remaining {
+            Some(remaining)
+        } else {
+            self.position += data.len();
+            None
+        }
+    }
+
+    fn reset(&mut self) {
+        self.position = 0;
+    }
+}
+
 /// A chunker which partitions data written to it into chunks.
 pub struct IncrementalChunker<T: ChunkerImpl> {
     chunker: T,
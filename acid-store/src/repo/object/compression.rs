@@ -14,6 +14,8 @@
  * limitations under the License.
  */
 
+use std::collections::HashMap;
+
 use serde::{Deserialize, Serialize};
 
 #[cfg(feature = "compression")]
@@ -23,8 +25,13 @@ use {
     lz4::{Decoder as Lz4Decoder, EncoderBuilder as Lz4EncoderBuilder},
     std::io::{Read, Write},
     xz2::read::{XzDecoder, XzEncoder},
+    zstd::stream::read::{Decoder as ZstdDecoder, Encoder as ZstdEncoder},
+    zstd_safe::{get_dict_id_from_dict, get_dict_id_from_frame},
 };
 
+/// A map of the IDs of trained compression dictionaries to their contents.
+pub type Dictionaries = HashMap<u32, Vec<u8>>;
+
 /// A data compression method.
 #[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
 #[non_exhaustive]
@@ -58,11 +65,35 @@ pub enum Compression {
         /// This is usually a number in the range 0-9.
         level: u32,
     },
+
+    /// Compress data using the Zstandard compression algorithm.
+    ///
+    /// Chunks can optionally be compressed using a dictionary trained with
+    /// `ObjectRepository::train_dictionary`.
+    #[cfg(feature = "compression")]
+    Zstd {
+        /// The compression level to use.
+        ///
+        /// This is usually a number in the range 1-22. Negative levels are faster but compress
+        /// less.
+        level: i32,
+    },
 }
 
 impl Compression {
     /// Compresses the given `data` and returns it.
     pub(super) fn compress(&self, data: &[u8]) -> crate::Result<Vec<u8>> {
+        self.compress_with(data, None)
+    }
+
+    /// Compresses the given `data` using the given `dictionary` and returns it.
+    ///
+    /// The `dictionary` is ignored unless this is `Compression::Zstd`.
+    pub(super) fn compress_with(
+        &self,
+        data: &[u8],
+        dictionary: Option<&[u8]>,
+    ) -> crate::Result<Vec<u8>> {
         match self {
             Compression::None => Ok(data.to_vec()),
             #[cfg(feature = "compression")]
@@ -86,11 +117,34 @@ impl Compression {
                 result?;
                 Ok(output)
             }
+            #[cfg(feature = "compression")]
+            Compression::Zstd { level } => {
+                let mut output = Vec::with_capacity(data.len());
+                match dictionary {
+                    Some(dictionary) => ZstdEncoder::with_dictionary(data, *level, dictionary)?
+                        .read_to_end(&mut output)?,
+                    None => ZstdEncoder::new(data, *level)?.read_to_end(&mut output)?,
+                };
+                Ok(output)
+            }
         }
     }
 
-    /// Wraps the given `reader` to decompress its bytes using this compression method.
-    pub(super) fn decompress<'a>(&self, data: &[u8]) -> crate::Result<Vec<u8>> {
+    /// Decompresses the given `data` and returns it.
+    pub(super) fn decompress(&self, data: &[u8]) -> crate::Result<Vec<u8>> {
+        self.decompress_with(data, &Dictionaries::new())
+    }
+
+    /// Decompresses the given `data` using the dictionary it was compressed with and returns it.
+    ///
+    /// # Errors
+    /// - `Error::Corrupt`: The dictionary the data was compressed with is not in `dictionaries`.
+    /// - `Error::Io`: An I/O error occurred.
+    pub(super) fn decompress_with(
+        &self,
+        data: &[u8],
+        dictionaries: &Dictionaries,
+    ) -> crate::Result<Vec<u8>> {
         match self {
             Compression::None => Ok(data.to_vec()),
             #[cfg(feature = "compression")]
@@ -114,6 +168,35 @@ impl Compression {
                 result?;
                 Ok(output)
             }
+            #[cfg(feature = "compression")]
+            Compression::Zstd { .. } => {
+                let mut output = Vec::with_capacity(data.len());
+                match get_dict_id_from_frame(data) {
+                    0 => ZstdDecoder::new(data)?.read_to_end(&mut output)?,
+                    dictionary_id => {
+                        let dictionary = dictionaries
+                            .get(&dictionary_id)
+                            .ok_or(crate::Error::Corrupt)?;
+                        ZstdDecoder::with_dictionary(data, dictionary)?.read_to_end(&mut output)?
+                    }
+                };
+                Ok(output)
+            }
         }
     }
 }
+
+/// Train a Zstandard compression dictionary of at most `max_size` bytes using `samples`.
+///
+/// This returns the ID of the dictionary and its contents.
+///
+/// # Errors
+/// - `Error::Io`: The dictionary could not be trained.
+#[cfg(feature = "compression")]
+pub fn train_dictionary<T: AsRef<[u8]>>(
+    samples: &[T],
+    max_size: usize,
+) -> crate::Result<(u32, Vec<u8>)> {
+    let dictionary = zstd::dict::from_samples(samples, max_size)?;
+    Ok((get_dict_id_from_dict(&dictionary), dictionary))
+}
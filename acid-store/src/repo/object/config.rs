@@ -14,27 +14,46 @@
  * limitations under the License.
  */
 
+use super::chunking::Chunking;
 use super::compression::Compression;
 use super::encryption::{Encryption, ResourceLimit};
+use super::header::HeaderMode;
+
+/// The number of bits which determine the average chunk size by default.
+const DEFAULT_CHUNKER_BITS: u32 = 20;
+
+/// The default chunking method.
+const DEFAULT_CHUNKING: Chunking = Chunking::Zpaq {
+    bits: DEFAULT_CHUNKER_BITS,
+};
 
 /// The configuration for an repository.
 ///
 /// This type is used to configure a repository when it is created. Once a repository is created,
-/// the config values provided cannot be changed. This type implements `Default` to provide a
-/// reasonable default configuration.
-#[derive(Debug, Clone, PartialEq, Eq)]
+/// its configuration can only be changed by rewriting its data with
+/// `ObjectRepository::reconfigure`. This type implements `Default` to provide a reasonable default
+/// configuration.
+#[derive(Debug, Clone)]
 #[non_exhaustive]
 pub struct RepositoryConfig {
-    /// A value which determines the chunk size for the repository.
+    /// The method used to split data into chunks.
     ///
-    /// Data is deduplicated, read into memory, and written to the data store in chunks. This value
-    /// determines the average size of those chunks which will be 2^`chunker_bits` bytes.
+    /// Data is deduplicated, read into memory, and written to the data store in chunks. The chunk
+    /// size affects deduplication ratios, memory usage, and I/O performance. The default chunking
+    /// method should be fine for most cases.
+    ///
+    /// The default value is `Chunking::Zpaq { bits: 20 }` (1MiB average chunk size).
+    pub chunking: Chunking,
+
+    /// A value which determines the chunk size for the repository.
     ///
-    /// The chunk size affects deduplication ratios, memory usage, and I/O performance. Some
-    /// experimentation may be required to determine the optimal chunk size for a given workload.
-    /// The default chunk size should be fine for most cases.
+    /// If `chunking` is left at its default value, the repository uses
+    /// `Chunking::Zpaq { bits: chunker_bits }`. Otherwise, this value is ignored. The config
+    /// returned by `RepositoryInfo::config` always has the default value here, so that changing
+    /// `chunking` in it has an effect.
     ///
     /// The default value is `20` (1MiB average chunk size).
+    #[deprecated(since = "0.12.0", note = "Use `chunking` instead.")]
     pub chunker_bits: u32,
 
     /// The compression method to use in the repository.
@@ -56,16 +75,60 @@ pub struct RepositoryConfig {
     ///
     /// The default value is `ResourceLimit::Interactive`.
     pub operations_limit: ResourceLimit,
+
+    /// How the header of the repository is stored.
+    ///
+    /// Use `HeaderMode::Paged` for repositories with too many objects or chunks to keep their
+    /// whole header in memory.
+    ///
+    /// The default value is `HeaderMode::Memory`.
+    pub header_mode: HeaderMode,
 }
 
+impl RepositoryConfig {
+    /// Return whether the values in this config are valid.
+    pub(super) fn is_valid(&self) -> bool {
+        self.chunking_method().is_valid()
+    }
+
+    /// Return the chunking method to use, taking the deprecated `chunker_bits` into account.
+    #[allow(deprecated)]
+    pub(super) fn chunking_method(&self) -> Chunking {
+        if self.chunking == DEFAULT_CHUNKING {
+            Chunking::Zpaq {
+                bits: self.chunker_bits,
+            }
+        } else {
+            self.chunking
+        }
+    }
+}
+
+impl PartialEq for RepositoryConfig {
+    fn eq(&self, other: &Self) -> bool {
+        // Configs which select the same chunking method are equal, whichever field selects it.
+        self.chunking_method() == other.chunking_method()
+            && self.compression == other.compression
+            && self.encryption == other.encryption
+            && self.memory_limit == other.memory_limit
+            && self.operations_limit == other.operations_limit
+            && self.header_mode == other.header_mode
+    }
+}
+
+impl Eq for RepositoryConfig {}
+
 impl Default for RepositoryConfig {
+    #[allow(deprecated)]
     fn default() -> Self {
         RepositoryConfig {
-            chunker_bits: 20,
+            chunking: DEFAULT_CHUNKING,
+            chunker_bits: DEFAULT_CHUNKER_BITS,
             compression: Compression::None,
             encryption: Encryption::None,
             memory_limit: ResourceLimit::Interactive,
             operations_limit: ResourceLimit::Interactive,
+            header_mode: HeaderMode::Memory,
         }
     }
 }
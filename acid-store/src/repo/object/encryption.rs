@@ -22,17 +22,31 @@ use zeroize::Zeroize;
 use {
     rand::rngs::OsRng,
     rand::RngCore,
+    sodiumoxide::crypto::aead::aes256gcm::{
+        self, Aes256Gcm, Key as AesKey, Nonce as AesNonce, NONCEBYTES as AES_NONCEBYTES,
+    },
     sodiumoxide::crypto::aead::xchacha20poly1305_ietf::{
         gen_nonce, open, seal, Key as ChaChaKey, Nonce, KEYBYTES, NONCEBYTES,
     },
+    sodiumoxide::crypto::generichash,
     sodiumoxide::crypto::pwhash::argon2id13::{
         derive_key, gen_salt, MemLimit, OpsLimit, Salt, MEMLIMIT_INTERACTIVE, MEMLIMIT_MODERATE,
         MEMLIMIT_SENSITIVE, OPSLIMIT_INTERACTIVE, OPSLIMIT_MODERATE, OPSLIMIT_SENSITIVE,
     },
+    sodiumoxide::utils::memcmp,
 };
 
 static ENCRYPTION_INIT: Once = Once::new();
 
+/// The size of the commitment to the key which is stored with data encrypted by a key-committing
+/// cipher.
+#[cfg(feature = "encryption")]
+const COMMITMENT_BYTES: usize = 32;
+
+/// The context which is hashed along with the nonce to produce a key commitment.
+#[cfg(feature = "encryption")]
+const COMMITMENT_CONTEXT: &[u8] = b"acid-store key commitment";
+
 /// Initialize the environment for encryption.
 fn init() {
     #[cfg(feature = "encryption")]
@@ -86,42 +100,182 @@ pub enum Encryption {
     /// Encrypt data using the XChaCha20-Poly1305 cipher.
     #[cfg(feature = "encryption")]
     XChaCha20Poly1305,
+
+    /// Encrypt data using the AES-256-GCM cipher.
+    ///
+    /// This is only available on x86 and x86-64 CPUs with the AES-NI and CLMUL instructions, where
+    /// it is faster than XChaCha20-Poly1305. A repository which uses this cipher cannot be created
+    /// or opened on a machine without them.
+    ///
+    /// Nonces are generated randomly, and because they are shorter than those used by
+    /// XChaCha20-Poly1305, no more than about 2^32 blocks should be written to a repository.
+    #[cfg(feature = "encryption")]
+    Aes256Gcm,
+
+    /// Encrypt data using the XChaCha20-Poly1305 cipher with a commitment to the key.
+    ///
+    /// Like most AEAD ciphers, XChaCha20-Poly1305 and AES-256-GCM allow a ciphertext to be crafted
+    /// which decrypts successfully under more than one key. This stores a BLAKE2b hash of the key
+    /// and nonce with each block and checks it before decrypting, so a block can only be decrypted
+    /// with the key which encrypted it. This adds 32 bytes to each block.
+    #[cfg(feature = "encryption")]
+    XChaCha20Poly1305Committing,
+
+    /// Encrypt data using the AES-256-GCM cipher with a commitment to the key.
+    ///
+    /// This has the same hardware requirements as `Aes256Gcm` and commits to the key the same way
+    /// as `XChaCha20Poly1305Committing`.
+    #[cfg(feature = "encryption")]
+    Aes256GcmCommitting,
+
+    /// Choose a cipher automatically when the repository is created.
+    ///
+    /// This selects `Aes256Gcm` if the CPU supports hardware-accelerated AES and
+    /// `XChaCha20Poly1305` otherwise. The cipher which was selected is reported by
+    /// `RepositoryInfo::encryption`.
+    #[cfg(feature = "encryption")]
+    Auto,
 }
 
 impl Encryption {
     /// Encrypt the given `cleartext` with the given `key`.
-    pub(super) fn encrypt(&self, cleartext: &[u8], key: &EncryptionKey) -> Vec<u8> {
+    ///
+    /// # Errors
+    /// - `Error::Unsupported`: The cipher is not supported on this machine.
+    pub(super) fn encrypt(&self, cleartext: &[u8], key: &EncryptionKey) -> crate::Result<Vec<u8>> {
         init();
         match self {
-            Encryption::None => cleartext.to_vec(),
-            #[cfg(feature = "encryption")]
-            Encryption::XChaCha20Poly1305 => {
-                let nonce = gen_nonce();
-                let chacha_key = ChaChaKey::from_slice(key.0.as_ref()).unwrap();
-                let mut ciphertext = seal(&cleartext, None, &nonce, &chacha_key);
-                let mut output = nonce.as_ref().to_vec();
-                output.append(&mut ciphertext);
-                output
-            }
+            Encryption::None => Ok(cleartext.to_vec()),
+            #[cfg(feature = "encryption")]
+            Encryption::XChaCha20Poly1305 => Ok(seal_xchacha(cleartext, key, false)),
+            #[cfg(feature = "encryption")]
+            Encryption::Aes256Gcm => seal_aes(cleartext, key, false),
+            #[cfg(feature = "encryption")]
+            Encryption::XChaCha20Poly1305Committing => Ok(seal_xchacha(cleartext, key, true)),
+            #[cfg(feature = "encryption")]
+            Encryption::Aes256GcmCommitting => seal_aes(cleartext, key, true),
+            #[cfg(feature = "encryption")]
+            Encryption::Auto => self.resolve().encrypt(cleartext, key),
         }
     }
 
     /// Decrypt the given `ciphertext` with the given `key`.
+    ///
+    /// # Errors
+    /// - `Error::InvalidData`: Ciphertext verification failed.
+    /// - `Error::Unsupported`: The cipher is not supported on this machine.
     pub(super) fn decrypt(&self, ciphertext: &[u8], key: &EncryptionKey) -> crate::Result<Vec<u8>> {
         init();
         match self {
             Encryption::None => Ok(ciphertext.to_vec()),
             #[cfg(feature = "encryption")]
-            Encryption::XChaCha20Poly1305 => {
-                let nonce = Nonce::from_slice(&ciphertext[..NONCEBYTES]).unwrap();
-                let chacha_key = ChaChaKey::from_slice(key.0.as_ref()).unwrap();
-                open(&ciphertext[NONCEBYTES..], None, &nonce, &chacha_key)
-                    .map_err(|_| crate::Error::InvalidData)
-            }
+            Encryption::XChaCha20Poly1305 => open_xchacha(ciphertext, key, false),
+            #[cfg(feature = "encryption")]
+            Encryption::Aes256Gcm => open_aes(ciphertext, key, false),
+            #[cfg(feature = "encryption")]
+            Encryption::XChaCha20Poly1305Committing => open_xchacha(ciphertext, key, true),
+            #[cfg(feature = "encryption")]
+            Encryption::Aes256GcmCommitting => open_aes(ciphertext, key, true),
+            #[cfg(feature = "encryption")]
+            Encryption::Auto => self.resolve().decrypt(ciphertext, key),
         }
     }
 }
 
+/// Return a commitment to `key` which is stored alongside data encrypted with `nonce`.
+#[cfg(feature = "encryption")]
+fn key_commitment(key: &EncryptionKey, nonce: &[u8]) -> Vec<u8> {
+    let mut state = generichash::State::new(Some(COMMITMENT_BYTES), Some(key.as_ref())).unwrap();
+    state.update(COMMITMENT_CONTEXT).unwrap();
+    state.update(nonce).unwrap();
+    state.finalize().unwrap().as_ref().to_vec()
+}
+
+/// Return `nonce` followed by a commitment to `key` if `committing` is `true` and `ciphertext`.
+#[cfg(feature = "encryption")]
+fn frame_ciphertext(
+    nonce: &[u8],
+    key: &EncryptionKey,
+    committing: bool,
+    mut ciphertext: Vec<u8>,
+) -> Vec<u8> {
+    let mut output = nonce.to_vec();
+    if committing {
+        output.extend_from_slice(&key_commitment(key, nonce));
+    }
+    output.append(&mut ciphertext);
+    output
+}
+
+/// Split `data` into its nonce and ciphertext, checking the commitment to `key` if `committing` is
+/// `true`.
+#[cfg(feature = "encryption")]
+fn unframe_ciphertext<'a>(
+    data: &'a [u8],
+    nonce_size: usize,
+    key: &EncryptionKey,
+    committing: bool,
+) -> crate::Result<(&'a [u8], &'a [u8])> {
+    let header_size = if committing {
+        nonce_size + COMMITMENT_BYTES
+    } else {
+        nonce_size
+    };
+    if data.len() < header_size {
+        return Err(crate::Error::InvalidData);
+    }
+    let (nonce, rest) = data.split_at(nonce_size);
+    let (commitment, ciphertext) = rest.split_at(header_size - nonce_size);
+    if committing && !memcmp(commitment, &key_commitment(key, nonce)) {
+        return Err(crate::Error::InvalidData);
+    }
+    Ok((nonce, ciphertext))
+}
+
+/// Encrypt `cleartext` with XChaCha20-Poly1305.
+#[cfg(feature = "encryption")]
+fn seal_xchacha(cleartext: &[u8], key: &EncryptionKey, committing: bool) -> Vec<u8> {
+    let nonce = gen_nonce();
+    let chacha_key = ChaChaKey::from_slice(key.0.as_ref()).unwrap();
+    let ciphertext = seal(cleartext, None, &nonce, &chacha_key);
+    frame_ciphertext(nonce.as_ref(), key, committing, ciphertext)
+}
+
+/// Decrypt `data` with XChaCha20-Poly1305.
+#[cfg(feature = "encryption")]
+fn open_xchacha(data: &[u8], key: &EncryptionKey, committing: bool) -> crate::Result<Vec<u8>> {
+    let (nonce, ciphertext) = unframe_ciphertext(data, NONCEBYTES, key, committing)?;
+    let nonce = Nonce::from_slice(nonce).unwrap();
+    let chacha_key = ChaChaKey::from_slice(key.0.as_ref()).unwrap();
+    open(ciphertext, None, &nonce, &chacha_key).map_err(|_| crate::Error::InvalidData)
+}
+
+/// Encrypt `cleartext` with AES-256-GCM.
+#[cfg(feature = "encryption")]
+fn seal_aes(cleartext: &[u8], key: &EncryptionKey, committing: bool) -> crate::Result<Vec<u8>> {
+    let aes = Aes256Gcm::new().map_err(|_| crate::Error::Unsupported)?;
+    let nonce = aes.gen_initial_nonce();
+    let aes_key = AesKey::from_slice(key.0.as_ref()).unwrap();
+    let ciphertext = aes.seal(cleartext, None, &nonce, &aes_key);
+    Ok(frame_ciphertext(
+        nonce.as_ref(),
+        key,
+        committing,
+        ciphertext,
+    ))
+}
+
+/// Decrypt `data` with AES-256-GCM.
+#[cfg(feature = "encryption")]
+fn open_aes(data: &[u8], key: &EncryptionKey, committing: bool) -> crate::Result<Vec<u8>> {
+    let aes = Aes256Gcm::new().map_err(|_| crate::Error::Unsupported)?;
+    let (nonce, ciphertext) = unframe_ciphertext(data, AES_NONCEBYTES, key, committing)?;
+    let nonce = AesNonce::from_slice(nonce).unwrap();
+    let aes_key = AesKey::from_slice(key.0.as_ref()).unwrap();
+    aes.open(ciphertext, None, &nonce, &aes_key)
+        .map_err(|_| crate::Error::InvalidData)
+}
+
 impl Encryption {
     /// The key size for this encryption method.
     pub(super) fn key_size(&self) -> usize {
@@ -129,6 +283,40 @@ impl Encryption {
             Encryption::None => 0,
             #[cfg(feature = "encryption")]
             Encryption::XChaCha20Poly1305 => KEYBYTES,
+            #[cfg(feature = "encryption")]
+            Encryption::Aes256Gcm => aes256gcm::KEYBYTES,
+            #[cfg(feature = "encryption")]
+            Encryption::XChaCha20Poly1305Committing => KEYBYTES,
+            #[cfg(feature = "encryption")]
+            Encryption::Aes256GcmCommitting => aes256gcm::KEYBYTES,
+            #[cfg(feature = "encryption")]
+            Encryption::Auto => self.resolve().key_size(),
+        }
+    }
+
+    /// Return the cipher which `Encryption::Auto` selects on this machine or `self` otherwise.
+    pub(super) fn resolve(&self) -> Encryption {
+        match self {
+            #[cfg(feature = "encryption")]
+            Encryption::Auto => {
+                init();
+                if aes256gcm::is_available() {
+                    Encryption::Aes256Gcm
+                } else {
+                    Encryption::XChaCha20Poly1305
+                }
+            }
+            _ => *self,
+        }
+    }
+
+    /// Return whether this encryption method can be used on this machine.
+    pub(super) fn is_supported(&self) -> bool {
+        init();
+        match self {
+            #[cfg(feature = "encryption")]
+            Encryption::Aes256Gcm | Encryption::Aes256GcmCommitting => aes256gcm::is_available(),
+            _ => true,
         }
     }
 }
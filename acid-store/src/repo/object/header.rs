@@ -14,48 +14,785 @@
  * limitations under the License.
  */
 
-use serde::de::DeserializeOwned;
-use serde::{Deserialize, Serialize};
+use std::borrow::{Borrow, Cow};
 use std::collections::{HashMap, HashSet};
 use std::hash::Hash;
+
+use rmp_serde::{from_read, to_vec};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
+use super::btree::{BTree, PageStore};
+use super::metadata::UnusedBlock;
 use super::object::{Chunk, ObjectHandle};
 
+/// The message of the panic when a method which can't return an error fails to read the header.
+pub const HEADER_READ_FAILED: &str = "Could not read the repository header.";
+
 /// A type which can be used as a key in an `ObjectRepository`.
 pub trait Key: Eq + Hash + Clone + Serialize + DeserializeOwned {}
 
 impl<T> Key for T where T: Eq + Hash + Clone + Serialize + DeserializeOwned {}
 
+/// How the header of a repository is stored.
+///
+/// The header maps each object in the repository to the chunks which make it up and each chunk to
+/// the block which stores it. It is kept in memory while the repository is open unless it is paged.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub enum HeaderMode {
+    /// The whole header is kept in memory.
+    ///
+    /// Changes are written as a journal of deltas which are periodically compacted into a new
+    /// snapshot of the header. This is fast, but the memory used grows with the number of objects
+    /// and chunks in the repository.
+    Memory,
+
+    /// The chunk map and object index are stored on disk as B-tree pages.
+    ///
+    /// Pages are stored as blocks in the data store and read lazily as they are needed. The most
+    /// recently used pages are kept in a cache of `cache_pages` pages, each of which holds up to 64
+    /// entries. Only the changes which have been made since the last commit are kept in memory, so
+    /// opening the repository doesn't read the object index, and `ObjectRepository::keys` reads it
+    /// one page at a time.
+    ///
+    /// Objects are looked up by their serialized key, so a borrowed form of the key type which is
+    /// passed to methods like `ObjectRepository::contains` must serialize the same way as the key
+    /// type itself, like `str` and `String` do.
+    ///
+    /// Methods which can't return an error, like `ObjectRepository::contains` and `Object::size`,
+    /// panic if a page can't be read. Each of them has a counterpart prefixed with `try_` which
+    /// returns the error instead.
+    ///
+    /// `ObjectRepository::stats` reflects the state of the repository as of the last commit.
+    Paged {
+        /// The maximum number of pages to keep in memory.
+        cache_pages: usize,
+    },
+}
+
 /// The header for an `ObjectRepository`.
+#[derive(Debug, Clone)]
+pub enum Header<K: Key> {
+    /// A header which is kept in memory.
+    Memory(MemoryHeader<K>),
+
+    /// A header which is stored in B-tree pages.
+    Paged(PagedHeader<K>),
+}
+
+impl<K: Key> Header<K> {
+    /// Return whether there is an object with the given `key`.
+    pub fn contains_object<Q>(&self, pages: &impl PageStore, key: &Q) -> crate::Result<bool>
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + Serialize + ?Sized,
+    {
+        match self {
+            Header::Memory(header) => Ok(header.objects.contains_key(key)),
+            Header::Paged(header) => Ok(header.object(pages, key)?.is_some()),
+        }
+    }
+
+    /// Return an iterator over the keys of all objects.
+    ///
+    /// With a `PagedHeader`, the pages of the object index are read from `pages` as the iterator
+    /// reaches them.
+    pub fn object_keys<'a, P: PageStore + 'a>(
+        &'a self,
+        pages: P,
+    ) -> Box<dyn Iterator<Item = crate::Result<K>> + 'a> {
+        match self {
+            Header::Memory(header) => Box::new(header.objects.keys().cloned().map(Ok)),
+            Header::Paged(header) => Box::new(header.object_keys(pages)),
+        }
+    }
+
+    /// Return the handle of the object with the given `key` or `None` if there is none.
+    pub fn object<Q>(
+        &self,
+        pages: &impl PageStore,
+        key: &Q,
+    ) -> crate::Result<Option<Cow<ObjectHandle>>>
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + Serialize + ?Sized,
+    {
+        match self {
+            Header::Memory(header) => Ok(header.objects.get(key).map(Cow::Borrowed)),
+            Header::Paged(header) => header.object(pages, key),
+        }
+    }
+
+    /// Insert an object into the header, replacing any existing object with the same key.
+    pub fn insert_object(&mut self, key: K, handle: ObjectHandle) {
+        match self {
+            Header::Memory(header) => {
+                header.insert_object(key, handle);
+            }
+            Header::Paged(header) => {
+                header.changed_objects.insert(key, Some(handle));
+            }
+        }
+    }
+
+    /// Remove the object with the given `key` and return whether it existed.
+    ///
+    /// # Errors
+    /// - `Error::KeyType`: The type `K` does not match the keys in the header.
+    /// - `Error::Corrupt`: A page of the header could not be read.
+    /// - `Error::Store`: An error occurred with the data store.
+    pub fn remove_object<Q>(&mut self, pages: &impl PageStore, key: &Q) -> crate::Result<bool>
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + Serialize + ?Sized,
+    {
+        match self {
+            Header::Memory(header) => Ok(header.remove_object(key).is_some()),
+            Header::Paged(header) => header.remove_object(pages, key),
+        }
+    }
+
+    /// Modify the handle of the object with the given `key` using `f`.
+    ///
+    /// # Errors
+    /// - `Error::NotFound`: There is no object with the given `key`.
+    pub fn update_object(
+        &mut self,
+        pages: &impl PageStore,
+        key: &K,
+        f: impl FnOnce(&mut ObjectHandle),
+    ) -> crate::Result<()> {
+        match self {
+            Header::Memory(header) => {
+                f(header.object_mut(key).ok_or(crate::Error::NotFound)?);
+                Ok(())
+            }
+            Header::Paged(header) => header.update_object(pages, key, f),
+        }
+    }
+
+    /// Return the ID of the block which stores `chunk` or `None` if there is none.
+    pub fn chunk_block(
+        &self,
+        pages: &impl PageStore,
+        chunk: &Chunk,
+    ) -> crate::Result<Option<Uuid>> {
+        match self {
+            Header::Memory(header) => Ok(header.chunks.get(chunk).copied()),
+            Header::Paged(header) => match header.new_chunks.get(chunk) {
+                Some(block_id) => Ok(Some(*block_id)),
+                None => Ok(header
+                    .chunks
+                    .get(pages, &chunk_key(chunk))?
+                    .map(|entry| entry.block_id)),
+            },
+        }
+    }
+
+    /// Insert a chunk into the header.
+    pub fn insert_chunk(&mut self, chunk: Chunk, block_id: Uuid) {
+        match self {
+            Header::Memory(header) => header.insert_chunk(chunk, block_id),
+            Header::Paged(header) => {
+                header.new_chunks.insert(chunk, block_id);
+            }
+        }
+    }
+
+    /// Call `f` with each chunk in the header.
+    pub fn for_each_chunk(
+        &self,
+        pages: &impl PageStore,
+        mut f: impl FnMut(Chunk) -> crate::Result<()>,
+    ) -> crate::Result<()> {
+        match self {
+            Header::Memory(header) => header.chunks.keys().copied().try_for_each(f),
+            Header::Paged(header) => {
+                header.new_chunks.keys().copied().try_for_each(&mut f)?;
+                header.chunks.for_each(pages, |key, _| {
+                    f(from_read(key).map_err(|_| crate::Error::Corrupt)?)
+                })
+            }
+        }
+    }
+
+    /// Return the blocks which store chunks that were added since `earlier`.
+    ///
+    /// `earlier` must be a copy of this header from before any of the changes since the last
+    /// commit.
+    pub fn chunks_added_since(&self, earlier: &Self) -> Vec<UnusedBlock> {
+        let (chunks, earlier_chunks) = match (self, earlier) {
+            (Header::Memory(header), Header::Memory(earlier)) => (&header.chunks, &earlier.chunks),
+            (Header::Paged(header), Header::Paged(earlier)) => {
+                (&header.new_chunks, &earlier.new_chunks)
+            }
+            _ => panic!("The headers must use the same header mode."),
+        };
+        chunks
+            .iter()
+            .filter(|(chunk, _)| !earlier_chunks.contains_key(chunk))
+            .map(|(chunk, block_id)| UnusedBlock {
+                id: *block_id,
+                size: chunk.size as u64,
+            })
+            .collect()
+    }
+
+    /// Return the blocks which store the chunks and pages of this header.
+    ///
+    /// This includes chunks which have been written since the last commit, but not the block which
+    /// the metadata points to.
+    ///
+    /// # Errors
+    /// - `Error::Corrupt`: The header is corrupt.
+    /// - `Error::InvalidData`: Ciphertext verification failed.
+    /// - `Error::Store`: An error occurred with the data store.
+    pub fn blocks(&self, pages: &impl PageStore) -> crate::Result<Vec<UnusedBlock>> {
+        let mut blocks = Vec::new();
+        match self {
+            Header::Memory(header) => {
+                blocks.extend(header.chunks.iter().map(|(chunk, block_id)| UnusedBlock {
+                    id: *block_id,
+                    size: chunk.size as u64,
+                }));
+            }
+            Header::Paged(header) => {
+                blocks.extend(
+                    header
+                        .new_chunks
+                        .iter()
+                        .map(|(chunk, block_id)| UnusedBlock {
+                            id: *block_id,
+                            size: chunk.size as u64,
+                        }),
+                );
+                header.chunks.for_each(pages, |key, entry| {
+                    let chunk: Chunk = from_read(key).map_err(|_| crate::Error::Corrupt)?;
+                    blocks.push(UnusedBlock {
+                        id: entry.block_id,
+                        size: chunk.size as u64,
+                    });
+                    Ok(())
+                })?;
+                let mut add_page = |id| blocks.push(UnusedBlock { id, size: 0 });
+                header.objects.for_each_page(pages, &mut add_page)?;
+                header.chunks.for_each_page(pages, &mut add_page)?;
+            }
+        }
+        Ok(blocks)
+    }
+
+    /// Return whether any objects or chunks have changed since the last commit.
+    pub fn has_changes(&self) -> bool {
+        match self {
+            Header::Memory(header) => header.dirty_count() > 0,
+            Header::Paged(header) => {
+                !header.changed_objects.is_empty() || !header.new_chunks.is_empty()
+            }
+        }
+    }
+
+    /// Return the apparent and actual size of the data in the repository.
+    pub fn sizes(&self) -> (u64, u64) {
+        match self {
+            Header::Memory(header) => {
+                let apparent_size = header.objects.values().map(|object| object.size).sum();
+                let actual_size = header.chunks.keys().map(|chunk| chunk.size as u64).sum();
+                (apparent_size, actual_size)
+            }
+            Header::Paged(header) => (header.apparent_size, header.actual_size),
+        }
+    }
+}
+
+/// A header which is kept entirely in memory.
+///
+/// The header is stored as a base snapshot followed by a journal of `HeaderDelta` values. Changes
+/// to the header should be made through the methods on this type so that they are recorded in the
+/// next delta.
 #[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
-pub struct Header<K: Eq + Hash> {
+pub struct MemoryHeader<K: Eq + Hash> {
     /// A map of chunk hashes to the IDs of those chunks.
     pub chunks: HashMap<Chunk, Uuid>,
 
     /// A map of object keys to information about those objects.
     pub objects: HashMap<K, ObjectHandle>,
+
+    /// The chunks which have been added or removed since the last commit.
+    #[serde(skip)]
+    dirty_chunks: HashSet<Chunk>,
+
+    /// The keys of objects which have been added, modified or removed since the last commit.
+    #[serde(skip, default = "HashSet::new")]
+    dirty_objects: HashSet<K>,
 }
 
-impl<K: Key> Default for Header<K> {
+impl<K: Key> Default for MemoryHeader<K> {
     fn default() -> Self {
-        Header {
+        MemoryHeader {
             chunks: HashMap::new(),
             objects: HashMap::new(),
+            dirty_chunks: HashSet::new(),
+            dirty_objects: HashSet::new(),
         }
     }
 }
 
-impl<K: Key> Header<K> {
+/// The changes made to a `MemoryHeader` between two commits.
+///
+/// A value of `None` means that the entry was removed.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct HeaderDelta<K: Eq + Hash> {
+    /// The chunks which were added or removed.
+    pub chunks: Vec<(Chunk, Option<Uuid>)>,
+
+    /// The objects which were added, modified or removed.
+    pub objects: Vec<(K, Option<ObjectHandle>)>,
+}
+
+impl<K: Key> MemoryHeader<K> {
+    /// Insert an object into the header, returning the old object if there was one.
+    pub fn insert_object(&mut self, key: K, handle: ObjectHandle) -> Option<ObjectHandle> {
+        self.dirty_objects.insert(key.clone());
+        self.objects.insert(key, handle)
+    }
+
+    /// Remove the object with the given `key` from the header and return it.
+    pub fn remove_object<Q>(&mut self, key: &Q) -> Option<ObjectHandle>
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        let (key, handle) = self.objects.remove_entry(key)?;
+        self.dirty_objects.insert(key);
+        Some(handle)
+    }
+
+    /// Return a mutable reference to the object with the given `key`.
+    ///
+    /// The object is assumed to be modified and will be included in the next delta.
+    pub fn object_mut(&mut self, key: &K) -> Option<&mut ObjectHandle> {
+        let handle = self.objects.get_mut(key)?;
+        self.dirty_objects.insert(key.clone());
+        Some(handle)
+    }
+
+    /// Insert a chunk into the header.
+    pub fn insert_chunk(&mut self, chunk: Chunk, block_id: Uuid) {
+        self.dirty_chunks.insert(chunk);
+        self.chunks.insert(chunk, block_id);
+    }
+
     /// Remove chunks not referenced by any object from the header.
-    pub fn clean_chunks(&mut self) {
+    ///
+    /// This returns the removed chunks and the blocks which stored them.
+    pub fn clean_chunks(&mut self) -> Vec<(Chunk, Uuid)> {
         let referenced_chunks = self
             .objects
             .values()
             .flat_map(|object| &object.chunks)
             .collect::<HashSet<_>>();
+        let dirty_chunks = &mut self.dirty_chunks;
+        let mut removed_blocks = Vec::new();
+
+        self.chunks.retain(|chunk, block_id| {
+            let is_referenced = referenced_chunks.contains(chunk);
+            if !is_referenced {
+                dirty_chunks.insert(*chunk);
+                removed_blocks.push((*chunk, *block_id));
+            }
+            is_referenced
+        });
+
+        removed_blocks
+    }
+
+    /// The number of entries in the header.
+    pub fn entry_count(&self) -> usize {
+        self.chunks.len() + self.objects.len()
+    }
+
+    /// The number of entries which have changed since the last commit.
+    pub fn dirty_count(&self) -> usize {
+        self.dirty_chunks.len() + self.dirty_objects.len()
+    }
+
+    /// Return the changes which have been made since the last commit.
+    pub fn delta(&self) -> HeaderDelta<K> {
+        HeaderDelta {
+            chunks: self
+                .dirty_chunks
+                .iter()
+                .map(|chunk| (*chunk, self.chunks.get(chunk).copied()))
+                .collect(),
+            objects: self
+                .dirty_objects
+                .iter()
+                .map(|key| (key.clone(), self.objects.get(key).cloned()))
+                .collect(),
+        }
+    }
+
+    /// Mark all changes as committed.
+    pub fn clear_dirty(&mut self) {
+        self.dirty_chunks.clear();
+        self.dirty_objects.clear();
+    }
+
+    /// Apply a `delta` which was read from the journal.
+    pub fn apply(&mut self, delta: HeaderDelta<K>) {
+        for (chunk, block_id) in delta.chunks {
+            match block_id {
+                Some(block_id) => self.chunks.insert(chunk, block_id),
+                None => self.chunks.remove(&chunk),
+            };
+        }
+        for (key, handle) in delta.objects {
+            match handle {
+                Some(handle) => self.objects.insert(key, handle),
+                None => self.objects.remove(&key),
+            };
+        }
+    }
+}
+
+/// Return the key which `chunk` is stored under in the chunk map.
+fn chunk_key(chunk: &Chunk) -> Vec<u8> {
+    to_vec(chunk).expect("Could not serialize chunk.")
+}
+
+/// Return the key which the object with the given `key` is stored under in the object index.
+fn object_key<Q: Serialize + ?Sized>(key: &Q) -> Vec<u8> {
+    to_vec(key).expect("Could not serialize key.")
+}
+
+/// An entry in the chunk map of a `PagedHeader`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub struct ChunkEntry {
+    /// The ID of the block which stores the chunk.
+    pub block_id: Uuid,
+
+    /// The number of times the chunk is referenced by objects.
+    pub references: u64,
+}
+
+/// The root of a `PagedHeader`, which is stored in the block the metadata points to.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct PagedRoot {
+    /// The ID of the root node of the object index.
+    pub objects: Option<Uuid>,
+
+    /// The number of objects in the object index.
+    pub object_count: u64,
+
+    /// The ID of the root node of the chunk map.
+    pub chunks: Option<Uuid>,
+
+    /// The number of chunks in the chunk map.
+    pub chunk_count: u64,
+
+    /// The combined size of all the objects in the repository.
+    pub apparent_size: u64,
+
+    /// The combined size of all the chunks in the repository.
+    pub actual_size: u64,
+}
+
+/// A header which stores the chunk map and object index as B-tree pages.
+///
+/// Changes are kept in memory until they are applied to the trees by `stage`.
+#[derive(Debug, Clone)]
+pub struct PagedHeader<K: Key> {
+    /// A map of object keys to information about those objects as of the last commit.
+    objects: BTree<ObjectHandle>,
+
+    /// A map of chunks to the blocks which store them as of the last commit.
+    chunks: BTree<ChunkEntry>,
+
+    /// Objects which have been added, modified or removed since the last commit.
+    ///
+    /// A value of `None` means that the object was removed.
+    changed_objects: HashMap<K, Option<ObjectHandle>>,
+
+    /// Chunks which have been written since the last commit.
+    new_chunks: HashMap<Chunk, Uuid>,
+
+    /// The combined size of all the objects as of the last commit.
+    apparent_size: u64,
+
+    /// The combined size of all the chunks as of the last commit.
+    actual_size: u64,
+}
+
+impl<K: Key> PagedHeader<K> {
+    /// Create a new empty header which caches up to `cache_pages` pages.
+    pub fn new(cache_pages: usize) -> Self {
+        Self::from_root(PagedRoot::default(), cache_pages)
+    }
+
+    /// Create a header from its `root` without reading any of its pages.
+    fn from_root(root: PagedRoot, cache_pages: usize) -> Self {
+        PagedHeader {
+            objects: BTree::open(root.objects, root.object_count, cache_pages),
+            chunks: BTree::open(root.chunks, root.chunk_count, cache_pages),
+            changed_objects: HashMap::new(),
+            new_chunks: HashMap::new(),
+            apparent_size: root.apparent_size,
+            actual_size: root.actual_size,
+        }
+    }
+
+    /// Open the header with the given `root`.
+    ///
+    /// Only the first key in the object index is read from `pages`, to check that it has the type
+    /// `K`.
+    ///
+    /// # Errors
+    /// - `Error::KeyType`: The type `K` does not match the keys in the header.
+    /// - `Error::Corrupt`: A page of the header could not be read.
+    /// - `Error::Store`: An error occurred with the data store.
+    pub fn open(
+        root: PagedRoot,
+        cache_pages: usize,
+        pages: &impl PageStore,
+    ) -> crate::Result<Self> {
+        let header = Self::from_root(root, cache_pages);
+        if let Some(key) = header.objects.keys(pages).next() {
+            from_read::<_, K>(key?.as_slice()).map_err(|_| crate::Error::KeyType)?;
+        }
+        Ok(header)
+    }
+
+    /// Discard the changes which have been made since the last commit.
+    ///
+    /// This returns the blocks which store chunks that were written since the last commit.
+    pub fn rollback(&mut self) -> Vec<UnusedBlock> {
+        self.changed_objects.clear();
+        self.new_chunks
+            .drain()
+            .map(|(chunk, block_id)| UnusedBlock {
+                id: block_id,
+                size: chunk.size as u64,
+            })
+            .collect()
+    }
+
+    /// Return an iterator over the keys of all objects, reading the object index from `pages`.
+    ///
+    /// Keys which were committed are returned in the order they are stored in the index, followed
+    /// by the keys of objects which were added since the last commit.
+    fn object_keys<'a, P: PageStore + 'a>(
+        &'a self,
+        pages: P,
+    ) -> impl Iterator<Item = crate::Result<K>> + 'a {
+        let committed_keys = self
+            .objects
+            .keys(pages)
+            .map(|key| from_read::<_, K>(key?.as_slice()).map_err(|_| crate::Error::KeyType))
+            .filter(move |key| match key {
+                Ok(key) => !self.changed_objects.contains_key(key),
+                Err(_) => true,
+            });
+        let new_keys = self
+            .changed_objects
+            .iter()
+            .filter(|(_, handle)| handle.is_some())
+            .map(|(key, _)| Ok(key.clone()));
+        committed_keys.chain(new_keys)
+    }
+
+    /// Return the handle of the object with the given `key` or `None` if there is none.
+    fn object<Q>(&self, pages: &impl PageStore, key: &Q) -> crate::Result<Option<Cow<ObjectHandle>>>
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + Serialize + ?Sized,
+    {
+        if let Some(handle) = self.changed_objects.get(key) {
+            return Ok(handle.as_ref().map(Cow::Borrowed));
+        }
+        Ok(self.objects.get(pages, &object_key(key))?.map(Cow::Owned))
+    }
+
+    /// Remove the object with the given `key` and return whether it existed.
+    fn remove_object<Q>(&mut self, pages: &impl PageStore, key: &Q) -> crate::Result<bool>
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + Serialize + ?Sized,
+    {
+        if let Some(handle) = self.changed_objects.get_mut(key) {
+            return Ok(handle.take().is_some());
+        }
+
+        // The object was last changed before the last commit, so we need its key from the index.
+        let index_key = object_key(key);
+        if self.objects.get(pages, &index_key)?.is_none() {
+            return Ok(false);
+        }
+        let key = from_read(index_key.as_slice()).map_err(|_| crate::Error::KeyType)?;
+        self.changed_objects.insert(key, None);
+        Ok(true)
+    }
+
+    /// Modify the handle of the object with the given `key` using `f`.
+    fn update_object(
+        &mut self,
+        pages: &impl PageStore,
+        key: &K,
+        f: impl FnOnce(&mut ObjectHandle),
+    ) -> crate::Result<()> {
+        if !self.changed_objects.contains_key(key) {
+            let handle = self
+                .objects
+                .get(pages, &object_key(key))?
+                .ok_or(crate::Error::NotFound)?;
+            self.changed_objects.insert(key.clone(), Some(handle));
+        }
+
+        match self.changed_objects.get_mut(key) {
+            Some(Some(handle)) => {
+                f(handle);
+                Ok(())
+            }
+            _ => Err(crate::Error::NotFound),
+        }
+    }
+
+    /// Apply the changes made since the last commit to copies of the B-trees.
+    ///
+    /// The header itself is not modified until the returned `StagedHeader` is passed to `finish`.
+    ///
+    /// # Errors
+    /// - `Error::Corrupt`: An object references a chunk which doesn't exist.
+    /// - `Error::InvalidData`: Ciphertext verification failed.
+    /// - `Error::Store`: An error occurred with the data store.
+    pub fn stage(&self, pages: &impl PageStore) -> crate::Result<StagedHeader> {
+        let mut objects = self.objects.clone();
+        let mut chunks = self.chunks.clone();
+        let mut apparent_size = self.apparent_size;
+        let mut actual_size = self.actual_size;
+
+        // Apply changes to the object index and count how the number of references to each chunk
+        // has changed.
+        let mut reference_changes = HashMap::new();
+        for chunk in self.new_chunks.keys() {
+            reference_changes.insert(*chunk, 0i64);
+        }
+        for (key, handle) in self.changed_objects.iter() {
+            let old_handle = match handle {
+                Some(handle) => objects.insert(pages, object_key(key), handle.clone())?,
+                None => objects.remove(pages, &object_key(key))?,
+            };
+            if let Some(old_handle) = old_handle {
+                apparent_size -= old_handle.size;
+                for chunk in old_handle.chunks {
+                    *reference_changes.entry(chunk).or_default() -= 1;
+                }
+            }
+            if let Some(handle) = handle {
+                apparent_size += handle.size;
+                for chunk in handle.chunks.iter() {
+                    *reference_changes.entry(*chunk).or_default() += 1;
+                }
+            }
+        }
+
+        // Apply changes to the chunk map, removing chunks which are no longer referenced.
+        let mut removed_chunks = Vec::new();
+        for (chunk, change) in reference_changes {
+            let key = chunk_key(&chunk);
+            let (entry, is_new) = match chunks.get(pages, &key)? {
+                Some(entry) => (entry, false),
+                None => {
+                    let block_id = self.new_chunks.get(&chunk).ok_or(crate::Error::Corrupt)?;
+                    let entry = ChunkEntry {
+                        block_id: *block_id,
+                        references: 0,
+                    };
+                    (entry, true)
+                }
+            };
+
+            let references = entry.references as i64 + change;
+            if references > 0 {
+                let new_entry = ChunkEntry {
+                    block_id: entry.block_id,
+                    references: references as u64,
+                };
+                if new_entry != entry || is_new {
+                    chunks.insert(pages, key, new_entry)?;
+                }
+                if is_new {
+                    actual_size += chunk.size as u64;
+                }
+            } else {
+                removed_chunks.push(UnusedBlock {
+                    id: entry.block_id,
+                    size: chunk.size as u64,
+                });
+                if !is_new {
+                    chunks.remove(pages, &key)?;
+                    actual_size -= chunk.size as u64;
+                }
+            }
+        }
+
+        Ok(StagedHeader {
+            objects,
+            chunks,
+            apparent_size,
+            actual_size,
+            removed_chunks,
+        })
+    }
+
+    /// Replace the B-trees in this header with those in `staged` once they have been committed.
+    pub fn finish(&mut self, staged: StagedHeader) {
+        self.objects = staged.objects;
+        self.chunks = staged.chunks;
+        self.apparent_size = staged.apparent_size;
+        self.actual_size = staged.actual_size;
+        self.changed_objects.clear();
+        self.new_chunks.clear();
+    }
+}
+
+/// The B-trees of a `PagedHeader` with uncommitted changes applied.
+#[derive(Debug)]
+pub struct StagedHeader {
+    objects: BTree<ObjectHandle>,
+    chunks: BTree<ChunkEntry>,
+    apparent_size: u64,
+    actual_size: u64,
+    removed_chunks: Vec<UnusedBlock>,
+}
+
+impl StagedHeader {
+    /// Write the modified pages of the B-trees to `pages`.
+    ///
+    /// This returns the new root of the header and the blocks which can be removed once the root
+    /// has been committed.
+    pub fn flush(
+        &mut self,
+        pages: &impl PageStore,
+    ) -> crate::Result<(PagedRoot, Vec<UnusedBlock>)> {
+        let mut freed_pages = self.objects.flush(pages)?;
+        freed_pages.extend(self.chunks.flush(pages)?);
+        let mut unused_blocks = freed_pages
+            .into_iter()
+            .map(|id| UnusedBlock { id, size: 0 })
+            .collect::<Vec<_>>();
+        unused_blocks.extend(self.removed_chunks.iter().copied());
+
+        let root = PagedRoot {
+            objects: self.objects.root(),
+            object_count: self.objects.entry_count(),
+            chunks: self.chunks.root(),
+            chunk_count: self.chunks.entry_count(),
+            apparent_size: self.apparent_size,
+            actual_size: self.actual_size,
+        };
 
-        self.chunks
-            .retain(|chunk, _| referenced_chunks.contains(chunk));
+        Ok((root, unused_blocks))
     }
 }
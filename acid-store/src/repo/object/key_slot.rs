@@ -0,0 +1,171 @@
+/*
+ * Copyright 2019-2020 Wren Powell
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use serde::{Deserialize, Serialize};
+
+use super::encryption::{Encryption, EncryptionKey, KeySalt, ResourceLimit};
+
+/// The size of a raw key used to unlock a key slot in bytes.
+pub const RAW_KEY_SIZE: usize = 32;
+
+/// The name of the key slot which is created along with a repository.
+pub const DEFAULT_KEY_SLOT: &str = "default";
+
+/// A secret which unlocks a key slot in a repository.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlotKey<'a> {
+    /// A password which a key is derived from using the Argon2id key derivation function.
+    Password(&'a [u8]),
+
+    /// A raw key which is used as-is without key derivation.
+    ///
+    /// This should be generated using a cryptographically secure random number generator.
+    Raw(&'a [u8; RAW_KEY_SIZE]),
+}
+
+/// The kind of secret which unlocks a key slot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeySlotKind {
+    /// The slot is unlocked with a password.
+    Password,
+
+    /// The slot is unlocked with a raw key.
+    Raw,
+}
+
+/// Information about a key slot in a repository.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeySlotInfo {
+    name: String,
+    kind: KeySlotKind,
+}
+
+impl KeySlotInfo {
+    /// The name of the key slot.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The kind of secret which unlocks the key slot.
+    pub fn kind(&self) -> KeySlotKind {
+        self.kind
+    }
+}
+
+/// A copy of a repository's master key encrypted with a key which is provided by the user.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeySlot {
+    /// The name of the key slot, which is unique within a repository.
+    pub name: String,
+
+    /// The salt used to derive a key from a password or `None` if this slot uses a raw key.
+    pub salt: Option<KeySalt>,
+
+    /// The maximum amount of memory used to derive a key from a password.
+    ///
+    /// This is stored with each slot so that changing the limits for a repository doesn't change
+    /// the key which unlocks its existing slots.
+    pub memory_limit: ResourceLimit,
+
+    /// The maximum number of computations performed to derive a key from a password.
+    pub operations_limit: ResourceLimit,
+
+    /// The master key encrypted with the key for this slot.
+    pub master_key: Vec<u8>,
+}
+
+impl KeySlot {
+    /// Create a new key slot named `name` which stores `master_key` encrypted with `key`.
+    pub fn new(
+        name: &str,
+        key: SlotKey,
+        master_key: &EncryptionKey,
+        encryption: Encryption,
+        memory_limit: ResourceLimit,
+        operations_limit: ResourceLimit,
+    ) -> crate::Result<Self> {
+        let salt = match key {
+            SlotKey::Password(_) => Some(KeySalt::generate()),
+            SlotKey::Raw(_) => None,
+        };
+        let slot_key = Self::slot_key(
+            key,
+            salt.as_ref(),
+            encryption,
+            memory_limit,
+            operations_limit,
+        );
+        Ok(Self {
+            name: name.to_string(),
+            salt,
+            memory_limit,
+            operations_limit,
+            master_key: encryption.encrypt(master_key.as_ref(), &slot_key)?,
+        })
+    }
+
+    /// Return information about this key slot.
+    pub fn info(&self) -> KeySlotInfo {
+        KeySlotInfo {
+            name: self.name.clone(),
+            kind: match self.salt {
+                Some(_) => KeySlotKind::Password,
+                None => KeySlotKind::Raw,
+            },
+        }
+    }
+
+    /// Decrypt the master key in this slot using `key`.
+    ///
+    /// This returns `None` if `key` is the wrong kind of key for this slot or it is incorrect.
+    pub fn unlock(&self, key: SlotKey, encryption: Encryption) -> Option<EncryptionKey> {
+        match (key, &self.salt) {
+            (SlotKey::Password(_), Some(_)) | (SlotKey::Raw(_), None) => {}
+            _ => return None,
+        }
+        let slot_key = Self::slot_key(
+            key,
+            self.salt.as_ref(),
+            encryption,
+            self.memory_limit,
+            self.operations_limit,
+        );
+        encryption
+            .decrypt(&self.master_key, &slot_key)
+            .ok()
+            .map(EncryptionKey::new)
+    }
+
+    /// Return the key which encrypts the master key given the secret `key` provided by the user.
+    fn slot_key(
+        key: SlotKey,
+        salt: Option<&KeySalt>,
+        encryption: Encryption,
+        memory_limit: ResourceLimit,
+        operations_limit: ResourceLimit,
+    ) -> EncryptionKey {
+        match key {
+            SlotKey::Password(password) => EncryptionKey::derive(
+                password,
+                salt.expect("A password key slot has no salt."),
+                encryption.key_size(),
+                memory_limit,
+                operations_limit,
+            ),
+            SlotKey::Raw(raw_key) => EncryptionKey::new(raw_key.to_vec()),
+        }
+    }
+}
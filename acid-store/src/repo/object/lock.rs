@@ -14,17 +14,24 @@
  * limitations under the License.
  */
 
+use std::fs::{create_dir_all, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread::sleep;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
 use dirs::{data_dir, runtime_dir};
 use fs2::FileExt;
 use lazy_static::lazy_static;
-use std::fs::{create_dir_all, File, OpenOptions};
-use std::path::PathBuf;
-use std::sync::{Arc, Weak};
 use uuid::Uuid;
 use weak_table::WeakHashSet;
 
+/// How long to wait between attempts to acquire a lock with `LockStrategy::Timeout`.
+const LOCK_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
 lazy_static! {
-    /// The path of the directory where repository lock files are stored.
+    /// The path of the directory where repository lock files are stored by default.
     static ref LOCKS_DIR: PathBuf = runtime_dir()
         .unwrap_or_else(|| data_dir().expect("Unsupported platform"))
         .join("acid-store")
@@ -42,6 +49,26 @@ pub struct Lock {
 
     /// The file lock that is held to lock the resource between processes.
     file: File,
+
+    /// Whether this is an exclusive lock, in which case `file` contains its `LockOwner`.
+    exclusive: bool,
+}
+
+impl Lock {
+    /// Return whether this is an exclusive lock.
+    pub fn is_exclusive(&self) -> bool {
+        self.exclusive
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        if self.exclusive {
+            // Clear the owner so it isn't mistaken for a process which exited without releasing
+            // the lock. The OS releases the file lock when the file is closed.
+            self.file.set_len(0).ok();
+        }
+    }
 }
 
 /// A strategy for handling a repository which is already locked.
@@ -52,52 +79,330 @@ pub enum LockStrategy {
 
     /// Block and wait for the lock on the repository to be released.
     Wait,
+
+    /// Block and wait for the lock on the repository to be released, returning an `Err` if it is
+    /// not released within the given amount of time.
+    Timeout(Duration),
+}
+
+/// Information about the process which holds an exclusive lock on a repository.
+///
+/// This is recorded in the lock file when the lock is acquired and is meant for diagnostics.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LockOwner {
+    pid: u32,
+    hostname: String,
+    time: SystemTime,
+}
+
+impl LockOwner {
+    /// Return information about the current process.
+    fn current() -> Self {
+        Self {
+            pid: std::process::id(),
+            hostname: hostname(),
+            time: SystemTime::now(),
+        }
+    }
+
+    /// Serialize this owner as text which can be read by a person.
+    fn encode(&self) -> String {
+        let seconds = self
+            .time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        format!("{}\n{}\n{}\n", self.pid, self.hostname, seconds)
+    }
+
+    /// Deserialize an owner serialized with `encode`.
+    fn decode(data: &str) -> Option<Self> {
+        let mut lines = data.lines();
+        let pid = lines.next()?.parse().ok()?;
+        let hostname = lines.next()?.to_owned();
+        let seconds = lines.next()?.parse().ok()?;
+        Some(Self {
+            pid,
+            hostname,
+            time: UNIX_EPOCH + Duration::from_secs(seconds),
+        })
+    }
+
+    /// The ID of the process which holds the lock.
+    pub fn pid(&self) -> u32 {
+        self.pid
+    }
+
+    /// The hostname of the machine the process which holds the lock is running on.
+    pub fn hostname(&self) -> &str {
+        &self.hostname
+    }
+
+    /// The time the lock was acquired.
+    ///
+    /// This is only precise to the second.
+    pub fn time(&self) -> SystemTime {
+        self.time
+    }
+
+    /// Return whether the process which holds the lock is still running.
+    ///
+    /// Locks are released by the OS when the process which holds them exits, so a lock held by a
+    /// process which is no longer running has most likely been inherited by a child process. This
+    /// returns `None` if the process is running on another machine or this can't be determined
+    /// on this platform.
+    pub fn is_alive(&self) -> Option<bool> {
+        if self.hostname != hostname() {
+            return None;
+        }
+        process_is_alive(self.pid)
+    }
+}
+
+/// Return the hostname of this machine.
+#[cfg(unix)]
+fn hostname() -> String {
+    let mut buffer = [0u8; 256];
+    // SAFETY: The length passed is the length of `buffer`.
+    let result =
+        unsafe { libc::gethostname(buffer.as_mut_ptr() as *mut libc::c_char, buffer.len()) };
+    if result != 0 {
+        return String::new();
+    }
+    let length = buffer
+        .iter()
+        .position(|&byte| byte == 0)
+        .unwrap_or(buffer.len());
+    String::from_utf8_lossy(&buffer[..length]).into_owned()
+}
+
+/// Return the hostname of this machine.
+#[cfg(not(unix))]
+fn hostname() -> String {
+    std::env::var("COMPUTERNAME").unwrap_or_default()
+}
+
+/// Return whether a process with the given `pid` is running on this machine.
+#[cfg(unix)]
+fn process_is_alive(pid: u32) -> Option<bool> {
+    // Sending signal 0 checks whether the process exists without sending a signal.
+    // SAFETY: Signal 0 has no effect on the process.
+    if unsafe { libc::kill(pid as libc::pid_t, 0) } == 0 {
+        return Some(true);
+    }
+    match io::Error::last_os_error().raw_os_error() {
+        // The process exists but belongs to another user.
+        Some(libc::EPERM) => Some(true),
+        Some(libc::ESRCH) => Some(false),
+        _ => None,
+    }
+}
+
+/// Return whether a process with the given `pid` is running on this machine.
+#[cfg(not(unix))]
+fn process_is_alive(_pid: u32) -> Option<bool> {
+    None
+}
+
+/// Acquire an exclusive or shared file lock on `file` using `strategy`.
+fn acquire_file_lock(file: &File, strategy: LockStrategy, exclusive: bool) -> crate::Result<()> {
+    let try_lock = || {
+        if exclusive {
+            FileExt::try_lock_exclusive(file)
+        } else {
+            FileExt::try_lock_shared(file)
+        }
+    };
+
+    match strategy {
+        LockStrategy::Abort => try_lock().map_err(|_| crate::Error::Locked),
+        LockStrategy::Wait => {
+            if exclusive {
+                FileExt::lock_exclusive(file)?;
+            } else {
+                FileExt::lock_shared(file)?;
+            }
+            Ok(())
+        }
+        LockStrategy::Timeout(timeout) => {
+            let start = Instant::now();
+            loop {
+                if try_lock().is_ok() {
+                    return Ok(());
+                }
+                let elapsed = start.elapsed();
+                if elapsed >= timeout {
+                    return Err(crate::Error::Locked);
+                }
+                sleep(LOCK_POLL_INTERVAL.min(timeout - elapsed));
+            }
+        }
+    }
 }
 
 /// A value which keeps track of locks on resources identified by UUIDs.
 ///
 /// This locks resources between processes using OS file locks, and it locks resources within a
-/// process using weak references.
+/// process using weak references. The table is only locked while it is checked or updated, so a
+/// thread waiting on a file lock doesn't block other threads from acquiring unrelated locks.
 #[derive(Debug)]
-pub struct LockTable(WeakHashSet<Weak<Uuid>>);
+pub struct LockTable {
+    /// The resources which are locked within this process.
+    entries: Mutex<LockEntries>,
+}
+
+/// The resources which are locked within a process.
+#[derive(Debug)]
+struct LockEntries {
+    /// The resources which are locked exclusively.
+    exclusive: WeakHashSet<Weak<Uuid>>,
+
+    /// The resources which are locked shared.
+    ///
+    /// Each resource has one reference, which is shared between all the locks on it.
+    shared: WeakHashSet<Weak<Uuid>>,
+}
 
 impl LockTable {
     /// Create a new empty `LockTable`.
     pub fn new() -> Self {
-        Self(WeakHashSet::new())
+        Self {
+            entries: Mutex::new(LockEntries {
+                exclusive: WeakHashSet::new(),
+                shared: WeakHashSet::new(),
+            }),
+        }
     }
 
-    /// Attempt to acquire a lock on the given `id` using a given `strategy`.
+    /// Return the path of the lock file for the resource with the given `id`.
     ///
-    /// This returns a new lock or returns an `Err` if a lock could not be acquired.
-    pub fn acquire_lock(&mut self, id: Uuid, strategy: LockStrategy) -> crate::Result<Lock> {
-        // Create the lock file if it doesn't already exist.
-        create_dir_all(LOCKS_DIR.as_path())?;
+    /// If `lock_dir` is `None`, this is in the default locks directory.
+    fn lock_path(id: Uuid, lock_dir: Option<&Path>) -> PathBuf {
         let mut buffer = Uuid::encode_buffer();
         let file_name = format!("{}.lock", id.to_hyphenated().encode_lower(&mut buffer));
-        let lock_file = OpenOptions::new()
+        lock_dir.unwrap_or(&LOCKS_DIR).join(file_name)
+    }
+
+    /// Open the lock file for the resource with the given `id`, creating it if necessary.
+    fn open_lock_file(id: Uuid, lock_dir: Option<&Path>) -> crate::Result<File> {
+        create_dir_all(lock_dir.unwrap_or(&LOCKS_DIR))?;
+        Ok(OpenOptions::new()
+            .read(true)
             .write(true)
             .create(true)
-            .open(LOCKS_DIR.join(file_name))?;
+            .truncate(false)
+            .open(Self::lock_path(id, lock_dir))?)
+    }
+
+    /// Attempt to acquire a lock on the given `id` using a given `strategy`.
+    ///
+    /// The lock file is kept in `lock_dir`, or the default locks directory if it is `None`. This
+    /// returns a new lock or returns an `Err` if a lock could not be acquired.
+    pub fn acquire_lock(
+        &self,
+        id: Uuid,
+        lock_dir: Option<&Path>,
+        strategy: LockStrategy,
+    ) -> crate::Result<Lock> {
+        let mut lock_file = Self::open_lock_file(id, lock_dir)?;
 
         // Check if this repository is already locked within this process.
-        if self.0.contains(&id) {
-            Err(crate::Error::Locked)
-        } else {
-            match strategy {
-                LockStrategy::Abort => lock_file
-                    .try_lock_exclusive()
-                    .map_err(|_| crate::Error::Locked)?,
-                LockStrategy::Wait => lock_file.lock_exclusive()?,
-            };
+        {
+            let entries = self.entries.lock().unwrap();
+            if entries.exclusive.contains(&id) || entries.shared.contains(&id) {
+                return Err(crate::Error::Locked);
+            }
+        }
+
+        acquire_file_lock(&lock_file, strategy, true)?;
 
+        // Another thread may have locked the repository while we were waiting for the file lock.
+        // If so, dropping `lock_file` releases the file lock.
+        let id_arc = {
+            let mut entries = self.entries.lock().unwrap();
+            if entries.exclusive.contains(&id) || entries.shared.contains(&id) {
+                return Err(crate::Error::Locked);
+            }
             let id_arc = Arc::from(id);
-            self.0.insert(Arc::clone(&id_arc));
+            entries.exclusive.insert(Arc::clone(&id_arc));
+            id_arc
+        };
+
+        // If the lock file still names an owner, that process exited without releasing the lock,
+        // and the OS has already released it. Replace it with the current process.
+        lock_file.set_len(0)?;
+        lock_file.seek(SeekFrom::Start(0))?;
+        lock_file.write_all(LockOwner::current().encode().as_bytes())?;
+        lock_file.sync_data()?;
+
+        Ok(Lock {
+            id: id_arc,
+            file: lock_file,
+            exclusive: true,
+        })
+    }
+
+    /// Attempt to acquire a shared lock on the given `id` using a given `strategy`.
+    ///
+    /// Any number of shared locks can be held on a resource at once, but not while it is locked
+    /// exclusively. The lock file is kept in `lock_dir`, or the default locks directory if it is
+    /// `None`. This returns a new lock or returns an `Err` if a lock could not be acquired.
+    pub fn acquire_shared_lock(
+        &self,
+        id: Uuid,
+        lock_dir: Option<&Path>,
+        strategy: LockStrategy,
+    ) -> crate::Result<Lock> {
+        let lock_file = Self::open_lock_file(id, lock_dir)?;
 
-            Ok(Lock {
-                id: id_arc,
-                file: lock_file,
-            })
+        // Check if this repository is already locked exclusively within this process.
+        if self.entries.lock().unwrap().exclusive.contains(&id) {
+            return Err(crate::Error::Locked);
         }
+
+        acquire_file_lock(&lock_file, strategy, false)?;
+
+        // Another thread may have locked the repository exclusively while we were waiting for the
+        // file lock. If so, dropping `lock_file` releases the file lock.
+        let mut entries = self.entries.lock().unwrap();
+        if entries.exclusive.contains(&id) {
+            return Err(crate::Error::Locked);
+        }
+        let id_arc = match entries.shared.get(&id) {
+            Some(id_arc) => id_arc,
+            None => {
+                let id_arc = Arc::from(id);
+                entries.shared.insert(Arc::clone(&id_arc));
+                id_arc
+            }
+        };
+
+        Ok(Lock {
+            id: id_arc,
+            file: lock_file,
+            exclusive: false,
+        })
+    }
+
+    /// Return the owner of the exclusive lock on the given `id`.
+    ///
+    /// This returns `None` if the resource is not locked exclusively.
+    pub fn owner(id: Uuid, lock_dir: Option<&Path>) -> crate::Result<Option<LockOwner>> {
+        let mut lock_file = match File::open(Self::lock_path(id, lock_dir)) {
+            Ok(file) => file,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(error.into()),
+        };
+
+        // If a shared lock can be acquired, the resource is not locked exclusively, and any owner
+        // in the file is left over from a process which exited without releasing the lock.
+        if FileExt::try_lock_shared(&lock_file).is_ok() {
+            FileExt::unlock(&lock_file)?;
+            return Ok(None);
+        }
+
+        let mut contents = String::new();
+        lock_file.read_to_string(&mut contents)?;
+        Ok(LockOwner::decode(&contents))
     }
 }
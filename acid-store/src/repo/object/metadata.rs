@@ -14,12 +14,19 @@
  * limitations under the License.
  */
 
+use rmp_serde::from_read;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use std::time::SystemTime;
+use std::collections::BTreeMap;
+use std::iter::once;
+use std::time::{Duration, SystemTime};
 use uuid::Uuid;
 
+use super::chunking::Chunking;
 use super::config::RepositoryConfig;
 use super::encryption::{KeySalt, ResourceLimit};
+use super::header::HeaderMode;
+use super::key_slot::{KeySlot, DEFAULT_KEY_SLOT};
 use super::{Compression, Encryption};
 
 /// Metadata for a repository.
@@ -28,10 +35,8 @@ pub struct RepositoryMetadata {
     /// The unique ID of this repository.
     pub id: Uuid,
 
-    /// The number of bits that define a chunk boundary.
-    ///
-    /// The average size of a chunk will be 2^`chunker_bits` bytes.
-    pub chunker_bits: u32,
+    /// The method used to split data into chunks.
+    pub chunking: Chunking,
 
     /// The compression method being used in this repository.
     pub compression: Compression,
@@ -45,34 +50,167 @@ pub struct RepositoryMetadata {
     /// The maximum number of computations the key derivation function will perform.
     pub operations_limit: ResourceLimit,
 
-    /// The master encryption key encrypted with the user's password.
-    pub master_key: Vec<u8>,
-
-    /// The salt used to derive a key from the user's password.
-    pub salt: KeySalt,
+    /// The key slots which each store a copy of the master encryption key.
+    ///
+    /// This is empty if encryption is disabled.
+    pub key_slots: Vec<KeySlot>,
 
-    /// The ID of the chunk which stores the repository's header.
+    /// The ID of the block which stores the latest snapshot of the repository's header.
     pub header: Uuid,
 
+    /// The IDs of the blocks which store changes to the header since the latest snapshot.
+    ///
+    /// These are applied in order on top of the snapshot when the repository is opened.
+    pub journal: Vec<Uuid>,
+
+    /// How the header of the repository is stored.
+    ///
+    /// If the header is paged, `header` is the ID of the block which stores its root.
+    pub header_mode: HeaderMode,
+
+    /// The compression dictionaries which have been trained for this repository, oldest first.
+    ///
+    /// New chunks are compressed using the newest dictionary.
+    pub dictionaries: Vec<DictionaryBlock>,
+
     /// The time this repository was created.
     pub creation_time: SystemTime,
+
+    /// The ID of the block which stores the reconfiguration in progress, if there is one.
+    pub reconfiguration: Option<Uuid>,
 }
 
 impl RepositoryMetadata {
+    /// Deserialize repository metadata from `data`, which may be in the legacy format.
+    ///
+    /// Legacy metadata is converted to the current format in memory.
+    ///
+    /// # Errors
+    /// - `Error::Corrupt`: The metadata could not be deserialized in either format.
+    pub fn decode(data: &[u8]) -> crate::Result<Self> {
+        if let Ok(metadata) = from_read(data) {
+            return Ok(metadata);
+        }
+        let legacy_metadata: LegacyMetadata = from_read(data).map_err(|_| crate::Error::Corrupt)?;
+        Ok(legacy_metadata.upgrade())
+    }
+
     /// Create a `RepositoryInfo` using the metadata in this struct.
     pub fn to_info(&self) -> RepositoryInfo {
         RepositoryInfo {
             id: self.id,
             config: RepositoryConfig {
-                chunker_bits: self.chunker_bits,
+                chunking: self.chunking,
                 compression: self.compression,
                 encryption: self.encryption,
                 memory_limit: self.memory_limit,
                 operations_limit: self.operations_limit,
+                header_mode: self.header_mode,
+                ..RepositoryConfig::default()
             },
             created: self.creation_time,
         }
     }
+
+    /// Return the blocks referenced by this metadata which store the header and dictionaries.
+    ///
+    /// If the header is paged, this only includes the block which stores its root.
+    pub fn blocks(&self) -> Vec<UnusedBlock> {
+        once(self.header)
+            .chain(self.journal.iter().copied())
+            .chain(
+                self.dictionaries
+                    .iter()
+                    .map(|dictionary| dictionary.block_id),
+            )
+            .map(|id| UnusedBlock { id, size: 0 })
+            .collect()
+    }
+
+    /// Return the blocks which store dictionaries that were trained since `earlier`.
+    pub fn dictionaries_added_since(&self, earlier: &RepositoryMetadata) -> Vec<UnusedBlock> {
+        self.dictionaries
+            .iter()
+            .filter(|dictionary| !earlier.dictionaries.contains(dictionary))
+            .map(|dictionary| UnusedBlock {
+                id: dictionary.block_id,
+                size: 0,
+            })
+            .collect()
+    }
+}
+
+/// Metadata for a repository in the legacy format, which has a single password and no journal.
+///
+/// The header and chunks of a legacy repository are stored the same way as in the current format,
+/// so only the metadata needs to be converted to open it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct LegacyMetadata {
+    /// The unique ID of this repository.
+    id: Uuid,
+
+    /// The number of bits that define a chunk boundary for ZPAQ chunking.
+    chunker_bits: u32,
+
+    /// The compression method being used in this repository.
+    compression: Compression,
+
+    /// The encryption method being used in this repository.
+    encryption: Encryption,
+
+    /// The maximum amount of memory the key derivation function will use in bytes.
+    memory_limit: ResourceLimit,
+
+    /// The maximum number of computations the key derivation function will perform.
+    operations_limit: ResourceLimit,
+
+    /// The master encryption key encrypted with the user's password.
+    master_key: Vec<u8>,
+
+    /// The salt used to derive a key from the user's password.
+    salt: KeySalt,
+
+    /// The ID of the block which stores the repository's header.
+    header: Uuid,
+
+    /// The time this repository was created.
+    creation_time: SystemTime,
+}
+
+impl LegacyMetadata {
+    /// Convert this metadata to the current format.
+    ///
+    /// The password becomes the key slot named `DEFAULT_KEY_SLOT`.
+    fn upgrade(self) -> RepositoryMetadata {
+        let key_slots = if self.encryption == Encryption::None {
+            Vec::new()
+        } else {
+            vec![KeySlot {
+                name: DEFAULT_KEY_SLOT.to_string(),
+                salt: Some(self.salt),
+                memory_limit: self.memory_limit,
+                operations_limit: self.operations_limit,
+                master_key: self.master_key,
+            }]
+        };
+        RepositoryMetadata {
+            id: self.id,
+            chunking: Chunking::Zpaq {
+                bits: self.chunker_bits,
+            },
+            compression: self.compression,
+            encryption: self.encryption,
+            memory_limit: self.memory_limit,
+            operations_limit: self.operations_limit,
+            key_slots,
+            header: self.header,
+            journal: Vec::new(),
+            header_mode: HeaderMode::Memory,
+            dictionaries: Vec::new(),
+            creation_time: self.creation_time,
+            reconfiguration: None,
+        }
+    }
 }
 
 /// Information about a repository.
@@ -98,6 +236,83 @@ impl RepositoryInfo {
     pub fn created(&self) -> SystemTime {
         self.created
     }
+
+    /// The encryption method used by this repository.
+    ///
+    /// If the repository was created with `Encryption::Auto`, this is the cipher which was
+    /// selected.
+    pub fn encryption(&self) -> Encryption {
+        self.config.encryption
+    }
+}
+
+/// Metadata about an object in a repository.
+///
+/// This is stored in the repository's header along with the object, so it can be read and changed
+/// without reading or rewriting the object's data.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ObjectMetadata {
+    pub(super) created: SystemTime,
+    pub(super) modified: SystemTime,
+    pub(super) tags: BTreeMap<String, Vec<u8>>,
+}
+
+impl ObjectMetadata {
+    /// The time the object was created.
+    ///
+    /// This is the time its key was last passed to `ObjectRepository::insert`. For objects which
+    /// were written before the repository was upgraded from the legacy format, this and the
+    /// modification time are `UNIX_EPOCH`.
+    pub fn created(&self) -> SystemTime {
+        self.created
+    }
+
+    /// The time the data in the object was last modified.
+    ///
+    /// Changing the object's tags does not change this.
+    pub fn modified(&self) -> SystemTime {
+        self.modified
+    }
+
+    /// Return an iterator over the names of the user-defined tags associated with the object.
+    ///
+    /// Tags can be changed with `ObjectRepository::set_tag` and `ObjectRepository::remove_tag`.
+    pub fn tag_names(&self) -> impl Iterator<Item = &str> {
+        self.tags.keys().map(String::as_str)
+    }
+
+    /// Return the value of the tag with the given `name` or `None` if there is no such tag.
+    ///
+    /// # Errors
+    /// - `Error::Deserialize`: The value of the tag could not be deserialized as a `T`.
+    pub fn tag<T: DeserializeOwned>(&self, name: &str) -> crate::Result<Option<T>> {
+        match self.tags.get(name) {
+            Some(value) => from_read(value.as_slice())
+                .map(Some)
+                .map_err(|_| crate::Error::Deserialize),
+            None => Ok(None),
+        }
+    }
+}
+
+/// A reference to a block which stores a trained compression dictionary.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DictionaryBlock {
+    /// The ID of the dictionary, which is stored in data compressed with it.
+    pub id: u32,
+
+    /// The ID of the block which stores the dictionary.
+    pub block_id: Uuid,
+}
+
+/// A block which is no longer used by the repository and is waiting to be removed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UnusedBlock {
+    /// The ID of the block.
+    pub id: Uuid,
+
+    /// The size of the chunk stored in the block, or `0` if the block stores part of the header.
+    pub size: u64,
 }
 
 /// Statistics about a repository.
@@ -122,3 +337,77 @@ impl RepositoryStats {
         self.actual_size
     }
 }
+
+/// Cumulative statistics about the encoding of chunks in a repository.
+///
+/// These statistics are kept in memory and count from when the repository was opened, or from when
+/// an object handle was created for the statistics returned by `Object::encoding_stats`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct EncodingStats {
+    pub(super) compression_time: Duration,
+    pub(super) decompression_time: Duration,
+    pub(super) encryption_time: Duration,
+    pub(super) decryption_time: Duration,
+    pub(super) bytes_written: u64,
+    pub(super) bytes_read: u64,
+}
+
+impl EncodingStats {
+    /// The total time spent compressing chunks.
+    pub fn compression_time(&self) -> Duration {
+        self.compression_time
+    }
+
+    /// The total time spent decompressing chunks.
+    pub fn decompression_time(&self) -> Duration {
+        self.decompression_time
+    }
+
+    /// The total time spent encrypting chunks.
+    pub fn encryption_time(&self) -> Duration {
+        self.encryption_time
+    }
+
+    /// The total time spent decrypting chunks.
+    pub fn decryption_time(&self) -> Duration {
+        self.decryption_time
+    }
+
+    /// The total number of encoded bytes written to the data store as chunks.
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes_written
+    }
+
+    /// The total number of encoded bytes read from the data store as chunks.
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes_read
+    }
+}
+
+/// Statistics about unused blocks removed by garbage collection.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct GcStats {
+    pub(super) blocks: u64,
+    pub(super) bytes: u64,
+    pub(super) remaining: u64,
+}
+
+impl GcStats {
+    /// The number of blocks which were removed.
+    pub fn blocks(&self) -> u64 {
+        self.blocks
+    }
+
+    /// The total size of the chunks which were removed.
+    ///
+    /// This counts the unencoded size of chunks of data and does not include blocks which stored
+    /// the repository's header.
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    /// The number of unused blocks which are still waiting to be removed.
+    pub fn remaining(&self) -> u64 {
+        self.remaining
+    }
+}
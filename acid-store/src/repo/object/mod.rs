@@ -14,25 +14,36 @@
  * limitations under the License.
  */
 
+pub use self::chunking::Chunking;
 pub use self::compression::Compression;
 pub use self::config::RepositoryConfig;
 pub use self::encryption::{Encryption, ResourceLimit};
-pub use self::header::Key;
-pub use self::lock::LockStrategy;
-pub use self::metadata::{RepositoryInfo, RepositoryStats};
+pub use self::header::{HeaderMode, Key};
+pub use self::key_slot::{KeySlotInfo, KeySlotKind, SlotKey, DEFAULT_KEY_SLOT, RAW_KEY_SIZE};
+pub use self::lock::{LockOwner, LockStrategy};
+pub use self::metadata::{EncodingStats, GcStats, ObjectMetadata, RepositoryInfo, RepositoryStats};
 pub use self::object::{ContentId, Object, ReadOnlyObject};
 pub use self::open_repo::OpenRepo;
+pub use self::read_only::{ReadMode, ReadOnlyObjectRepository};
+pub use self::reconfigure::ReconfigureProgress;
 pub use self::repository::ObjectRepository;
+pub use self::savepoint::Savepoint;
 
+mod btree;
 mod chunk_store;
 mod chunking;
 mod compression;
 mod config;
 mod encryption;
 mod header;
+mod key_slot;
 mod lock;
 mod metadata;
 mod object;
 mod open_repo;
+mod pool;
+mod read_only;
+mod reconfigure;
 mod repository;
+mod savepoint;
 mod state;
@@ -14,12 +14,15 @@
  * limitations under the License.
  */
 
+use std::borrow::Cow;
 use std::clone::Clone;
 use std::cmp::min;
+use std::collections::BTreeMap;
 use std::fmt::Debug;
 use std::hash::Hash;
 use std::io::{self, Read, Seek, SeekFrom, Write};
 use std::mem::replace;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use blake2::digest::{Input, VariableOutput};
 use blake2::VarBlake2b;
@@ -31,12 +34,19 @@ use crate::repo::object::chunk_store::{ChunkReader, ChunkWriter};
 use crate::repo::object::state::{ChunkLocation, ObjectState};
 use crate::store::DataStore;
 
-use super::header::Key;
+use super::header::{Key, HEADER_READ_FAILED};
+use super::metadata::{EncodingStats, ObjectMetadata};
 use super::state::RepositoryState;
 
 /// The size of the checksums used for uniquely identifying chunks.
 pub const CHUNK_HASH_SIZE: usize = 32;
 
+/// The number of batches of chunks which are buffered before they are written.
+///
+/// Each batch has one chunk per encoding thread. Buffering more than one batch lets the next batch
+/// be encoded while the previous one is written to the data store.
+const WRITE_BATCHES: usize = 4;
+
 /// A 256-bit checksum used for uniquely identifying a chunk.
 pub type ChunkHash = [u8; CHUNK_HASH_SIZE];
 
@@ -69,14 +79,60 @@ pub struct ObjectHandle {
 
     /// The checksums of the chunks which make up the data.
     pub chunks: Vec<Chunk>,
+
+    /// The time the object was created.
+    ///
+    /// This and the following fields are missing from handles in legacy repositories, so they
+    /// have defaults.
+    #[serde(default = "unknown_time")]
+    pub created: SystemTime,
+
+    /// The time the data in the object was last modified.
+    #[serde(default = "unknown_time")]
+    pub modified: SystemTime,
+
+    /// The user-defined tags associated with the object.
+    ///
+    /// Each value is serialized with MessagePack.
+    #[serde(default)]
+    pub tags: BTreeMap<String, Vec<u8>>,
 }
 
-impl Default for ObjectHandle {
-    fn default() -> Self {
+/// Return the time which is recorded for objects that were written before times were recorded.
+fn unknown_time() -> SystemTime {
+    UNIX_EPOCH
+}
+
+impl ObjectHandle {
+    /// Return a handle for a new empty object.
+    pub fn new() -> Self {
+        let now = SystemTime::now();
         Self {
             size: 0,
             chunks: Vec::new(),
+            created: now,
+            modified: now,
+            tags: BTreeMap::new(),
+        }
+    }
+
+    /// Return the metadata for this object.
+    pub fn metadata(&self) -> ObjectMetadata {
+        ObjectMetadata {
+            created: self.created,
+            modified: self.modified,
+            tags: self.tags.clone(),
+        }
+    }
+
+    /// Return a `ContentId` representing the contents of this object.
+    pub fn content_id(&self) -> ContentId {
+        // The content ID is just a hash of all the chunk hashes, which is cheap to compute.
+        let mut concatenation = Vec::new();
+        for chunk in &self.chunks {
+            concatenation.extend_from_slice(&chunk.hash);
         }
+        ContentId(chunk_hash(concatenation.as_slice()))
     }
 }
 
@@ -92,7 +148,7 @@ impl Default for ObjectHandle {
 /// valid for the lifetime of a repository, meaning that they can be compared across invocations of
 /// the library.
 #[derive(Debug, PartialEq, Eq, Hash, Clone, Copy, Serialize, Deserialize)]
-pub struct ContentId([u8; 32]);
+pub struct ContentId(pub(crate) [u8; 32]);
 
 /// A wrapper for getting information about an object.
 struct ObjectInfo<'a, K: Key, S: DataStore> {
@@ -102,31 +158,38 @@ struct ObjectInfo<'a, K: Key, S: DataStore> {
 }
 
 impl<'a, K: Key, S: DataStore> ObjectInfo<'a, K, S> {
+    /// Return the handle for this object.
+    fn handle(&self) -> crate::Result<Cow<'a, ObjectHandle>> {
+        self.repo_state
+            .object(self.key)?
+            .ok_or(crate::Error::NotFound)
+    }
+
     /// Return the size of the object in bytes.
-    fn size(&self) -> u64 {
-        let handle = self.repo_state.header.objects.get(&self.key).unwrap();
-        handle.size
+    fn size(&self) -> crate::Result<u64> {
+        Ok(self.handle()?.size)
     }
 
     /// Return a `ContentId` representing the contents of this object.
-    fn content_id(&self) -> ContentId {
-        let handle = self.repo_state.header.objects.get(&self.key).unwrap();
+    fn content_id(&self) -> crate::Result<ContentId> {
+        Ok(self.handle()?.content_id())
+    }
 
-        // The content ID is just a hash of all the chunk hashes, which is cheap to compute.
-        let mut concatenation = Vec::new();
-        for chunk in &handle.chunks {
-            concatenation.extend_from_slice(&chunk.hash);
-        }
-        ContentId(chunk_hash(concatenation.as_slice()))
+    /// Return the metadata for this object.
+    fn metadata(&self) -> crate::Result<ObjectMetadata> {
+        Ok(self.handle()?.metadata())
     }
 
     /// Verify the integrity of the data in this object.
     fn verify(&self) -> crate::Result<bool> {
-        let handle = self.repo_state.header.objects.get(&self.key).unwrap();
+        let handle = self.handle()?;
         let expected_chunks = handle.chunks.iter().copied().collect::<Vec<_>>();
 
         for chunk in expected_chunks {
-            match self.repo_state.read_chunk(chunk) {
+            match self
+                .repo_state
+                .read_chunk(chunk, Some(&self.object_state.counters))
+            {
                 Ok(data) => {
                     if data.len() != chunk.size || chunk_hash(&data) != chunk.hash {
                         return Ok(false);
@@ -142,8 +205,8 @@ impl<'a, K: Key, S: DataStore> ObjectInfo<'a, K, S> {
     }
 
     /// Return the chunk at the current seek position or `None` if there is none.
-    fn current_chunk(&self) -> Option<ChunkLocation> {
-        let handle = self.repo_state.header.objects.get(&self.key).unwrap();
+    fn current_chunk(&self) -> crate::Result<Option<ChunkLocation>> {
+        let handle = self.handle()?;
 
         let mut chunk_start = 0u64;
         let mut chunk_end = 0u64;
@@ -151,19 +214,19 @@ impl<'a, K: Key, S: DataStore> ObjectInfo<'a, K, S> {
         for (index, chunk) in handle.chunks.iter().enumerate() {
             chunk_end += chunk.size as u64;
             if self.object_state.position >= chunk_start && self.object_state.position < chunk_end {
-                return Some(ChunkLocation {
+                return Ok(Some(ChunkLocation {
                     chunk: *chunk,
                     start: chunk_start,
                     end: chunk_end,
                     position: self.object_state.position,
                     index,
-                });
+                }));
             }
             chunk_start += chunk.size as u64;
         }
 
         // There are no chunks in the object.
-        None
+        Ok(None)
     }
 }
 
@@ -188,15 +251,15 @@ impl<'a, K: Key, S: DataStore> ObjectReader<'a, K, S> {
     /// The returned slice will be no longer than `size`.
     fn read_chunk(&mut self, size: usize) -> crate::Result<&[u8]> {
         // If the object is empty, there's no data to read.
-        let current_location = match self.object_info().current_chunk() {
+        let current_location = match self.object_info().current_chunk()? {
             Some(location) => location,
             None => return Ok(&[]),
         };
 
         // If we're reading from a new chunk, read the contents of that chunk into the read buffer.
         if Some(current_location.chunk) != self.object_state.buffered_chunk {
+            self.object_state.read_buffer = self.next_chunk(&current_location)?;
             self.object_state.buffered_chunk = Some(current_location.chunk);
-            self.object_state.read_buffer = self.repo_state.read_chunk(current_location.chunk)?;
         }
 
         let start = current_location.relative_position();
@@ -204,6 +267,39 @@ impl<'a, K: Key, S: DataStore> ObjectReader<'a, K, S> {
         Ok(&self.object_state.read_buffer[start..end])
     }
 
+    /// Return the contents of the chunk at the given `location`.
+    ///
+    /// If the repository uses more than one encoding thread, this decodes the chunks which follow
+    /// it in parallel so that they are ready when the object is read sequentially.
+    fn next_chunk(&mut self, location: &ChunkLocation) -> crate::Result<Vec<u8>> {
+        let read_ahead = &mut self.object_state.read_ahead;
+        if let Some(index) = read_ahead
+            .iter()
+            .position(|(chunk, _)| *chunk == location.chunk)
+        {
+            let (_, data) = read_ahead.swap_remove(index);
+            return Ok(data);
+        }
+
+        let threads = self.repo_state.encoding_threads;
+        if threads <= 1 {
+            return self
+                .repo_state
+                .read_chunk(location.chunk, Some(&self.object_state.counters));
+        }
+
+        let handle = self.object_info().handle()?;
+        let end_index = min(location.index + threads, handle.chunks.len());
+        let chunks = &handle.chunks[location.index..end_index];
+        let mut chunks_data = self
+            .repo_state
+            .read_chunks(chunks, Some(&self.object_state.counters))?;
+        let data = chunks_data.remove(0);
+        self.object_state.read_ahead = chunks[1..].iter().copied().zip(chunks_data).collect();
+
+        Ok(data)
+    }
+
     /// Attempt to deserialize the bytes in the object as a value of type `T`.
     fn deserialize<T: DeserializeOwned>(&mut self) -> crate::Result<T> {
         self.seek(SeekFrom::Start(0))?;
@@ -213,10 +309,7 @@ impl<'a, K: Key, S: DataStore> ObjectReader<'a, K, S> {
 
 impl<'a, K: Key, S: DataStore> Seek for ObjectReader<'a, K, S> {
     fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
-        let object_size = {
-            let handle = self.repo_state.header.objects.get(&self.key).unwrap();
-            handle.size
-        };
+        let object_size = self.object_info().handle()?.size;
 
         let new_position = match pos {
             SeekFrom::Start(offset) => min(object_size, offset),
@@ -288,9 +381,8 @@ impl<'a, K: Key, S: DataStore> ObjectWriter<'a, K, S> {
 
     fn truncate(&mut self, length: u64) -> crate::Result<()> {
         self.flush()?;
-        let handle = self.repo_state.header.objects.get(&self.key).unwrap();
 
-        if length >= handle.size {
+        if length >= self.object_info().handle()?.size {
             return Ok(());
         }
 
@@ -299,26 +391,30 @@ impl<'a, K: Key, S: DataStore> ObjectWriter<'a, K, S> {
 
         // Truncating the object may mean slicing a chunk in half. Because we can't edit chunks
         // in-place, we need to read the final chunk, slice it, and write it back.
-        let end_location = match self.object_info().current_chunk() {
+        let end_location = match self.object_info().current_chunk()? {
             Some(location) => location,
             None => return Ok(()),
         };
-        let last_chunk = self.repo_state.read_chunk(end_location.chunk)?;
+        let last_chunk = self
+            .repo_state
+            .read_chunk(end_location.chunk, Some(&self.object_state.counters))?;
         let new_last_chunk = &last_chunk[..end_location.relative_position()];
-        let new_last_chunk = self.repo_state.write_chunk(&new_last_chunk)?;
+        let new_last_chunk = self
+            .repo_state
+            .write_chunk(&new_last_chunk, Some(&self.object_state.counters))?;
 
-        let key = self.key.clone();
-        let mut handle = self.repo_state.header.objects.get_mut(&key).unwrap();
+        self.repo_state.update_object(self.key, |handle| {
+            // Remove all chunks including and after the final chunk.
+            handle.chunks.drain(end_location.index..);
 
-        // Remove all chunks including and after the final chunk.
-        handle.chunks.drain(end_location.index..);
+            // Append the new final chunk which has been sliced.
+            handle.chunks.push(new_last_chunk);
 
-        // Append the new final chunk which has been sliced.
-        handle.chunks.push(new_last_chunk);
-
-        // Update the object size.
-        let current_size = handle.size;
-        handle.size = min(length, current_size);
+            // Update the object size.
+            let current_size = handle.size;
+            handle.size = min(length, current_size);
+            handle.modified = SystemTime::now();
+        })?;
 
         // Restore the seek position.
         self.object_state.position = min(original_position, length);
@@ -337,11 +433,23 @@ impl<'a, K: Key, S: DataStore> ObjectWriter<'a, K, S> {
     }
 
     /// Write chunks stored in the chunker to the repository.
-    fn write_chunks(&mut self) -> crate::Result<()> {
-        for chunk_data in self.object_state.chunker.chunks() {
-            let chunk = self.repo_state.write_chunk(&chunk_data)?;
-            self.object_state.new_chunks.push(chunk);
+    ///
+    /// Chunks are written in groups of `WRITE_BATCHES` batches of `encoding_threads` so that they
+    /// can be encoded in parallel while earlier batches are written to the data store. If `flush`
+    /// is `true`, all pending chunks are written.
+    fn write_chunks(&mut self, flush: bool) -> crate::Result<()> {
+        let new_chunks = self.object_state.chunker.chunks();
+        self.object_state.pending_chunks.extend(new_chunks);
+
+        let batch_size = self.repo_state.encoding_threads * WRITE_BATCHES;
+        if flush || self.object_state.pending_chunks.len() >= batch_size {
+            let pending_chunks = replace(&mut self.object_state.pending_chunks, Vec::new());
+            let chunks = self
+                .repo_state
+                .write_chunks(&pending_chunks, Some(&self.object_state.counters))?;
+            self.object_state.new_chunks.extend(chunks);
         }
+
         Ok(())
     }
 }
@@ -353,9 +461,9 @@ impl<'a, K: Key, S: DataStore> ObjectWriter<'a, K, S> {
 impl<'a, K: Key, S: DataStore> Write for ObjectWriter<'a, K, S> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         // Check if this is the first time `write` is being called after calling `flush`.
-        if self.object_state.chunker.is_empty() {
+        if self.object_state.is_flushed() {
             // Because we're starting a new write, we need to set the starting location.
-            self.object_state.start_location = self.object_info().current_chunk();
+            self.object_state.start_location = self.object_info().current_chunk()?;
 
             if let Some(location) = &self.object_state.start_location {
                 let chunk = location.chunk;
@@ -363,7 +471,9 @@ impl<'a, K: Key, S: DataStore> Write for ObjectWriter<'a, K, S> {
 
                 // We need to make sure the data before the seek position is saved when we replace
                 // the chunk. Read this data from the repository and write it to the chunker.
-                let first_chunk = self.repo_state.read_chunk(chunk)?;
+                let first_chunk = self
+                    .repo_state
+                    .read_chunk(chunk, Some(&self.object_state.counters))?;
                 self.object_state
                     .chunker
                     .write_all(&first_chunk[..position])?;
@@ -372,7 +482,7 @@ impl<'a, K: Key, S: DataStore> Write for ObjectWriter<'a, K, S> {
 
         // Chunk the data and write any complete chunks to the repository.
         self.object_state.chunker.write_all(buf)?;
-        self.write_chunks()?;
+        self.write_chunks(false)?;
 
         // Advance the seek position.
         self.object_state.position += buf.len() as u64;
@@ -381,17 +491,19 @@ impl<'a, K: Key, S: DataStore> Write for ObjectWriter<'a, K, S> {
     }
 
     fn flush(&mut self) -> io::Result<()> {
-        if self.object_state.chunker.is_empty() {
+        if self.object_state.is_flushed() {
             // No new data has been written since data was last flushed.
             return Ok(());
         }
 
-        let current_chunk = self.object_info().current_chunk();
+        let current_chunk = self.object_info().current_chunk()?;
 
         if let Some(location) = &current_chunk {
             // We need to make sure the data after the seek position is saved when we replace the
             // current chunk. Read this data from the repository and write it to the chunker.
-            let last_chunk = self.repo_state.read_chunk(location.chunk)?;
+            let last_chunk = self
+                .repo_state
+                .read_chunk(location.chunk, Some(&self.object_state.counters))?;
             self.object_state
                 .chunker
                 .write_all(&last_chunk[location.relative_position()..])?;
@@ -399,7 +511,7 @@ impl<'a, K: Key, S: DataStore> Write for ObjectWriter<'a, K, S> {
 
         // Write all the remaining data in the chunker to the repository.
         self.object_state.chunker.flush()?;
-        self.write_chunks()?;
+        self.write_chunks(true)?;
 
         // Find the index of the first chunk which is being overwritten.
         let start_index = self
@@ -409,28 +521,22 @@ impl<'a, K: Key, S: DataStore> Write for ObjectWriter<'a, K, S> {
             .map(|location| location.index)
             .unwrap_or(0);
 
-        let end_index = {
-            let handle = self.repo_state.header.objects.get(&self.key).unwrap();
-
-            // Find the index of the last chunk which is being overwritten.
-            match &current_chunk {
-                Some(location) => location.index + 1,
-                None => handle.chunks.len(),
-            }
+        // Find the index of the last chunk which is being overwritten.
+        let end_index = match &current_chunk {
+            Some(location) => location.index + 1,
+            None => self.object_info().handle()?.chunks.len(),
         };
 
         let new_chunks = replace(&mut self.object_state.new_chunks, Vec::new());
 
-        {
-            let key = self.key.clone();
-            let mut handle = self.repo_state.header.objects.get_mut(&key).unwrap();
-
+        self.repo_state.update_object(self.key, |handle| {
             // Update chunk references in the object handle to reflect changes.
             handle.chunks.splice(start_index..end_index, new_chunks);
 
             // Update the size of the object in the object handle to reflect changes.
             handle.size = handle.chunks.iter().map(|chunk| chunk.size as u64).sum();
-        }
+            handle.modified = SystemTime::now();
+        })?;
 
         self.object_state.start_location = None;
 
@@ -458,10 +564,10 @@ pub struct ReadOnlyObject<'a, K: Key, S: DataStore> {
 
 impl<'a, K: Key, S: DataStore> ReadOnlyObject<'a, K, S> {
     pub(super) fn new(repo_state: &'a RepositoryState<K, S>, key: K) -> Self {
-        let chunker_bits = repo_state.metadata.chunker_bits;
+        let chunking = repo_state.metadata.chunking;
         Self {
             repo_state,
-            object_state: ObjectState::new(chunker_bits),
+            object_state: ObjectState::new(chunking),
             key,
         }
     }
@@ -486,6 +592,17 @@ impl<'a, K: Key, S: DataStore> ReadOnlyObject<'a, K, S> {
     ///
     /// See `Object::size` for details.
     pub fn size(&self) -> u64 {
+        self.try_size().expect(HEADER_READ_FAILED)
+    }
+
+    /// Return the size of the object in bytes.
+    ///
+    /// See `Object::try_size` for details.
+    ///
+    /// # Errors
+    /// - `Error::InvalidData`: Ciphertext verification failed.
+    /// - `Error::Store`: An error occurred with the data store.
+    pub fn try_size(&self) -> crate::Result<u64> {
         self.object_info().size()
     }
 
@@ -493,9 +610,31 @@ impl<'a, K: Key, S: DataStore> ReadOnlyObject<'a, K, S> {
     ///
     /// See `Object::content_id` for details.
     pub fn content_id(&self) -> ContentId {
+        self.try_content_id().expect(HEADER_READ_FAILED)
+    }
+
+    /// Return a `ContentId` representing the contents of this object.
+    ///
+    /// See `Object::try_content_id` for details.
+    ///
+    /// # Errors
+    /// - `Error::InvalidData`: Ciphertext verification failed.
+    /// - `Error::Store`: An error occurred with the data store.
+    pub fn try_content_id(&self) -> crate::Result<ContentId> {
         self.object_info().content_id()
     }
 
+    /// Return the metadata for this object.
+    ///
+    /// See `Object::metadata` for details.
+    ///
+    /// # Errors
+    /// - `Error::InvalidData`: Ciphertext verification failed.
+    /// - `Error::Store`: An error occurred with the data store.
+    pub fn metadata(&self) -> crate::Result<ObjectMetadata> {
+        self.object_info().metadata()
+    }
+
     /// Verify the integrity of the data in this object.
     ///
     /// See `Object::verify` for details.
@@ -503,6 +642,13 @@ impl<'a, K: Key, S: DataStore> ReadOnlyObject<'a, K, S> {
         self.object_info().verify()
     }
 
+    /// Return statistics about the chunks encoded and decoded through this object handle.
+    ///
+    /// See `Object::encoding_stats` for details.
+    pub fn encoding_stats(&self) -> EncodingStats {
+        self.object_state.counters.snapshot()
+    }
+
     /// Deserialize a value serialized with `Object::serialize`.
     ///
     /// See `Object::deserialize` for details.
@@ -557,10 +703,10 @@ pub struct Object<'a, K: Key, S: DataStore> {
 
 impl<'a, K: Key, S: DataStore> Object<'a, K, S> {
     pub(super) fn new(repo_state: &'a mut RepositoryState<K, S>, key: K) -> Self {
-        let chunker_bits = repo_state.metadata.chunker_bits;
+        let chunking = repo_state.metadata.chunking;
         Self {
             repo_state,
-            object_state: ObjectState::new(chunker_bits),
+            object_state: ObjectState::new(chunking),
             key,
         }
     }
@@ -593,7 +739,22 @@ impl<'a, K: Key, S: DataStore> Object<'a, K, S> {
     ///
     /// Unflushed data is not accounted for when calculating the size, so you may want to explicitly
     /// flush written data with `flush` before calling this method.
+    ///
+    /// # Panics
+    /// With `HeaderMode::Paged`, this panics if the object's handle can't be read from the data
+    /// store. Use `try_size` to handle that error.
     pub fn size(&self) -> u64 {
+        self.try_size().expect(HEADER_READ_FAILED)
+    }
+
+    /// Return the size of the object in bytes.
+    ///
+    /// This is like `size`, but returns an error if the object's handle can't be read.
+    ///
+    /// # Errors
+    /// - `Error::InvalidData`: Ciphertext verification failed.
+    /// - `Error::Store`: An error occurred with the data store.
+    pub fn try_size(&self) -> crate::Result<u64> {
         self.object_info().size()
     }
 
@@ -604,10 +765,40 @@ impl<'a, K: Key, S: DataStore> Object<'a, K, S> {
     ///
     /// Unflushed data is not accounted for when calculating the `ContentId`, so you may want to
     /// explicitly flush written data with `flush` before calling this method.
+    ///
+    /// # Panics
+    /// With `HeaderMode::Paged`, this panics if the object's handle can't be read from the data
+    /// store. Use `try_content_id` to handle that error.
     pub fn content_id(&self) -> ContentId {
+        self.try_content_id().expect(HEADER_READ_FAILED)
+    }
+
+    /// Return a `ContentId` representing the contents of this object.
+    ///
+    /// This is like `content_id`, but returns an error if the object's handle can't be read.
+    ///
+    /// # Errors
+    /// - `Error::InvalidData`: Ciphertext verification failed.
+    /// - `Error::Store`: An error occurred with the data store.
+    pub fn try_content_id(&self) -> crate::Result<ContentId> {
         self.object_info().content_id()
     }
 
+    /// Return the metadata for this object.
+    ///
+    /// Unflushed data is not accounted for in the modification time, so you may want to explicitly
+    /// flush written data with `flush` before calling this method. To change the tags of an
+    /// object, use `ObjectRepository::set_tag`.
+    ///
+    /// With `HeaderMode::Paged`, this may need to read the object's handle from the data store.
+    ///
+    /// # Errors
+    /// - `Error::InvalidData`: Ciphertext verification failed.
+    /// - `Error::Store`: An error occurred with the data store.
+    pub fn metadata(&self) -> crate::Result<ObjectMetadata> {
+        self.object_info().metadata()
+    }
+
     /// Verify the integrity of the data in this object.
     ///
     /// This returns `true` if the object is valid and `false` if it is corrupt.
@@ -623,6 +814,15 @@ impl<'a, K: Key, S: DataStore> Object<'a, K, S> {
         self.object_info().verify()
     }
 
+    /// Return statistics about the chunks encoded and decoded through this object handle.
+    ///
+    /// Unlike `ObjectRepository::encoding_stats`, these only count the work done by reading from,
+    /// writing to and verifying this object since the handle was created. They are not affected by
+    /// other threads using the same repository, so they can be used to measure a single operation.
+    pub fn encoding_stats(&self) -> EncodingStats {
+        self.object_state.counters.snapshot()
+    }
+
     /// Truncate the object to the given `length`.
     ///
     /// If the given `length` is greater than or equal to the current size of the object, this does
@@ -17,6 +17,7 @@
 use crate::store::DataStore;
 
 use super::config::RepositoryConfig;
+use super::key_slot::{SlotKey, RAW_KEY_SIZE};
 use super::lock::LockStrategy;
 
 /// A repository which can be opened.
@@ -26,12 +27,18 @@ pub trait OpenRepo<S: DataStore> {
     /// If encryption is enabled, a `password` must be provided. Otherwise, this argument can be
     /// `None`.
     ///
+    /// A repository which was created by a version of the library from before key slots and
+    /// header journals were added is upgraded to the current format when it is opened. Its
+    /// password becomes the key slot named `DEFAULT_KEY_SLOT`.
+    ///
     /// # Errors
     /// - `Error::NotFound`: There is no repository in the given `store`.
     /// - `Error::Corrupt`: The repository is corrupt. This is most likely unrecoverable.
-    /// - `Error::Locked`: The repository is locked and `LockStrategy::Abort` was used.
+    /// - `Error::Locked`: The repository is locked and the `LockStrategy` gave up waiting.
     /// - `Error::Password`: The password provided is invalid.
     /// - `Error::KeyType`: The type `K` does not match the data in the repository.
+    /// - `Error::Unsupported`: The repository's encryption method is not supported on this
+    /// machine.
     /// - `Error::UnsupportedFormat`: This repository is an unsupported format. This can happen if
     /// the repository format is no longer supported by the current version of the library or if the
     /// repository being opened is of a different type.
@@ -40,6 +47,53 @@ pub trait OpenRepo<S: DataStore> {
     where
         Self: Sized;
 
+    /// Open the repository in the given data `store` using a raw `key`, failing if it doesn't exist.
+    ///
+    /// This unlocks a key slot which was added with `SlotKey::Raw`. Unlike opening a repository
+    /// with a password, this does not use a key derivation function, so it is suitable for
+    /// automated services which store a randomly generated key.
+    ///
+    /// # Errors
+    /// - `Error::NotFound`: There is no repository in the given `store`.
+    /// - `Error::Corrupt`: The repository is corrupt. This is most likely unrecoverable.
+    /// - `Error::Locked`: The repository is locked and the `LockStrategy` gave up waiting.
+    /// - `Error::Password`: The key provided is invalid.
+    /// - `Error::KeyType`: The type `K` does not match the data in the repository.
+    /// - `Error::Unsupported`: The repository's encryption method is not supported on this machine.
+    /// - `Error::UnsupportedFormat`: This repository is an unsupported format.
+    /// - `Error::Store`: An error occurred with the data store.
+    fn open_repo_with_key(
+        store: S,
+        strategy: LockStrategy,
+        key: &[u8; RAW_KEY_SIZE],
+    ) -> crate::Result<Self>
+    where
+        Self: Sized;
+
+    /// Open the repository in the given data `store` by unlocking the key slot named `slot`.
+    ///
+    /// Opening a repository with `open_repo` tries each of its password key slots in turn, and key
+    /// derivation is deliberately expensive. If the repository has several key slots, naming the
+    /// slot to unlock means the key is only derived once.
+    ///
+    /// # Errors
+    /// - `Error::NotFound`: There is no repository in the given `store`.
+    /// - `Error::Corrupt`: The repository is corrupt. This is most likely unrecoverable.
+    /// - `Error::Locked`: The repository is locked and the `LockStrategy` gave up waiting.
+    /// - `Error::Password`: There is no key slot named `slot` or the key provided is invalid.
+    /// - `Error::KeyType`: The type `K` does not match the data in the repository.
+    /// - `Error::Unsupported`: The repository's encryption method is not supported on this machine.
+    /// - `Error::UnsupportedFormat`: This repository is an unsupported format.
+    /// - `Error::Store`: An error occurred with the data store.
+    fn open_repo_with_slot(
+        store: S,
+        strategy: LockStrategy,
+        slot: &str,
+        key: SlotKey,
+    ) -> crate::Result<Self>
+    where
+        Self: Sized;
+
     /// Create a new repository backed by the given data `store`, failing if one already exists.
     ///
     /// A `config` must be provided to configure the new repository. If encryption is enabled, a
@@ -48,6 +102,8 @@ pub trait OpenRepo<S: DataStore> {
     /// # Errors
     /// - `Error::AlreadyExists`: A repository already exists in the given `store`.
     /// - `Error::Password` A password was required but not provided or provided but not required.
+    /// - `Error::InvalidConfig`: The given `config` is invalid.
+    /// - `Error::Unsupported`: The encryption method is not supported on this machine.
     /// - `Error::Store`: An error occurred with the data store.
     fn new_repo(store: S, config: RepositoryConfig, password: Option<&[u8]>) -> crate::Result<Self>
     where
@@ -60,10 +116,13 @@ pub trait OpenRepo<S: DataStore> {
     ///
     /// # Errors
     /// - `Error::Corrupt`: The repository is corrupt. This is most likely unrecoverable.
-    /// - `Error::Locked`: The repository is locked and `LockStrategy::Abort` was used.
+    /// - `Error::Locked`: The repository is locked and the `LockStrategy` gave up waiting.
     /// - `Error::Password`: The password provided is invalid.
     /// - `Error::Password` A password was required but not provided or provided but not required.
+    /// - `Error::InvalidConfig`: The given `config` is invalid.
     /// - `Error::KeyType`: The type `K` does not match the data in the repository.
+    /// - `Error::Unsupported`: The repository's encryption method is not supported on this
+    /// machine.
     /// - `Error::UnsupportedFormat`: This repository is an unsupported format. This can happen if
     /// the repository format is no longer supported by the current version of the library or if the
     /// repository being opened is of a different type.
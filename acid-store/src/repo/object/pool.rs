@@ -0,0 +1,62 @@
+/*
+ * Copyright 2019-2020 Wren Powell
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use rayon::{ThreadPool, ThreadPoolBuilder};
+
+/// A pool of worker threads which encode and decode chunks in parallel.
+///
+/// The threads are not started until they are first needed.
+#[derive(Debug, Default)]
+pub struct EncodingPool {
+    /// The pool of threads and the number of threads in it, if it has been started.
+    pool: Mutex<Option<(usize, Arc<ThreadPool>)>>,
+}
+
+impl EncodingPool {
+    /// Return a pool with the given number of `threads`.
+    ///
+    /// This returns `None` if `threads` is `1`, in which case chunks should be encoded and decoded
+    /// on the calling thread. If the number of `threads` differs from the last call, a new pool is
+    /// started.
+    ///
+    /// # Errors
+    /// - `Error::Io`: The threads could not be started.
+    pub fn get(&self, threads: usize) -> crate::Result<Option<Arc<ThreadPool>>> {
+        if threads <= 1 {
+            return Ok(None);
+        }
+
+        let mut pool = self.pool.lock().unwrap();
+        match pool.as_ref() {
+            Some((current_threads, current_pool)) if *current_threads == threads => {
+                Ok(Some(Arc::clone(current_pool)))
+            }
+            _ => {
+                let new_pool = ThreadPoolBuilder::new()
+                    .num_threads(threads)
+                    .thread_name(|index| format!("acid-store-encoder-{}", index))
+                    .build()
+                    .map_err(|error| io::Error::new(io::ErrorKind::Other, error))?;
+                let new_pool = Arc::new(new_pool);
+                *pool = Some((threads, Arc::clone(&new_pool)));
+                Ok(Some(new_pool))
+            }
+        }
+    }
+}
@@ -0,0 +1,325 @@
+/*
+ * Copyright 2019-2020 Wren Powell
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::borrow::{Borrow, ToOwned};
+use std::collections::HashSet;
+use std::hash::Hash;
+
+use serde::Serialize;
+
+use crate::store::DataStore;
+
+use super::header::{Key, HEADER_READ_FAILED};
+use super::key_slot::{KeySlot, KeySlotInfo, SlotKey, RAW_KEY_SIZE};
+use super::lock::LockStrategy;
+use super::metadata::{EncodingStats, GcStats, ObjectMetadata, RepositoryInfo, RepositoryStats};
+use super::object::ReadOnlyObject;
+use super::repository::{ObjectRepository, REPO_LOCKS};
+use super::state::RepositoryState;
+
+/// How a `ReadOnlyObjectRepository` is protected from changes made by writers.
+#[derive(Debug, PartialEq, Eq, Hash, Copy, Clone)]
+pub enum ReadMode {
+    /// Take a shared lock on the repository.
+    ///
+    /// Any number of readers can hold a shared lock at once, but the repository cannot be opened
+    /// for writing while one is held. The `LockStrategy` determines what happens if the repository
+    /// is already open for writing.
+    Shared(LockStrategy),
+
+    /// Take no lock and read a snapshot of the repository as of its last commit.
+    ///
+    /// This allows reading a repository while it is open for writing elsewhere. If a writer
+    /// commits while the repository is being opened, it is read again. Changes which are committed
+    /// after the repository is opened are not visible. If the writer calls `gc`, blocks
+    /// which are only referenced by the snapshot may be removed, in which case reading the objects
+    /// which use them will fail.
+    ///
+    /// Because this does not create a lock file, it can be used to open repositories on read-only
+    /// media.
+    Snapshot,
+}
+
+/// A read-only view of an `ObjectRepository`.
+///
+/// This provides the methods of `ObjectRepository` which don't modify the repository. Opening a
+/// `ReadOnlyObjectRepository` never writes to the data store, and it doesn't take the exclusive
+/// lock that `ObjectRepository` takes. How it is protected from concurrent writers depends on the
+/// `ReadMode` it is opened with.
+#[derive(Debug)]
+pub struct ReadOnlyObjectRepository<K: Key, S: DataStore> {
+    /// The state for this object repository.
+    state: RepositoryState<K, S>,
+}
+
+impl<K: Key, S: DataStore> ReadOnlyObjectRepository<K, S> {
+    /// Open the repository in the given data `store` for reading, failing if it doesn't exist.
+    ///
+    /// If encryption is enabled, a `password` must be provided. Otherwise, this argument can be
+    /// `None`.
+    ///
+    /// # Errors
+    /// - `Error::NotFound`: There is no repository in the given `store`.
+    /// - `Error::Corrupt`: The repository is corrupt. This is most likely unrecoverable.
+    /// - `Error::Locked`: The repository is open for writing and the `LockStrategy` gave up, or
+    /// the repository was committed to each time a snapshot of it was read.
+    /// - `Error::Password`: The password provided is invalid.
+    /// - `Error::KeyType`: The type `K` does not match the data in the repository.
+    /// - `Error::Unsupported`: The repository's encryption method is not supported on this machine.
+    /// - `Error::UnsupportedFormat`: This repository is an unsupported format.
+    /// - `Error::Store`: An error occurred with the data store.
+    pub fn open(store: S, mode: ReadMode, password: Option<&[u8]>) -> crate::Result<Self> {
+        Self::open_with_slot_key(store, mode, None, password.map(SlotKey::Password))
+    }
+
+    /// Open the repository in the given data `store` for reading using a raw `key`.
+    ///
+    /// This unlocks a key slot which was added with `SlotKey::Raw`.
+    ///
+    /// # Errors
+    /// - `Error::NotFound`: There is no repository in the given `store`.
+    /// - `Error::Corrupt`: The repository is corrupt. This is most likely unrecoverable.
+    /// - `Error::Locked`: The repository is open for writing and the `LockStrategy` gave up, or
+    /// the repository was committed to each time a snapshot of it was read.
+    /// - `Error::Password`: The key provided is invalid.
+    /// - `Error::KeyType`: The type `K` does not match the data in the repository.
+    /// - `Error::Unsupported`: The repository's encryption method is not supported on this machine.
+    /// - `Error::UnsupportedFormat`: This repository is an unsupported format.
+    /// - `Error::Store`: An error occurred with the data store.
+    pub fn open_with_key(
+        store: S,
+        mode: ReadMode,
+        key: &[u8; RAW_KEY_SIZE],
+    ) -> crate::Result<Self> {
+        Self::open_with_slot_key(store, mode, None, Some(SlotKey::Raw(key)))
+    }
+
+    /// Open the repository in the given data `store` for reading by unlocking the key slot named
+    /// `slot`.
+    ///
+    /// See `OpenRepo::open_repo_with_slot` for details.
+    ///
+    /// # Errors
+    /// - `Error::NotFound`: There is no repository in the given `store`.
+    /// - `Error::Corrupt`: The repository is corrupt. This is most likely unrecoverable.
+    /// - `Error::Locked`: The repository is open for writing and the `LockStrategy` gave up, or
+    /// the repository was committed to each time a snapshot of it was read.
+    /// - `Error::Password`: There is no key slot named `slot` or the key provided is invalid.
+    /// - `Error::KeyType`: The type `K` does not match the data in the repository.
+    /// - `Error::Unsupported`: The repository's encryption method is not supported on this machine.
+    /// - `Error::UnsupportedFormat`: This repository is an unsupported format.
+    /// - `Error::Store`: An error occurred with the data store.
+    pub fn open_with_slot(
+        store: S,
+        mode: ReadMode,
+        slot: &str,
+        key: SlotKey,
+    ) -> crate::Result<Self> {
+        Self::open_with_slot_key(store, mode, Some(slot), Some(key))
+    }
+
+    /// Open the repository in `store` using `mode`, unlocking the key slot named `slot` with `key`.
+    fn open_with_slot_key(
+        store: S,
+        mode: ReadMode,
+        slot: Option<&str>,
+        key: Option<SlotKey>,
+    ) -> crate::Result<Self> {
+        let state = ObjectRepository::<K, S>::open_state(
+            store,
+            |id, lock_dir| match mode {
+                ReadMode::Shared(strategy) => REPO_LOCKS
+                    .acquire_shared_lock(id, lock_dir, strategy)
+                    .map(Some),
+                ReadMode::Snapshot => Ok(None),
+            },
+            slot,
+            key,
+        )?;
+        Ok(ReadOnlyObjectRepository { state })
+    }
+
+    /// Return whether the given `key` exists in this repository.
+    ///
+    /// See `ObjectRepository::contains`.
+    ///
+    /// # Panics
+    /// With `HeaderMode::Paged`, this panics if the header can't be read from the data store. Use
+    /// `try_contains` to handle that error.
+    pub fn contains<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + Serialize + ?Sized,
+    {
+        self.try_contains(key).expect(HEADER_READ_FAILED)
+    }
+
+    /// Return whether the given `key` exists in this repository.
+    ///
+    /// See `ObjectRepository::try_contains`.
+    ///
+    /// # Errors
+    /// - `Error::InvalidData`: Ciphertext verification failed.
+    /// - `Error::Store`: An error occurred with the data store.
+    pub fn try_contains<Q>(&self, key: &Q) -> crate::Result<bool>
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + Serialize + ?Sized,
+    {
+        self.state.contains(key)
+    }
+
+    /// Return the object associated with `key` or `None` if it doesn't exist.
+    ///
+    /// See `ObjectRepository::get`.
+    ///
+    /// # Panics
+    /// With `HeaderMode::Paged`, this panics if the header can't be read from the data store. Use
+    /// `try_get` to handle that error.
+    pub fn get<Q>(&self, key: &Q) -> Option<ReadOnlyObject<K, S>>
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + Serialize + ToOwned<Owned = K> + ?Sized,
+    {
+        self.try_get(key).expect(HEADER_READ_FAILED)
+    }
+
+    /// Return the object associated with `key` or `None` if it doesn't exist.
+    ///
+    /// See `ObjectRepository::try_get`.
+    ///
+    /// # Errors
+    /// - `Error::InvalidData`: Ciphertext verification failed.
+    /// - `Error::Store`: An error occurred with the data store.
+    pub fn try_get<Q>(&self, key: &Q) -> crate::Result<Option<ReadOnlyObject<K, S>>>
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + Serialize + ToOwned<Owned = K> + ?Sized,
+    {
+        if !self.try_contains(key)? {
+            return Ok(None);
+        }
+        Ok(Some(ReadOnlyObject::new(&self.state, key.to_owned())))
+    }
+
+    /// Return the metadata for the object associated with `key`.
+    ///
+    /// See `ObjectRepository::metadata`.
+    ///
+    /// # Errors
+    /// - `Error::NotFound`: There is no object associated with `key`.
+    /// - `Error::InvalidData`: Ciphertext verification failed.
+    /// - `Error::Store`: An error occurred with the data store.
+    pub fn metadata<Q>(&self, key: &Q) -> crate::Result<ObjectMetadata>
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + Serialize + ?Sized,
+    {
+        Ok(self
+            .state
+            .object(key)?
+            .ok_or(crate::Error::NotFound)?
+            .metadata())
+    }
+
+    /// Return an iterator over all the keys in this repository.
+    ///
+    /// See `ObjectRepository::keys`.
+    ///
+    /// # Panics
+    /// With `HeaderMode::Paged`, the iterator panics if the keys can't be read from the data
+    /// store. Use `try_keys` to handle that error.
+    pub fn keys(&self) -> impl Iterator<Item = K> + '_ {
+        self.try_keys().map(|key| key.expect(HEADER_READ_FAILED))
+    }
+
+    /// Return an iterator over all the keys in this repository.
+    ///
+    /// See `ObjectRepository::try_keys`.
+    ///
+    /// # Errors
+    /// - `Error::KeyType`: The type `K` does not match the keys in the repository.
+    /// - `Error::InvalidData`: Ciphertext verification failed.
+    /// - `Error::Store`: An error occurred with the data store.
+    pub fn try_keys(&self) -> impl Iterator<Item = crate::Result<K>> + '_ {
+        self.state.keys()
+    }
+
+    /// Verify the integrity of all the data in the repository.
+    ///
+    /// This returns the keys of all the objects which are corrupt. See `ObjectRepository::verify`.
+    ///
+    /// # Errors
+    /// - `Error::InvalidData`: Ciphertext verification failed.
+    /// - `Error::Store`: An error occurred with the data store.
+    /// - `Error::Io`: An I/O error occurred.
+    pub fn verify(&self) -> crate::Result<HashSet<K>> {
+        self.state.verify()
+    }
+
+    /// Return information about each of the key slots in this repository.
+    ///
+    /// If encryption is disabled, this is empty.
+    pub fn key_slots(&self) -> Vec<KeySlotInfo> {
+        self.state
+            .metadata
+            .key_slots
+            .iter()
+            .map(KeySlot::info)
+            .collect()
+    }
+
+    /// Return how much space would be reclaimed by calling `ObjectRepository::gc`.
+    pub fn gc_dry_run(&self) -> GcStats {
+        GcStats {
+            blocks: self.state.unused_blocks.len() as u64,
+            bytes: self
+                .state
+                .unused_blocks
+                .iter()
+                .map(|block| block.size)
+                .sum(),
+            remaining: 0,
+        }
+    }
+
+    /// Return information about the repository.
+    pub fn info(&self) -> RepositoryInfo {
+        self.state.metadata.to_info()
+    }
+
+    /// Calculate statistics about the repository.
+    pub fn stats(&self) -> RepositoryStats {
+        let (apparent_size, actual_size) = self.state.header.sizes();
+
+        RepositoryStats {
+            apparent_size,
+            actual_size,
+        }
+    }
+
+    /// Return statistics about the time spent encoding and decoding data.
+    ///
+    /// These statistics are cumulative from when the repository was opened.
+    pub fn encoding_stats(&self) -> EncodingStats {
+        self.state.counters.snapshot()
+    }
+
+    /// Consume this repository and return the wrapped `DataStore`.
+    pub fn into_store(self) -> S {
+        self.state.store.into_inner().unwrap()
+    }
+}
@@ -0,0 +1,119 @@
+/*
+ * Copyright 2019-2020 Wren Powell
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::collections::HashMap;
+use std::mem::swap;
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::store::DataStore;
+
+use super::compression::Dictionaries;
+use super::config::RepositoryConfig;
+use super::encryption::EncryptionKey;
+use super::header::{Header, Key};
+use super::metadata::RepositoryMetadata;
+use super::object::ContentId;
+use super::state::RepositoryState;
+
+/// The progress of reconfiguring a repository with `ObjectRepository::reconfigure`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ReconfigureProgress {
+    pub(super) objects: u64,
+    pub(super) bytes: u64,
+    pub(super) remaining: u64,
+}
+
+impl ReconfigureProgress {
+    /// The number of objects which were rewritten.
+    pub fn objects(&self) -> u64 {
+        self.objects
+    }
+
+    /// The combined size of the objects which were rewritten.
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    /// The number of objects which still need to be rewritten.
+    pub fn remaining(&self) -> u64 {
+        self.remaining
+    }
+
+    /// Whether the repository has switched to its new configuration.
+    pub fn is_complete(&self) -> bool {
+        self.remaining == 0
+    }
+}
+
+/// A reconfiguration which is in progress, as it is stored in the data store.
+///
+/// This is not encrypted, like the repository metadata.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReconfigureRecord {
+    /// The metadata the repository will have once it has been reconfigured.
+    pub metadata: RepositoryMetadata,
+
+    /// The ID of the block which stores the `ContentId` of each object which has been rewritten.
+    ///
+    /// This block is encoded using the new configuration.
+    pub sources: Uuid,
+}
+
+/// A reconfiguration which is in progress.
+///
+/// This holds the parts of the repository's state which depend on its configuration as they will
+/// be once it has been reconfigured.
+#[derive(Debug)]
+pub struct Reconfiguration<K: Key> {
+    /// The new metadata for the repository.
+    pub metadata: RepositoryMetadata,
+
+    /// The new header, which contains the objects which have been rewritten.
+    pub header: Header<K>,
+
+    /// The new master encryption key.
+    pub master_key: EncryptionKey,
+
+    /// The compression dictionaries, which are stored again using the new configuration.
+    pub dictionaries: Dictionaries,
+
+    /// The `ContentId` each object had when it was rewritten.
+    ///
+    /// An object whose `ContentId` no longer matches has been changed and must be rewritten again.
+    pub sources: HashMap<K, ContentId>,
+
+    /// The ID of the block which stores `sources` as of the last time progress was saved.
+    pub sources_page: Option<Uuid>,
+}
+
+impl<K: Key> Reconfiguration<K> {
+    /// Return the configuration the repository is being changed to.
+    pub fn config(&self) -> RepositoryConfig {
+        self.metadata.to_info().config().clone()
+    }
+
+    /// Swap the parts of `state` which depend on its configuration with those in `self`.
+    ///
+    /// Calling this twice restores both to how they were.
+    pub fn swap<S: DataStore>(&mut self, state: &mut RepositoryState<K, S>) {
+        swap(&mut self.metadata, &mut state.metadata);
+        swap(&mut self.header, &mut state.header);
+        swap(&mut self.master_key, &mut state.master_key);
+        swap(&mut self.dictionaries, &mut state.dictionaries);
+    }
+}
@@ -15,35 +15,70 @@
  */
 
 use std::borrow::{Borrow, ToOwned};
-use std::collections::HashSet;
+use std::cmp::min;
+use std::collections::{HashMap, HashSet};
 use std::fmt::Debug;
 use std::hash::Hash;
-use std::sync::{Mutex, RwLock};
-use std::time::SystemTime;
+use std::io::Write;
+use std::iter::once;
+use std::mem::replace;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
 
 use rmp_serde::{from_read, to_vec};
+use serde::Serialize;
 use uuid::Uuid;
 
 use lazy_static::lazy_static;
 
-use crate::repo::object::chunk_store::{ChunkEncoder, ChunkReader};
+use crate::repo::object::chunk_store::{ChunkReader, ChunkWriter};
 use crate::repo::object::object::ReadOnlyObject;
 use crate::repo::OpenRepo;
 use crate::store::DataStore;
 
+use super::btree::PageStore;
+use super::chunking::{Chunker, IncrementalChunker};
+#[cfg(feature = "compression")]
+use super::compression::train_dictionary;
+use super::compression::Dictionaries;
 use super::config::RepositoryConfig;
-use super::encryption::{Encryption, EncryptionKey, KeySalt};
-use super::header::{Header, Key};
-use super::lock::{LockStrategy, LockTable};
-use super::metadata::{RepositoryInfo, RepositoryMetadata, RepositoryStats};
-use super::object::{chunk_hash, Object, ObjectHandle};
-use super::state::RepositoryState;
+use super::encryption::{Encryption, EncryptionKey};
+use super::header::{
+    Header, HeaderDelta, HeaderMode, Key, MemoryHeader, PagedHeader, PagedRoot, HEADER_READ_FAILED,
+};
+use super::key_slot::{KeySlot, KeySlotInfo, SlotKey, DEFAULT_KEY_SLOT, RAW_KEY_SIZE};
+use super::lock::{Lock, LockOwner, LockStrategy, LockTable};
+use super::metadata::{
+    DictionaryBlock, EncodingStats, GcStats, ObjectMetadata, RepositoryInfo, RepositoryMetadata,
+    RepositoryStats, UnusedBlock,
+};
+use super::object::{Object, ObjectHandle};
+use super::pool::EncodingPool;
+use super::reconfigure::{Reconfiguration, ReconfigureProgress, ReconfigureRecord};
+use super::savepoint::{SavedState, Savepoint};
+use super::state::{EncodingCounters, HeaderPages, RepositoryState};
+
+/// The maximum number of deltas in the header journal before a new snapshot is written.
+const MAX_JOURNAL_LEN: usize = 32;
+
+/// The maximum number of unused blocks which are removed from the data store at once by `gc`.
+const GC_BATCH_SIZE: usize = 256;
+
+/// The maximum number of times to read a repository which is opened without a lock.
+///
+/// Each attempt is restarted if a writer committed while the repository was being read.
+const SNAPSHOT_READ_ATTEMPTS: usize = 8;
 
 lazy_static! {
     /// The block ID of the block which stores unencrypted metadata for the repository.
     static ref METADATA_BLOCK_ID: Uuid =
         Uuid::parse_str("8691d360-29c6-11ea-8bc1-2fc8cfe66f33").unwrap();
 
+    /// The block ID of the block which stores the list of blocks waiting to be removed.
+    static ref UNUSED_BLOCK_ID: Uuid =
+        Uuid::parse_str("5a3d9c0e-cb70-11f1-8f2a-02fc00000001").unwrap();
+
     /// The block ID of the block which stores the repository format version.
     static ref VERSION_BLOCK_ID: Uuid =
         Uuid::parse_str("cbf28b1c-3550-11ea-8cb0-87d7a14efe10").unwrap();
@@ -53,10 +88,17 @@ lazy_static! {
     /// This must be changed any time a backwards-incompatible change is made to the repository
     /// format.
     static ref VERSION_ID: Uuid =
+        Uuid::parse_str("6f0b7c24-d2e9-11f1-a4d3-02fc00000001").unwrap();
+
+    /// The legacy repository format version ID.
+    ///
+    /// Repositories in this format are upgraded to the current format when they are opened for
+    /// writing, and they can be read without being upgraded.
+    static ref LEGACY_VERSION_ID: Uuid =
         Uuid::parse_str("036e2a8e-4b53-11ea-a6e9-57c2a822fccf").unwrap();
 
     /// A table of locks on repositories.
-    static ref REPO_LOCKS: RwLock<LockTable> = RwLock::new(LockTable::new());
+    pub(super) static ref REPO_LOCKS: LockTable = LockTable::new();
 }
 
 /// A persistent object store.
@@ -64,13 +106,14 @@ lazy_static! {
 /// An `ObjectRepository` maps keys of type `K` to seekable binary blobs called objects and stores
 /// them persistently in a `DataStore`.
 ///
-/// Data in a repository is transparently deduplicated using content-defined block-level
-/// deduplication via the ZPAQ chunking algorithm. The data and metadata in the repository can
-/// optionally be compressed and encrypted.
+/// Data in a repository is transparently deduplicated using block-level deduplication. Data is
+/// split into chunks using the `Chunking` method in the repository's config. The data and
+/// metadata in the repository can optionally be compressed and encrypted.
 ///
 /// A repository cannot be open more than once simultaneously. Once it is opened, it is locked from
 /// further open attempts until the `ObjectRepository` is dropped. This lock prevents the repository
-/// from being opened from other threads and processes, but not from other machines.
+/// from being opened from other threads and processes, but not from other machines. To read a
+/// repository without locking it exclusively, use `ReadOnlyObjectRepository`.
 ///
 /// Changes made to a repository are not persisted to the data store until `commit` is called. When
 /// the `ObjectRepository` is dropped, any uncommitted changes are rolled back automatically.
@@ -81,6 +124,13 @@ lazy_static! {
 /// key, which is used to encrypt all data in the repository. This setup means that the repository's
 /// password can be changed without re-encrypting any data.
 ///
+/// A repository can have multiple key slots, each of which stores a copy of the master key
+/// encrypted with a different password or raw key. A repository is created with one key slot named
+/// `DEFAULT_KEY_SLOT`, and more can be added with `add_key_slot`. Key slots which use a raw key
+/// skip key derivation, and they are unlocked with `OpenRepo::open_repo_with_key`. A repository
+/// with several password key slots can be opened with `OpenRepo::open_repo_with_slot` so that the
+/// key is only derived for one of them.
+///
 /// The master key is generated using the operating system's secure random number generator. Both
 /// the master key and the derived key are zeroed in memory once they go out of scope.
 ///
@@ -96,86 +146,34 @@ pub struct ObjectRepository<K: Key, S: DataStore> {
 }
 
 impl<K: Key, S: DataStore> OpenRepo<S> for ObjectRepository<K, S> {
-    fn open_repo(
-        mut store: S,
+    fn open_repo(store: S, strategy: LockStrategy, password: Option<&[u8]>) -> crate::Result<Self>
+    where
+        Self: Sized,
+    {
+        Self::open_with_key(store, strategy, None, password.map(SlotKey::Password))
+    }
+
+    fn open_repo_with_key(
+        store: S,
         strategy: LockStrategy,
-        password: Option<&[u8]>,
+        key: &[u8; RAW_KEY_SIZE],
     ) -> crate::Result<Self>
     where
         Self: Sized,
     {
-        // Acquire a lock on the repository.
-        let repository_id = Self::peek_info(&mut store)?.id();
-        let lock = REPO_LOCKS
-            .write()
-            .unwrap()
-            .acquire_lock(repository_id, strategy)?;
-
-        // Read the repository version to see if this is a compatible repository.
-        let serialized_version = store
-            .read_block(*VERSION_BLOCK_ID)
-            .map_err(anyhow::Error::from)?
-            .ok_or(crate::Error::NotFound)?;
-        let version =
-            Uuid::from_slice(serialized_version.as_slice()).map_err(|_| crate::Error::Corrupt)?;
-        if version != *VERSION_ID {
-            return Err(crate::Error::UnsupportedFormat);
-        }
-
-        // We read the metadata again after reading the UUID to prevent a race condition when
-        // acquiring the lock.
-        let serialized_metadata = store
-            .read_block(*METADATA_BLOCK_ID)
-            .map_err(anyhow::Error::from)?
-            .ok_or(crate::Error::Corrupt)?;
-        let metadata: RepositoryMetadata =
-            from_read(serialized_metadata.as_slice()).map_err(|_| crate::Error::Corrupt)?;
-
-        // Decrypt the master key for the repository.
-        let master_key = match password {
-            Some(password_bytes) => {
-                let user_key = EncryptionKey::derive(
-                    password_bytes,
-                    &metadata.salt,
-                    metadata.encryption.key_size(),
-                    metadata.memory_limit,
-                    metadata.operations_limit,
-                );
-                EncryptionKey::new(
-                    metadata
-                        .encryption
-                        .decrypt(&metadata.master_key, &user_key)
-                        .map_err(|_| crate::Error::Password)?,
-                )
-            }
-            None => EncryptionKey::new(Vec::new()),
-        };
-
-        // Read, decrypt, decompress, and deserialize the header.
-        let encrypted_header = store
-            .read_block(metadata.header)
-            .map_err(anyhow::Error::from)?
-            .ok_or(crate::Error::Corrupt)?;
-        let compressed_header = metadata
-            .encryption
-            .decrypt(&encrypted_header, &master_key)
-            .map_err(|_| crate::Error::Corrupt)?;
-        let serialized_header = metadata
-            .compression
-            .decompress(&compressed_header)
-            .map_err(|_| crate::Error::Corrupt)?;
-        let header: Header<K> =
-            from_read(serialized_header.as_slice()).map_err(|_| crate::Error::KeyType)?;
-
-        let state = RepositoryState {
-            store: Mutex::new(store),
-            metadata,
-            header,
-            master_key,
-            lock,
-        };
+        Self::open_with_key(store, strategy, None, Some(SlotKey::Raw(key)))
+    }
 
-        Ok(ObjectRepository { state })
+    fn open_repo_with_slot(
+        store: S,
+        strategy: LockStrategy,
+        slot: &str,
+        key: SlotKey,
+    ) -> crate::Result<Self>
+    where
+        Self: Sized,
+    {
+        Self::open_with_key(store, strategy, Some(slot), Some(key))
     }
 
     fn new_repo(
@@ -186,6 +184,17 @@ impl<K: Key, S: DataStore> OpenRepo<S> for ObjectRepository<K, S> {
     where
         Self: Sized,
     {
+        if !config.is_valid() {
+            return Err(crate::Error::InvalidConfig);
+        }
+
+        // Select a cipher if one should be chosen automatically.
+        let mut config = config;
+        config.encryption = config.encryption.resolve();
+        if !config.encryption.is_supported() {
+            return Err(crate::Error::Unsupported);
+        }
+
         // Return an error if a password was required but not provided.
         if password.is_none() && config.encryption != Encryption::None {
             return Err(crate::Error::Password);
@@ -199,9 +208,7 @@ impl<K: Key, S: DataStore> OpenRepo<S> for ObjectRepository<K, S> {
         // Acquire an exclusive lock on the repository.
         let id = Uuid::new_v4();
         let lock = REPO_LOCKS
-            .write()
-            .unwrap()
-            .acquire_lock(id, LockStrategy::Abort)
+            .acquire_lock(id, store.lock_dir().as_deref(), LockStrategy::Abort)
             .map_err(|_| crate::Error::AlreadyExists)?;
 
         // Check if the repository already exists.
@@ -214,36 +221,13 @@ impl<K: Key, S: DataStore> OpenRepo<S> for ObjectRepository<K, S> {
         }
 
         // Generate the master encryption key.
-        let master_key = match password {
-            Some(..) => EncryptionKey::generate(config.encryption.key_size()),
-            None => EncryptionKey::new(Vec::new()),
-        };
-
-        // Encrypt the master encryption key.
-        let salt = match password {
-            Some(..) => KeySalt::generate(),
-            None => KeySalt::empty(),
-        };
-
-        let encrypted_master_key = match password {
-            Some(password_bytes) => {
-                let user_key = EncryptionKey::derive(
-                    password_bytes,
-                    &salt,
-                    config.encryption.key_size(),
-                    config.memory_limit,
-                    config.operations_limit,
-                );
-                config.encryption.encrypt(master_key.as_ref(), &user_key)
-            }
-            None => Vec::new(),
-        };
+        let (master_key, key_slots) =
+            Self::generate_master_key(&config, password.map(SlotKey::Password))?;
 
         // Generate and write the header.
-        let header = Header::default();
-        let serialized_header = to_vec(&header).expect("Could not serialize header.");
+        let (header, serialized_header) = Self::empty_header(config.header_mode);
         let compressed_header = config.compression.compress(&serialized_header)?;
-        let encrypted_header = config.encryption.encrypt(&compressed_header, &master_key);
+        let encrypted_header = config.encryption.encrypt(&compressed_header, &master_key)?;
         let header_id = Uuid::new_v4();
         store
             .write_block(header_id, &encrypted_header)
@@ -252,17 +236,28 @@ impl<K: Key, S: DataStore> OpenRepo<S> for ObjectRepository<K, S> {
         // Create the repository metadata with a reference to the newly-written header.
         let metadata = RepositoryMetadata {
             id,
-            chunker_bits: config.chunker_bits,
+            chunking: config.chunking_method(),
             compression: config.compression,
             encryption: config.encryption,
             memory_limit: config.memory_limit,
             operations_limit: config.operations_limit,
-            master_key: encrypted_master_key,
-            salt,
+            key_slots,
             header: header_id,
+            journal: Vec::new(),
+            header_mode: config.header_mode,
+            dictionaries: Vec::new(),
             creation_time: SystemTime::now(),
+            reconfiguration: None,
         };
 
+        // Write the empty list of blocks waiting to be removed.
+        let unused_blocks = Vec::new();
+        let serialized_unused_blocks =
+            to_vec(&unused_blocks).expect("Could not serialize unused blocks.");
+        store
+            .write_block(*UNUSED_BLOCK_ID, &serialized_unused_blocks)
+            .map_err(anyhow::Error::from)?;
+
         // Write the repository metadata.
         let serialized_metadata = to_vec(&metadata).expect("Could not serialize metadata.");
         store
@@ -275,12 +270,21 @@ impl<K: Key, S: DataStore> OpenRepo<S> for ObjectRepository<K, S> {
             .write_block(*VERSION_BLOCK_ID, VERSION_ID.as_bytes())
             .map_err(anyhow::Error::from)?;
 
+        let key_slot = metadata.key_slots.first().map(|slot| slot.name.clone());
         let state = RepositoryState {
             store: Mutex::new(store),
             metadata,
             header,
             master_key,
-            lock,
+            key_slot,
+            lock: Some(lock),
+            counters: EncodingCounters::default(),
+            pool: EncodingPool::default(),
+            encoding_threads: 1,
+            unused_blocks,
+            savepoints: Vec::new(),
+            dictionaries: Dictionaries::new(),
+            reconfiguration: None,
         };
 
         Ok(ObjectRepository { state })
@@ -305,23 +309,42 @@ impl<K: Key, S: DataStore> OpenRepo<S> for ObjectRepository<K, S> {
 
 impl<K: Key, S: DataStore> ObjectRepository<K, S> {
     /// Return whether the given `key` exists in this repository.
+    ///
+    /// # Panics
+    /// With `HeaderMode::Paged`, this panics if the header can't be read from the data store. Use
+    /// `try_contains` to handle that error.
     pub fn contains<Q>(&self, key: &Q) -> bool
     where
         K: Borrow<Q>,
-        Q: Eq + Hash + ?Sized,
+        Q: Eq + Hash + Serialize + ?Sized,
+    {
+        self.try_contains(key).expect(HEADER_READ_FAILED)
+    }
+
+    /// Return whether the given `key` exists in this repository.
+    ///
+    /// This is like `contains`, but returns an error if the header can't be read.
+    ///
+    /// # Errors
+    /// - `Error::InvalidData`: Ciphertext verification failed.
+    /// - `Error::Store`: An error occurred with the data store.
+    pub fn try_contains<Q>(&self, key: &Q) -> crate::Result<bool>
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + Serialize + ?Sized,
     {
-        self.state.header.objects.contains_key(key)
+        self.state.contains(key)
     }
 
     /// Insert the given `key` into the repository and return a new object.
     ///
-    /// If the given `key` already exists in the repository, its object is replaced. The returned
-    /// object represents the data associated with the `key`.
+    /// If the given `key` already exists in the repository, its object is replaced with a new
+    /// empty object, which has a new creation time and no tags. The returned object represents the
+    /// data associated with the `key`.
     pub fn insert(&mut self, key: K) -> Object<K, S> {
         self.state
             .header
-            .objects
-            .insert(key.clone(), ObjectHandle::default());
+            .insert_object(key.clone(), ObjectHandle::new());
 
         Object::new(&mut self.state, key)
     }
@@ -332,243 +355,1566 @@ impl<K: Key, S: DataStore> ObjectRepository<K, S> {
     ///
     /// The space used by the given object isn't freed and made available for new objects until
     /// `commit` is called.
+    ///
+    /// # Panics
+    /// With `HeaderMode::Paged`, this panics if the header can't be read from the data store. Use
+    /// `try_remove` to handle that error.
     pub fn remove<Q>(&mut self, key: &Q) -> bool
     where
         K: Borrow<Q>,
-        Q: Eq + Hash + ?Sized,
+        Q: Eq + Hash + Serialize + ?Sized,
+    {
+        self.try_remove(key).expect(HEADER_READ_FAILED)
+    }
+
+    /// Remove the object associated with `key` from the repository.
+    ///
+    /// This is like `remove`, but returns an error if the header can't be read.
+    ///
+    /// # Errors
+    /// - `Error::KeyType`: The type `K` does not match the keys in the repository.
+    /// - `Error::InvalidData`: Ciphertext verification failed.
+    /// - `Error::Store`: An error occurred with the data store.
+    pub fn try_remove<Q>(&mut self, key: &Q) -> crate::Result<bool>
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + Serialize + ?Sized,
     {
-        self.state.header.objects.remove(key).is_some()
+        let pages = HeaderPages::new(
+            &self.state.store,
+            &self.state.metadata,
+            &self.state.master_key,
+        );
+        self.state.header.remove_object(&pages, key)
     }
 
     /// Return the object associated with `key` or `None` if it doesn't exist.
     ///
     /// The returned object provides read-only access to the data. To get read-write access, use
     /// `get_mut`.
+    ///
+    /// # Panics
+    /// With `HeaderMode::Paged`, this panics if the header can't be read from the data store. Use
+    /// `try_get` to handle that error.
     pub fn get<Q>(&self, key: &Q) -> Option<ReadOnlyObject<K, S>>
     where
         K: Borrow<Q>,
-        Q: Eq + Hash + ToOwned<Owned = K> + ?Sized,
+        Q: Eq + Hash + Serialize + ToOwned<Owned = K> + ?Sized,
     {
-        self.state.header.objects.get(&key)?;
-        Some(ReadOnlyObject::new(&self.state, key.to_owned()))
+        self.try_get(key).expect(HEADER_READ_FAILED)
+    }
+
+    /// Return the object associated with `key` or `None` if it doesn't exist.
+    ///
+    /// This is like `get`, but returns an error if the header can't be read.
+    ///
+    /// # Errors
+    /// - `Error::InvalidData`: Ciphertext verification failed.
+    /// - `Error::Store`: An error occurred with the data store.
+    pub fn try_get<Q>(&self, key: &Q) -> crate::Result<Option<ReadOnlyObject<K, S>>>
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + Serialize + ToOwned<Owned = K> + ?Sized,
+    {
+        if !self.try_contains(key)? {
+            return Ok(None);
+        }
+        Ok(Some(ReadOnlyObject::new(&self.state, key.to_owned())))
     }
 
     /// Return the object associated with `key` or `None` if it doesn't exist.
     ///
     /// The returned object provides read-write access to the data. To get read-only access, use
     /// `get`.
+    ///
+    /// # Panics
+    /// With `HeaderMode::Paged`, this panics if the header can't be read from the data store. Use
+    /// `try_get_mut` to handle that error.
     pub fn get_mut<Q>(&mut self, key: &Q) -> Option<Object<K, S>>
     where
         K: Borrow<Q>,
-        Q: Eq + Hash + ToOwned<Owned = K> + ?Sized,
+        Q: Eq + Hash + Serialize + ToOwned<Owned = K> + ?Sized,
+    {
+        self.try_get_mut(key).expect(HEADER_READ_FAILED)
+    }
+
+    /// Return the object associated with `key` or `None` if it doesn't exist.
+    ///
+    /// This is like `get_mut`, but returns an error if the header can't be read.
+    ///
+    /// # Errors
+    /// - `Error::InvalidData`: Ciphertext verification failed.
+    /// - `Error::Store`: An error occurred with the data store.
+    pub fn try_get_mut<Q>(&mut self, key: &Q) -> crate::Result<Option<Object<K, S>>>
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + Serialize + ToOwned<Owned = K> + ?Sized,
     {
-        self.state.header.objects.get(&key)?;
-        Some(Object::new(&mut self.state, key.to_owned()))
+        if !self.try_contains(key)? {
+            return Ok(None);
+        }
+        Ok(Some(Object::new(&mut self.state, key.to_owned())))
+    }
+
+    /// Return an iterator over all the keys in this repository.
+    ///
+    /// # Panics
+    /// With `HeaderMode::Paged`, the keys are read from the data store as the iterator reaches
+    /// them, and the iterator panics if they can't be read. Use `try_keys` to handle that error.
+    pub fn keys(&self) -> impl Iterator<Item = K> + '_ {
+        self.try_keys().map(|key| key.expect(HEADER_READ_FAILED))
     }
 
     /// Return an iterator over all the keys in this repository.
-    pub fn keys<'a>(&'a self) -> impl Iterator<Item = &'a K> + 'a {
-        self.state.header.objects.keys()
+    ///
+    /// This is like `keys`, but returns an error for each key which can't be read.
+    ///
+    /// # Errors
+    /// - `Error::KeyType`: The type `K` does not match the keys in the repository.
+    /// - `Error::InvalidData`: Ciphertext verification failed.
+    /// - `Error::Store`: An error occurred with the data store.
+    pub fn try_keys(&self) -> impl Iterator<Item = crate::Result<K>> + '_ {
+        self.state.keys()
     }
 
     /// Copy the object at `source` to `dest`.
     ///
-    /// This is a cheap operation which does not require copying the bytes in the object.
+    /// This is a cheap operation which does not require copying the bytes in the object. The copy
+    /// has the same metadata as the original.
     ///
     /// # Errors
     /// - `Error::NotFound`: There is no object at `source`.
     /// - `Error::AlreadyExists`: There is already an object at `dest`.
+    /// - `Error::InvalidData`: Ciphertext verification failed.
+    /// - `Error::Store`: An error occurred with the data store.
     pub fn copy<Q>(&mut self, source: &Q, dest: K) -> crate::Result<()>
     where
         K: Borrow<Q>,
-        Q: Eq + Hash + ?Sized,
+        Q: Eq + Hash + Serialize + ?Sized,
     {
-        if self.contains(dest.borrow()) {
+        if self.state.contains::<K>(&dest)? {
             return Err(crate::Error::AlreadyExists);
         }
 
         let source_object = self
             .state
-            .header
-            .objects
-            .get(source)
+            .object(source)?
             .ok_or(crate::Error::NotFound)?
-            .clone();
+            .into_owned();
 
-        self.state.header.objects.insert(dest, source_object);
+        self.state.header.insert_object(dest, source_object);
 
         Ok(())
     }
 
-    /// Return a list of blocks in `store` excluding those used to store metadata.
-    fn list_data_blocks(&mut self) -> crate::Result<Vec<Uuid>> {
-        let all_blocks = self
+    /// Return the metadata for the object associated with `key`.
+    ///
+    /// This does not read the object's data.
+    ///
+    /// # Errors
+    /// - `Error::NotFound`: There is no object associated with `key`.
+    /// - `Error::InvalidData`: Ciphertext verification failed.
+    /// - `Error::Store`: An error occurred with the data store.
+    pub fn metadata<Q>(&self, key: &Q) -> crate::Result<ObjectMetadata>
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + Serialize + ?Sized,
+    {
+        Ok(self
             .state
-            .store
-            .lock()
-            .unwrap()
-            .list_blocks()
-            .map_err(anyhow::Error::from)?;
-
-        Ok(all_blocks
-            .iter()
-            .copied()
-            .filter(|id| {
-                *id != *METADATA_BLOCK_ID
-                    && *id != *VERSION_BLOCK_ID
-                    && *id != self.state.metadata.header
-            })
-            .collect())
+            .object(key)?
+            .ok_or(crate::Error::NotFound)?
+            .metadata())
     }
 
-    /// Commit changes which have been made to the repository.
+    /// Set the tag named `name` on the object associated with `key` to `value`.
     ///
-    /// No changes are saved persistently until this method is called. Committing a repository is an
-    /// atomic and consistent operation; changes cannot be partially committed and interrupting a
-    /// commit will never leave the repository in an inconsistent state.
+    /// Any existing tag with the same name is replaced. The value can be any serializable type, and
+    /// it can be read with `ObjectMetadata::tag`. This does not rewrite the object's data or change
+    /// its modification time. Tags are stored in the repository's header, so they should be kept
+    /// small. The change does not take effect until `commit` is called.
     ///
     /// # Errors
+    /// - `Error::NotFound`: There is no object associated with `key`.
+    /// - `Error::Serialize`: The `value` could not be serialized.
     /// - `Error::InvalidData`: Ciphertext verification failed.
     /// - `Error::Store`: An error occurred with the data store.
-    /// - `Error::Io`: An I/O error occurred.
-    pub fn commit(&mut self) -> crate::Result<()> {
-        // Remove chunks which are not referenced by any object.
-        self.state.header.clean_chunks();
-
-        // Serialize and encode the header.
-        let serialized_header = to_vec(&self.state.header).expect("Could not serialize header.");
-        let encoded_header = self.state.encode_data(&serialized_header)?;
-
-        // Write the new header to the data store.
-        let header_id = Uuid::new_v4();
-        self.state
-            .store
-            .lock()
-            .unwrap()
-            .write_block(header_id, &encoded_header)
-            .map_err(anyhow::Error::from)?;
-        self.state.metadata.header = header_id;
-
-        // Write the repository metadata, atomically completing the commit.
-        let serialized_metadata =
-            to_vec(&self.state.metadata).expect("Could not serialize metadata.");
-        self.state
-            .store
-            .lock()
-            .unwrap()
-            .write_block(*METADATA_BLOCK_ID, &serialized_metadata)
-            .map_err(anyhow::Error::from)?;
-
-        // After changes are committed, remove any unused chunks from the data store.
-        let referenced_chunks = self
-            .state
-            .header
-            .chunks
-            .values()
-            .copied()
-            .collect::<HashSet<_>>();
-
-        let data_blocks = self.list_data_blocks()?;
-
-        // We need to be careful getting a lock on the data store to avoid a panic. We're scoping it
-        // just to be careful.
-        {
-            let mut store = self.state.store.lock().unwrap();
-            for stored_chunk in data_blocks {
-                if !referenced_chunks.contains(&stored_chunk) {
-                    store
-                        .remove_block(stored_chunk)
-                        .map_err(anyhow::Error::from)?;
-                }
-            }
+    pub fn set_tag<Q, T>(&mut self, key: &Q, name: &str, value: &T) -> crate::Result<()>
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + Serialize + ToOwned<Owned = K> + ?Sized,
+        T: Serialize + ?Sized,
+    {
+        if !self.try_contains(key)? {
+            return Err(crate::Error::NotFound);
         }
-
-        Ok(())
+        let serialized_value = to_vec(value).map_err(|_| crate::Error::Serialize)?;
+        self.state.update_object(&key.to_owned(), |handle| {
+            handle.tags.insert(name.to_owned(), serialized_value);
+        })
     }
 
-    /// Verify the integrity of all the data in the repository.
+    /// Remove the tag named `name` from the object associated with `key`.
     ///
-    /// This returns the set of keys of objects which are corrupt. This is more efficient than
-    /// calling `Object::verify` on each object in the repository.
+    /// This returns `true` if the tag was removed or `false` if it didn't exist. See `set_tag` for
+    /// details.
     ///
     /// # Errors
+    /// - `Error::NotFound`: There is no object associated with `key`.
     /// - `Error::InvalidData`: Ciphertext verification failed.
     /// - `Error::Store`: An error occurred with the data store.
-    /// - `Error::Io`: An I/O error occurred.
-    pub fn verify(&self) -> crate::Result<HashSet<&K>> {
-        let mut corrupt_chunks = HashSet::new();
-        let expected_chunks = self.state.header.chunks.keys().copied().collect::<Vec<_>>();
-
-        // Get the set of hashes of chunks which are corrupt.
-        for chunk in expected_chunks {
-            match self.state.read_chunk(chunk) {
-                Ok(data) => {
-                    if data.len() != chunk.size || chunk_hash(&data) != chunk.hash {
-                        corrupt_chunks.insert(chunk.hash);
-                    }
-                }
-                Err(crate::Error::InvalidData) => {
-                    // Ciphertext verification failed. No need to check the hash.
-                    corrupt_chunks.insert(chunk.hash);
-                }
-                Err(error) => return Err(error),
-            };
+    pub fn remove_tag<Q>(&mut self, key: &Q, name: &str) -> crate::Result<bool>
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + Serialize + ToOwned<Owned = K> + ?Sized,
+    {
+        if !self.try_contains(key)? {
+            return Err(crate::Error::NotFound);
         }
+        let mut removed = false;
+        self.state.update_object(&key.to_owned(), |handle| {
+            removed = handle.tags.remove(name).is_some();
+        })?;
+        Ok(removed)
+    }
 
-        // If there are no corrupt chunks, there are no corrupt objects.
-        if corrupt_chunks.is_empty() {
-            return Ok(HashSet::new());
-        }
+    /// Open the repository in `store`, unlocking the key slot named `slot` with `key`.
+    ///
+    /// If `slot` is `None`, each key slot which `key` could unlock is tried.
+    fn open_with_key(
+        store: S,
+        strategy: LockStrategy,
+        slot: Option<&str>,
+        key: Option<SlotKey>,
+    ) -> crate::Result<Self> {
+        let state = Self::open_state(
+            store,
+            |id, lock_dir| REPO_LOCKS.acquire_lock(id, lock_dir, strategy).map(Some),
+            slot,
+            key,
+        )?;
+        Ok(ObjectRepository { state })
+    }
 
-        let mut corrupt_objects = HashSet::new();
+    /// Read the state of the repository in `store` as of its last commit, unlocking the key slot
+    /// named `slot` with `key`.
+    ///
+    /// If `slot` is `None`, each key slot which `key` could unlock is tried. This calls `lock`
+    /// with the ID of the repository and the store's lock directory to lock it before reading
+    /// anything else. If `lock` returns `None`, the metadata is read again after reading the state,
+    /// and the state is read again if a writer committed in the meantime.
+    ///
+    /// A repository in the legacy format is upgraded if `lock` returns an exclusive lock, and it is
+    /// read without being upgraded otherwise. This does not write to `store` except to upgrade it.
+    ///
+    /// # Errors
+    /// - `Error::Locked`: No lock was acquired, and the repository changed each time it was read.
+    pub(super) fn open_state(
+        mut store: S,
+        lock: impl FnOnce(Uuid, Option<&Path>) -> crate::Result<Option<Lock>>,
+        slot: Option<&str>,
+        key: Option<SlotKey>,
+    ) -> crate::Result<RepositoryState<K, S>> {
+        // Acquire a lock on the repository.
+        let repository_id = Self::peek_info(&mut store)?.id();
+        let lock = lock(repository_id, store.lock_dir().as_deref())?;
 
-        for (key, object) in self.state.header.objects.iter() {
-            for chunk in &object.chunks {
-                // If any one of the object's chunks is corrupt, the object is corrupt.
-                if corrupt_chunks.contains(&chunk.hash) {
-                    corrupt_objects.insert(key);
-                    break;
-                }
+        // Read the repository version to see if this is a compatible repository.
+        let serialized_version = store
+            .read_block(*VERSION_BLOCK_ID)
+            .map_err(anyhow::Error::from)?
+            .ok_or(crate::Error::NotFound)?;
+        let version =
+            Uuid::from_slice(serialized_version.as_slice()).map_err(|_| crate::Error::Corrupt)?;
+        let mut legacy = false;
+        if version == *LEGACY_VERSION_ID {
+            if lock.as_ref().map_or(false, Lock::is_exclusive) {
+                Self::upgrade_legacy(&mut store)?;
+            } else {
+                legacy = true;
             }
+        } else if version != *VERSION_ID {
+            return Err(crate::Error::UnsupportedFormat);
         }
 
-        Ok(corrupt_objects)
-    }
+        let store = Mutex::new(store);
 
-    /// Change the password for this repository.
-    ///
-    /// This replaces the existing password with `new_password`. Changing the password does not
-    /// require re-encrypting any data. The change does not take effect until `commit` is called.
-    /// If encryption is disabled, this method does nothing.
-    #[cfg(feature = "encryption")]
-    pub fn change_password(&mut self, new_password: &[u8]) {
-        let salt = KeySalt::generate();
-        let user_key = EncryptionKey::derive(
-            new_password,
-            &salt,
-            self.state.metadata.encryption.key_size(),
-            self.state.metadata.memory_limit,
-            self.state.metadata.operations_limit,
-        );
+        // Read everything which is referenced by the given metadata.
+        let read_committed = |metadata: &RepositoryMetadata| -> crate::Result<_> {
+            // Return an error if this machine can't decrypt the repository.
+            if !metadata.encryption.is_supported() {
+                return Err(crate::Error::Unsupported);
+            }
 
-        let encrypted_master_key = self
-            .state
-            .metadata
-            .encryption
-            .encrypt(self.state.master_key.as_ref(), &user_key);
+            // Decrypt the master key for the repository.
+            let (master_key, key_slot) = Self::decrypt_master_key(metadata, slot, key)?;
+
+            // Read the list of blocks which are waiting to be removed. Legacy repositories don't
+            // have one.
+            let unused_blocks: Vec<UnusedBlock> = match store
+                .lock()
+                .unwrap()
+                .read_block(*UNUSED_BLOCK_ID)
+                .map_err(anyhow::Error::from)?
+            {
+                Some(serialized_unused_blocks) => from_read(serialized_unused_blocks.as_slice())
+                    .map_err(|_| crate::Error::Corrupt)?,
+                None if legacy => Vec::new(),
+                None => return Err(crate::Error::Corrupt),
+            };
+
+            let pages = HeaderPages::new(&store, metadata, &master_key);
+            let header = Self::read_header(&pages, metadata)?;
+            let dictionaries = Self::read_dictionaries(&pages, metadata)?;
+
+            Ok((master_key, key_slot, unused_blocks, header, dictionaries))
+        };
+
+        let mut attempts = 0;
+        let (metadata, (master_key, key_slot, unused_blocks, header, dictionaries)) = loop {
+            // We read the metadata again after reading the UUID to prevent a race condition when
+            // acquiring the lock.
+            let metadata = Self::read_metadata(&mut store.lock().unwrap())?;
+            let committed = read_committed(&metadata);
+
+            // Without a lock, a writer may have committed while we were reading, in which case
+            // what we read may be inconsistent or already removed. Read it again if so.
+            if lock.is_some() || Self::read_metadata(&mut store.lock().unwrap())? == metadata {
+                break (metadata, committed?);
+            }
+
+            attempts += 1;
+            if attempts == SNAPSHOT_READ_ATTEMPTS {
+                return Err(crate::Error::Locked);
+            }
+        };
+
+        let state = RepositoryState {
+            store,
+            metadata,
+            header,
+            master_key,
+            key_slot,
+            lock,
+            counters: EncodingCounters::default(),
+            pool: EncodingPool::default(),
+            encoding_threads: 1,
+            unused_blocks,
+            savepoints: Vec::new(),
+            dictionaries,
+            reconfiguration: None,
+        };
 
-        self.state.metadata.salt = salt;
-        self.state.metadata.master_key = encrypted_master_key;
+        Ok(state)
     }
 
-    /// Return information about the repository.
-    pub fn info(&self) -> RepositoryInfo {
-        self.state.metadata.to_info()
+    /// Read the repository metadata from `store`.
+    ///
+    /// Metadata in the legacy format is converted to the current format.
+    fn read_metadata(store: &mut S) -> crate::Result<RepositoryMetadata> {
+        let serialized_metadata = store
+            .read_block(*METADATA_BLOCK_ID)
+            .map_err(anyhow::Error::from)?
+            .ok_or(crate::Error::Corrupt)?;
+        RepositoryMetadata::decode(serialized_metadata.as_slice())
     }
 
-    /// Return information about the repository in `store` without opening it.
+    /// Upgrade the repository in `store` from the legacy format to the current format.
     ///
-    /// # Errors
+    /// The header and chunks of a legacy repository are readable in the current format, so this
+    /// only rewrites the metadata. The metadata is written before the version, so if this is
+    /// interrupted, it is finished the next time the repository is opened for writing.
+    fn upgrade_legacy(store: &mut S) -> crate::Result<()> {
+        let metadata = Self::read_metadata(store)?;
+
+        // Write the empty list of blocks waiting to be removed.
+        if store
+            .read_block(*UNUSED_BLOCK_ID)
+            .map_err(anyhow::Error::from)?
+            .is_none()
+        {
+            let serialized_unused_blocks =
+                to_vec(&Vec::<UnusedBlock>::new()).expect("Could not serialize unused blocks.");
+            store
+                .write_block(*UNUSED_BLOCK_ID, &serialized_unused_blocks)
+                .map_err(anyhow::Error::from)?;
+        }
+
+        let serialized_metadata = to_vec(&metadata).expect("Could not serialize metadata.");
+        store
+            .write_block(*METADATA_BLOCK_ID, &serialized_metadata)
+            .map_err(anyhow::Error::from)?;
+        store
+            .write_block(*VERSION_BLOCK_ID, VERSION_ID.as_bytes())
+            .map_err(anyhow::Error::from)?;
+        Ok(())
+    }
+
+    /// Decrypt the master key in `metadata` using `key`.
+    ///
+    /// This tries the key slot named `slot` or, if `slot` is `None`, each key slot which `key` could
+    /// unlock in order. It returns the master key and the name of the slot which was unlocked.
+    ///
+    /// # Errors
+    /// - `Error::Password`: The key provided is invalid, no key was provided, or there is no key
+    /// slot named `slot`.
+    fn decrypt_master_key(
+        metadata: &RepositoryMetadata,
+        slot: Option<&str>,
+        key: Option<SlotKey>,
+    ) -> crate::Result<(EncryptionKey, Option<String>)> {
+        if metadata.encryption == Encryption::None {
+            return Ok((EncryptionKey::new(Vec::new()), None));
+        }
+
+        let key = key.ok_or(crate::Error::Password)?;
+        metadata
+            .key_slots
+            .iter()
+            .filter(|key_slot| slot.map_or(true, |name| key_slot.name == name))
+            .find_map(|key_slot| {
+                key_slot
+                    .unlock(key, metadata.encryption)
+                    .map(|master_key| (master_key, Some(key_slot.name.clone())))
+            })
+            .ok_or(crate::Error::Password)
+    }
+
+    /// Generate a new master key for a repository with the given `config`.
+    ///
+    /// This returns the master key and a key slot named `DEFAULT_KEY_SLOT` which stores the master
+    /// key encrypted with `key`.
+    fn generate_master_key(
+        config: &RepositoryConfig,
+        key: Option<SlotKey>,
+    ) -> crate::Result<(EncryptionKey, Vec<KeySlot>)> {
+        match key {
+            Some(key) => {
+                let master_key = EncryptionKey::generate(config.encryption.key_size());
+                let key_slot = KeySlot::new(
+                    DEFAULT_KEY_SLOT,
+                    key,
+                    &master_key,
+                    config.encryption,
+                    config.memory_limit,
+                    config.operations_limit,
+                )?;
+                Ok((master_key, vec![key_slot]))
+            }
+            None => Ok((EncryptionKey::new(Vec::new()), Vec::new())),
+        }
+    }
+
+    /// Return a new empty header and its serialized form for the given `header_mode`.
+    fn empty_header(header_mode: HeaderMode) -> (Header<K>, Vec<u8>) {
+        match header_mode {
+            HeaderMode::Memory => {
+                let header = MemoryHeader::default();
+                let serialized_header = to_vec(&header).expect("Could not serialize header.");
+                (Header::Memory(header), serialized_header)
+            }
+            HeaderMode::Paged { cache_pages } => {
+                let root = PagedRoot::default();
+                let serialized_root = to_vec(&root).expect("Could not serialize header.");
+                (
+                    Header::Paged(PagedHeader::new(cache_pages)),
+                    serialized_root,
+                )
+            }
+        }
+    }
+
+    /// Read the header referenced by `metadata` from `pages`.
+    fn read_header(
+        pages: &HeaderPages<S>,
+        metadata: &RepositoryMetadata,
+    ) -> crate::Result<Header<K>> {
+        match metadata.header_mode {
+            HeaderMode::Memory => Ok(Header::Memory(Self::read_memory_header(pages, metadata)?)),
+            HeaderMode::Paged { cache_pages } => {
+                // Read the root of the header. Pages are read as they are needed.
+                let serialized_root = pages.read_page(metadata.header)?;
+                let root: PagedRoot =
+                    from_read(serialized_root.as_slice()).map_err(|_| crate::Error::Corrupt)?;
+                Ok(Header::Paged(PagedHeader::open(root, cache_pages, pages)?))
+            }
+        }
+    }
+
+    /// Read the compression dictionaries referenced by `metadata` from `pages`.
+    fn read_dictionaries(
+        pages: &HeaderPages<S>,
+        metadata: &RepositoryMetadata,
+    ) -> crate::Result<Dictionaries> {
+        let mut dictionaries = Dictionaries::new();
+        for dictionary in metadata.dictionaries.iter() {
+            dictionaries.insert(dictionary.id, pages.read_page(dictionary.block_id)?);
+        }
+        Ok(dictionaries)
+    }
+
+    /// Read the `MemoryHeader` referenced by `metadata` from `pages`.
+    fn read_memory_header(
+        pages: &HeaderPages<S>,
+        metadata: &RepositoryMetadata,
+    ) -> crate::Result<MemoryHeader<K>> {
+        // Read the base snapshot of the header and replay the journal on top of it.
+        let serialized_header = pages.read_page(metadata.header)?;
+        let mut header: MemoryHeader<K> =
+            from_read(serialized_header.as_slice()).map_err(|_| crate::Error::KeyType)?;
+        for delta_id in metadata.journal.iter().copied() {
+            let serialized_delta = pages.read_page(delta_id)?;
+            let delta: HeaderDelta<K> =
+                from_read(serialized_delta.as_slice()).map_err(|_| crate::Error::KeyType)?;
+            header.apply(delta);
+        }
+        Ok(header)
+    }
+
+    /// Encode and write a block of the header and return its ID.
+    fn write_header_block(&self, data: &[u8]) -> crate::Result<Uuid> {
+        let block_id = Uuid::new_v4();
+        self.state.pages().write_page(block_id, data)?;
+        Ok(block_id)
+    }
+
+    /// Write the repository metadata, atomically completing a commit.
+    fn write_metadata(&self) -> crate::Result<()> {
+        let serialized_metadata =
+            to_vec(&self.state.metadata).expect("Could not serialize metadata.");
+        self.state
+            .store
+            .lock()
+            .unwrap()
+            .write_block(*METADATA_BLOCK_ID, &serialized_metadata)
+            .map_err(anyhow::Error::from)?;
+        Ok(())
+    }
+
+    /// Write the list of blocks which are waiting to be removed.
+    fn write_unused_blocks(&self) -> crate::Result<()> {
+        let serialized_unused_blocks =
+            to_vec(&self.state.unused_blocks).expect("Could not serialize unused blocks.");
+        self.state
+            .store
+            .lock()
+            .unwrap()
+            .write_block(*UNUSED_BLOCK_ID, &serialized_unused_blocks)
+            .map_err(anyhow::Error::from)?;
+        Ok(())
+    }
+
+    /// Commit changes which have been made to the repository.
+    ///
+    /// No changes are saved persistently until this method is called. Committing a repository is an
+    /// atomic and consistent operation; changes cannot be partially committed and interrupting a
+    /// commit will never leave the repository in an inconsistent state.
+    ///
+    /// Only the parts of the header which have changed since the last commit are written. Once
+    /// enough changes have accumulated, the whole header is rewritten as a new snapshot.
+    ///
+    /// Committing does not remove data which is no longer used from the data store. Instead, the
+    /// blocks which store it are recorded so they can be removed later by `gc`.
+    ///
+    /// # Errors
+    /// - `Error::InvalidData`: Ciphertext verification failed.
+    /// - `Error::Store`: An error occurred with the data store.
+    /// - `Error::Io`: An I/O error occurred.
+    pub fn commit(&mut self) -> crate::Result<()> {
+        self.commit_with(Self::write_metadata)
+    }
+
+    /// Commit changes, using `write_metadata` to atomically complete the commit.
+    fn commit_with(
+        &mut self,
+        write_metadata: impl FnOnce(&Self) -> crate::Result<()>,
+    ) -> crate::Result<()> {
+        match self.state.header {
+            Header::Memory(_) => self.commit_memory(write_metadata),
+            Header::Paged(_) => self.commit_paged(write_metadata),
+        }
+    }
+
+    /// Commit changes to a repository with a `MemoryHeader`.
+    fn commit_memory(
+        &mut self,
+        write_metadata: impl FnOnce(&Self) -> crate::Result<()>,
+    ) -> crate::Result<()> {
+        // Remove chunks which are not referenced by any object.
+        let removed_chunks = match &mut self.state.header {
+            Header::Memory(header) => header.clean_chunks(),
+            Header::Paged(_) => unreachable!(),
+        };
+        let mut unused_blocks = removed_chunks
+            .iter()
+            .map(|(chunk, block_id)| UnusedBlock {
+                id: *block_id,
+                size: chunk.size as u64,
+            })
+            .collect::<Vec<_>>();
+
+        // The location of the header is changed in place, so keep the old one to restore if the
+        // commit fails.
+        let old_header_id = self.state.metadata.header;
+        let old_journal = self.state.metadata.journal.clone();
+        match self.write_memory_header(write_metadata) {
+            Ok(true) => unused_blocks.extend(
+                once(old_header_id)
+                    .chain(old_journal)
+                    .map(|id| UnusedBlock { id, size: 0 }),
+            ),
+            Ok(false) => {}
+            Err(error) => {
+                self.state.metadata.header = old_header_id;
+                self.state.metadata.journal = old_journal;
+                if let Header::Memory(header) = &mut self.state.header {
+                    for (chunk, block_id) in removed_chunks {
+                        header.insert_chunk(chunk, block_id);
+                    }
+                }
+                return Err(error);
+            }
+        }
+        self.state.savepoints.clear();
+
+        if let Header::Memory(header) = &mut self.state.header {
+            header.clear_dirty();
+        }
+
+        // Record the blocks which are no longer used so they can be removed by `gc`. This is done
+        // after the commit is complete so that interrupting it can only leak blocks.
+        self.state.unused_blocks.extend(unused_blocks);
+        self.write_unused_blocks()
+    }
+
+    /// Write the changes to a `MemoryHeader` and then the repository metadata.
+    ///
+    /// This writes either a new snapshot of the header or a delta to append to the journal and
+    /// returns whether it wrote a snapshot. A new snapshot is written once the journal is long or
+    /// the changes make up a large part of the header, so that opening the repository doesn't have
+    /// to replay too many deltas.
+    fn write_memory_header(
+        &mut self,
+        write_metadata: impl FnOnce(&Self) -> crate::Result<()>,
+    ) -> crate::Result<bool> {
+        let mut write_snapshot = false;
+        if let Header::Memory(header) = &self.state.header {
+            write_snapshot = self.state.metadata.journal.len() >= MAX_JOURNAL_LEN
+                || header.dirty_count() * 2 > header.entry_count();
+            if write_snapshot {
+                let serialized_header = to_vec(header).expect("Could not serialize header.");
+                let header_id = self.write_header_block(&serialized_header)?;
+                self.state.metadata.header = header_id;
+                self.state.metadata.journal.clear();
+            } else if header.dirty_count() > 0 {
+                let serialized_delta =
+                    to_vec(&header.delta()).expect("Could not serialize header.");
+                let delta_id = self.write_header_block(&serialized_delta)?;
+                self.state.metadata.journal.push(delta_id);
+            }
+        }
+
+        // Write the repository metadata, atomically completing the commit.
+        write_metadata(self)?;
+        Ok(write_snapshot)
+    }
+
+    /// Commit changes to a repository with a `PagedHeader`.
+    fn commit_paged(
+        &mut self,
+        write_metadata: impl FnOnce(&Self) -> crate::Result<()>,
+    ) -> crate::Result<()> {
+        // Apply the changes to copies of the B-trees and write their modified pages. The header in
+        // memory isn't changed until the commit is complete.
+        let pages = self.state.pages();
+        let mut staged = match &self.state.header {
+            Header::Paged(header) => header.stage(&pages)?,
+            Header::Memory(_) => unreachable!(),
+        };
+        let (root, unused_blocks) = staged.flush(&pages)?;
+
+        // Write the new root of the header and the repository metadata, atomically completing the
+        // commit.
+        let serialized_root = to_vec(&root).expect("Could not serialize header.");
+        let root_id = self.write_header_block(&serialized_root)?;
+        let old_root_id = replace(&mut self.state.metadata.header, root_id);
+        if let Err(error) = write_metadata(self) {
+            self.state.metadata.header = old_root_id;
+            return Err(error);
+        }
+        self.state.savepoints.clear();
+
+        if let Header::Paged(header) = &mut self.state.header {
+            header.finish(staged);
+        }
+
+        // Record the pages and chunks which are no longer used so they can be removed by `gc`.
+        self.state.unused_blocks.extend(unused_blocks);
+        self.state.unused_blocks.push(UnusedBlock {
+            id: old_root_id,
+            size: 0,
+        });
+        self.write_unused_blocks()
+    }
+
+    /// Return whether any objects have changed since the last commit.
+    pub(crate) fn has_changes(&self) -> bool {
+        self.state.header.has_changes()
+    }
+
+    /// Roll back all changes which have been made since the last commit.
+    ///
+    /// This restores the repository to the state it was in as of the last commit without
+    /// re-opening it. Any savepoints are discarded. Data which was written since the last commit is
+    /// recorded so it can be removed by `gc`.
+    ///
+    /// # Errors
+    /// - `Error::Corrupt`: The repository is corrupt. This is most likely unrecoverable.
+    /// - `Error::InvalidData`: Ciphertext verification failed.
+    /// - `Error::Store`: An error occurred with the data store.
+    pub fn rollback(&mut self) -> crate::Result<()> {
+        let metadata = Self::read_metadata(&mut self.state.store.lock().unwrap())?;
+        let pages = HeaderPages::new(&self.state.store, &metadata, &self.state.master_key);
+        let mut unused_blocks = match &mut self.state.header {
+            Header::Memory(_) => {
+                let committed_header = Header::Memory(Self::read_memory_header(&pages, &metadata)?);
+                let current_header = replace(&mut self.state.header, committed_header);
+                current_header.chunks_added_since(&self.state.header)
+            }
+            Header::Paged(header) => header.rollback(),
+        };
+        unused_blocks.extend(self.state.metadata.dictionaries_added_since(&metadata));
+
+        self.state.metadata = metadata;
+        self.state.savepoints.clear();
+        self.state.unused_blocks.extend(unused_blocks);
+        self.write_unused_blocks()
+    }
+
+    /// Create a savepoint which uncommitted changes can be rolled back to with `rollback_to`.
+    ///
+    /// Savepoints are discarded when changes are committed or rolled back. Each savepoint keeps a
+    /// copy of the repository's header in memory, so with `HeaderMode::Memory` creating a
+    /// savepoint in a large repository can be expensive.
+    pub fn savepoint(&mut self) -> Savepoint {
+        let savepoint = Savepoint { id: Uuid::new_v4() };
+        self.state.savepoints.push(SavedState {
+            savepoint,
+            metadata: self.state.metadata.clone(),
+            header: self.state.header.clone(),
+        });
+        savepoint
+    }
+
+    /// Roll back the changes which have been made since `savepoint` was created.
+    ///
+    /// Any savepoints created after `savepoint` are discarded, but `savepoint` itself can be rolled
+    /// back to again. Data which was written since `savepoint` was created is recorded so it can be
+    /// removed by `gc`.
+    ///
+    /// # Errors
+    /// - `Error::NotFound`: The savepoint has been discarded or belongs to another repository.
+    /// - `Error::Store`: An error occurred with the data store.
+    pub fn rollback_to(&mut self, savepoint: Savepoint) -> crate::Result<()> {
+        let index = self
+            .state
+            .savepoints
+            .iter()
+            .position(|saved| saved.savepoint == savepoint)
+            .ok_or(crate::Error::NotFound)?;
+        self.state.savepoints.truncate(index + 1);
+
+        let saved = &self.state.savepoints[index];
+        let mut unused_blocks = self.state.header.chunks_added_since(&saved.header);
+        unused_blocks.extend(
+            self.state
+                .metadata
+                .dictionaries_added_since(&saved.metadata),
+        );
+        self.state.header = saved.header.clone();
+        self.state.metadata = saved.metadata.clone();
+
+        self.state.unused_blocks.extend(unused_blocks);
+        self.write_unused_blocks()
+    }
+
+    /// Train a dictionary for Zstandard compression using `samples`.
+    ///
+    /// If the repository uses `Compression::Zstd`, chunks which are written after this are
+    /// compressed using the new dictionary, which can significantly improve the compression ratio
+    /// of small chunks with similar contents. The `samples` should be representative of the data
+    /// stored in the repository, and the dictionary will be at most `max_size` bytes.
+    ///
+    /// Chunks which have already been written are not compressed again. Dictionaries which were
+    /// trained previously are kept in the repository so that those chunks can still be read. The
+    /// dictionary is encrypted if encryption is enabled.
+    ///
+    /// Like other changes, the new dictionary is not persisted until `commit` is called.
+    ///
+    /// # Errors
+    /// - `Error::Io`: The dictionary could not be trained, usually because of too few samples.
+    /// - `Error::Store`: An error occurred with the data store.
+    #[cfg(feature = "compression")]
+    pub fn train_dictionary<T: AsRef<[u8]>>(
+        &mut self,
+        samples: &[T],
+        max_size: usize,
+    ) -> crate::Result<()> {
+        let (id, dictionary) = train_dictionary(samples, max_size)?;
+        let block_id = Uuid::new_v4();
+        self.state.pages().write_page(block_id, &dictionary)?;
+        self.state.dictionaries.insert(id, dictionary);
+        self.state
+            .metadata
+            .dictionaries
+            .push(DictionaryBlock { id, block_id });
+        Ok(())
+    }
+
+    /// Remove blocks which are no longer used by the repository from the data store.
+    ///
+    /// Committing changes only records which blocks are no longer used; this method removes them.
+    /// If `budget` is `Some`, this stops once that much time has elapsed and leaves the remaining
+    /// blocks to be removed by a later call. At least one block is removed per call.
+    ///
+    /// Blocks written by changes which were never committed are not tracked and are not removed.
+    ///
+    /// # Errors
+    /// - `Error::Store`: An error occurred with the data store.
+    pub fn gc(&mut self, budget: Option<Duration>) -> crate::Result<GcStats> {
+        let start_time = Instant::now();
+        let mut stats = GcStats::default();
+
+        {
+            // Blocks are removed in batches, and the budget is checked between batches. Batches
+            // start small and grow so that a short budget isn't overrun by a large batch.
+            let mut store = self.state.store.lock().unwrap();
+            let mut batch_size = 1;
+            while !self.state.unused_blocks.is_empty() {
+                if let Some(budget) = budget {
+                    if stats.blocks > 0 && start_time.elapsed() >= budget {
+                        break;
+                    }
+                }
+                let batch_start = self.state.unused_blocks.len().saturating_sub(batch_size);
+                let batch_ids = self.state.unused_blocks[batch_start..]
+                    .iter()
+                    .map(|block| block.id)
+                    .collect::<Vec<_>>();
+                store
+                    .remove_blocks(&batch_ids)
+                    .map_err(anyhow::Error::from)?;
+                for block in self.state.unused_blocks.drain(batch_start..) {
+                    stats.blocks += 1;
+                    stats.bytes += block.size;
+                }
+                batch_size = min(batch_size * 2, GC_BATCH_SIZE);
+            }
+        }
+
+        // Removing a block which has already been removed is not an error, so if this fails, the
+        // blocks which were removed are harmlessly removed again by the next call.
+        if stats.blocks > 0 {
+            self.write_unused_blocks()?;
+        }
+
+        stats.remaining = self.state.unused_blocks.len() as u64;
+        Ok(stats)
+    }
+
+    /// Remove every block in the data store which is not used by the repository.
+    ///
+    /// Unlike `gc`, this lists every block in the data store and removes those which the repository
+    /// doesn't reference, so it also removes blocks which were never recorded as unused, such as
+    /// those written by a process which was interrupted before it could commit. This can be slow
+    /// for data stores with many blocks, so `gc` should be preferred for routine cleanup.
+    ///
+    /// Uncommitted changes are kept. The data store must not be shared with anything other than
+    /// this repository.
+    ///
+    /// The returned `GcStats` only counts the size of blocks which had been recorded as unused;
+    /// the size of other blocks is not known.
+    ///
+    /// # Errors
+    /// - `Error::Password`: A reconfiguration is in progress which has not been resumed since the
+    /// repository was opened, so the blocks it uses can't be read. Call `reconfigure` to resume it
+    /// first.
+    /// - `Error::Corrupt`: The repository is corrupt. This is most likely unrecoverable.
+    /// - `Error::InvalidData`: Ciphertext verification failed.
+    /// - `Error::Store`: An error occurred with the data store.
+    pub fn gc_full(&mut self) -> crate::Result<GcStats> {
+        let referenced = self.referenced_blocks()?;
+        let unused_sizes = self
+            .state
+            .unused_blocks
+            .iter()
+            .map(|block| (block.id, block.size))
+            .collect::<HashMap<_, _>>();
+        let mut stats = GcStats::default();
+
+        {
+            let mut store = self.state.store.lock().unwrap();
+            let unreferenced = store
+                .list_blocks()
+                .map_err(anyhow::Error::from)?
+                .into_iter()
+                .filter(|id| !referenced.contains(id))
+                .collect::<Vec<_>>();
+            for batch_ids in unreferenced.chunks(GC_BATCH_SIZE) {
+                store
+                    .remove_blocks(batch_ids)
+                    .map_err(anyhow::Error::from)?;
+                stats.blocks += batch_ids.len() as u64;
+                stats.bytes += batch_ids
+                    .iter()
+                    .filter_map(|id| unused_sizes.get(id))
+                    .sum::<u64>();
+            }
+        }
+
+        // Every unused block has now been removed, whether or not it was listed.
+        if !self.state.unused_blocks.is_empty() {
+            self.state.unused_blocks.clear();
+            self.write_unused_blocks()?;
+        }
+
+        Ok(stats)
+    }
+
+    /// Return the IDs of every block which is used by the repository, including its uncommitted
+    /// changes and the reconfiguration in progress.
+    fn referenced_blocks(&self) -> crate::Result<HashSet<Uuid>> {
+        let mut referenced = HashSet::new();
+        referenced.extend(&[*METADATA_BLOCK_ID, *UNUSED_BLOCK_ID, *VERSION_BLOCK_ID]);
+        let mut blocks = self.state.header.blocks(&self.state.pages())?;
+        blocks.extend(self.state.metadata.blocks());
+
+        if let Some(record_id) = self.state.metadata.reconfiguration {
+            let reconfiguration = self
+                .state
+                .reconfiguration
+                .as_ref()
+                .ok_or(crate::Error::Password)?;
+            let pages = HeaderPages::new(
+                &self.state.store,
+                &reconfiguration.metadata,
+                &reconfiguration.master_key,
+            );
+            blocks.extend(reconfiguration.header.blocks(&pages)?);
+            blocks.extend(reconfiguration.metadata.blocks());
+            referenced.insert(record_id);
+            referenced.extend(reconfiguration.sources_page);
+        }
+
+        referenced.extend(blocks.into_iter().map(|block| block.id));
+        Ok(referenced)
+    }
+
+    /// Return how much space would be reclaimed by calling `gc` without removing anything.
+    pub fn gc_dry_run(&self) -> GcStats {
+        GcStats {
+            blocks: self.state.unused_blocks.len() as u64,
+            bytes: self
+                .state
+                .unused_blocks
+                .iter()
+                .map(|block| block.size)
+                .sum(),
+            remaining: 0,
+        }
+    }
+
+    /// Rewrite the data in the repository using a new `config`.
+    ///
+    /// This changes the chunking method, compression method, encryption method, key derivation
+    /// limits and header mode of the repository without copying its objects to a new repository. The
+    /// repository can still be used normally while it is being reconfigured.
+    ///
+    /// Objects are rewritten in batches. If `budget` is `Some`, this stops once that much time has
+    /// elapsed and saves its progress; call it again with the same `config` to continue. At least
+    /// one object is rewritten per call. Once every object has been rewritten, the repository
+    /// switches to the new configuration atomically and the blocks which store the old data are
+    /// recorded so they can be removed by `gc`. The returned `ReconfigureProgress` reports whether
+    /// this has happened.
+    ///
+    /// Progress is saved in the data store, so if reconfiguring is interrupted, it continues where
+    /// it left off the next time this is called, even if the repository has been re-opened.
+    /// Objects which are changed in the meantime are rewritten again. Calling this with a
+    /// different `config` abandons the reconfiguration in progress.
+    ///
+    /// If the encryption method doesn't change, the master key and every key slot are kept and `key`
+    /// must be `None`. Changing the key derivation limits only affects key slots which are added
+    /// later.
+    ///
+    /// If the encryption method changes to one which encrypts data, a `key` must be provided;
+    /// otherwise, it must be `None`. A new master key is generated, and once the repository has
+    /// been reconfigured, it can only be opened with `key`, which is stored in a key slot named
+    /// `DEFAULT_KEY_SLOT`. Because the master key changes, every other key slot is removed. The same
+    /// key must be provided each time this is called.
+    ///
+    /// Any uncommitted changes are committed before anything is rewritten. Changing
+    /// `chunking` changes the `ContentId` of every object.
+    ///
+    /// # Errors
+    /// - `Error::Password` A key was required but not provided or provided but not required.
+    /// - `Error::Password`: The key doesn't match the reconfiguration in progress.
+    /// - `Error::InvalidConfig`: The given `config` is invalid.
+    /// - `Error::Unsupported`: The encryption method is not supported on this machine.
+    /// - `Error::Corrupt`: The repository is corrupt. This is most likely unrecoverable.
+    /// - `Error::InvalidData`: Ciphertext verification failed.
+    /// - `Error::Store`: An error occurred with the data store.
+    /// - `Error::Io`: An I/O error occurred.
+    pub fn reconfigure(
+        &mut self,
+        config: RepositoryConfig,
+        key: Option<SlotKey>,
+        budget: Option<Duration>,
+    ) -> crate::Result<ReconfigureProgress> {
+        let start_time = Instant::now();
+
+        if !config.is_valid() {
+            return Err(crate::Error::InvalidConfig);
+        }
+
+        // Select a cipher if one should be chosen automatically.
+        let mut config = config;
+        config.encryption = config.encryption.resolve();
+        if !config.encryption.is_supported() {
+            return Err(crate::Error::Unsupported);
+        }
+
+        // Return an error if a key was required but not provided or provided but not required. A
+        // key is only needed to create a new master key.
+        let keeps_master_key = config.encryption == self.state.metadata.encryption;
+        if key.is_none() != (keeps_master_key || config.encryption == Encryption::None) {
+            return Err(crate::Error::Password);
+        }
+
+        self.commit()?;
+
+        // Continue the reconfiguration in progress if it uses the same configuration.
+        let in_progress = match self.state.reconfiguration.take() {
+            Some(reconfiguration) => Some(reconfiguration),
+            None => self.read_reconfiguration(key)?,
+        };
+        let mut reconfiguration = match in_progress {
+            Some(reconfiguration) if reconfiguration.config() == config => reconfiguration,
+            Some(reconfiguration) => {
+                self.abandon_reconfiguration(reconfiguration)?;
+                self.start_reconfiguration(&config, key)?
+            }
+            None => self.start_reconfiguration(&config, key)?,
+        };
+
+        match self.rewrite_objects(&mut reconfiguration, start_time, budget) {
+            Ok(progress) if progress.is_complete() => {
+                self.finish_reconfiguration(reconfiguration)?;
+                Ok(progress)
+            }
+            Ok(progress) => {
+                let result = self.save_reconfiguration(&mut reconfiguration);
+                self.state.reconfiguration = Some(reconfiguration);
+                result.map(|_| progress)
+            }
+            Err(error) => {
+                self.state.reconfiguration = Some(reconfiguration);
+                Err(error)
+            }
+        }
+    }
+
+    /// Start reconfiguring the repository using `config`.
+    ///
+    /// This writes an empty header and copies of the compression dictionaries using the new
+    /// configuration, but nothing is saved until `save_reconfiguration` is called. If the encryption
+    /// method doesn't change, the master key and key slots are kept; otherwise, a new master key is
+    /// generated and stored in a key slot for `key`.
+    fn start_reconfiguration(
+        &mut self,
+        config: &RepositoryConfig,
+        key: Option<SlotKey>,
+    ) -> crate::Result<Reconfiguration<K>> {
+        let (master_key, key_slots) = if config.encryption == self.state.metadata.encryption {
+            (
+                self.state.master_key.clone(),
+                self.state.metadata.key_slots.clone(),
+            )
+        } else {
+            Self::generate_master_key(config, key)?
+        };
+        let mut metadata = RepositoryMetadata {
+            id: self.state.metadata.id,
+            chunking: config.chunking_method(),
+            compression: config.compression,
+            encryption: config.encryption,
+            memory_limit: config.memory_limit,
+            operations_limit: config.operations_limit,
+            key_slots,
+            header: Uuid::new_v4(),
+            journal: Vec::new(),
+            header_mode: config.header_mode,
+            dictionaries: Vec::new(),
+            creation_time: self.state.metadata.creation_time,
+            reconfiguration: None,
+        };
+
+        let (header, serialized_header) = Self::empty_header(config.header_mode);
+        let mut dictionary_blocks = Vec::new();
+        {
+            let pages = HeaderPages::new(&self.state.store, &metadata, &master_key);
+            pages.write_page(metadata.header, &serialized_header)?;
+            for dictionary in self.state.metadata.dictionaries.iter() {
+                let block_id = Uuid::new_v4();
+                pages.write_page(block_id, &self.state.dictionaries[&dictionary.id])?;
+                dictionary_blocks.push(DictionaryBlock {
+                    id: dictionary.id,
+                    block_id,
+                });
+            }
+        }
+        metadata.dictionaries = dictionary_blocks;
+
+        Ok(Reconfiguration {
+            metadata,
+            header,
+            master_key,
+            dictionaries: self.state.dictionaries.clone(),
+            sources: HashMap::new(),
+            sources_page: None,
+        })
+    }
+
+    /// Read the reconfiguration in progress from the data store, decrypting it with `key`.
+    ///
+    /// This returns `None` if there is no reconfiguration in progress.
+    fn read_reconfiguration(
+        &mut self,
+        key: Option<SlotKey>,
+    ) -> crate::Result<Option<Reconfiguration<K>>> {
+        let record_id = match self.state.metadata.reconfiguration {
+            Some(record_id) => record_id,
+            None => return Ok(None),
+        };
+        let serialized_record = self
+            .state
+            .store
+            .lock()
+            .unwrap()
+            .read_block(record_id)
+            .map_err(anyhow::Error::from)?
+            .ok_or(crate::Error::Corrupt)?;
+        let record: ReconfigureRecord =
+            from_read(serialized_record.as_slice()).map_err(|_| crate::Error::Corrupt)?;
+
+        // If the encryption method doesn't change, the reconfiguration uses the same master key.
+        let master_key = if record.metadata.encryption == self.state.metadata.encryption {
+            self.state.master_key.clone()
+        } else {
+            Self::decrypt_master_key(&record.metadata, None, key)?.0
+        };
+
+        let pages = HeaderPages::new(&self.state.store, &record.metadata, &master_key);
+        let header = Self::read_header(&pages, &record.metadata)?;
+        let dictionaries = Self::read_dictionaries(&pages, &record.metadata)?;
+        let serialized_sources = pages.read_page(record.sources)?;
+        let sources =
+            from_read(serialized_sources.as_slice()).map_err(|_| crate::Error::KeyType)?;
+
+        Ok(Some(Reconfiguration {
+            metadata: record.metadata,
+            header,
+            master_key,
+            dictionaries,
+            sources,
+            sources_page: Some(record.sources),
+        }))
+    }
+
+    /// Rewrite objects using the configuration in `reconfiguration` until `budget` runs out.
+    fn rewrite_objects(
+        &mut self,
+        reconfiguration: &mut Reconfiguration<K>,
+        start_time: Instant,
+        budget: Option<Duration>,
+    ) -> crate::Result<ReconfigureProgress> {
+        let new_pages = HeaderPages::new(
+            &self.state.store,
+            &reconfiguration.metadata,
+            &reconfiguration.master_key,
+        );
+
+        // Forget objects which have been changed or removed since they were rewritten.
+        let mut stale_keys = Vec::new();
+        for key in reconfiguration.header.object_keys(&new_pages) {
+            let key = key?;
+            let is_current = match self.state.object(&key)? {
+                Some(handle) => reconfiguration.sources.get(&key) == Some(&handle.content_id()),
+                None => false,
+            };
+            if !is_current {
+                stale_keys.push(key);
+            }
+        }
+        for key in stale_keys {
+            reconfiguration.header.remove_object(&new_pages, &key)?;
+            reconfiguration.sources.remove(&key);
+        }
+
+        let mut pending_keys = Vec::new();
+        for key in self.state.keys() {
+            let key = key?;
+            if !reconfiguration.header.contains_object(&new_pages, &key)? {
+                pending_keys.push(key);
+            }
+        }
+
+        let mut progress = ReconfigureProgress {
+            remaining: pending_keys.len() as u64,
+            ..ReconfigureProgress::default()
+        };
+        for key in pending_keys {
+            if let Some(budget) = budget {
+                if progress.objects > 0 && start_time.elapsed() >= budget {
+                    break;
+                }
+            }
+            progress.bytes += self.rewrite_object(reconfiguration, key)?;
+            progress.objects += 1;
+            progress.remaining -= 1;
+        }
+
+        Ok(progress)
+    }
+
+    /// Rewrite the object with the given `key` using the configuration in `reconfiguration`.
+    ///
+    /// This returns the size of the object.
+    fn rewrite_object(
+        &mut self,
+        reconfiguration: &mut Reconfiguration<K>,
+        key: K,
+    ) -> crate::Result<u64> {
+        let handle = self
+            .state
+            .object(&key)?
+            .ok_or(crate::Error::NotFound)?
+            .into_owned();
+        let mut new_handle = ObjectHandle {
+            size: handle.size,
+            chunks: Vec::new(),
+            created: handle.created,
+            modified: handle.modified,
+            tags: handle.tags.clone(),
+        };
+
+        // Read each chunk using the current configuration and split the data into chunks again
+        // using the new one.
+        let mut chunker = IncrementalChunker::new(reconfiguration.metadata.chunking.chunker());
+        for chunk in handle.chunks.iter().copied() {
+            let data = self.state.read_chunk(chunk, None)?;
+            chunker.write_all(&data)?;
+            self.write_rewritten_chunks(reconfiguration, &mut chunker, &mut new_handle)?;
+        }
+        chunker.flush()?;
+        self.write_rewritten_chunks(reconfiguration, &mut chunker, &mut new_handle)?;
+
+        reconfiguration
+            .sources
+            .insert(key.clone(), handle.content_id());
+        reconfiguration.header.insert_object(key, new_handle);
+
+        Ok(handle.size)
+    }
+
+    /// Write the chunks in `chunker` using the configuration in `reconfiguration`.
+    fn write_rewritten_chunks(
+        &mut self,
+        reconfiguration: &mut Reconfiguration<K>,
+        chunker: &mut IncrementalChunker<Chunker>,
+        handle: &mut ObjectHandle,
+    ) -> crate::Result<()> {
+        let chunks = chunker.chunks();
+        reconfiguration.swap(&mut self.state);
+        let result = self.state.write_chunks(&chunks, None);
+        reconfiguration.swap(&mut self.state);
+        handle.chunks.extend(result?);
+        Ok(())
+    }
+
+    /// Save the progress of `reconfiguration` to the data store.
+    fn save_reconfiguration(
+        &mut self,
+        reconfiguration: &mut Reconfiguration<K>,
+    ) -> crate::Result<()> {
+        reconfiguration.swap(&mut self.state);
+        let result = self.commit_reconfiguration(reconfiguration);
+        reconfiguration.swap(&mut self.state);
+        let (record_id, sources_page) = result?;
+
+        // The previous record and list of sources are no longer used.
+        let old_blocks = self
+            .state
+            .metadata
+            .reconfiguration
+            .replace(record_id)
+            .into_iter()
+            .chain(reconfiguration.sources_page.replace(sources_page));
+        self.state
+            .unused_blocks
+            .extend(old_blocks.map(|id| UnusedBlock { id, size: 0 }));
+        self.write_unused_blocks()
+    }
+
+    /// Commit the new header while the state of `reconfiguration` is swapped into the repository.
+    ///
+    /// This returns the IDs of the blocks which store the new record and list of sources.
+    fn commit_reconfiguration(
+        &mut self,
+        reconfiguration: &Reconfiguration<K>,
+    ) -> crate::Result<(Uuid, Uuid)> {
+        let serialized_sources =
+            to_vec(&reconfiguration.sources).expect("Could not serialize sources.");
+        let sources_page = self.write_header_block(&serialized_sources)?;
+        let record_id = Uuid::new_v4();
+
+        self.commit_with(|repository| {
+            // Write the new record and then point the current metadata at it, atomically saving
+            // the progress.
+            let record = ReconfigureRecord {
+                metadata: repository.state.metadata.clone(),
+                sources: sources_page,
+            };
+            let mut metadata = reconfiguration.metadata.clone();
+            metadata.reconfiguration = Some(record_id);
+
+            let serialized_record = to_vec(&record).expect("Could not serialize record.");
+            let serialized_metadata = to_vec(&metadata).expect("Could not serialize metadata.");
+            let mut store = repository.state.store.lock().unwrap();
+            store
+                .write_block(record_id, &serialized_record)
+                .map_err(anyhow::Error::from)?;
+            store
+                .write_block(*METADATA_BLOCK_ID, &serialized_metadata)
+                .map_err(anyhow::Error::from)?;
+            Ok(())
+        })?;
+
+        Ok((record_id, sources_page))
+    }
+
+    /// Switch the repository to the configuration in `reconfiguration`.
+    ///
+    /// Every object must have been rewritten.
+    fn finish_reconfiguration(
+        &mut self,
+        mut reconfiguration: Reconfiguration<K>,
+    ) -> crate::Result<()> {
+        // Find the blocks which store data using the old configuration. Changes have already been
+        // committed, so this includes all of them.
+        let mut unused_blocks = self.state.header.blocks(&self.state.pages())?;
+        unused_blocks.extend(self.state.metadata.blocks());
+        unused_blocks.extend(
+            self.state
+                .metadata
+                .reconfiguration
+                .into_iter()
+                .chain(reconfiguration.sources_page)
+                .map(|id| UnusedBlock { id, size: 0 }),
+        );
+
+        // If the master key is kept, so are the key slots, including any which were changed since
+        // the reconfiguration started.
+        let keeps_master_key =
+            reconfiguration.metadata.encryption == self.state.metadata.encryption;
+        if keeps_master_key {
+            reconfiguration.metadata.key_slots = self.state.metadata.key_slots.clone();
+        }
+
+        // Committing the new header writes the new metadata, atomically switching the repository
+        // to the new configuration.
+        reconfiguration.swap(&mut self.state);
+        if let Err(error) = self.commit() {
+            reconfiguration.swap(&mut self.state);
+            self.state.reconfiguration = Some(reconfiguration);
+            return Err(error);
+        }
+        if !keeps_master_key {
+            self.state.key_slot = self
+                .state
+                .metadata
+                .key_slots
+                .first()
+                .map(|slot| slot.name.clone());
+        }
+
+        self.state.unused_blocks.extend(unused_blocks);
+        self.write_unused_blocks()
+    }
+
+    /// Abandon `reconfiguration` and record the blocks it wrote so they can be removed by `gc`.
+    fn abandon_reconfiguration(
+        &mut self,
+        reconfiguration: Reconfiguration<K>,
+    ) -> crate::Result<()> {
+        let pages = HeaderPages::new(
+            &self.state.store,
+            &reconfiguration.metadata,
+            &reconfiguration.master_key,
+        );
+        let mut unused_blocks = reconfiguration.header.blocks(&pages)?;
+        unused_blocks.extend(reconfiguration.metadata.blocks());
+        unused_blocks.extend(
+            self.state
+                .metadata
+                .reconfiguration
+                .into_iter()
+                .chain(reconfiguration.sources_page)
+                .map(|id| UnusedBlock { id, size: 0 }),
+        );
+
+        let record_id = self.state.metadata.reconfiguration.take();
+        if let Err(error) = self.write_metadata() {
+            self.state.metadata.reconfiguration = record_id;
+            return Err(error);
+        }
+
+        self.state.unused_blocks.extend(unused_blocks);
+        self.write_unused_blocks()
+    }
+
+    /// Verify the integrity of all the data in the repository.
+    ///
+    /// This returns the set of keys of objects which are corrupt. This is more efficient than
+    /// calling `Object::verify` on each object in the repository.
+    ///
+    /// # Errors
+    /// - `Error::InvalidData`: Ciphertext verification failed.
+    /// - `Error::Store`: An error occurred with the data store.
+    /// - `Error::Io`: An I/O error occurred.
+    pub fn verify(&self) -> crate::Result<HashSet<K>> {
+        self.state.verify()
+    }
+
+    /// Change the password for this repository.
+    ///
+    /// This replaces the key in the key slot which was used to open the repository with
+    /// `new_password`. Other key slots are not affected. Changing the password does not require
+    /// re-encrypting any data. The change does not take effect until `commit` is called. If
+    /// encryption is disabled, this method does nothing.
+    ///
+    /// A key slot which uses a raw key can't be changed to use a password. To replace it, use
+    /// `add_key_slot` and `remove_key_slot` instead.
+    ///
+    /// # Errors
+    /// - `Error::Password`: The repository was opened using a key slot which uses a raw key.
+    /// - `Error::Unsupported`: The encryption method is not supported on this machine.
+    #[cfg(feature = "encryption")]
+    pub fn change_password(&mut self, new_password: &[u8]) -> crate::Result<()> {
+        let name = match &self.state.key_slot {
+            Some(name) => name.clone(),
+            None => return Ok(()),
+        };
+        let is_raw = self
+            .state
+            .metadata
+            .key_slots
+            .iter()
+            .any(|slot| slot.name == name && slot.salt.is_none());
+        if is_raw {
+            return Err(crate::Error::Password);
+        }
+        let key_slot = self.new_slot(&name, SlotKey::Password(new_password))?;
+        self.remove_slot(&name);
+        self.state.metadata.key_slots.push(key_slot);
+        Ok(())
+    }
+
+    /// Add a new key slot named `name` which can be unlocked with `key`.
+    ///
+    /// Each key slot stores a copy of the repository's master key, so the repository can be
+    /// opened using the key for any of its slots. Adding a key slot does not require
+    /// re-encrypting any data. The change does not take effect until `commit` is called.
+    ///
+    /// # Errors
+    /// - `Error::AlreadyExists`: There is already a key slot named `name`.
+    /// - `Error::Password`: Encryption is disabled for this repository.
+    /// - `Error::Unsupported`: The encryption method is not supported on this machine.
+    #[cfg(feature = "encryption")]
+    pub fn add_key_slot(&mut self, name: &str, key: SlotKey) -> crate::Result<()> {
+        if self.state.metadata.encryption == Encryption::None {
+            return Err(crate::Error::Password);
+        }
+        if self
+            .state
+            .metadata
+            .key_slots
+            .iter()
+            .any(|slot| slot.name == name)
+        {
+            return Err(crate::Error::AlreadyExists);
+        }
+
+        let key_slot = self.new_slot(name, key)?;
+        self.state.metadata.key_slots.push(key_slot);
+        Ok(())
+    }
+
+    /// Remove the key slot named `name`.
+    ///
+    /// This returns `true` if the key slot was removed or `false` if there is no key slot named
+    /// `name` or it is the only one, because the repository could not be opened without any key
+    /// slots. The change does not take effect until `commit` is called.
+    #[cfg(feature = "encryption")]
+    pub fn remove_key_slot(&mut self, name: &str) -> bool {
+        if self.state.metadata.key_slots.len() <= 1 {
+            return false;
+        }
+        self.remove_slot(name)
+    }
+
+    /// Return information about each of the key slots in this repository.
+    ///
+    /// If encryption is disabled, this is empty.
+    pub fn key_slots(&self) -> Vec<KeySlotInfo> {
+        self.state
+            .metadata
+            .key_slots
+            .iter()
+            .map(KeySlot::info)
+            .collect()
+    }
+
+    /// Return a new key slot named `name` which stores the master key encrypted with `key`.
+    #[cfg(feature = "encryption")]
+    fn new_slot(&self, name: &str, key: SlotKey) -> crate::Result<KeySlot> {
+        let metadata = &self.state.metadata;
+        KeySlot::new(
+            name,
+            key,
+            &self.state.master_key,
+            metadata.encryption,
+            metadata.memory_limit,
+            metadata.operations_limit,
+        )
+    }
+
+    /// Remove the key slot named `name` and return whether it existed.
+    #[cfg(feature = "encryption")]
+    fn remove_slot(&mut self, name: &str) -> bool {
+        let key_slots = &mut self.state.metadata.key_slots;
+        let original_len = key_slots.len();
+        key_slots.retain(|slot| slot.name != name);
+        key_slots.len() != original_len
+    }
+
+    /// Return information about the repository.
+    pub fn info(&self) -> RepositoryInfo {
+        self.state.metadata.to_info()
+    }
+
+    /// Return information about the repository in `store` without opening it.
+    ///
+    /// # Errors
     /// - `Error::NotFound`: There is no repository in the given `store`.
     /// - `Error::Corrupt`: The repository is corrupt. This is most likely unrecoverable.
     /// - `Error::Store`: An error occurred with the data store.
@@ -581,28 +1927,32 @@ impl<K: Key, S: DataStore> ObjectRepository<K, S> {
             Some(data) => data,
             None => return Err(crate::Error::NotFound),
         };
-        let metadata: RepositoryMetadata =
-            from_read(serialized_metadata.as_slice()).map_err(|_| crate::Error::Corrupt)?;
+        let metadata = RepositoryMetadata::decode(serialized_metadata.as_slice())?;
 
         Ok(metadata.to_info())
     }
 
+    /// Return information about the process which has the repository in `store` open for writing.
+    ///
+    /// This returns `None` if the repository is not open for writing. It can be used to find out
+    /// why opening a repository failed with `Error::Locked`.
+    ///
+    /// # Errors
+    /// - `Error::NotFound`: There is no repository in the given `store`.
+    /// - `Error::Corrupt`: The repository is corrupt. This is most likely unrecoverable.
+    /// - `Error::Io`: An I/O error occurred.
+    /// - `Error::Store`: An error occurred with the data store.
+    pub fn peek_lock_owner(store: &mut S) -> crate::Result<Option<LockOwner>> {
+        let id = Self::peek_info(store)?.id();
+        LockTable::owner(id, store.lock_dir().as_deref())
+    }
+
     /// Calculate statistics about the repository.
+    ///
+    /// If the repository uses `HeaderMode::Paged`, this reflects the state of the repository as of
+    /// the last commit.
     pub fn stats(&self) -> RepositoryStats {
-        let apparent_size = self
-            .state
-            .header
-            .objects
-            .values()
-            .map(|object| object.size)
-            .sum();
-        let actual_size = self
-            .state
-            .header
-            .chunks
-            .keys()
-            .map(|chunk| chunk.size as u64)
-            .sum();
+        let (apparent_size, actual_size) = self.state.header.sizes();
 
         RepositoryStats {
             apparent_size,
@@ -610,6 +1960,41 @@ impl<K: Key, S: DataStore> ObjectRepository<K, S> {
         }
     }
 
+    /// Return statistics about the time spent encoding and decoding data.
+    ///
+    /// These statistics are cumulative from when the repository was opened. Take the difference
+    /// between two snapshots to measure the cost of a sequence of operations. Other threads using
+    /// the repository at the same time are counted too; use `Object::encoding_stats` to measure the
+    /// cost of reading or writing a single object.
+    pub fn encoding_stats(&self) -> EncodingStats {
+        self.state.counters.snapshot()
+    }
+
+    /// Return the number of threads used to compress and encrypt chunks.
+    pub fn encoding_threads(&self) -> usize {
+        self.state.encoding_threads
+    }
+
+    /// Set the number of `threads` used to compress and encrypt chunks.
+    ///
+    /// When this is greater than `1`, chunks are encoded on a pool of worker threads as they are
+    /// written, and each batch of chunks is encoded while the previous batch is written to the data
+    /// store. Chunks are also decoded ahead of time as objects are read sequentially. Chunks are
+    /// still written to the data store in order on the calling thread.
+    ///
+    /// This is not stored in the repository, so it must be set each time the repository is
+    /// opened. The default value is `1`, which encodes and decodes chunks on the calling thread.
+    ///
+    /// # Errors
+    /// - `Error::InvalidConfig`: The number of `threads` is `0`.
+    pub fn set_encoding_threads(&mut self, threads: usize) -> crate::Result<()> {
+        if threads == 0 {
+            return Err(crate::Error::InvalidConfig);
+        }
+        self.state.encoding_threads = threads;
+        Ok(())
+    }
+
     /// Consume this repository and return the wrapped `DataStore`.
     pub fn into_store(self) -> S {
         self.state.store.into_inner().unwrap()
@@ -0,0 +1,42 @@
+/*
+ * Copyright 2019-2020 Wren Powell
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use uuid::Uuid;
+
+use super::header::{Header, Key};
+use super::metadata::RepositoryMetadata;
+
+/// A point in the uncommitted changes to a repository which can be rolled back to.
+///
+/// Savepoints are created by `ObjectRepository::savepoint`. A savepoint is only valid until
+/// changes are committed or rolled back past it.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub struct Savepoint {
+    pub(super) id: Uuid,
+}
+
+/// The state of a repository at a `Savepoint`.
+#[derive(Debug)]
+pub struct SavedState<K: Key> {
+    /// The savepoint which refers to this state.
+    pub savepoint: Savepoint,
+
+    /// The metadata for the repository at the savepoint.
+    pub metadata: RepositoryMetadata,
+
+    /// The repository's header at the savepoint.
+    pub header: Header<K>,
+}
@@ -14,19 +14,32 @@
  * limitations under the License.
  */
 
-use cdchunking::ZPAQ;
+use std::borrow::{Borrow, Cow};
+use std::collections::HashSet;
 use std::fmt::{self, Debug, Formatter};
+use std::hash::Hash;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
-use crate::repo::object::chunking::IncrementalChunker;
-use crate::repo::object::object::Chunk;
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::repo::object::chunk_store::ChunkReader;
+use crate::repo::object::chunking::{Chunker, Chunking, IncrementalChunker};
+use crate::repo::object::object::{chunk_hash, Chunk, ObjectHandle};
 use crate::repo::Key;
 use crate::store::DataStore;
 
+use super::btree::PageStore;
+use super::compression::Dictionaries;
 use super::encryption::EncryptionKey;
 use super::header::Header;
 use super::lock::Lock;
-use super::metadata::RepositoryMetadata;
+use super::metadata::{EncodingStats, RepositoryMetadata, UnusedBlock};
+use super::pool::EncodingPool;
+use super::reconfigure::Reconfiguration;
+use super::savepoint::SavedState;
 
 /// The state associated with an `ObjectRepository`.
 #[derive(Debug)]
@@ -43,8 +56,274 @@ pub struct RepositoryState<K: Key, S: DataStore> {
     /// The master encryption key for the repository.
     pub master_key: EncryptionKey,
 
+    /// The name of the key slot which was used to unlock the repository.
+    ///
+    /// This is `None` if encryption is disabled.
+    pub key_slot: Option<String>,
+
     /// The lock on the repository.
-    pub lock: Lock,
+    ///
+    /// This is `None` if the repository was opened as a snapshot without taking a lock.
+    pub lock: Option<Lock>,
+
+    /// Counters for time spent encoding and decoding chunks.
+    pub counters: EncodingCounters,
+
+    /// The worker threads which encode and decode chunks.
+    pub pool: EncodingPool,
+
+    /// The number of threads used to encode and decode chunks.
+    ///
+    /// This is not stored in the repository, so it is `1` each time the repository is opened.
+    pub encoding_threads: usize,
+
+    /// Blocks which are no longer used as of the last commit and are waiting to be removed.
+    pub unused_blocks: Vec<UnusedBlock>,
+
+    /// The states of the repository at each savepoint since the last commit, oldest first.
+    pub savepoints: Vec<SavedState<K>>,
+
+    /// The compression dictionaries which have been read or trained, indexed by their ID.
+    pub dictionaries: Dictionaries,
+
+    /// The reconfiguration which is in progress, if it has been loaded.
+    pub reconfiguration: Option<Reconfiguration<K>>,
+}
+
+impl<K: Key, S: DataStore> RepositoryState<K, S> {
+    /// Return the pages of the repository's header.
+    pub fn pages(&self) -> HeaderPages<S> {
+        HeaderPages::new(&self.store, &self.metadata, &self.master_key)
+    }
+
+    /// Return whether there is an object with the given `key`.
+    pub fn contains<Q>(&self, key: &Q) -> crate::Result<bool>
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + Serialize + ?Sized,
+    {
+        self.header.contains_object(&self.pages(), key)
+    }
+
+    /// Return the handle of the object with the given `key` or `None` if there is none.
+    pub fn object<Q>(&self, key: &Q) -> crate::Result<Option<Cow<ObjectHandle>>>
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + Serialize + ?Sized,
+    {
+        self.header.object(&self.pages(), key)
+    }
+
+    /// Return an iterator over the keys of all objects, which reads the header lazily.
+    pub fn keys(&self) -> impl Iterator<Item = crate::Result<K>> + '_ {
+        self.header.object_keys(self.pages())
+    }
+
+    /// Modify the handle of the object with the given `key` using `f`.
+    ///
+    /// # Errors
+    /// - `Error::NotFound`: There is no object with the given `key`.
+    pub fn update_object(
+        &mut self,
+        key: &K,
+        f: impl FnOnce(&mut ObjectHandle),
+    ) -> crate::Result<()> {
+        let pages = HeaderPages::new(&self.store, &self.metadata, &self.master_key);
+        self.header.update_object(&pages, key, f)
+    }
+
+    /// Return the keys of the objects in the repository which are corrupt.
+    ///
+    /// See `ObjectRepository::verify`.
+    pub fn verify(&self) -> crate::Result<HashSet<K>> {
+        let mut corrupt_chunks = HashSet::new();
+
+        // Get the set of hashes of chunks which are corrupt.
+        self.header.for_each_chunk(&self.pages(), |chunk| {
+            match self.read_chunk(chunk, None) {
+                Ok(data) => {
+                    if data.len() != chunk.size || chunk_hash(&data) != chunk.hash {
+                        corrupt_chunks.insert(chunk.hash);
+                    }
+                }
+                Err(crate::Error::InvalidData) => {
+                    // Ciphertext verification failed. No need to check the hash.
+                    corrupt_chunks.insert(chunk.hash);
+                }
+                Err(error) => return Err(error),
+            };
+            Ok(())
+        })?;
+
+        // If there are no corrupt chunks, there are no corrupt objects.
+        if corrupt_chunks.is_empty() {
+            return Ok(HashSet::new());
+        }
+
+        let mut corrupt_objects = HashSet::new();
+
+        for key in self.keys() {
+            let key = key?;
+            let object = match self.object(&key)? {
+                Some(object) => object,
+                None => continue,
+            };
+            for chunk in &object.chunks {
+                // If any one of the object's chunks is corrupt, the object is corrupt.
+                if corrupt_chunks.contains(&chunk.hash) {
+                    corrupt_objects.insert(key);
+                    break;
+                }
+            }
+        }
+
+        Ok(corrupt_objects)
+    }
+}
+
+/// The blocks in a data store which store the header of a repository.
+///
+/// Header blocks are compressed and encrypted like chunks, but they are not counted in the
+/// repository's `EncodingCounters`.
+pub struct HeaderPages<'a, S: DataStore> {
+    store: &'a Mutex<S>,
+    metadata: &'a RepositoryMetadata,
+    master_key: &'a EncryptionKey,
+}
+
+impl<'a, S: DataStore> HeaderPages<'a, S> {
+    /// Create a new instance which reads and writes header blocks in `store`.
+    pub fn new(
+        store: &'a Mutex<S>,
+        metadata: &'a RepositoryMetadata,
+        master_key: &'a EncryptionKey,
+    ) -> Self {
+        Self {
+            store,
+            metadata,
+            master_key,
+        }
+    }
+
+    /// Compress and encrypt a page of the header.
+    fn encode_page(&self, data: &[u8]) -> crate::Result<Vec<u8>> {
+        let compressed_page = self.metadata.compression.compress(data)?;
+        self.metadata
+            .encryption
+            .encrypt(&compressed_page, self.master_key)
+    }
+}
+
+impl<'a, S: DataStore> PageStore for HeaderPages<'a, S> {
+    fn read_page(&self, id: Uuid) -> crate::Result<Vec<u8>> {
+        let encrypted_page = self
+            .store
+            .lock()
+            .unwrap()
+            .read_block(id)
+            .map_err(anyhow::Error::from)?
+            .ok_or(crate::Error::Corrupt)?;
+        let compressed_page = self
+            .metadata
+            .encryption
+            .decrypt(&encrypted_page, self.master_key)
+            .map_err(|_| crate::Error::Corrupt)?;
+        self.metadata
+            .compression
+            .decompress(&compressed_page)
+            .map_err(|_| crate::Error::Corrupt)
+    }
+
+    fn write_page(&self, id: Uuid, data: &[u8]) -> crate::Result<()> {
+        let encrypted_page = self.encode_page(data)?;
+        self.store
+            .lock()
+            .unwrap()
+            .write_block(id, &encrypted_page)
+            .map_err(anyhow::Error::from)?;
+        Ok(())
+    }
+
+    fn write_pages(&self, pages: &[(Uuid, Vec<u8>)]) -> crate::Result<()> {
+        let encrypted_pages = pages
+            .iter()
+            .map(|(id, data)| Ok((*id, self.encode_page(data)?)))
+            .collect::<crate::Result<Vec<_>>>()?;
+        let blocks = encrypted_pages
+            .iter()
+            .map(|(id, data)| (*id, data.as_slice()))
+            .collect::<Vec<_>>();
+        self.store
+            .lock()
+            .unwrap()
+            .write_blocks(&blocks)
+            .map_err(anyhow::Error::from)?;
+        Ok(())
+    }
+}
+
+/// Atomic counters which track the cost of encoding and decoding chunks.
+///
+/// Times are stored in nanoseconds so that they can be updated without taking a lock.
+#[derive(Debug, Default)]
+pub struct EncodingCounters {
+    pub compression_nanos: AtomicU64,
+    pub decompression_nanos: AtomicU64,
+    pub encryption_nanos: AtomicU64,
+    pub decryption_nanos: AtomicU64,
+    pub bytes_written: AtomicU64,
+    pub bytes_read: AtomicU64,
+}
+
+impl EncodingCounters {
+    /// Return a snapshot of the current values of the counters.
+    pub fn snapshot(&self) -> EncodingStats {
+        let duration = |counter: &AtomicU64| Duration::from_nanos(counter.load(Ordering::Relaxed));
+        EncodingStats {
+            compression_time: duration(&self.compression_nanos),
+            decompression_time: duration(&self.decompression_nanos),
+            encryption_time: duration(&self.encryption_nanos),
+            decryption_time: duration(&self.decryption_nanos),
+            bytes_written: self.bytes_written.load(Ordering::Relaxed),
+            bytes_read: self.bytes_read.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// The `EncodingCounters` which the cost of an operation is added to.
+///
+/// Every operation is counted against the repository. Operations performed through an object
+/// handle are also counted against that handle, so that the cost of a single read or write can be
+/// measured while other threads use the same repository.
+#[derive(Debug, Clone, Copy)]
+pub struct CounterSet<'a> {
+    /// The counters for the whole repository.
+    pub repository: &'a EncodingCounters,
+
+    /// The counters for the object handle which performed the operation, if there is one.
+    pub object: Option<&'a EncodingCounters>,
+}
+
+impl<'a> CounterSet<'a> {
+    /// Call `f` and add the time it took to the counter returned by `counter`.
+    pub fn time<T>(
+        &self,
+        counter: impl Fn(&EncodingCounters) -> &AtomicU64,
+        f: impl FnOnce() -> T,
+    ) -> T {
+        let start = Instant::now();
+        let result = f();
+        self.add(counter, start.elapsed().as_nanos() as u64);
+        result
+    }
+
+    /// Add `value` to the counter returned by `counter`.
+    pub fn add(&self, counter: impl Fn(&EncodingCounters) -> &AtomicU64, value: u64) {
+        counter(self.repository).fetch_add(value, Ordering::Relaxed);
+        if let Some(object) = self.object {
+            counter(object).fetch_add(value, Ordering::Relaxed);
+        }
+    }
 }
 
 /// The location of a chunk in a stream of bytes.
@@ -76,7 +355,12 @@ impl ChunkLocation {
 /// The state associated with an `Object`.
 pub struct ObjectState {
     /// An object responsible for buffering and chunking data which has been written.
-    pub chunker: IncrementalChunker<ZPAQ>,
+    pub chunker: IncrementalChunker<Chunker>,
+
+    /// The data of chunks which have been produced by the chunker but not yet written.
+    ///
+    /// Chunks are written in batches so that they can be encoded in parallel.
+    pub pending_chunks: Vec<Vec<u8>>,
 
     /// The list of chunks which have been written since `flush` was last called.
     pub new_chunks: Vec<Chunk>,
@@ -96,20 +380,37 @@ pub struct ObjectState {
 
     /// The contents of the chunk which was most recently read from.
     pub read_buffer: Vec<u8>,
+
+    /// Chunks which were decoded ahead of the chunk which was most recently read from.
+    pub read_ahead: Vec<(Chunk, Vec<u8>)>,
+
+    /// The cost of the chunks encoded and decoded through this object handle.
+    pub counters: EncodingCounters,
 }
 
 impl ObjectState {
-    /// Create a new empty state for a repository with a given chunk size.
-    pub fn new(chunker_bits: u32) -> Self {
+    /// Create a new empty state for a repository which uses the given `chunking` method.
+    pub fn new(chunking: Chunking) -> Self {
         Self {
-            chunker: IncrementalChunker::new(ZPAQ::new(chunker_bits as usize)),
+            chunker: IncrementalChunker::new(chunking.chunker()),
+            pending_chunks: Vec::new(),
             new_chunks: Vec::new(),
             start_location: None,
             position: 0,
             buffered_chunk: None,
             read_buffer: Vec::new(),
+            read_ahead: Vec::new(),
+            counters: EncodingCounters::default(),
         }
     }
+
+    /// Return whether all the data written to the object has been flushed.
+    ///
+    /// The chunker may be empty before the object is flushed if the data written so far ended on
+    /// a chunk boundary.
+    pub fn is_flushed(&self) -> bool {
+        self.chunker.is_empty() && self.pending_chunks.is_empty() && self.new_chunks.is_empty()
+    }
 }
 
 impl Debug for ObjectState {
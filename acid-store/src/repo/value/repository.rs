@@ -18,6 +18,7 @@ use std::borrow::Borrow;
 use std::collections::HashSet;
 use std::fmt::Debug;
 use std::hash::Hash;
+use std::time::Duration;
 
 use serde::de::DeserializeOwned;
 use serde::Serialize;
@@ -28,8 +29,9 @@ use lazy_static::lazy_static;
 use crate::repo::key_id::{KeyId, KeyTable};
 use crate::repo::version_id::{check_version, write_version};
 use crate::repo::{
-    Key, LockStrategy, ObjectRepository, OpenRepo, RepositoryConfig, RepositoryInfo,
-    RepositoryStats,
+    EncodingStats, GcStats, Key, KeySlotInfo, LockStrategy, ObjectRepository, OpenRepo,
+    ReconfigureProgress, RepositoryConfig, RepositoryInfo, RepositoryStats, Savepoint, SlotKey,
+    RAW_KEY_SIZE,
 };
 use crate::store::DataStore;
 
@@ -54,6 +56,9 @@ lazy_static! {
 pub struct ValueRepository<K: Key, S: DataStore> {
     repository: ObjectRepository<ValueKey, S>,
     key_table: KeyTable<K>,
+
+    /// The key table as of each savepoint, oldest first.
+    saved_key_tables: Vec<(Savepoint, KeyTable<K>)>,
 }
 
 impl<K: Key, S: DataStore> OpenRepo<S> for ValueRepository<K, S> {
@@ -62,23 +67,32 @@ impl<K: Key, S: DataStore> OpenRepo<S> for ValueRepository<K, S> {
         Self: Sized,
     {
         let repository = ObjectRepository::open_repo(store, strategy, password)?;
+        Self::from_repository(repository)
+    }
 
-        // Read the repository version to see if this is a compatible repository.
-        let object = repository
-            .get(&ValueKey::RepositoryVersion)
-            .ok_or(crate::Error::NotFound)?;
-        check_version(object, *VERSION_ID)?;
-
-        // Read and deserialize the key table.
-        let mut object = repository
-            .get(&ValueKey::KeyTable)
-            .ok_or(crate::Error::Corrupt)?;
-        let key_table = object.deserialize()?;
+    fn open_repo_with_key(
+        store: S,
+        strategy: LockStrategy,
+        key: &[u8; RAW_KEY_SIZE],
+    ) -> crate::Result<Self>
+    where
+        Self: Sized,
+    {
+        let repository = ObjectRepository::open_repo_with_key(store, strategy, key)?;
+        Self::from_repository(repository)
+    }
 
-        Ok(Self {
-            repository,
-            key_table,
-        })
+    fn open_repo_with_slot(
+        store: S,
+        strategy: LockStrategy,
+        slot: &str,
+        key: SlotKey,
+    ) -> crate::Result<Self>
+    where
+        Self: Sized,
+    {
+        let repository = ObjectRepository::open_repo_with_slot(store, strategy, slot, key)?;
+        Self::from_repository(repository)
     }
 
     fn new_repo(store: S, config: RepositoryConfig, password: Option<&[u8]>) -> crate::Result<Self>
@@ -102,6 +116,7 @@ impl<K: Key, S: DataStore> OpenRepo<S> for ValueRepository<K, S> {
         Ok(Self {
             repository,
             key_table,
+            saved_key_tables: Vec::new(),
         })
     }
 
@@ -123,6 +138,27 @@ impl<K: Key, S: DataStore> OpenRepo<S> for ValueRepository<K, S> {
 }
 
 impl<K: Key, S: DataStore> ValueRepository<K, S> {
+    /// Open the repository stored in the given `repository`, which has already been unlocked.
+    fn from_repository(repository: ObjectRepository<ValueKey, S>) -> crate::Result<Self> {
+        // Read the repository version to see if this is a compatible repository.
+        let object = repository
+            .try_get(&ValueKey::RepositoryVersion)?
+            .ok_or(crate::Error::NotFound)?;
+        check_version(object, *VERSION_ID)?;
+
+        // Read and deserialize the key table.
+        let mut object = repository
+            .try_get(&ValueKey::KeyTable)?
+            .ok_or(crate::Error::Corrupt)?;
+        let key_table = object.deserialize()?;
+
+        Ok(Self {
+            repository,
+            key_table,
+            saved_key_tables: Vec::new(),
+        })
+    }
+
     /// Return whether the given `key` exists in this repository.
     pub fn contains<Q>(&self, key: &Q) -> bool
     where
@@ -184,7 +220,7 @@ impl<K: Key, S: DataStore> ValueRepository<K, S> {
         let key_id = self.key_table.get(key).ok_or(crate::Error::NotFound)?;
         let mut object = self
             .repository
-            .get(&ValueKey::Data(*key_id))
+            .try_get(&ValueKey::Data(*key_id))?
             .ok_or(crate::Error::NotFound)?;
 
         object.deserialize()
@@ -214,17 +250,68 @@ impl<K: Key, S: DataStore> ValueRepository<K, S> {
             .copy(&ValueKey::Data(source_key_id), ValueKey::Data(dest_key_id))
     }
 
+    /// Write the key table to its object in the repository.
+    fn write_key_table(&mut self) -> crate::Result<()> {
+        let mut object = self
+            .repository
+            .try_get_mut(&ValueKey::KeyTable)?
+            .expect("This repository has no key table.");
+        object.serialize(&self.key_table)
+    }
+
+    /// Read the key table from its object in the repository.
+    fn read_key_table(&self) -> crate::Result<KeyTable<K>> {
+        let mut object = self
+            .repository
+            .try_get(&ValueKey::KeyTable)?
+            .ok_or(crate::Error::Corrupt)?;
+        object.deserialize()
+    }
+
     /// Commit changes which have been made to the repository.
     ///
     /// See `ObjectRepository::commit` for details.
     pub fn commit(&mut self) -> crate::Result<()> {
-        let mut object = self
-            .repository
-            .get_mut(&ValueKey::KeyTable)
-            .expect("This repository has no key table.");
-        object.serialize(&self.key_table)?;
-        drop(object);
-        self.repository.commit()
+        self.write_key_table()?;
+        self.repository.commit()?;
+        self.saved_key_tables.clear();
+        Ok(())
+    }
+
+    /// Roll back all changes which have been made since the last commit.
+    ///
+    /// See `ObjectRepository::rollback` for details.
+    pub fn rollback(&mut self) -> crate::Result<()> {
+        self.repository.rollback()?;
+        self.key_table = self.read_key_table()?;
+        self.saved_key_tables.clear();
+        Ok(())
+    }
+
+    /// Create a savepoint which uncommitted changes can be rolled back to with `rollback_to`.
+    ///
+    /// See `ObjectRepository::savepoint` for details. Each savepoint also keeps a copy of the table
+    /// of keys in memory.
+    pub fn savepoint(&mut self) -> Savepoint {
+        let savepoint = self.repository.savepoint();
+        self.saved_key_tables
+            .push((savepoint, self.key_table.clone()));
+        savepoint
+    }
+
+    /// Roll back the changes which have been made since `savepoint` was created.
+    ///
+    /// See `ObjectRepository::rollback_to` for details.
+    pub fn rollback_to(&mut self, savepoint: Savepoint) -> crate::Result<()> {
+        self.repository.rollback_to(savepoint)?;
+        let index = self
+            .saved_key_tables
+            .iter()
+            .position(|(saved, _)| *saved == savepoint)
+            .ok_or(crate::Error::NotFound)?;
+        self.saved_key_tables.truncate(index + 1);
+        self.key_table = self.saved_key_tables[index].1.clone();
+        Ok(())
     }
 
     /// Verify the integrity of all the data in the repository.
@@ -239,7 +326,7 @@ impl<K: Key, S: DataStore> ValueRepository<K, S> {
         let corrupt_key_ids = self
             .repository
             .verify()?
-            .iter()
+            .into_iter()
             .filter_map(|value_key| match value_key {
                 ValueKey::Data(key) => Some(key),
                 _ => None,
@@ -250,7 +337,7 @@ impl<K: Key, S: DataStore> ValueRepository<K, S> {
             .key_table
             .iter()
             .filter_map(|(key, key_id)| {
-                if corrupt_key_ids.contains(&key_id) {
+                if corrupt_key_ids.contains(key_id) {
                     Some(key)
                 } else {
                     None
@@ -263,8 +350,43 @@ impl<K: Key, S: DataStore> ValueRepository<K, S> {
     ///
     /// See `ObjectRepository::change_password` for details.
     #[cfg(feature = "encryption")]
-    pub fn change_password(&mut self, new_password: &[u8]) {
-        self.repository.change_password(new_password);
+    pub fn change_password(&mut self, new_password: &[u8]) -> crate::Result<()> {
+        self.repository.change_password(new_password)
+    }
+
+    /// Add a new key slot named `name` which can be unlocked with `key`.
+    ///
+    /// See `ObjectRepository::add_key_slot` for details.
+    #[cfg(feature = "encryption")]
+    pub fn add_key_slot(&mut self, name: &str, key: SlotKey) -> crate::Result<()> {
+        self.repository.add_key_slot(name, key)
+    }
+
+    /// Remove the key slot named `name`.
+    ///
+    /// See `ObjectRepository::remove_key_slot` for details.
+    #[cfg(feature = "encryption")]
+    pub fn remove_key_slot(&mut self, name: &str) -> bool {
+        self.repository.remove_key_slot(name)
+    }
+
+    /// Return information about each of the key slots in this repository.
+    ///
+    /// See `ObjectRepository::key_slots` for details.
+    pub fn key_slots(&self) -> Vec<KeySlotInfo> {
+        self.repository.key_slots()
+    }
+
+    /// Train a dictionary for Zstandard compression using `samples`.
+    ///
+    /// See `ObjectRepository::train_dictionary` for details.
+    #[cfg(feature = "compression")]
+    pub fn train_dictionary<T: AsRef<[u8]>>(
+        &mut self,
+        samples: &[T],
+        max_size: usize,
+    ) -> crate::Result<()> {
+        self.repository.train_dictionary(samples, max_size)
     }
 
     /// Return information about the repository.
@@ -284,6 +406,62 @@ impl<K: Key, S: DataStore> ValueRepository<K, S> {
         self.repository.stats()
     }
 
+    /// Return statistics about the time spent encoding and decoding data.
+    ///
+    /// See `ObjectRepository::encoding_stats` for details.
+    pub fn encoding_stats(&self) -> EncodingStats {
+        self.repository.encoding_stats()
+    }
+
+    /// Return the number of threads used to compress and encrypt chunks.
+    ///
+    /// See `ObjectRepository::encoding_threads` for details.
+    pub fn encoding_threads(&self) -> usize {
+        self.repository.encoding_threads()
+    }
+
+    /// Set the number of `threads` used to compress and encrypt chunks.
+    ///
+    /// See `ObjectRepository::set_encoding_threads` for details.
+    pub fn set_encoding_threads(&mut self, threads: usize) -> crate::Result<()> {
+        self.repository.set_encoding_threads(threads)
+    }
+
+    /// Remove blocks which are no longer used by the repository from the data store.
+    ///
+    /// See `ObjectRepository::gc` for details.
+    pub fn gc(&mut self, budget: Option<Duration>) -> crate::Result<GcStats> {
+        self.repository.gc(budget)
+    }
+
+    /// Remove every block in the data store which is not used by the repository.
+    ///
+    /// See `ObjectRepository::gc_full` for details.
+    pub fn gc_full(&mut self) -> crate::Result<GcStats> {
+        self.repository.gc_full()
+    }
+
+    /// Return how much space would be reclaimed by calling `gc` without removing anything.
+    ///
+    /// See `ObjectRepository::gc_dry_run` for details.
+    pub fn gc_dry_run(&self) -> GcStats {
+        self.repository.gc_dry_run()
+    }
+
+    /// Rewrite the data in the repository using a new `config`.
+    ///
+    /// See `ObjectRepository::reconfigure` for details.
+    pub fn reconfigure(
+        &mut self,
+        config: RepositoryConfig,
+        key: Option<SlotKey>,
+        budget: Option<Duration>,
+    ) -> crate::Result<ReconfigureProgress> {
+        // Uncommitted changes are committed first, so the key table needs to be written.
+        self.write_key_table()?;
+        self.repository.reconfigure(config, key, budget)
+    }
+
     /// Consume this repository and return the wrapped `DataStore`.
     pub fn into_store(self) -> S {
         self.repository.into_store()
@@ -17,7 +17,9 @@
 //! An object store with support for content versioning.
 
 pub use self::repository::VersionRepository;
+pub use self::retention::{PruneReport, RetentionPolicy};
 pub use self::version::Version;
 
 mod repository;
+mod retention;
 mod version;
@@ -17,7 +17,7 @@
 use std::borrow::Borrow;
 use std::fmt::Debug;
 use std::hash::Hash;
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 
 use uuid::Uuid;
 
@@ -26,11 +26,13 @@ use lazy_static::lazy_static;
 use crate::repo::key_id::{KeyId, KeyTable};
 use crate::repo::version_id::{check_version, write_version};
 use crate::repo::{
-    Key, LockStrategy, Object, ObjectRepository, OpenRepo, ReadOnlyObject, RepositoryConfig,
-    RepositoryInfo, RepositoryStats,
+    EncodingStats, GcStats, Key, KeySlotInfo, LockStrategy, Object, ObjectRepository, OpenRepo,
+    ReadOnlyObject, ReconfigureProgress, RepositoryConfig, RepositoryInfo, RepositoryStats,
+    Savepoint, SlotKey, RAW_KEY_SIZE,
 };
 use crate::store::DataStore;
 
+use super::retention::{PruneReport, RetentionPolicy};
 use super::version::{Version, VersionKey};
 
 lazy_static! {
@@ -94,6 +96,9 @@ lazy_static! {
 pub struct VersionRepository<K: Key, S: DataStore> {
     repository: ObjectRepository<VersionKey, S>,
     key_table: KeyTable<K>,
+
+    /// The key table as of each savepoint, oldest first.
+    saved_key_tables: Vec<(Savepoint, KeyTable<K>)>,
 }
 
 impl<K: Key, S: DataStore> OpenRepo<S> for VersionRepository<K, S> {
@@ -102,23 +107,32 @@ impl<K: Key, S: DataStore> OpenRepo<S> for VersionRepository<K, S> {
         Self: Sized,
     {
         let repository = ObjectRepository::open_repo(store, strategy, password)?;
+        Self::from_repository(repository)
+    }
 
-        // Read the repository version to see if this is a compatible repository.
-        let object = repository
-            .get(&VersionKey::RepositoryVersion)
-            .ok_or(crate::Error::NotFound)?;
-        check_version(object, *VERSION_ID)?;
-
-        // Read and deserialize the key table.
-        let mut object = repository
-            .get(&VersionKey::KeyTable)
-            .ok_or(crate::Error::Corrupt)?;
-        let key_table = object.deserialize()?;
+    fn open_repo_with_key(
+        store: S,
+        strategy: LockStrategy,
+        key: &[u8; RAW_KEY_SIZE],
+    ) -> crate::Result<Self>
+    where
+        Self: Sized,
+    {
+        let repository = ObjectRepository::open_repo_with_key(store, strategy, key)?;
+        Self::from_repository(repository)
+    }
 
-        Ok(Self {
-            repository,
-            key_table,
-        })
+    fn open_repo_with_slot(
+        store: S,
+        strategy: LockStrategy,
+        slot: &str,
+        key: SlotKey,
+    ) -> crate::Result<Self>
+    where
+        Self: Sized,
+    {
+        let repository = ObjectRepository::open_repo_with_slot(store, strategy, slot, key)?;
+        Self::from_repository(repository)
     }
 
     fn new_repo(store: S, config: RepositoryConfig, password: Option<&[u8]>) -> crate::Result<Self>
@@ -142,6 +156,7 @@ impl<K: Key, S: DataStore> OpenRepo<S> for VersionRepository<K, S> {
         Ok(Self {
             repository,
             key_table,
+            saved_key_tables: Vec::new(),
         })
     }
 
@@ -163,6 +178,27 @@ impl<K: Key, S: DataStore> OpenRepo<S> for VersionRepository<K, S> {
 }
 
 impl<K: Key, S: DataStore> VersionRepository<K, S> {
+    /// Open the repository stored in the given `repository`, which has already been unlocked.
+    fn from_repository(repository: ObjectRepository<VersionKey, S>) -> crate::Result<Self> {
+        // Read the repository version to see if this is a compatible repository.
+        let object = repository
+            .try_get(&VersionKey::RepositoryVersion)?
+            .ok_or(crate::Error::NotFound)?;
+        check_version(object, *VERSION_ID)?;
+
+        // Read and deserialize the key table.
+        let mut object = repository
+            .try_get(&VersionKey::KeyTable)?
+            .ok_or(crate::Error::Corrupt)?;
+        let key_table = object.deserialize()?;
+
+        Ok(Self {
+            repository,
+            key_table,
+            saved_key_tables: Vec::new(),
+        })
+    }
+
     /// Return whether the given `key` exists in this repository.
     pub fn contains<Q>(&self, key: &Q) -> bool
     where
@@ -211,10 +247,11 @@ impl<K: Key, S: DataStore> VersionRepository<K, S> {
             self.remove_version(key, version.id)?;
         }
 
-        let key_id = self.key_table.remove(key).ok_or(crate::Error::NotFound)?;
+        let key_id = *self.key_table.get(key).ok_or(crate::Error::NotFound)?;
 
-        self.repository.remove(&VersionKey::Index(key_id));
-        self.repository.remove(&VersionKey::Object(key_id));
+        self.repository.try_remove(&VersionKey::Index(key_id))?;
+        self.repository.try_remove(&VersionKey::Object(key_id))?;
+        self.key_table.remove(key);
 
         Ok(())
     }
@@ -269,10 +306,10 @@ impl<K: Key, S: DataStore> VersionRepository<K, S> {
 
         let object = self
             .repository
-            .get(&VersionKey::Object(key_id))
+            .try_get(&VersionKey::Object(key_id))?
             .expect("There is no object associated with this key.");
-        let size = object.size();
-        let content_id = object.content_id();
+        let size = object.try_size()?;
+        let content_id = object.try_content_id()?;
         drop(object);
 
         let next_id = versions.iter().map(|version| version.id).max().unwrap_or(0) + 1;
@@ -307,7 +344,8 @@ impl<K: Key, S: DataStore> VersionRepository<K, S> {
         Q: Hash + Eq + ?Sized,
     {
         let key_id = *self.key_table.get(key).ok_or(crate::Error::NotFound)?;
-        self.repository.remove(&VersionKey::Version(key_id, id));
+        self.repository
+            .try_remove(&VersionKey::Version(key_id, id))?;
         let mut versions = self.list_versions(key)?;
         versions.retain(|version| version.id() != id);
         self.write_versions(key_id, versions.as_slice())?;
@@ -341,7 +379,7 @@ impl<K: Key, S: DataStore> VersionRepository<K, S> {
         let key_id = self.key_table.get(key).ok_or(crate::Error::NotFound)?;
         let mut object = self
             .repository
-            .get(&VersionKey::Index(*key_id))
+            .try_get(&VersionKey::Index(*key_id))?
             .ok_or(crate::Error::NotFound)?;
 
         object.deserialize()
@@ -365,11 +403,11 @@ impl<K: Key, S: DataStore> VersionRepository<K, S> {
         let current_key = VersionKey::Object(*key_id);
         let version_key = VersionKey::Version(*key_id, id);
 
-        if !self.repository.contains(&version_key) {
+        if !self.repository.try_contains(&version_key)? {
             return Err(crate::Error::NotFound);
         }
 
-        self.repository.remove(&current_key);
+        self.repository.try_remove(&current_key)?;
         self.repository.copy(&version_key, current_key)
     }
 
@@ -379,25 +417,111 @@ impl<K: Key, S: DataStore> VersionRepository<K, S> {
         object.serialize(&versions)
     }
 
+    /// Write the key table to its object in the repository.
+    fn write_key_table(&mut self) -> crate::Result<()> {
+        let mut object = self
+            .repository
+            .try_get_mut(&VersionKey::KeyTable)?
+            .expect("This repository has no key table.");
+        object.serialize(&self.key_table)
+    }
+
+    /// Read the key table from its object in the repository.
+    fn read_key_table(&self) -> crate::Result<KeyTable<K>> {
+        let mut object = self
+            .repository
+            .try_get(&VersionKey::KeyTable)?
+            .ok_or(crate::Error::Corrupt)?;
+        object.deserialize()
+    }
+
     /// Commit changes which have been made to the repository.
     ///
     /// See `ObjectRepository::commit` for details.
     pub fn commit(&mut self) -> crate::Result<()> {
-        let mut object = self
-            .repository
-            .get_mut(&VersionKey::KeyTable)
-            .expect("This repository has no key table.");
-        object.serialize(&self.key_table)?;
-        drop(object);
-        self.repository.commit()
+        self.write_key_table()?;
+        self.repository.commit()?;
+        self.saved_key_tables.clear();
+        Ok(())
+    }
+
+    /// Roll back all changes which have been made since the last commit.
+    ///
+    /// See `ObjectRepository::rollback` for details.
+    pub fn rollback(&mut self) -> crate::Result<()> {
+        self.repository.rollback()?;
+        self.key_table = self.read_key_table()?;
+        self.saved_key_tables.clear();
+        Ok(())
+    }
+
+    /// Create a savepoint which uncommitted changes can be rolled back to with `rollback_to`.
+    ///
+    /// See `ObjectRepository::savepoint` for details. Each savepoint also keeps a copy of the table
+    /// of keys in memory.
+    pub fn savepoint(&mut self) -> Savepoint {
+        let savepoint = self.repository.savepoint();
+        self.saved_key_tables
+            .push((savepoint, self.key_table.clone()));
+        savepoint
+    }
+
+    /// Roll back the changes which have been made since `savepoint` was created.
+    ///
+    /// See `ObjectRepository::rollback_to` for details.
+    pub fn rollback_to(&mut self, savepoint: Savepoint) -> crate::Result<()> {
+        self.repository.rollback_to(savepoint)?;
+        let index = self
+            .saved_key_tables
+            .iter()
+            .position(|(saved, _)| *saved == savepoint)
+            .ok_or(crate::Error::NotFound)?;
+        self.saved_key_tables.truncate(index + 1);
+        self.key_table = self.saved_key_tables[index].1.clone();
+        Ok(())
     }
 
     /// Change the password for this repository.
     ///
     /// See `ObjectRepository::change_password` for details.
     #[cfg(feature = "encryption")]
-    pub fn change_password(&mut self, new_password: &[u8]) {
-        self.repository.change_password(new_password);
+    pub fn change_password(&mut self, new_password: &[u8]) -> crate::Result<()> {
+        self.repository.change_password(new_password)
+    }
+
+    /// Add a new key slot named `name` which can be unlocked with `key`.
+    ///
+    /// See `ObjectRepository::add_key_slot` for details.
+    #[cfg(feature = "encryption")]
+    pub fn add_key_slot(&mut self, name: &str, key: SlotKey) -> crate::Result<()> {
+        self.repository.add_key_slot(name, key)
+    }
+
+    /// Remove the key slot named `name`.
+    ///
+    /// See `ObjectRepository::remove_key_slot` for details.
+    #[cfg(feature = "encryption")]
+    pub fn remove_key_slot(&mut self, name: &str) -> bool {
+        self.repository.remove_key_slot(name)
+    }
+
+    /// Return information about each of the key slots in this repository.
+    ///
+    /// See `ObjectRepository::key_slots` for details.
+    pub fn key_slots(&self) -> Vec<KeySlotInfo> {
+        self.repository.key_slots()
+    }
+
+    /// Train a dictionary for Zstandard compression using `samples`.
+    ///
+    /// See `ObjectRepository::train_dictionary` for details.
+    #[cfg(feature = "compression")]
+    pub fn train_dictionary<T: AsRef<[u8]>>(
+        &mut self,
+        samples: &[T],
+        max_size: usize,
+    ) -> crate::Result<()> {
+        self.repository.train_dictionary(samples, max_size)
     }
 
     /// Return information about the repository.
@@ -417,6 +541,113 @@ impl<K: Key, S: DataStore> VersionRepository<K, S> {
         self.repository.stats()
     }
 
+    /// Return statistics about the time spent encoding and decoding data.
+    ///
+    /// See `ObjectRepository::encoding_stats` for details.
+    pub fn encoding_stats(&self) -> EncodingStats {
+        self.repository.encoding_stats()
+    }
+
+    /// Return the number of threads used to compress and encrypt chunks.
+    ///
+    /// See `ObjectRepository::encoding_threads` for details.
+    pub fn encoding_threads(&self) -> usize {
+        self.repository.encoding_threads()
+    }
+
+    /// Set the number of `threads` used to compress and encrypt chunks.
+    ///
+    /// See `ObjectRepository::set_encoding_threads` for details.
+    pub fn set_encoding_threads(&mut self, threads: usize) -> crate::Result<()> {
+        self.repository.set_encoding_threads(threads)
+    }
+
+    /// Remove blocks which are no longer used by the repository from the data store.
+    ///
+    /// See `ObjectRepository::gc` for details.
+    pub fn gc(&mut self, budget: Option<Duration>) -> crate::Result<GcStats> {
+        self.repository.gc(budget)
+    }
+
+    /// Remove every block in the data store which is not used by the repository.
+    ///
+    /// See `ObjectRepository::gc_full` for details.
+    pub fn gc_full(&mut self) -> crate::Result<GcStats> {
+        self.repository.gc_full()
+    }
+
+    /// Remove the versions which are not kept by the given retention `policy`.
+    ///
+    /// This removes versions of every key in the repository according to `policy`, commits the
+    /// removal, and then garbage collects the blocks which are no longer used. This returns the
+    /// versions which were removed and how much space garbage collection reclaimed.
+    ///
+    /// Because this commits the repository, it can't be called while there are uncommitted
+    /// changes. They must be committed or rolled back first.
+    ///
+    /// # Errors
+    /// - `Error::Uncommitted`: There are uncommitted changes in the repository.
+    /// - `Error::InvalidData`: Ciphertext verification failed.
+    /// - `Error::Store`: An error occurred with the data store.
+    /// - `Error::Io`: An I/O error occurred.
+    pub fn prune(&mut self, policy: &RetentionPolicy) -> crate::Result<PruneReport<K>>
+    where
+        K: Ord,
+    {
+        if self.repository.has_changes() {
+            return Err(crate::Error::Uncommitted);
+        }
+
+        let now = SystemTime::now();
+        let keys = self.keys().cloned().collect::<Vec<_>>();
+        let mut removed = Vec::new();
+        let mut remaining = Vec::new();
+
+        for key in keys {
+            let versions = self.list_versions(&key)?;
+            let kept = policy.keep(&versions, now);
+            for version in versions {
+                if kept.contains(&version.id) {
+                    remaining.push((key.clone(), version));
+                } else {
+                    removed.push((key.clone(), version));
+                }
+            }
+        }
+
+        removed.extend(policy.limit_size(&mut remaining));
+
+        for (key, version) in &removed {
+            self.remove_version(key, version.id)?;
+        }
+
+        self.commit()?;
+        let gc_stats = self.gc(None)?;
+
+        Ok(PruneReport { removed, gc_stats })
+    }
+
+    /// Return how much space would be reclaimed by calling `gc` without removing anything.
+    ///
+    /// See `ObjectRepository::gc_dry_run` for details.
+    pub fn gc_dry_run(&self) -> GcStats {
+        self.repository.gc_dry_run()
+    }
+
+    /// Rewrite the data in the repository using a new `config`.
+    ///
+    /// See `ObjectRepository::reconfigure` for details.
+    pub fn reconfigure(
+        &mut self,
+        config: RepositoryConfig,
+        key: Option<SlotKey>,
+        budget: Option<Duration>,
+    ) -> crate::Result<ReconfigureProgress> {
+        // Uncommitted changes are committed first, so the key table needs to be written.
+        self.write_key_table()?;
+        self.repository.reconfigure(config, key, budget)
+    }
+
     /// Consume this repository and return the wrapped `DataStore`.
     pub fn into_store(self) -> S {
         self.repository.into_store()
@@ -0,0 +1,484 @@
+/*
+ * Copyright 2019-2020 Wren Powell
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::collections::HashSet;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::repo::GcStats;
+
+use super::version::Version;
+
+/// The number of seconds in a day.
+const SECONDS_PER_DAY: u64 = 60 * 60 * 24;
+
+/// A policy which determines which versions `VersionRepository::prune` removes.
+///
+/// The `keep_last`, `keep_daily`, `keep_weekly`, and `keep_monthly` rules are applied to the
+/// versions of each key separately, and a version is kept if any of them keeps it. If none of these
+/// rules are set, every version is kept. Days, weeks, and months are calendar days, weeks, and
+/// months in UTC, and weeks start on Monday. Of several versions created at the same time, the one
+/// with the greatest ID is considered the most recent.
+///
+/// The `max_size` limit is applied after these rules to the versions of all keys combined.
+///
+/// The default policy keeps every version.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct RetentionPolicy {
+    /// Keep the `n` most recent versions of each key.
+    pub keep_last: Option<usize>,
+
+    /// Keep the most recent version of each key from each of the last `n` days.
+    ///
+    /// The current day counts as one of these days.
+    pub keep_daily: Option<u32>,
+
+    /// Keep the most recent version of each key from each of the last `n` weeks.
+    ///
+    /// The current week counts as one of these weeks.
+    pub keep_weekly: Option<u32>,
+
+    /// Keep the most recent version of each key from each of the last `n` months.
+    ///
+    /// The current month counts as one of these months.
+    pub keep_monthly: Option<u32>,
+
+    /// The maximum combined size in bytes of all the versions in the repository.
+    ///
+    /// If the versions kept by the other rules are larger than this, the oldest versions are
+    /// removed until they fit, even if another rule would keep them. Versions created at the same
+    /// time are removed in order of their key and then their ID. This counts the size of each
+    /// version as returned by `Version::size`, which does not account for deduplication.
+    pub max_size: Option<u64>,
+}
+
+impl RetentionPolicy {
+    /// Return the IDs of the `versions` of a single key which are kept by this policy's rules.
+    ///
+    /// This does not apply `max_size`.
+    pub(super) fn keep(&self, versions: &[Version], now: SystemTime) -> HashSet<usize> {
+        if self.keep_last.is_none()
+            && self.keep_daily.is_none()
+            && self.keep_weekly.is_none()
+            && self.keep_monthly.is_none()
+        {
+            return versions.iter().map(|version| version.id).collect();
+        }
+
+        // Sort the versions from newest to oldest.
+        let mut versions = versions.iter().collect::<Vec<_>>();
+        versions.sort_by(|a, b| b.created.cmp(&a.created).then(b.id.cmp(&a.id)));
+
+        let mut kept = HashSet::new();
+
+        if let Some(count) = self.keep_last {
+            kept.extend(versions.iter().take(count).map(|version| version.id));
+        }
+
+        if let Some(count) = self.keep_daily {
+            kept.extend(keep_periodic(&versions, now, count, day));
+        }
+
+        if let Some(count) = self.keep_weekly {
+            kept.extend(keep_periodic(&versions, now, count, week));
+        }
+
+        if let Some(count) = self.keep_monthly {
+            kept.extend(keep_periodic(&versions, now, count, month));
+        }
+
+        kept
+    }
+
+    /// Remove the oldest of the `remaining` versions until they fit in `max_size`.
+    ///
+    /// This returns the removed versions and the keys they belonged to.
+    pub(super) fn limit_size<K: Ord>(
+        &self,
+        remaining: &mut Vec<(K, Version)>,
+    ) -> Vec<(K, Version)> {
+        let max_size = match self.max_size {
+            Some(max_size) => max_size,
+            None => return Vec::new(),
+        };
+
+        remaining.sort_by(|(a_key, a), (b_key, b)| {
+            a.created
+                .cmp(&b.created)
+                .then(a_key.cmp(b_key))
+                .then(a.id.cmp(&b.id))
+        });
+        let mut total_size = remaining
+            .iter()
+            .map(|(_, version)| version.size)
+            .sum::<u64>();
+        let mut oldest_kept = 0;
+        while total_size > max_size {
+            total_size -= remaining[oldest_kept].1.size;
+            oldest_kept += 1;
+        }
+        remaining.drain(..oldest_kept).collect()
+    }
+}
+
+/// Return the IDs of the newest version in each of the last `count` periods.
+///
+/// The `versions` must be sorted from newest to oldest, and `period` returns the index of the
+/// period a point in time falls in.
+fn keep_periodic(
+    versions: &[&Version],
+    now: SystemTime,
+    count: u32,
+    period: fn(SystemTime) -> u64,
+) -> Vec<usize> {
+    let current_period = period(now);
+    let mut seen_periods = HashSet::new();
+    versions
+        .iter()
+        .filter(|version| {
+            let version_period = period(version.created);
+            version_period + u64::from(count) > current_period
+                && seen_periods.insert(version_period)
+        })
+        .map(|version| version.id)
+        .collect()
+}
+
+/// Return the number of UTC days between the Unix epoch and `time`.
+fn day(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+        / SECONDS_PER_DAY
+}
+
+/// Return the number of weeks starting on Monday between the Unix epoch and `time`.
+fn week(time: SystemTime) -> u64 {
+    // The Unix epoch was a Thursday.
+    (day(time) + 3) / 7
+}
+
+/// Return the index of the calendar month in UTC which `time` falls in.
+fn month(time: SystemTime) -> u64 {
+    // This converts the day to a date using the algorithm from
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days, which counts years
+    // from March so that leap days come last.
+    let days = day(time) + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    (era * 400 + year_of_era) * 12 + month_from_march
+}
+
+/// A report of the changes made by `VersionRepository::prune`.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct PruneReport<K> {
+    pub(super) removed: Vec<(K, Version)>,
+    pub(super) gc_stats: GcStats,
+}
+
+impl<K> PruneReport<K> {
+    /// The versions which were removed and the keys they belonged to.
+    pub fn removed(&self) -> &[(K, Version)] {
+        &self.removed
+    }
+
+    /// Statistics about the space reclaimed by garbage collection after removing versions.
+    pub fn gc_stats(&self) -> GcStats {
+        self.gc_stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::repo::ContentId;
+
+    use super::*;
+
+    /// The start of 2019-12-29, a Sunday, in seconds since the Unix epoch.
+    const DEC_29_2019: u64 = 1_577_577_600;
+
+    /// The start of 2020-01-01, a Wednesday.
+    const JAN_1_2020: u64 = 1_577_836_800;
+
+    /// The start of 2020-01-06, a Monday.
+    const JAN_6_2020: u64 = 1_578_268_800;
+
+    /// The start of 2020-01-27, a Monday.
+    const JAN_27_2020: u64 = 1_580_083_200;
+
+    /// The start of 2020-02-01, a Saturday.
+    const FEB_1_2020: u64 = 1_580_515_200;
+
+    /// The start of 2020-03-01, the day after a leap day.
+    const MAR_1_2020: u64 = 1_583_020_800;
+
+    const HOUR: u64 = 60 * 60;
+
+    /// Return the time `seconds` after the Unix epoch.
+    fn at(seconds: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(seconds)
+    }
+
+    /// Return a version with the given `id` which was created `seconds` after the Unix epoch.
+    fn version(id: usize, seconds: u64, size: u64) -> Version {
+        Version {
+            id,
+            created: at(seconds),
+            size,
+            content_id: ContentId([0u8; 32]),
+        }
+    }
+
+    /// Return the sorted IDs of the `versions` which `policy` keeps at `now`.
+    fn kept(policy: RetentionPolicy, versions: &[Version], now: u64) -> Vec<usize> {
+        let mut ids = policy
+            .keep(versions, at(now))
+            .into_iter()
+            .collect::<Vec<_>>();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn default_policy_keeps_every_version() {
+        let versions = vec![version(1, DEC_29_2019, 1), version(2, JAN_1_2020, 1)];
+        assert_eq!(
+            kept(RetentionPolicy::default(), &versions, JAN_6_2020),
+            [1, 2]
+        );
+    }
+
+    #[test]
+    fn keep_last_breaks_ties_by_id() {
+        let versions = vec![
+            version(2, JAN_1_2020, 1),
+            version(3, JAN_1_2020, 1),
+            version(1, JAN_1_2020, 1),
+        ];
+        let policy = RetentionPolicy {
+            keep_last: Some(2),
+            ..RetentionPolicy::default()
+        };
+        assert_eq!(kept(policy, &versions, JAN_1_2020), [2, 3]);
+    }
+
+    #[test]
+    fn keep_daily_breaks_ties_by_id() {
+        let versions = vec![
+            version(1, JAN_1_2020 + HOUR, 1),
+            version(3, JAN_1_2020 + 2 * HOUR, 1),
+            version(2, JAN_1_2020 + 2 * HOUR, 1),
+        ];
+        let policy = RetentionPolicy {
+            keep_daily: Some(1),
+            ..RetentionPolicy::default()
+        };
+        assert_eq!(kept(policy, &versions, JAN_1_2020 + 3 * HOUR), [3]);
+    }
+
+    #[test]
+    fn days_start_at_utc_midnight() {
+        let versions = vec![
+            version(1, JAN_1_2020 - 1, 1),
+            version(2, JAN_1_2020, 1),
+            version(3, JAN_1_2020 + 12 * HOUR, 1),
+        ];
+        let now = JAN_1_2020 + 18 * HOUR;
+
+        let one_day = RetentionPolicy {
+            keep_daily: Some(1),
+            ..RetentionPolicy::default()
+        };
+        assert_eq!(kept(one_day, &versions, now), [3]);
+
+        let two_days = RetentionPolicy {
+            keep_daily: Some(2),
+            ..RetentionPolicy::default()
+        };
+        assert_eq!(kept(two_days, &versions, now), [1, 3]);
+    }
+
+    #[test]
+    fn days_before_the_window_are_not_kept() {
+        let versions = vec![
+            version(1, DEC_29_2019 + 23 * HOUR, 1),
+            version(2, JAN_1_2020 - HOUR, 1),
+            version(3, JAN_1_2020 + HOUR, 1),
+        ];
+        let policy = RetentionPolicy {
+            keep_daily: Some(3),
+            ..RetentionPolicy::default()
+        };
+        assert_eq!(kept(policy, &versions, JAN_1_2020 + 2 * HOUR), [2, 3]);
+    }
+
+    #[test]
+    fn weeks_start_on_monday() {
+        let versions = vec![
+            version(1, JAN_6_2020 - HOUR, 1),
+            version(2, JAN_6_2020 + HOUR, 1),
+            version(3, JAN_6_2020 + 2 * HOUR, 1),
+        ];
+        let now = JAN_6_2020 + 24 * HOUR;
+
+        let one_week = RetentionPolicy {
+            keep_weekly: Some(1),
+            ..RetentionPolicy::default()
+        };
+        assert_eq!(kept(one_week, &versions, now), [3]);
+
+        let two_weeks = RetentionPolicy {
+            keep_weekly: Some(2),
+            ..RetentionPolicy::default()
+        };
+        assert_eq!(kept(two_weeks, &versions, now), [1, 3]);
+    }
+
+    #[test]
+    fn weeks_span_month_boundaries() {
+        // Tuesday, January 28 and Saturday, February 1 are in the same week.
+        let versions = vec![
+            version(1, JAN_27_2020 + 24 * HOUR, 1),
+            version(2, FEB_1_2020, 1),
+        ];
+        let now = FEB_1_2020 + HOUR;
+
+        let weekly = RetentionPolicy {
+            keep_weekly: Some(1),
+            ..RetentionPolicy::default()
+        };
+        assert_eq!(kept(weekly, &versions, now), [2]);
+
+        let monthly = RetentionPolicy {
+            keep_monthly: Some(2),
+            ..RetentionPolicy::default()
+        };
+        assert_eq!(kept(monthly, &versions, now), [1, 2]);
+    }
+
+    #[test]
+    fn months_span_year_boundaries() {
+        let versions = vec![
+            version(1, JAN_1_2020 - HOUR, 1),
+            version(2, JAN_1_2020 + HOUR, 1),
+        ];
+        let policy = RetentionPolicy {
+            keep_monthly: Some(1),
+            ..RetentionPolicy::default()
+        };
+        assert_eq!(kept(policy, &versions, JAN_1_2020 + 2 * HOUR), [2]);
+    }
+
+    #[test]
+    fn months_have_different_lengths() {
+        let versions = vec![
+            version(1, FEB_1_2020 - 1, 1),
+            version(2, FEB_1_2020, 1),
+            version(3, MAR_1_2020 - 1, 1),
+            version(4, MAR_1_2020, 1),
+        ];
+        let now = MAR_1_2020 + 24 * HOUR;
+
+        let one_month = RetentionPolicy {
+            keep_monthly: Some(1),
+            ..RetentionPolicy::default()
+        };
+        assert_eq!(kept(one_month, &versions, now), [4]);
+
+        let three_months = RetentionPolicy {
+            keep_monthly: Some(3),
+            ..RetentionPolicy::default()
+        };
+        assert_eq!(kept(three_months, &versions, now), [1, 3, 4]);
+    }
+
+    #[test]
+    fn rules_are_combined() {
+        let versions = vec![
+            version(1, DEC_29_2019, 1),
+            version(2, JAN_1_2020, 1),
+            version(3, JAN_1_2020 + HOUR, 1),
+            version(4, JAN_1_2020 + 2 * HOUR, 1),
+        ];
+        let policy = RetentionPolicy {
+            keep_last: Some(2),
+            keep_monthly: Some(2),
+            ..RetentionPolicy::default()
+        };
+        assert_eq!(kept(policy, &versions, JAN_1_2020 + 3 * HOUR), [1, 3, 4]);
+    }
+
+    #[test]
+    fn max_size_removes_oldest_versions() {
+        let mut remaining = vec![
+            ("a", version(2, JAN_1_2020 + HOUR, 10)),
+            ("b", version(1, JAN_1_2020, 10)),
+            ("a", version(1, DEC_29_2019, 10)),
+        ];
+        let policy = RetentionPolicy {
+            max_size: Some(15),
+            ..RetentionPolicy::default()
+        };
+
+        let removed = policy.limit_size(&mut remaining);
+
+        assert_eq!(
+            removed,
+            vec![
+                ("a", version(1, DEC_29_2019, 10)),
+                ("b", version(1, JAN_1_2020, 10)),
+            ]
+        );
+        assert_eq!(remaining, vec![("a", version(2, JAN_1_2020 + HOUR, 10))]);
+    }
+
+    #[test]
+    fn max_size_breaks_ties_by_key_and_id() {
+        let mut remaining = vec![
+            ("b", version(1, JAN_1_2020, 10)),
+            ("a", version(2, JAN_1_2020, 10)),
+            ("a", version(1, JAN_1_2020, 10)),
+        ];
+        let policy = RetentionPolicy {
+            max_size: Some(10),
+            ..RetentionPolicy::default()
+        };
+
+        let removed = policy.limit_size(&mut remaining);
+
+        assert_eq!(
+            removed,
+            vec![
+                ("a", version(1, JAN_1_2020, 10)),
+                ("a", version(2, JAN_1_2020, 10)),
+            ]
+        );
+        assert_eq!(remaining, vec![("b", version(1, JAN_1_2020, 10))]);
+    }
+
+    #[test]
+    fn no_max_size_removes_nothing() {
+        let mut remaining = vec![("a", version(1, JAN_1_2020, u64::MAX))];
+        let removed = RetentionPolicy::default().limit_size(&mut remaining);
+        assert!(removed.is_empty());
+        assert_eq!(remaining.len(), 1);
+    }
+}
@@ -0,0 +1,373 @@
+/*
+ * Copyright 2019-2020 Wren Powell
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::fs::{create_dir_all, read, read_dir, remove_file, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use lru::LruCache;
+use uuid::Uuid;
+
+use super::common::DataStore;
+
+/// Where a `CachedStore` keeps its cache.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CacheLocation {
+    /// Keep cached blocks in memory.
+    Memory,
+
+    /// Keep cached blocks as files in a directory in the local file system.
+    ///
+    /// Any cached blocks already in the directory are removed when the `CachedStore` is created,
+    /// so the directory should not be shared.
+    Directory(PathBuf),
+}
+
+/// The configuration for a `CachedStore`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CacheConfig {
+    /// Where to keep cached blocks.
+    pub location: CacheLocation,
+
+    /// The maximum combined size of the cached blocks in bytes.
+    ///
+    /// Once the cache is full, the least recently used blocks are evicted. Blocks which are larger
+    /// than this are never cached.
+    pub max_size: u64,
+}
+
+impl CacheConfig {
+    /// Return a config for a cache in memory which holds up to `max_size` bytes.
+    pub fn memory(max_size: u64) -> Self {
+        CacheConfig {
+            location: CacheLocation::Memory,
+            max_size,
+        }
+    }
+
+    /// Return a config for a cache in the directory at `path` which holds up to `max_size` bytes.
+    pub fn directory(path: PathBuf, max_size: u64) -> Self {
+        CacheConfig {
+            location: CacheLocation::Directory(path),
+            max_size,
+        }
+    }
+}
+
+/// Statistics about how often blocks were read from the cache of a `CachedStore`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct CacheStats {
+    hits: u64,
+    misses: u64,
+    size: u64,
+}
+
+impl CacheStats {
+    /// The number of blocks which were read from the cache.
+    pub fn hits(&self) -> u64 {
+        self.hits
+    }
+
+    /// The number of blocks which were read from the underlying data store.
+    pub fn misses(&self) -> u64 {
+        self.misses
+    }
+
+    /// The combined size of the blocks which are currently cached.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+/// The storage for cached blocks.
+#[derive(Debug)]
+enum CacheStorage {
+    /// Blocks are stored in memory.
+    Memory(LruCache<Uuid, Vec<u8>>),
+
+    /// Blocks are stored as files in a directory, and their sizes are stored in memory.
+    Directory {
+        path: PathBuf,
+        sizes: LruCache<Uuid, u64>,
+    },
+}
+
+impl CacheStorage {
+    /// Return the path of the file which stores the block with the given `id`.
+    fn block_path(path: &Path, id: Uuid) -> PathBuf {
+        let mut buffer = Uuid::encode_buffer();
+        let hex = id.to_simple().encode_lower(&mut buffer);
+        path.join(hex)
+    }
+
+    fn get(&mut self, id: Uuid) -> Option<Vec<u8>> {
+        match self {
+            CacheStorage::Memory(blocks) => blocks.get(&id).cloned(),
+            CacheStorage::Directory { path, sizes } => {
+                sizes.get(&id)?;
+                read(Self::block_path(path, id)).ok()
+            }
+        }
+    }
+
+    /// Insert a block into the cache and return whether it was stored.
+    fn insert(&mut self, id: Uuid, data: &[u8]) -> bool {
+        match self {
+            CacheStorage::Memory(blocks) => {
+                blocks.put(id, data.to_vec());
+                true
+            }
+            CacheStorage::Directory { path, sizes } => {
+                let stored = File::create(Self::block_path(path, id))
+                    .and_then(|mut file| file.write_all(data))
+                    .is_ok();
+                if stored {
+                    sizes.put(id, data.len() as u64);
+                }
+                stored
+            }
+        }
+    }
+
+    /// Remove a block from the cache and return its size if it was cached.
+    fn remove(&mut self, id: Uuid) -> Option<u64> {
+        match self {
+            CacheStorage::Memory(blocks) => blocks.pop(&id).map(|data| data.len() as u64),
+            CacheStorage::Directory { path, sizes } => {
+                let size = sizes.pop(&id)?;
+                remove_file(Self::block_path(path, id)).ok();
+                Some(size)
+            }
+        }
+    }
+
+    /// Remove the least recently used block from the cache and return its size.
+    fn remove_lru(&mut self) -> Option<u64> {
+        let id = match self {
+            CacheStorage::Memory(blocks) => *blocks.peek_lru()?.0,
+            CacheStorage::Directory { sizes, .. } => *sizes.peek_lru()?.0,
+        };
+        self.remove(id)
+    }
+}
+
+/// A `DataStore` which caches blocks from another data store.
+///
+/// This is meant to speed up repeated reads from data stores which are slow to read from, like
+/// those which store data remotely. Blocks are cached when they are read or written, up to a
+/// maximum size, and the least recently used blocks are evicted once the cache is full. The cache
+/// can be kept either in memory or in a directory in the local file system.
+///
+/// Writes are write-through, so blocks are always written to the underlying data store before
+/// this store's methods return. Removing a block also removes it from the cache.
+///
+/// Failing to read from or write to the cache is not an error; the block is read from the
+/// underlying data store instead. The underlying data store must not be modified except through
+/// this store while it is in use, or the cache may return stale blocks.
+#[derive(Debug)]
+pub struct CachedStore<S: DataStore> {
+    /// The underlying data store.
+    store: S,
+
+    /// The cached blocks.
+    cache: CacheStorage,
+
+    /// The maximum combined size of the cached blocks.
+    max_size: u64,
+
+    /// Statistics about the cache.
+    stats: CacheStats,
+}
+
+impl<S: DataStore> CachedStore<S> {
+    /// Return a new `CachedStore` which caches blocks from `store` according to `config`.
+    ///
+    /// # Errors
+    /// - `Error::Io`: The cache directory could not be created or cleared.
+    pub fn new(store: S, config: CacheConfig) -> crate::Result<Self> {
+        let cache = match config.location {
+            CacheLocation::Memory => CacheStorage::Memory(LruCache::unbounded()),
+            CacheLocation::Directory(path) => {
+                create_dir_all(&path)?;
+
+                // Blocks cached by a previous `CachedStore` may be stale.
+                for entry in read_dir(&path)? {
+                    let entry = entry?;
+                    let is_block = entry
+                        .file_name()
+                        .to_str()
+                        .and_then(|name| Uuid::parse_str(name).ok())
+                        .is_some();
+                    if is_block {
+                        remove_file(entry.path())?;
+                    }
+                }
+
+                CacheStorage::Directory {
+                    path,
+                    sizes: LruCache::unbounded(),
+                }
+            }
+        };
+
+        Ok(CachedStore {
+            store,
+            cache,
+            max_size: config.max_size,
+            stats: CacheStats::default(),
+        })
+    }
+
+    /// Return statistics about the cache.
+    pub fn stats(&self) -> CacheStats {
+        self.stats
+    }
+
+    /// Remove every block from the cache.
+    pub fn clear_cache(&mut self) {
+        while let Some(size) = self.cache.remove_lru() {
+            self.stats.size -= size;
+        }
+    }
+
+    /// Return a reference to the underlying data store.
+    pub fn inner(&self) -> &S {
+        &self.store
+    }
+
+    /// Consume this store and return the underlying data store.
+    pub fn into_inner(mut self) -> S {
+        self.clear_cache();
+        self.store
+    }
+
+    /// Remove the block with the given `id` from the cache if it is cached.
+    fn invalidate(&mut self, id: Uuid) {
+        if let Some(size) = self.cache.remove(id) {
+            self.stats.size -= size;
+        }
+    }
+
+    /// Add a block to the cache, evicting other blocks if necessary.
+    fn cache_block(&mut self, id: Uuid, data: &[u8]) {
+        self.invalidate(id);
+
+        let size = data.len() as u64;
+        if size > self.max_size {
+            return;
+        }
+
+        while self.stats.size + size > self.max_size {
+            match self.cache.remove_lru() {
+                Some(evicted_size) => self.stats.size -= evicted_size,
+                None => break,
+            }
+        }
+
+        if self.cache.insert(id, data) {
+            self.stats.size += size;
+        }
+    }
+}
+
+impl<S: DataStore> DataStore for CachedStore<S> {
+    type Error = S::Error;
+
+    fn write_block(&mut self, id: Uuid, data: &[u8]) -> Result<(), Self::Error> {
+        // If the write fails, the block may or may not have been overwritten.
+        self.invalidate(id);
+        self.store.write_block(id, data)?;
+        self.cache_block(id, data);
+        Ok(())
+    }
+
+    fn read_block(&mut self, id: Uuid) -> Result<Option<Vec<u8>>, Self::Error> {
+        if let Some(data) = self.cache.get(id) {
+            self.stats.hits += 1;
+            return Ok(Some(data));
+        }
+
+        // The block may have been cached but could not be read from the cache.
+        self.invalidate(id);
+        self.stats.misses += 1;
+
+        let data = self.store.read_block(id)?;
+        if let Some(data) = &data {
+            self.cache_block(id, data);
+        }
+        Ok(data)
+    }
+
+    fn remove_block(&mut self, id: Uuid) -> Result<(), Self::Error> {
+        self.invalidate(id);
+        self.store.remove_block(id)
+    }
+
+    fn list_blocks(&mut self) -> Result<Vec<Uuid>, Self::Error> {
+        self.store.list_blocks()
+    }
+
+    fn lock_dir(&self) -> Option<PathBuf> {
+        self.store.lock_dir()
+    }
+
+    fn write_blocks(&mut self, blocks: &[(Uuid, &[u8])]) -> Result<(), Self::Error> {
+        for (id, _) in blocks {
+            self.invalidate(*id);
+        }
+        self.store.write_blocks(blocks)?;
+        for (id, data) in blocks {
+            self.cache_block(*id, data);
+        }
+        Ok(())
+    }
+
+    fn read_blocks(&mut self, ids: &[Uuid]) -> Result<Vec<Option<Vec<u8>>>, Self::Error> {
+        let mut blocks = Vec::with_capacity(ids.len());
+        let mut missed_ids = Vec::new();
+        for &id in ids {
+            let block = self.cache.get(id);
+            if block.is_some() {
+                self.stats.hits += 1;
+            } else {
+                self.invalidate(id);
+                self.stats.misses += 1;
+                missed_ids.push(id);
+            }
+            blocks.push(block);
+        }
+
+        // Only the blocks which weren't cached are read from the inner store.
+        let mut missed_blocks = self.store.read_blocks(&missed_ids)?.into_iter();
+        for (id, block) in ids.iter().zip(blocks.iter_mut()) {
+            if block.is_none() {
+                *block = missed_blocks.next().unwrap();
+                if let Some(data) = block {
+                    self.cache_block(*id, data);
+                }
+            }
+        }
+
+        Ok(blocks)
+    }
+
+    fn remove_blocks(&mut self, ids: &[Uuid]) -> Result<(), Self::Error> {
+        for id in ids {
+            self.invalidate(*id);
+        }
+        self.store.remove_blocks(ids)
+    }
+}
@@ -15,6 +15,7 @@
  */
 
 use std::error;
+use std::path::PathBuf;
 
 use uuid::Uuid;
 
@@ -25,6 +26,10 @@ use bitflags::bitflags;
 /// A `DataStore` persistently stores blocks of data uniquely identified by UUIDs. Data stores are
 /// used as the storage backend for repositories in the `repo` module. Data stores do not need to
 /// provide their own locking mechanisms to protect against concurrent access.
+///
+/// Every method is synchronous; there is no asynchronous counterpart to this trait. To reduce the
+/// number of round trips to a remote data store, repositories use the batch methods such as
+/// `write_blocks` and `remove_blocks` instead, which data stores can override.
 pub trait DataStore {
     /// The error type for this data store.
     type Error: error::Error + Send + Sync + 'static;
@@ -61,6 +66,54 @@ pub trait DataStore {
     ///
     /// This only lists the IDs of blocks which are stored persistently.
     fn list_blocks(&mut self) -> Result<Vec<Uuid>, Self::Error>;
+
+    /// Write each of the given `blocks` with its ID.
+    ///
+    /// Each block is written atomically as if by `write_block`, but the batch as a whole is not
+    /// atomic. If this method returns `Err`, any of the blocks may or may not have been written.
+    ///
+    /// The default implementation calls `write_block` for each block. Data stores which can write
+    /// multiple blocks in fewer round trips should override it.
+    fn write_blocks(&mut self, blocks: &[(Uuid, &[u8])]) -> Result<(), Self::Error> {
+        for (id, data) in blocks {
+            self.write_block(*id, data)?;
+        }
+        Ok(())
+    }
+
+    /// Return the bytes of each block with the given `ids`, in the same order.
+    ///
+    /// If there is no block with a given ID, its entry is `None`.
+    ///
+    /// The default implementation calls `read_block` for each block. Data stores which can read
+    /// multiple blocks in fewer round trips should override it.
+    fn read_blocks(&mut self, ids: &[Uuid]) -> Result<Vec<Option<Vec<u8>>>, Self::Error> {
+        ids.iter().map(|id| self.read_block(*id)).collect()
+    }
+
+    /// Remove each block with the given `ids` from the store.
+    ///
+    /// Each block is removed atomically as if by `remove_block`, but the batch as a whole is not
+    /// atomic. If this method returns `Err`, any of the blocks may or may not have been removed.
+    ///
+    /// The default implementation calls `remove_block` for each block. Data stores which can remove
+    /// multiple blocks in fewer round trips should override it.
+    fn remove_blocks(&mut self, ids: &[Uuid]) -> Result<(), Self::Error> {
+        for id in ids {
+            self.remove_block(*id)?;
+        }
+        Ok(())
+    }
+
+    /// Return the directory where repositories in this data store keep their lock files.
+    ///
+    /// If this returns `None`, lock files are kept in a directory shared by all repositories which
+    /// are opened by the current user. The default implementation returns `None`. To keep lock files
+    /// in a different directory, such as one next to the data, wrap a data store in a
+    /// `LocalLockStore`.
+    fn lock_dir(&self) -> Option<PathBuf> {
+        None
+    }
 }
 
 bitflags! {
@@ -0,0 +1,665 @@
+/*
+ * Copyright 2019-2020 Wren Powell
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+#![cfg(feature = "store-erasure")]
+
+use std::cmp::max;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryInto;
+use std::error;
+use std::path::PathBuf;
+
+use blake2::digest::{Input, VariableOutput};
+use blake2::VarBlake2b;
+use lazy_static::lazy_static;
+use reed_solomon_erasure::galois_8::ReedSolomon;
+use rmp_serde::{from_read, to_vec};
+use thiserror::Error as DeriveError;
+use uuid::Uuid;
+
+use super::common::DataStore;
+
+/// The size of the checksum which is stored with each shard in bytes.
+const CHECKSUM_SIZE: usize = 16;
+
+/// The size of the header which is stored with each shard in bytes.
+///
+/// The header consists of the checksum, the version of the block and the size of the block.
+const HEADER_SIZE: usize = CHECKSUM_SIZE + 8 + 8;
+
+/// The maximum number of shards a block can be split into.
+const MAX_SHARDS: usize = 256;
+
+/// The number of versions which are reserved each time the version counter is persisted.
+const VERSION_BATCH: u64 = 1024;
+
+lazy_static! {
+    /// The ID of the block in each underlying data store which stores the version counter and the
+    /// blocks whose removal failed partway.
+    ///
+    /// This block is not visible through the `ErasureStore`.
+    static ref STATE_BLOCK_ID: Uuid =
+        Uuid::parse_str("5d3c8f0e-d1b6-11f1-a0e2-02fc00000002").unwrap();
+}
+
+/// An error returned by an `ErasureStore`.
+#[derive(Debug, DeriveError)]
+pub enum ErasureError {
+    /// An error occurred with one of the underlying data stores.
+    #[error("An error occurred with underlying data store {index}.")]
+    Store {
+        /// The index of the data store which failed.
+        index: usize,
+
+        /// The error returned by the data store.
+        #[source]
+        source: Box<dyn error::Error + Send + Sync>,
+    },
+
+    /// Too many shards of the block are missing or corrupt to reconstruct it.
+    #[error("Too many shards of the block are missing or corrupt to reconstruct it.")]
+    Unrecoverable,
+}
+
+/// Statistics about the blocks which were repaired by `ErasureStore::repair`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct RepairStats {
+    blocks: u64,
+    shards: u64,
+    unrecoverable: u64,
+}
+
+impl RepairStats {
+    /// The number of blocks which were repaired.
+    pub fn blocks(&self) -> u64 {
+        self.blocks
+    }
+
+    /// The number of shards which were rewritten.
+    pub fn shards(&self) -> u64 {
+        self.shards
+    }
+
+    /// The number of blocks which couldn't be repaired because too many of their shards are lost.
+    pub fn unrecoverable(&self) -> u64 {
+        self.unrecoverable
+    }
+}
+
+/// The shards of one version of a block.
+#[derive(Debug)]
+struct ShardSet {
+    /// The version of the block the shards belong to.
+    version: u64,
+
+    /// The size of the block in bytes.
+    size: u64,
+
+    /// The shard stored in each data store, or `None` if it is missing or corrupt.
+    shards: Vec<Option<Vec<u8>>>,
+}
+
+impl ShardSet {
+    /// Return whether any shards are missing or corrupt.
+    fn is_degraded(&self) -> bool {
+        self.shards.iter().any(|shard| shard.is_none())
+    }
+}
+
+/// Compute the checksum of the `data` in a shard.
+fn checksum(data: &[u8]) -> [u8; CHECKSUM_SIZE] {
+    let mut hasher = VarBlake2b::new(CHECKSUM_SIZE).unwrap();
+    hasher.input(data);
+    let mut checksum = [0u8; CHECKSUM_SIZE];
+    hasher.variable_result(|result| checksum.copy_from_slice(result));
+    checksum
+}
+
+/// Serialize a `shard` of the given `version` of a block of the given `size`.
+fn encode_shard(version: u64, size: u64, shard: &[u8]) -> Vec<u8> {
+    let mut buffer = Vec::with_capacity(HEADER_SIZE + shard.len());
+    buffer.extend_from_slice(&[0u8; CHECKSUM_SIZE]);
+    buffer.extend_from_slice(&version.to_le_bytes());
+    buffer.extend_from_slice(&size.to_le_bytes());
+    buffer.extend_from_slice(shard);
+    let checksum = checksum(&buffer[CHECKSUM_SIZE..]);
+    buffer[..CHECKSUM_SIZE].copy_from_slice(&checksum);
+    buffer
+}
+
+/// Deserialize a shard and return its version, the size of its block, and its data.
+///
+/// This returns `None` if the shard is corrupt.
+fn decode_shard(mut buffer: Vec<u8>) -> Option<(u64, u64, Vec<u8>)> {
+    if buffer.len() < HEADER_SIZE || buffer[..CHECKSUM_SIZE] != checksum(&buffer[CHECKSUM_SIZE..]) {
+        return None;
+    }
+    let version = u64::from_le_bytes(buffer[CHECKSUM_SIZE..CHECKSUM_SIZE + 8].try_into().unwrap());
+    let size = u64::from_le_bytes(buffer[CHECKSUM_SIZE + 8..HEADER_SIZE].try_into().unwrap());
+    Some((version, size, buffer.split_off(HEADER_SIZE)))
+}
+
+/// A `DataStore` which splits blocks into shards with Reed-Solomon erasure coding.
+///
+/// Each block is split into a number of data shards, and parity shards are computed from them.
+/// Each shard is stored in a different underlying data store, so there is one data store for each
+/// shard. A block can be reconstructed from any combination of shards as long as there are at
+/// least as many as there are data shards, so the store can survive the loss of as many
+/// underlying data stores as there are parity shards. This uses less space than mirroring every
+/// block.
+///
+/// Each shard is stored with a checksum so that corrupt shards are treated as missing. Blocks with
+/// missing or corrupt shards are still readable, but they are recorded as degraded so they can be
+/// repaired with `repair`. Use `scan` to find every degraded block.
+///
+/// Each shard also records the version of its block. If a write fails partway through, reads use
+/// the newest version of the block which has enough shards to reconstruct it. Versions come from a
+/// counter which is persisted in the underlying data stores, so they keep increasing when the
+/// store is created again. Every so often, writing a block persists the counter, which fails unless
+/// more of the underlying data stores than there are parity shards are available.
+///
+/// If a removal fails partway through, the block is treated as removed, and this is recorded in
+/// the underlying data stores so that it still applies if the store is created again. The removal
+/// is finished the next time the block is read or `repair` is called.
+///
+/// Lock files are kept in the lock directory of the first underlying data store which has one.
+#[derive(Debug)]
+pub struct ErasureStore<S: DataStore> {
+    /// The underlying data stores, one for each shard.
+    stores: Vec<S>,
+
+    /// The number of data shards each block is split into.
+    data_shards: usize,
+
+    /// The encoder for computing and reconstructing shards.
+    codec: ReedSolomon,
+
+    /// The version of the block which was written most recently.
+    last_version: u64,
+
+    /// The highest version which has been reserved in the underlying data stores.
+    reserved_version: u64,
+
+    /// The IDs of blocks whose removal failed partway.
+    removing: HashSet<Uuid>,
+
+    /// Whether the state persisted in the underlying data stores has been read.
+    state_loaded: bool,
+
+    /// The IDs of blocks which are known to have missing or corrupt shards.
+    degraded: HashSet<Uuid>,
+}
+
+impl<S: DataStore> ErasureStore<S> {
+    /// Return a new `ErasureStore` which splits each block into `data_shards` data shards.
+    ///
+    /// The number of parity shards is the number of `stores` minus the number of data shards.
+    /// There must be at least one of each and no more than 256 in total.
+    ///
+    /// # Errors
+    /// - `Error::InvalidConfig`: The number of data shards or parity shards is invalid.
+    pub fn new(stores: Vec<S>, data_shards: usize) -> crate::Result<Self> {
+        if data_shards == 0 || stores.len() <= data_shards || stores.len() > MAX_SHARDS {
+            return Err(crate::Error::InvalidConfig);
+        }
+        let codec = ReedSolomon::new(data_shards, stores.len() - data_shards)
+            .map_err(|_| crate::Error::InvalidConfig)?;
+        Ok(ErasureStore {
+            stores,
+            data_shards,
+            codec,
+            last_version: 0,
+            reserved_version: 0,
+            removing: HashSet::new(),
+            state_loaded: false,
+            degraded: HashSet::new(),
+        })
+    }
+
+    /// The number of data shards each block is split into.
+    pub fn data_shards(&self) -> usize {
+        self.data_shards
+    }
+
+    /// The number of parity shards computed for each block.
+    ///
+    /// This is the number of underlying data stores which can be lost without losing data.
+    pub fn parity_shards(&self) -> usize {
+        self.stores.len() - self.data_shards
+    }
+
+    /// Return the underlying data stores.
+    pub fn stores(&self) -> &[S] {
+        &self.stores
+    }
+
+    /// Return the underlying data stores mutably.
+    ///
+    /// Shards written directly to these data stores must be written by an `ErasureStore`.
+    pub fn stores_mut(&mut self) -> &mut [S] {
+        &mut self.stores
+    }
+
+    /// Consume this store and return the underlying data stores.
+    pub fn into_inner(self) -> Vec<S> {
+        self.stores
+    }
+
+    /// Return the IDs of blocks which are known to have missing or corrupt shards.
+    ///
+    /// This only includes blocks which have been read or written since the store was created.
+    /// Use `scan` to check every block.
+    pub fn degraded_blocks(&self) -> Vec<Uuid> {
+        self.degraded.iter().copied().collect()
+    }
+
+    /// Check every block in the store and return the IDs of those with missing or corrupt shards.
+    ///
+    /// This includes blocks which have lost too many shards to be repaired.
+    ///
+    /// # Errors
+    /// - `Error::Store`: An error occurred with an underlying data store.
+    pub fn scan(&mut self) -> crate::Result<Vec<Uuid>> {
+        for id in self.list_blocks().map_err(anyhow::Error::from)? {
+            match self.read_shards(id) {
+                Ok(Some(shard_set)) if !shard_set.is_degraded() => {
+                    self.degraded.remove(&id);
+                }
+                Ok(_) | Err(ErasureError::Unrecoverable) => {
+                    self.degraded.insert(id);
+                }
+                Err(error) => return Err(anyhow::Error::from(error).into()),
+            }
+        }
+        Ok(self.degraded_blocks())
+    }
+
+    /// Rewrite the missing or corrupt shards of every block returned by `degraded_blocks`.
+    ///
+    /// This also finishes removing blocks whose removal failed partway.
+    ///
+    /// # Errors
+    /// - `Error::Store`: An error occurred with an underlying data store.
+    pub fn repair(&mut self) -> crate::Result<RepairStats> {
+        let mut stats = RepairStats::default();
+
+        self.load_state().map_err(anyhow::Error::from)?;
+        for id in self.removing.iter().copied().collect::<Vec<_>>() {
+            self.finish_removal(id);
+        }
+
+        for id in self.degraded_blocks() {
+            let mut shard_set = match self.read_shards(id) {
+                Ok(Some(shard_set)) => shard_set,
+                Ok(None) => {
+                    self.degraded.remove(&id);
+                    continue;
+                }
+                Err(ErasureError::Unrecoverable) => {
+                    stats.unrecoverable += 1;
+                    continue;
+                }
+                Err(error) => return Err(anyhow::Error::from(error).into()),
+            };
+
+            let missing = shard_set
+                .shards
+                .iter()
+                .map(|shard| shard.is_none())
+                .collect::<Vec<_>>();
+            self.codec
+                .reconstruct(&mut shard_set.shards)
+                .map_err(anyhow::Error::from)?;
+
+            for (index, shard) in shard_set.shards.iter().enumerate() {
+                if missing[index] {
+                    let encoded_shard =
+                        encode_shard(shard_set.version, shard_set.size, shard.as_ref().unwrap());
+                    self.stores[index]
+                        .write_block(id, &encoded_shard)
+                        .map_err(anyhow::Error::from)?;
+                    stats.shards += 1;
+                }
+            }
+
+            self.degraded.remove(&id);
+            stats.blocks += 1;
+        }
+
+        Ok(stats)
+    }
+
+    /// Return the size of each shard of a block of the given `size`.
+    fn shard_size(&self, size: usize) -> usize {
+        // Shards can't be empty.
+        let mut shard_size = size / self.data_shards;
+        if shard_size * self.data_shards < size {
+            shard_size += 1;
+        }
+        max(1, shard_size)
+    }
+
+    /// Read the state which was persisted in the underlying data stores by an earlier instance of
+    /// the store, if it hasn't been read already.
+    fn load_state(&mut self) -> Result<(), ErasureError> {
+        if self.state_loaded {
+            return Ok(());
+        }
+
+        let mut newest: Option<(u64, Vec<u8>)> = None;
+        let mut first_error = None;
+        let mut available = 0;
+
+        for (index, store) in self.stores.iter_mut().enumerate() {
+            match store.read_block(*STATE_BLOCK_ID) {
+                Ok(buffer) => {
+                    available += 1;
+                    match (buffer.and_then(decode_shard), &newest) {
+                        (Some((version, _, _)), Some((newest_version, _)))
+                            if *newest_version >= version => {}
+                        (Some((version, _, data)), _) => newest = Some((version, data)),
+                        (None, _) => {}
+                    }
+                }
+                Err(error) => {
+                    first_error.get_or_insert(ErasureError::Store {
+                        index,
+                        source: Box::new(error),
+                    });
+                }
+            }
+        }
+
+        // The state is saved in more stores than there are parity shards, so any `data_shards`
+        // stores include one with the newest state.
+        if available < self.data_shards {
+            return Err(first_error.unwrap_or(ErasureError::Unrecoverable));
+        }
+
+        if let Some((version, data)) = newest {
+            self.last_version = max(self.last_version, version);
+            self.reserved_version = self.last_version;
+            self.removing = from_read::<_, Vec<Uuid>>(data.as_slice())
+                .map(|ids| ids.into_iter().collect())
+                .unwrap_or_default();
+        }
+        self.state_loaded = true;
+        Ok(())
+    }
+
+    /// Save the state in the underlying data stores, reserving versions up to `reserved_version`.
+    ///
+    /// Each time the state is saved, `reserved_version` must be higher than the last time so that
+    /// the newest state can be found.
+    fn save_state(&mut self, reserved_version: u64) -> Result<(), ErasureError> {
+        let removing = self.removing.iter().copied().collect::<Vec<_>>();
+        let serialized = to_vec(&removing).expect("Could not serialize the store state.");
+        let encoded_state = encode_shard(reserved_version, serialized.len() as u64, &serialized);
+        let mut first_error = None;
+        let mut saved = 0;
+
+        for (index, store) in self.stores.iter_mut().enumerate() {
+            match store.write_block(*STATE_BLOCK_ID, &encoded_state) {
+                Ok(()) => saved += 1,
+                Err(error) => {
+                    first_error.get_or_insert(ErasureError::Store {
+                        index,
+                        source: Box::new(error),
+                    });
+                }
+            }
+        }
+
+        if saved <= self.parity_shards() {
+            return Err(first_error.unwrap_or(ErasureError::Unrecoverable));
+        }
+        self.reserved_version = reserved_version;
+        Ok(())
+    }
+
+    /// Return a version for a new shard which is newer than any written before.
+    fn next_version(&mut self) -> Result<u64, ErasureError> {
+        self.load_state()?;
+        if self.last_version >= self.reserved_version {
+            self.save_state(self.last_version + VERSION_BATCH)?;
+        }
+        self.last_version += 1;
+        Ok(self.last_version)
+    }
+
+    /// Stop treating the block with the given `id` as removed.
+    fn forget_removal(&mut self, id: Uuid) -> Result<(), ErasureError> {
+        if !self.removing.remove(&id) {
+            return Ok(());
+        }
+        let result = self.save_state(self.reserved_version + 1);
+        if result.is_err() {
+            // Otherwise, the saved state could make a block which is written later look removed.
+            self.removing.insert(id);
+        }
+        result
+    }
+
+    /// Try to remove the remaining shards of a block whose removal failed partway.
+    fn finish_removal(&mut self, id: Uuid) {
+        // If this fails, the block is still treated as removed, and this is tried again later.
+        if self.remove_shards(id).is_ok() {
+            self.forget_removal(id).ok();
+        }
+    }
+
+    /// Remove the shard of the block with the given `id` from each underlying data store.
+    fn remove_shards(&mut self, id: Uuid) -> Result<(), ErasureError> {
+        let mut first_error = None;
+        for (index, store) in self.stores.iter_mut().enumerate() {
+            if let Err(error) = store.remove_block(id) {
+                first_error.get_or_insert(ErasureError::Store {
+                    index,
+                    source: Box::new(error),
+                });
+            }
+        }
+
+        match first_error {
+            Some(error) => Err(error),
+            None => Ok(()),
+        }
+    }
+
+    /// Read the shards of the newest version of the block with the given `id` which can be
+    /// reconstructed.
+    ///
+    /// This returns `None` if there is no block with the given `id`.
+    fn read_shards(&mut self, id: Uuid) -> Result<Option<ShardSet>, ErasureError> {
+        let num_shards = self.stores.len();
+        let mut versions = HashMap::new();
+        let mut first_error = None;
+        let mut any_found = false;
+
+        for (index, store) in self.stores.iter_mut().enumerate() {
+            match store.read_block(id) {
+                Ok(Some(buffer)) => {
+                    any_found = true;
+                    if let Some((version, size, shard)) = decode_shard(buffer) {
+                        versions
+                            .entry((version, size))
+                            .or_insert_with(|| vec![None; num_shards])[index] = Some(shard);
+                    }
+                }
+                Ok(None) => {}
+                Err(error) => {
+                    first_error.get_or_insert(ErasureError::Store {
+                        index,
+                        source: Box::new(error),
+                    });
+                }
+            }
+        }
+
+        if !any_found {
+            return match first_error {
+                Some(error) => Err(error),
+                None => Ok(None),
+            };
+        }
+
+        let data_shards = self.data_shards;
+        let newest = versions
+            .into_iter()
+            .filter(|(_, shards)| shards.iter().flatten().count() >= data_shards)
+            .max_by_key(|((version, _), _)| *version);
+
+        match newest {
+            Some(((version, size), shards)) => Ok(Some(ShardSet {
+                version,
+                size,
+                shards,
+            })),
+            None => Err(first_error.unwrap_or(ErasureError::Unrecoverable)),
+        }
+    }
+}
+
+impl<S: DataStore> DataStore for ErasureStore<S> {
+    type Error = ErasureError;
+
+    fn write_block(&mut self, id: Uuid, data: &[u8]) -> Result<(), Self::Error> {
+        let version = self.next_version()?;
+        // This must be saved before any shards are written, or the new block could be removed
+        // when the store is created again.
+        self.forget_removal(id)?;
+
+        // Split the block into equally sized data shards and compute the parity shards.
+        let shard_size = self.shard_size(data.len());
+        let mut shards = vec![vec![0u8; shard_size]; self.stores.len()];
+        for (shard, chunk) in shards.iter_mut().zip(data.chunks(shard_size)) {
+            shard[..chunk.len()].copy_from_slice(chunk);
+        }
+        self.codec
+            .encode(&mut shards)
+            .expect("Could not compute parity shards.");
+
+        let mut first_error = None;
+        for (index, (store, shard)) in self.stores.iter_mut().zip(shards.iter()).enumerate() {
+            let encoded_shard = encode_shard(version, data.len() as u64, shard);
+            if let Err(error) = store.write_block(id, &encoded_shard) {
+                first_error.get_or_insert(ErasureError::Store {
+                    index,
+                    source: Box::new(error),
+                });
+            }
+        }
+
+        match first_error {
+            Some(error) => {
+                self.degraded.insert(id);
+                Err(error)
+            }
+            None => {
+                self.degraded.remove(&id);
+                Ok(())
+            }
+        }
+    }
+
+    fn read_block(&mut self, id: Uuid) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.load_state()?;
+        if self.removing.contains(&id) {
+            self.finish_removal(id);
+            return Ok(None);
+        }
+
+        let mut shard_set = match self.read_shards(id)? {
+            Some(shard_set) => shard_set,
+            None => return Ok(None),
+        };
+
+        if shard_set.is_degraded() {
+            self.degraded.insert(id);
+            self.codec
+                .reconstruct_data(&mut shard_set.shards)
+                .map_err(|_| ErasureError::Unrecoverable)?;
+        }
+
+        let mut data = Vec::with_capacity(shard_set.size as usize);
+        for shard in shard_set.shards.into_iter().take(self.data_shards) {
+            data.extend_from_slice(&shard.unwrap());
+        }
+        data.truncate(shard_set.size as usize);
+
+        Ok(Some(data))
+    }
+
+    fn remove_block(&mut self, id: Uuid) -> Result<(), Self::Error> {
+        self.load_state()?;
+        let result = self.remove_shards(id);
+        self.degraded.remove(&id);
+
+        match result {
+            Ok(()) => {
+                // The block is gone either way, so it doesn't matter if this fails.
+                self.forget_removal(id).ok();
+                Ok(())
+            }
+            Err(error) => {
+                // Some of the shards may be gone, so the block may no longer be readable.
+                if self.removing.insert(id) {
+                    let reserved_version = self.reserved_version + 1;
+                    self.save_state(reserved_version).ok();
+                }
+                Err(error)
+            }
+        }
+    }
+
+    fn list_blocks(&mut self) -> Result<Vec<Uuid>, Self::Error> {
+        self.load_state()?;
+        let mut block_ids = HashSet::new();
+        let mut first_error = None;
+        let mut any_succeeded = false;
+
+        // Every store has a shard of every block unless it has lost some, so blocks are listed
+        // as long as any store can list them.
+        for (index, store) in self.stores.iter_mut().enumerate() {
+            match store.list_blocks() {
+                Ok(ids) => {
+                    any_succeeded = true;
+                    block_ids.extend(ids);
+                }
+                Err(error) => {
+                    first_error.get_or_insert(ErasureError::Store {
+                        index,
+                        source: Box::new(error),
+                    });
+                }
+            }
+        }
+
+        block_ids.remove(&*STATE_BLOCK_ID);
+        for id in self.removing.iter() {
+            block_ids.remove(id);
+        }
+
+        match first_error {
+            Some(error) if !any_succeeded => Err(error),
+            _ => Ok(block_ids.into_iter().collect()),
+        }
+    }
+
+    fn lock_dir(&self) -> Option<PathBuf> {
+        self.stores.iter().find_map(|store| store.lock_dir())
+    }
+}
@@ -0,0 +1,249 @@
+/*
+ * Copyright 2019-2020 Wren Powell
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+#![cfg(feature = "store-faulty")]
+
+use std::error;
+use std::path::PathBuf;
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use thiserror::Error as DeriveError;
+use uuid::Uuid;
+
+use super::common::DataStore;
+
+/// The faults which a `FaultyStore` injects.
+///
+/// Faults are chosen using a pseudorandom number generator seeded with `seed`, so a `FaultyStore`
+/// with the same configuration injects the same faults given the same sequence of operations.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FaultConfig {
+    /// The seed for choosing which operations fail.
+    pub seed: u64,
+
+    /// The probability that an operation fails without reaching the underlying data store.
+    ///
+    /// This must be between `0.0` and `1.0`.
+    pub error_probability: f64,
+
+    /// The probability that a write is torn.
+    ///
+    /// When a write is torn, only part of the block is written to the underlying data store before
+    /// the operation fails and the store crashes. This violates the guarantee that
+    /// `DataStore::write_block` is atomic, and is meant to simulate a data store which doesn't
+    /// uphold it.
+    ///
+    /// This must be between `0.0` and `1.0`.
+    pub torn_write_probability: f64,
+
+    /// The number of operations after which the store crashes.
+    ///
+    /// Once the store has crashed, every operation fails until `FaultyStore::recover` is called.
+    /// If this is `None`, the store only crashes after a torn write.
+    pub crash_after: Option<u64>,
+}
+
+impl FaultConfig {
+    /// Return a config which never injects faults.
+    pub fn new(seed: u64) -> Self {
+        FaultConfig {
+            seed,
+            error_probability: 0.0,
+            torn_write_probability: 0.0,
+            crash_after: None,
+        }
+    }
+}
+
+/// An error returned by a `FaultyStore`.
+#[derive(Debug, DeriveError)]
+pub enum FaultyStoreError<E: error::Error + 'static> {
+    /// The operation failed because of an injected fault.
+    #[error("The operation failed because of an injected fault.")]
+    Injected,
+
+    /// The store has crashed because of an injected fault.
+    #[error("The store has crashed because of an injected fault.")]
+    Crashed,
+
+    /// An error occurred with the underlying data store.
+    #[error("An error occurred with the underlying data store.")]
+    Store(#[source] E),
+}
+
+/// A `DataStore` which wraps another data store and injects faults into its operations.
+///
+/// This is meant for testing how repositories behave when their data store fails. It can make
+/// operations fail at random, tear writes so that only part of a block is written, and simulate a
+/// crash after a given number of operations. Faults are chosen pseudorandomly from a seed so that
+/// failures can be reproduced.
+///
+/// Every call to a `DataStore` method counts as an operation, including calls which fail.
+#[derive(Debug)]
+pub struct FaultyStore<S: DataStore> {
+    /// The underlying data store.
+    store: S,
+
+    /// The faults to inject.
+    config: FaultConfig,
+
+    /// The random number generator for choosing which operations fail.
+    rng: StdRng,
+
+    /// The number of operations which have been attempted.
+    operations: u64,
+
+    /// The number of faults which have been injected.
+    faults: u64,
+
+    /// Whether the store has crashed.
+    crashed: bool,
+}
+
+impl<S: DataStore> FaultyStore<S> {
+    /// Return a new `FaultyStore` which wraps `store` and injects faults according to `config`.
+    pub fn new(store: S, config: FaultConfig) -> Self {
+        FaultyStore {
+            store,
+            config,
+            rng: StdRng::seed_from_u64(config.seed),
+            operations: 0,
+            faults: 0,
+            crashed: false,
+        }
+    }
+
+    /// The faults this store injects.
+    pub fn config(&self) -> FaultConfig {
+        self.config
+    }
+
+    /// Change the faults this store injects.
+    ///
+    /// This does not reseed the random number generator or reset the number of operations.
+    pub fn set_config(&mut self, config: FaultConfig) {
+        self.config = config;
+    }
+
+    /// The number of operations which have been attempted on this store.
+    pub fn operations(&self) -> u64 {
+        self.operations
+    }
+
+    /// The number of faults which have been injected.
+    ///
+    /// Operations which fail because the store has already crashed are not counted.
+    pub fn faults(&self) -> u64 {
+        self.faults
+    }
+
+    /// Whether the store has crashed.
+    pub fn is_crashed(&self) -> bool {
+        self.crashed
+    }
+
+    /// Recover the store from a crash so that operations reach the underlying data store again.
+    ///
+    /// This also disables `FaultConfig::crash_after` so that the store doesn't immediately crash
+    /// again.
+    pub fn recover(&mut self) {
+        self.crashed = false;
+        self.config.crash_after = None;
+    }
+
+    /// Return a reference to the underlying data store.
+    pub fn inner(&self) -> &S {
+        &self.store
+    }
+
+    /// Return a mutable reference to the underlying data store.
+    ///
+    /// Operations on the returned data store don't have faults injected.
+    pub fn inner_mut(&mut self) -> &mut S {
+        &mut self.store
+    }
+
+    /// Consume this store and return the underlying data store.
+    pub fn into_inner(self) -> S {
+        self.store
+    }
+
+    /// Start an operation and return an error if a fault should be injected.
+    fn start_operation(&mut self) -> Result<(), FaultyStoreError<S::Error>> {
+        self.operations += 1;
+
+        if self.crashed {
+            return Err(FaultyStoreError::Crashed);
+        }
+
+        if let Some(crash_after) = self.config.crash_after {
+            if self.operations > crash_after {
+                self.crashed = true;
+                self.faults += 1;
+                return Err(FaultyStoreError::Crashed);
+            }
+        }
+
+        if self.rng.gen_bool(self.config.error_probability) {
+            self.faults += 1;
+            return Err(FaultyStoreError::Injected);
+        }
+
+        Ok(())
+    }
+}
+
+impl<S: DataStore> DataStore for FaultyStore<S> {
+    type Error = FaultyStoreError<S::Error>;
+
+    fn write_block(&mut self, id: Uuid, data: &[u8]) -> Result<(), Self::Error> {
+        self.start_operation()?;
+
+        if self.rng.gen_bool(self.config.torn_write_probability) {
+            let torn_len = self.rng.gen_range(0, data.len() + 1);
+            self.store
+                .write_block(id, &data[..torn_len])
+                .map_err(FaultyStoreError::Store)?;
+            self.crashed = true;
+            self.faults += 1;
+            return Err(FaultyStoreError::Crashed);
+        }
+
+        self.store
+            .write_block(id, data)
+            .map_err(FaultyStoreError::Store)
+    }
+
+    fn read_block(&mut self, id: Uuid) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.start_operation()?;
+        self.store.read_block(id).map_err(FaultyStoreError::Store)
+    }
+
+    fn remove_block(&mut self, id: Uuid) -> Result<(), Self::Error> {
+        self.start_operation()?;
+        self.store.remove_block(id).map_err(FaultyStoreError::Store)
+    }
+
+    fn list_blocks(&mut self) -> Result<Vec<Uuid>, Self::Error> {
+        self.start_operation()?;
+        self.store.list_blocks().map_err(FaultyStoreError::Store)
+    }
+
+    fn lock_dir(&self) -> Option<PathBuf> {
+        self.store.lock_dir()
+    }
+}
@@ -0,0 +1,96 @@
+/*
+ * Copyright 2019-2020 Wren Powell
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::path::PathBuf;
+
+use uuid::Uuid;
+
+use super::common::DataStore;
+
+/// A `DataStore` wrapper which keeps the lock files for repositories in a given directory.
+///
+/// By default, repositories are locked using lock files in a directory which is shared by all
+/// repositories opened by the current user. Wrapping a data store in a `LocalLockStore` keeps them
+/// in `lock_dir` instead, such as a directory next to the data. This allows processes run by
+/// different users to exclude each other, as long as they can all write to `lock_dir`.
+///
+/// Every process which opens the repository must use the same lock directory, or they will not
+/// exclude each other. All other operations are passed through to the wrapped data store.
+#[derive(Debug)]
+pub struct LocalLockStore<S: DataStore> {
+    store: S,
+    lock_dir: PathBuf,
+}
+
+impl<S: DataStore> LocalLockStore<S> {
+    /// Wrap `store` so that repositories in it keep their lock files in `lock_dir`.
+    ///
+    /// The directory is created when a repository is first locked if it doesn't already exist.
+    pub fn new(store: S, lock_dir: PathBuf) -> Self {
+        Self { store, lock_dir }
+    }
+
+    /// Return a reference to the wrapped data store.
+    pub fn inner(&self) -> &S {
+        &self.store
+    }
+
+    /// Return a mutable reference to the wrapped data store.
+    pub fn inner_mut(&mut self) -> &mut S {
+        &mut self.store
+    }
+
+    /// Consume this wrapper and return the wrapped data store.
+    pub fn into_inner(self) -> S {
+        self.store
+    }
+}
+
+impl<S: DataStore> DataStore for LocalLockStore<S> {
+    type Error = S::Error;
+
+    fn write_block(&mut self, id: Uuid, data: &[u8]) -> Result<(), Self::Error> {
+        self.store.write_block(id, data)
+    }
+
+    fn read_block(&mut self, id: Uuid) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.store.read_block(id)
+    }
+
+    fn remove_block(&mut self, id: Uuid) -> Result<(), Self::Error> {
+        self.store.remove_block(id)
+    }
+
+    fn list_blocks(&mut self) -> Result<Vec<Uuid>, Self::Error> {
+        self.store.list_blocks()
+    }
+
+    fn write_blocks(&mut self, blocks: &[(Uuid, &[u8])]) -> Result<(), Self::Error> {
+        self.store.write_blocks(blocks)
+    }
+
+    fn read_blocks(&mut self, ids: &[Uuid]) -> Result<Vec<Option<Vec<u8>>>, Self::Error> {
+        self.store.read_blocks(ids)
+    }
+
+    fn remove_blocks(&mut self, ids: &[Uuid]) -> Result<(), Self::Error> {
+        self.store.remove_blocks(ids)
+    }
+
+    fn lock_dir(&self) -> Option<PathBuf> {
+        Some(self.lock_dir.clone())
+    }
+}
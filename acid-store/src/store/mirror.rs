@@ -0,0 +1,747 @@
+/*
+ * Copyright 2019-2020 Wren Powell
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::cmp::max;
+use std::collections::HashSet;
+use std::convert::TryInto;
+use std::error;
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use blake2::digest::{Input, VariableOutput};
+use blake2::VarBlake2b;
+use lazy_static::lazy_static;
+use rmp_serde::{from_read, to_vec};
+use thiserror::Error as DeriveError;
+use uuid::Uuid;
+
+use super::common::DataStore;
+
+/// The size of the checksum which is stored with each block in bytes.
+const CHECKSUM_SIZE: usize = 32;
+
+/// The size of the version which is stored with each block in bytes.
+const VERSION_SIZE: usize = 8;
+
+/// The size of the header which is stored with each block in bytes.
+const FRAME_HEADER_SIZE: usize = CHECKSUM_SIZE + VERSION_SIZE;
+
+lazy_static! {
+    /// The ID of the block in each member which stores which blocks are stale in which members.
+    ///
+    /// This block is not visible through the mirror.
+    static ref STALE_BLOCK_ID: Uuid =
+        Uuid::parse_str("0c6e5a3e-d0a4-11f1-9b7c-02fc00000001").unwrap();
+}
+
+/// An error returned by a `MirrorStore` or `MirrorSetStore`.
+#[derive(Debug, DeriveError)]
+pub enum MirrorError {
+    /// An error occurred with a member of the mirror.
+    #[error("An error occurred with member {index} of the mirror.")]
+    Member {
+        /// The index of the member which failed.
+        index: usize,
+
+        /// The error returned by the member.
+        #[source]
+        source: Box<dyn error::Error + Send + Sync>,
+    },
+
+    /// No member of the mirror has an intact copy of the block.
+    #[error("No member of the mirror has an intact copy of the block.")]
+    Corrupt,
+}
+
+/// Statistics about the blocks which were repaired by resyncing a mirror.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct ResyncStats {
+    repaired: u64,
+    removed: u64,
+    unrecoverable: u64,
+}
+
+impl ResyncStats {
+    /// The number of copies of blocks which were missing, corrupt, or out of date and were
+    /// rewritten.
+    pub fn repaired(&self) -> u64 {
+        self.repaired
+    }
+
+    /// The number of blocks which were removed because a failed write or removal left them in some
+    /// members.
+    pub fn removed(&self) -> u64 {
+        self.removed
+    }
+
+    /// The number of blocks which couldn't be repaired because no member has an intact copy.
+    pub fn unrecoverable(&self) -> u64 {
+        self.unrecoverable
+    }
+}
+
+/// A member of a mirror.
+///
+/// This is implemented for every `DataStore` so that members with different error types can be
+/// used through trait objects.
+trait Member {
+    fn write(&mut self, id: Uuid, data: &[u8]) -> anyhow::Result<()>;
+
+    fn read(&mut self, id: Uuid) -> anyhow::Result<Option<Vec<u8>>>;
+
+    fn remove(&mut self, id: Uuid) -> anyhow::Result<()>;
+
+    fn list(&mut self) -> anyhow::Result<Vec<Uuid>>;
+}
+
+impl<S: DataStore> Member for S {
+    fn write(&mut self, id: Uuid, data: &[u8]) -> anyhow::Result<()> {
+        Ok(self.write_block(id, data)?)
+    }
+
+    fn read(&mut self, id: Uuid) -> anyhow::Result<Option<Vec<u8>>> {
+        Ok(self.read_block(id)?)
+    }
+
+    fn remove(&mut self, id: Uuid) -> anyhow::Result<()> {
+        Ok(self.remove_block(id)?)
+    }
+
+    fn list(&mut self) -> anyhow::Result<Vec<Uuid>> {
+        Ok(self.list_blocks()?)
+    }
+}
+
+/// Return a `MirrorError` for an `error` returned by the member at `index`.
+fn member_error(index: usize, error: anyhow::Error) -> MirrorError {
+    MirrorError::Member {
+        index,
+        source: error.into(),
+    }
+}
+
+/// Return `data` with its checksum and `version` prepended.
+fn frame_block(data: &[u8], version: u64) -> Vec<u8> {
+    let mut framed = Vec::with_capacity(FRAME_HEADER_SIZE + data.len());
+    framed.extend_from_slice(&[0u8; CHECKSUM_SIZE]);
+    framed.extend_from_slice(&version.to_le_bytes());
+    framed.extend_from_slice(data);
+    let checksum = checksum(&framed[CHECKSUM_SIZE..]);
+    framed[..CHECKSUM_SIZE].copy_from_slice(&checksum);
+    framed
+}
+
+/// Return the data in a framed block or `None` if the checksum doesn't match.
+fn unframe_block(mut framed: Vec<u8>) -> Option<Vec<u8>> {
+    frame_version(&framed)?;
+    Some(framed.split_off(FRAME_HEADER_SIZE))
+}
+
+/// Return the version of a framed block or `None` if the checksum doesn't match.
+fn frame_version(framed: &[u8]) -> Option<u64> {
+    if framed.len() < FRAME_HEADER_SIZE
+        || framed[..CHECKSUM_SIZE] != checksum(&framed[CHECKSUM_SIZE..])
+    {
+        return None;
+    }
+    let version = framed[CHECKSUM_SIZE..FRAME_HEADER_SIZE].try_into().unwrap();
+    Some(u64::from_le_bytes(version))
+}
+
+/// Compute the checksum of `data`.
+fn checksum(data: &[u8]) -> [u8; CHECKSUM_SIZE] {
+    let mut hasher = VarBlake2b::new(CHECKSUM_SIZE).unwrap();
+    hasher.input(data);
+    let mut checksum = [0u8; CHECKSUM_SIZE];
+    hasher.variable_result(|result| checksum.copy_from_slice(result));
+    checksum
+}
+
+/// The state of a member of a mirror.
+#[derive(Debug, Default)]
+struct MemberState {
+    /// Whether the last operation on this member succeeded.
+    healthy: bool,
+
+    /// A moving average of how long it takes to read a block from this member.
+    latency: Option<Duration>,
+
+    /// The IDs of blocks which may be out of date in this member because a write or removal failed.
+    stale: HashSet<Uuid>,
+}
+
+impl MemberState {
+    /// Record how long a successful read took.
+    fn record_latency(&mut self, elapsed: Duration) {
+        self.latency = Some(match self.latency {
+            Some(latency) => (latency * 3 + elapsed) / 4,
+            None => elapsed,
+        });
+    }
+}
+
+/// The state of a mirror which is independent of the types of its members.
+#[derive(Debug)]
+struct Mirror {
+    members: Vec<MemberState>,
+
+    /// The version of the last copy of a block which was written.
+    last_version: u64,
+
+    /// Whether the stale blocks recorded in the members have been read.
+    stale_loaded: bool,
+}
+
+impl Mirror {
+    /// Create the state for a mirror with `len` members.
+    fn new(len: usize) -> Self {
+        Mirror {
+            members: (0..len)
+                .map(|_| MemberState {
+                    healthy: true,
+                    ..Default::default()
+                })
+                .collect(),
+            last_version: 0,
+            stale_loaded: false,
+        }
+    }
+
+    /// Return a version for a new copy of a block which is newer than any written before.
+    ///
+    /// Versions are based on the system clock so that they increase across instances of the
+    /// mirror.
+    fn next_version(&mut self) -> u64 {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_nanos() as u64)
+            .unwrap_or(0);
+        self.last_version = max(now, self.last_version + 1);
+        self.last_version
+    }
+
+    /// Read the stale blocks which were recorded in the members by an earlier instance of the
+    /// mirror, if they haven't been read already.
+    fn load_stale(&mut self, stores: &mut [&mut dyn Member]) -> Result<(), MirrorError> {
+        if self.stale_loaded {
+            return Ok(());
+        }
+
+        let mut first_error = None;
+        let mut any_succeeded = false;
+
+        // Each member which was healthy when a block became stale has a copy of the record, so
+        // the records from every member are combined.
+        for (index, store) in stores.iter_mut().enumerate() {
+            match store.read(*STALE_BLOCK_ID) {
+                Ok(framed) => {
+                    any_succeeded = true;
+                    let stale = framed
+                        .and_then(unframe_block)
+                        .and_then(|data| from_read::<_, Vec<(usize, Uuid)>>(data.as_slice()).ok())
+                        .unwrap_or_default();
+                    for (member, id) in stale {
+                        if let Some(state) = self.members.get_mut(member) {
+                            state.stale.insert(id);
+                        }
+                    }
+                }
+                Err(error) => {
+                    self.members[index].healthy = false;
+                    first_error.get_or_insert(member_error(index, error));
+                }
+            }
+        }
+
+        match first_error {
+            Some(error) if !any_succeeded => Err(error),
+            _ => {
+                self.stale_loaded = true;
+                Ok(())
+            }
+        }
+    }
+
+    /// Record which blocks are stale in which members in every member which can be written to.
+    ///
+    /// This is done after a write or removal fails so that the stale copies are still not read if
+    /// the mirror is created again before it is resynced.
+    fn save_stale(&mut self, stores: &mut [&mut dyn Member]) {
+        let stale = self
+            .members
+            .iter()
+            .enumerate()
+            .flat_map(|(index, state)| state.stale.iter().map(move |id| (index, *id)))
+            .collect::<Vec<_>>();
+        let serialized = to_vec(&stale).expect("Could not serialize stale blocks.");
+        let framed = frame_block(&serialized, self.next_version());
+
+        for (index, store) in stores.iter_mut().enumerate() {
+            if store.write(*STALE_BLOCK_ID, &framed).is_err() {
+                self.members[index].healthy = false;
+            }
+        }
+    }
+
+    /// Return whether any member may be missing blocks or have out-of-date blocks.
+    fn is_degraded(&self) -> bool {
+        self.members
+            .iter()
+            .any(|member| !member.healthy || !member.stale.is_empty())
+    }
+
+    fn write_block(
+        &mut self,
+        stores: &mut [&mut dyn Member],
+        id: Uuid,
+        data: &[u8],
+    ) -> Result<(), MirrorError> {
+        self.load_stale(stores)?;
+        let framed = frame_block(data, self.next_version());
+        let mut first_error = None;
+
+        for (index, store) in stores.iter_mut().enumerate() {
+            let state = &mut self.members[index];
+            match store.write(id, &framed) {
+                Ok(()) => {
+                    state.healthy = true;
+                    state.stale.remove(&id);
+                }
+                Err(error) => {
+                    // This member may still have the old copy of the block, so it must not be
+                    // read from until it has been repaired.
+                    state.healthy = false;
+                    state.stale.insert(id);
+                    first_error.get_or_insert(member_error(index, error));
+                }
+            }
+        }
+
+        match first_error {
+            Some(error) => {
+                self.save_stale(stores);
+                Err(error)
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn read_block(
+        &mut self,
+        stores: &mut [&mut dyn Member],
+        id: Uuid,
+    ) -> Result<Option<Vec<u8>>, MirrorError> {
+        self.load_stale(stores)?;
+
+        // Try healthy members first, fastest first. Members which may have an out-of-date copy of
+        // this block are skipped unless every member might.
+        let mut order = (0..stores.len())
+            .filter(|index| !self.members[*index].stale.contains(&id))
+            .collect::<Vec<_>>();
+        if order.is_empty() {
+            order = (0..stores.len()).collect();
+        }
+        order.sort_by_key(|index| {
+            let state = &self.members[*index];
+            (!state.healthy, state.latency)
+        });
+
+        let mut first_error = None;
+        let mut found_corrupt = false;
+
+        for index in order {
+            let state = &mut self.members[index];
+            let start = Instant::now();
+            match stores[index].read(id) {
+                Ok(Some(framed)) => match unframe_block(framed) {
+                    Some(data) => {
+                        state.healthy = true;
+                        state.record_latency(start.elapsed());
+                        return Ok(Some(data));
+                    }
+                    None => {
+                        state.healthy = false;
+                        found_corrupt = true;
+                    }
+                },
+                Ok(None) => {
+                    state.record_latency(start.elapsed());
+                }
+                Err(error) => {
+                    state.healthy = false;
+                    first_error.get_or_insert(member_error(index, error));
+                }
+            }
+        }
+
+        // The block only doesn't exist if every member agrees that it doesn't.
+        if found_corrupt {
+            Err(MirrorError::Corrupt)
+        } else if let Some(error) = first_error {
+            Err(error)
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn remove_block(
+        &mut self,
+        stores: &mut [&mut dyn Member],
+        id: Uuid,
+    ) -> Result<(), MirrorError> {
+        self.load_stale(stores)?;
+        let mut first_error = None;
+
+        for (index, store) in stores.iter_mut().enumerate() {
+            let state = &mut self.members[index];
+            match store.remove(id) {
+                Ok(()) => {
+                    state.healthy = true;
+                    state.stale.remove(&id);
+                }
+                Err(error) => {
+                    state.healthy = false;
+                    state.stale.insert(id);
+                    first_error.get_or_insert(member_error(index, error));
+                }
+            }
+        }
+
+        match first_error {
+            Some(error) => {
+                self.save_stale(stores);
+                Err(error)
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn list_blocks(&mut self, stores: &mut [&mut dyn Member]) -> Result<Vec<Uuid>, MirrorError> {
+        let mut block_ids = HashSet::new();
+        let mut first_error = None;
+        let mut any_succeeded = false;
+
+        // A block which is missing from a degraded member is still in the mirror, so this lists
+        // every block in any member.
+        for (index, store) in stores.iter_mut().enumerate() {
+            match store.list() {
+                Ok(ids) => {
+                    any_succeeded = true;
+                    block_ids.extend(ids);
+                }
+                Err(error) => {
+                    self.members[index].healthy = false;
+                    first_error.get_or_insert(member_error(index, error));
+                }
+            }
+        }
+
+        block_ids.remove(&*STALE_BLOCK_ID);
+
+        match first_error {
+            Some(error) if !any_succeeded => Err(error),
+            _ => Ok(block_ids.into_iter().collect()),
+        }
+    }
+
+    fn resync(&mut self, stores: &mut [&mut dyn Member]) -> crate::Result<ResyncStats> {
+        let mut stats = ResyncStats::default();
+        self.load_stale(stores).map_err(anyhow::Error::from)?;
+
+        let mut block_ids = HashSet::new();
+        for store in stores.iter_mut() {
+            block_ids.extend(store.list()?);
+        }
+        block_ids.remove(&*STALE_BLOCK_ID);
+
+        for id in block_ids {
+            let mut copies = Vec::with_capacity(stores.len());
+            for store in stores.iter_mut() {
+                copies.push(store.read(id)?);
+            }
+
+            // Use the newest intact copy from a member where it isn't stale. If several members
+            // have a copy with the same version, the first one is used.
+            let authoritative = copies
+                .iter()
+                .enumerate()
+                .filter(|(index, _)| !self.members[*index].stale.contains(&id))
+                .filter_map(|(_, copy)| copy.as_ref())
+                .filter_map(|framed| frame_version(framed).map(|version| (version, framed)))
+                .fold(
+                    None,
+                    |newest: Option<(u64, &Vec<u8>)>, (version, framed)| match newest {
+                        Some((newest_version, _)) if newest_version >= version => newest,
+                        _ => Some((version, framed)),
+                    },
+                )
+                .map(|(_, framed)| framed.clone());
+
+            match authoritative {
+                Some(framed) => {
+                    for (index, copy) in copies.iter().enumerate() {
+                        if copy.as_ref() != Some(&framed) {
+                            stores[index].write(id, &framed)?;
+                            stats.repaired += 1;
+                        }
+                    }
+                }
+                None => {
+                    let is_removed = copies.iter().enumerate().all(|(index, copy)| {
+                        copy.is_none() || self.members[index].stale.contains(&id)
+                    });
+                    if is_removed {
+                        // The only copies are in members where writing or removing the block
+                        // failed, so the block was never stored persistently.
+                        for store in stores.iter_mut() {
+                            store.remove(id)?;
+                        }
+                        stats.removed += 1;
+                    } else {
+                        stats.unrecoverable += 1;
+                    }
+                }
+            }
+        }
+
+        for state in self.members.iter_mut() {
+            state.healthy = true;
+            state.stale.clear();
+        }
+        for store in stores.iter_mut() {
+            store.remove(*STALE_BLOCK_ID)?;
+        }
+
+        Ok(stats)
+    }
+}
+
+/// A `DataStore` which mirrors blocks between two other data stores for redundancy.
+///
+/// Every block is written to both the primary and secondary data store. Blocks are read from
+/// whichever member has been faster and healthier, and if a copy is missing or corrupt, the block
+/// is read from the other member instead. Each copy of a block is stored with a checksum so that
+/// corrupt copies can be detected and a version so that the newest copy can be found. This means a
+/// member can't be used on its own except through a mirror, and a data store which already
+/// contains blocks that weren't written through a mirror can't be added as a member; those blocks
+/// would be treated as corrupt.
+///
+/// If writing or removing a block fails on one member, the operation returns `Err` and the mirror
+/// is degraded. Until it is repaired with `resync`, the copy of that block in the member where the
+/// operation failed is not read from. This is recorded in the other members so that it still
+/// applies if the mirror is created again, as long as the members are passed in the same order.
+///
+/// Lock files are kept in the lock directory of the primary data store, or of the secondary data
+/// store if the primary doesn't have one.
+///
+/// See `MirrorSetStore` for mirroring blocks between more than two data stores.
+#[derive(Debug)]
+pub struct MirrorStore<A: DataStore, B: DataStore> {
+    primary: A,
+    secondary: B,
+    mirror: Mirror,
+}
+
+impl<A: DataStore, B: DataStore> MirrorStore<A, B> {
+    /// Return a new `MirrorStore` which mirrors blocks between `primary` and `secondary`.
+    ///
+    /// If the two data stores are not already mirrors of each other, use `resync` to copy blocks
+    /// between them.
+    pub fn new(primary: A, secondary: B) -> Self {
+        MirrorStore {
+            primary,
+            secondary,
+            mirror: Mirror::new(2),
+        }
+    }
+
+    /// Return a reference to the primary data store.
+    pub fn primary(&self) -> &A {
+        &self.primary
+    }
+
+    /// Return a mutable reference to the primary data store.
+    ///
+    /// Blocks written directly to this data store are not mirrored.
+    pub fn primary_mut(&mut self) -> &mut A {
+        &mut self.primary
+    }
+
+    /// Return a reference to the secondary data store.
+    pub fn secondary(&self) -> &B {
+        &self.secondary
+    }
+
+    /// Return a mutable reference to the secondary data store.
+    ///
+    /// Blocks written directly to this data store are not mirrored.
+    pub fn secondary_mut(&mut self) -> &mut B {
+        &mut self.secondary
+    }
+
+    /// Consume this store and return the primary and secondary data stores.
+    pub fn into_inner(self) -> (A, B) {
+        (self.primary, self.secondary)
+    }
+
+    /// Return whether either member may be missing blocks or have out-of-date blocks.
+    ///
+    /// This accounts for failures which have been observed since the store was created and, once
+    /// a block has been accessed, failures which were recorded in the members before that.
+    pub fn is_degraded(&self) -> bool {
+        self.mirror.is_degraded()
+    }
+
+    /// Repair blocks which are missing, corrupt, or out of date in either member.
+    ///
+    /// This reads every block from both members and rewrites copies which don't match. When the
+    /// two members have different intact copies of a block and neither is known to be out of
+    /// date, the copy with the newest version is used, regardless of which member it is in.
+    /// Versions are based on the system clock.
+    ///
+    /// # Errors
+    /// - `Error::Store`: An error occurred with a member of the mirror.
+    pub fn resync(&mut self) -> crate::Result<ResyncStats> {
+        let mut stores: [&mut dyn Member; 2] = [&mut self.primary, &mut self.secondary];
+        self.mirror.resync(&mut stores)
+    }
+}
+
+impl<A: DataStore, B: DataStore> DataStore for MirrorStore<A, B> {
+    type Error = MirrorError;
+
+    fn write_block(&mut self, id: Uuid, data: &[u8]) -> Result<(), Self::Error> {
+        let mut stores: [&mut dyn Member; 2] = [&mut self.primary, &mut self.secondary];
+        self.mirror.write_block(&mut stores, id, data)
+    }
+
+    fn read_block(&mut self, id: Uuid) -> Result<Option<Vec<u8>>, Self::Error> {
+        let mut stores: [&mut dyn Member; 2] = [&mut self.primary, &mut self.secondary];
+        self.mirror.read_block(&mut stores, id)
+    }
+
+    fn remove_block(&mut self, id: Uuid) -> Result<(), Self::Error> {
+        let mut stores: [&mut dyn Member; 2] = [&mut self.primary, &mut self.secondary];
+        self.mirror.remove_block(&mut stores, id)
+    }
+
+    fn list_blocks(&mut self) -> Result<Vec<Uuid>, Self::Error> {
+        let mut stores: [&mut dyn Member; 2] = [&mut self.primary, &mut self.secondary];
+        self.mirror.list_blocks(&mut stores)
+    }
+
+    fn lock_dir(&self) -> Option<PathBuf> {
+        self.primary
+            .lock_dir()
+            .or_else(|| self.secondary.lock_dir())
+    }
+}
+
+/// A `DataStore` which mirrors blocks between any number of data stores of the same type.
+///
+/// This behaves like `MirrorStore`, but with any number of members. Like with `MirrorStore`, a data
+/// store which already contains blocks that weren't written through a mirror can't be added as a
+/// member, and the members must be passed in the same order each time the mirror is created. Lock
+/// files are kept in the lock directory of the first member which has one.
+#[derive(Debug)]
+pub struct MirrorSetStore<S: DataStore> {
+    members: Vec<S>,
+    mirror: Mirror,
+}
+
+impl<S: DataStore> MirrorSetStore<S> {
+    /// Return a new `MirrorSetStore` which mirrors blocks between `members`.
+    ///
+    /// If the data stores are not already mirrors of each other, use `resync` to copy blocks
+    /// between them.
+    pub fn new(members: Vec<S>) -> Self {
+        let len = members.len();
+        MirrorSetStore {
+            members,
+            mirror: Mirror::new(len),
+        }
+    }
+
+    /// Return the members of the mirror.
+    pub fn members(&self) -> &[S] {
+        &self.members
+    }
+
+    /// Return the members of the mirror mutably.
+    ///
+    /// Blocks written directly to these data stores are not mirrored.
+    pub fn members_mut(&mut self) -> &mut [S] {
+        &mut self.members
+    }
+
+    /// Consume this store and return its members.
+    pub fn into_inner(self) -> Vec<S> {
+        self.members
+    }
+
+    /// Return whether any member may be missing blocks or have out-of-date blocks.
+    ///
+    /// See `MirrorStore::is_degraded` for details.
+    pub fn is_degraded(&self) -> bool {
+        self.mirror.is_degraded()
+    }
+
+    /// Repair blocks which are missing, corrupt, or out of date in any member.
+    ///
+    /// See `MirrorStore::resync` for details.
+    ///
+    /// # Errors
+    /// - `Error::Store`: An error occurred with a member of the mirror.
+    pub fn resync(&mut self) -> crate::Result<ResyncStats> {
+        let (members, mirror) = (&mut self.members, &mut self.mirror);
+        mirror.resync(&mut as_members(members))
+    }
+}
+
+/// Return `stores` as a list of mirror members.
+fn as_members<S: DataStore>(stores: &mut [S]) -> Vec<&mut dyn Member> {
+    stores
+        .iter_mut()
+        .map(|store| store as &mut dyn Member)
+        .collect()
+}
+
+impl<S: DataStore> DataStore for MirrorSetStore<S> {
+    type Error = MirrorError;
+
+    fn write_block(&mut self, id: Uuid, data: &[u8]) -> Result<(), Self::Error> {
+        self.mirror
+            .write_block(&mut as_members(&mut self.members), id, data)
+    }
+
+    fn read_block(&mut self, id: Uuid) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.mirror
+            .read_block(&mut as_members(&mut self.members), id)
+    }
+
+    fn remove_block(&mut self, id: Uuid) -> Result<(), Self::Error> {
+        self.mirror
+            .remove_block(&mut as_members(&mut self.members), id)
+    }
+
+    fn list_blocks(&mut self) -> Result<Vec<Uuid>, Self::Error> {
+        self.mirror.list_blocks(&mut as_members(&mut self.members))
+    }
+
+    fn lock_dir(&self) -> Option<PathBuf> {
+        self.members.iter().find_map(|member| member.lock_dir())
+    }
+}
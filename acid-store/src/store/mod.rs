@@ -29,7 +29,10 @@
 //! module.
 //!
 //! This module additionally provides `MultiStore` which allows for storing multiple repositories in
-//! a single data store.
+//! a single data store, `MirrorStore` and `MirrorSetStore` which mirror blocks between multiple
+//! data stores for redundancy, `ErasureStore` which splits blocks between multiple data stores
+//! with erasure coding, `CachedStore` which caches blocks from slow data stores, and
+//! `LocalLockStore` which keeps the lock files for repositories in a given directory.
 //!
 //! # Examples
 //! Open a data store which stores data in a directory of the local file system. Create the data
@@ -45,10 +48,19 @@
 
 pub use multi::{MultiStore, ProxyStore};
 
+pub use self::cached::{CacheConfig, CacheLocation, CacheStats, CachedStore};
 pub use self::common::{DataStore, OpenOption, OpenStore};
 #[cfg(feature = "store-directory")]
 pub use self::directory::DirectoryStore;
+#[cfg(feature = "store-erasure")]
+pub use self::erasure::{ErasureError, ErasureStore, RepairStats};
+#[cfg(feature = "store-faulty")]
+pub use self::faulty::{FaultConfig, FaultyStore, FaultyStoreError};
+pub use self::local_lock::LocalLockStore;
 pub use self::memory::MemoryStore;
+pub use self::mirror::{MirrorError, MirrorSetStore, MirrorStore, ResyncStats};
+#[cfg(feature = "store-pack")]
+pub use self::pack::{PackConfig, PackStore};
 #[cfg(all(unix, feature = "store-rclone"))]
 pub use self::rclone::RcloneStore;
 #[cfg(feature = "store-redis")]
@@ -58,10 +70,16 @@ pub use self::s3::S3Store;
 #[cfg(feature = "store-sqlite")]
 pub use self::sqlite::SqliteStore;
 
+mod cached;
 mod common;
 mod directory;
+mod erasure;
+mod faulty;
+mod local_lock;
 mod memory;
+mod mirror;
 mod multi;
+mod pack;
 mod rclone;
 mod redis;
 mod s3;
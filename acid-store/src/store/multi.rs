@@ -17,6 +17,7 @@
 use std::borrow::Borrow;
 use std::collections::{HashMap, HashSet};
 use std::hash::Hash;
+use std::path::PathBuf;
 use std::sync::Mutex;
 
 use rmp_serde::{from_read, to_vec};
@@ -147,6 +148,66 @@ impl<'a, S: DataStore> DataStore for ProxyStore<'a, S> {
         self.write_table()
     }
 
+    fn write_blocks(&mut self, blocks: &[(Uuid, &[u8])]) -> Result<(), Self::Error> {
+        let backing_blocks = blocks
+            .iter()
+            .map(|(id, data)| {
+                let backing_id = Uuid::new_v4();
+                self.id_table.insert(*id, backing_id);
+                (backing_id, *data)
+            })
+            .collect::<Vec<_>>();
+
+        // Like in `write_block`, we must write the ID table before the blocks, but we only need
+        // to write it once for the whole batch.
+        self.write_table()?;
+        self.store.lock().unwrap().write_blocks(&backing_blocks)
+    }
+
+    fn read_blocks(&mut self, ids: &[Uuid]) -> Result<Vec<Option<Vec<u8>>>, Self::Error> {
+        let mapped_ids = ids
+            .iter()
+            .map(|id| self.id_table.get(id).copied())
+            .collect::<Vec<_>>();
+        let backing_ids = mapped_ids.iter().flatten().copied().collect::<Vec<_>>();
+        let mut backing_blocks = self
+            .store
+            .lock()
+            .unwrap()
+            .read_blocks(&backing_ids)?
+            .into_iter();
+
+        let mut blocks = Vec::with_capacity(ids.len());
+        for (id, backing_id) in ids.iter().zip(mapped_ids) {
+            if backing_id.is_none() {
+                blocks.push(None);
+                continue;
+            }
+            let block = backing_blocks.next().unwrap();
+            if block.is_none() {
+                // See `read_block` for why we remove the block from the ID table.
+                self.id_table.remove(id);
+            }
+            blocks.push(block);
+        }
+
+        Ok(blocks)
+    }
+
+    fn remove_blocks(&mut self, ids: &[Uuid]) -> Result<(), Self::Error> {
+        let backing_ids = ids
+            .iter()
+            .filter_map(|id| self.id_table.remove(id))
+            .collect::<Vec<_>>();
+        if backing_ids.is_empty() {
+            return Ok(());
+        }
+
+        // Like in `remove_block`, we must remove the blocks before we write the ID table.
+        self.store.lock().unwrap().remove_blocks(&backing_ids)?;
+        self.write_table()
+    }
+
     fn list_blocks(&mut self) -> Result<Vec<Uuid>, Self::Error> {
         let backing_ids = self
             .store
@@ -176,6 +237,10 @@ impl<'a, S: DataStore> DataStore for ProxyStore<'a, S> {
 
         Ok(block_ids)
     }
+
+    fn lock_dir(&self) -> Option<PathBuf> {
+        self.store.lock().unwrap().lock_dir()
+    }
 }
 
 /// A multiplexer for storing multiple repositories in one data store.
@@ -0,0 +1,701 @@
+/*
+ * Copyright 2019-2020 Wren Powell
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+#![cfg(feature = "store-pack")]
+
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fs::{create_dir, create_dir_all, read_dir, remove_dir_all, remove_file, rename, File};
+use std::fs::{read, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{sync_channel, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use blake2::digest::{Input, VariableOutput};
+use blake2::VarBlake2b;
+use rmp_serde::{from_read, to_vec};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use super::common::{DataStore, OpenOption, OpenStore};
+
+/// A UUID which acts as the version ID of the pack store format.
+const CURRENT_VERSION: &str = "5f0d7c2a-cd61-11f1-9a4e-02fc00000001";
+
+/// The names of files in the data store.
+const PACKS_DIRECTORY: &str = "packs";
+const JOURNAL_FILE: &str = "journal";
+const STAGING_JOURNAL_FILE: &str = "journal.tmp";
+const VERSION_FILE: &str = "version";
+
+/// The default maximum size of a pack file in bytes.
+const DEFAULT_PACK_SIZE: u64 = 64 * 1024 * 1024;
+
+/// The size of the checksum of each journal entry in bytes.
+const CHECKSUM_SIZE: usize = 8;
+
+/// The size of the header of each journal entry in bytes.
+const ENTRY_HEADER_SIZE: usize = 4 + CHECKSUM_SIZE;
+
+/// The number of superseded journal entries which are allowed before the journal is rewritten.
+const JOURNAL_SLACK: u64 = 1024;
+
+/// The configuration for opening a `PackStore`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PackConfig {
+    /// The path of the store's root directory.
+    pub path: PathBuf,
+
+    /// The size in bytes a pack file can grow to before a new one is started.
+    ///
+    /// Blocks are never split across pack files, so a pack file can be larger than this if a
+    /// large block is written to it.
+    pub pack_size: u64,
+}
+
+impl PackConfig {
+    /// Return a new config for a store at the given `path` with the default pack size of 64MiB.
+    pub fn new(path: PathBuf) -> Self {
+        PackConfig {
+            path,
+            pack_size: DEFAULT_PACK_SIZE,
+        }
+    }
+}
+
+/// The location of a block in a pack file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+struct BlockLocation {
+    /// The ID of the pack file the block is stored in.
+    pack: Uuid,
+
+    /// The offset of the block from the start of the pack file.
+    offset: u64,
+
+    /// The size of the block in bytes.
+    size: u64,
+}
+
+/// An entry in the journal of a `PackStore`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+enum JournalEntry {
+    /// A block was written to a pack file.
+    Write { id: Uuid, location: BlockLocation },
+
+    /// A block was removed.
+    Remove { id: Uuid },
+}
+
+/// The amount of data stored in a pack file.
+#[derive(Debug, Clone, Copy, Default)]
+struct PackUsage {
+    /// The size of the pack file in bytes.
+    total: u64,
+
+    /// The combined size of the blocks in the pack file which are still in use.
+    live: u64,
+}
+
+/// The state of a `PackStore`, which is shared with the thread which compacts pack files.
+#[derive(Debug)]
+struct PackState {
+    /// The path of the store's root directory.
+    path: PathBuf,
+
+    /// The size a pack file can grow to before a new one is started.
+    pack_size: u64,
+
+    /// The location of each block in the store.
+    index: HashMap<Uuid, BlockLocation>,
+
+    /// The amount of data stored in each pack file.
+    packs: HashMap<Uuid, PackUsage>,
+
+    /// The ID of the pack file which blocks are currently being appended to and its open file.
+    current_pack: Option<(Uuid, File)>,
+
+    /// The journal, opened for appending.
+    journal: File,
+
+    /// The number of entries in the journal.
+    journal_entries: u64,
+}
+
+impl PackState {
+    /// Open the store at `path`, replaying its journal to rebuild the index.
+    fn open(path: PathBuf, pack_size: u64) -> io::Result<Self> {
+        let journal_path = path.join(JOURNAL_FILE);
+        let journal_bytes = read(&journal_path)?;
+
+        // Replay the journal. An entry which is incomplete or corrupt was being written when the
+        // store was interrupted, so it and anything after it are discarded.
+        let mut index = HashMap::new();
+        let mut journal_entries = 0u64;
+        let mut valid_len = 0usize;
+        while let Some((entry, entry_len)) = decode_entry(&journal_bytes[valid_len..]) {
+            match entry {
+                JournalEntry::Write { id, location } => index.insert(id, location),
+                JournalEntry::Remove { id } => index.remove(&id),
+            };
+            journal_entries += 1;
+            valid_len += entry_len;
+        }
+
+        let journal = OpenOptions::new().append(true).open(&journal_path)?;
+        if valid_len < journal_bytes.len() {
+            journal.set_len(valid_len as u64)?;
+            journal.sync_data()?;
+        }
+
+        // Find how much of each pack file is still in use, and remove pack files which aren't.
+        let mut packs = HashMap::new();
+        for entry in read_dir(path.join(PACKS_DIRECTORY))? {
+            let entry = entry?;
+            let pack_id = entry
+                .file_name()
+                .to_str()
+                .and_then(|name| Uuid::parse_str(name).ok())
+                .ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidData, "Pack file name is invalid.")
+                })?;
+            let usage = PackUsage {
+                total: entry.metadata()?.len(),
+                live: 0,
+            };
+            packs.insert(pack_id, usage);
+        }
+        for location in index.values() {
+            let usage = packs.get_mut(&location.pack).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, "A pack file is missing.")
+            })?;
+            usage.live += location.size;
+        }
+
+        let mut state = PackState {
+            path,
+            pack_size,
+            index,
+            packs,
+            current_pack: None,
+            journal,
+            journal_entries,
+        };
+
+        let unused_packs = state
+            .packs
+            .iter()
+            .filter(|(_, usage)| usage.live == 0)
+            .map(|(pack_id, _)| *pack_id)
+            .collect::<Vec<_>>();
+        for pack_id in unused_packs {
+            state.remove_pack(pack_id)?;
+        }
+
+        Ok(state)
+    }
+
+    /// Return the path of the pack file with the given `id`.
+    fn pack_path(&self, id: Uuid) -> PathBuf {
+        let mut buffer = Uuid::encode_buffer();
+        let hex = id.to_simple().encode_lower(&mut buffer);
+        self.path.join(PACKS_DIRECTORY).join(hex)
+    }
+
+    /// Return the pack file which new blocks should be appended to.
+    ///
+    /// This starts a new pack file if the current one is full or there isn't one. Blocks are never
+    /// appended to an existing pack file other than the current one, because it may be a sparse
+    /// pack file which is being compacted.
+    fn current_pack(&mut self) -> io::Result<(Uuid, &mut File)> {
+        let is_full = match &self.current_pack {
+            Some((pack_id, _)) => self.packs[pack_id].total >= self.pack_size,
+            None => true,
+        };
+
+        if is_full {
+            let pack_id = Uuid::new_v4();
+            let file = OpenOptions::new()
+                .create_new(true)
+                .append(true)
+                .open(self.pack_path(pack_id))?;
+            self.packs.insert(pack_id, PackUsage::default());
+            self.current_pack = Some((pack_id, file));
+        }
+
+        let (pack_id, file) = self.current_pack.as_mut().unwrap();
+        Ok((*pack_id, file))
+    }
+
+    /// Append `entries` to the journal and persist them together.
+    fn append_entries(&mut self, entries: &[JournalEntry]) -> io::Result<()> {
+        let buffer = entries.iter().flat_map(encode_entry).collect::<Vec<_>>();
+        self.journal.write_all(&buffer)?;
+        self.journal.sync_data()?;
+        self.journal_entries += entries.len() as u64;
+        Ok(())
+    }
+
+    /// Remove the pack file with the given `id`, which must not contain any blocks in use.
+    fn remove_pack(&mut self, id: Uuid) -> io::Result<()> {
+        if let Some((current_id, _)) = &self.current_pack {
+            if *current_id == id {
+                self.current_pack = None;
+            }
+        }
+        self.packs.remove(&id);
+        match remove_file(self.pack_path(id)) {
+            Err(error) if error.kind() != io::ErrorKind::NotFound => Err(error),
+            _ => Ok(()),
+        }
+    }
+
+    /// Record that the block at `location` is no longer in use.
+    fn release(&mut self, location: BlockLocation) -> io::Result<()> {
+        let usage = self.packs.get_mut(&location.pack).unwrap();
+        usage.live -= location.size;
+
+        // A pack file with nothing in it can be removed without copying anything.
+        let is_current = match &self.current_pack {
+            Some((pack_id, _)) => *pack_id == location.pack,
+            None => false,
+        };
+        if usage.live == 0 && !is_current {
+            self.remove_pack(location.pack)?;
+        }
+
+        Ok(())
+    }
+
+    fn write_block(&mut self, id: Uuid, data: &[u8]) -> io::Result<()> {
+        self.write_blocks(&[(id, data)])
+    }
+
+    fn write_blocks(&mut self, blocks: &[(Uuid, &[u8])]) -> io::Result<()> {
+        // Append the data to the pack files and make sure it is persisted before it is referenced
+        // by the journal. A pack file is synced once it is full, since the next block will be
+        // appended to a different one, and once all the blocks have been appended.
+        let mut entries = Vec::with_capacity(blocks.len());
+        for (index, (id, data)) in blocks.iter().enumerate() {
+            let pack_size = self.pack_size;
+            let (pack_id, file) = self.current_pack()?;
+            let offset = file.seek(SeekFrom::End(0))?;
+            let result = file.write_all(data);
+            let total = file.seek(SeekFrom::End(0))?;
+            let result = result.and_then(|_| {
+                if total >= pack_size || index == blocks.len() - 1 {
+                    file.sync_data()
+                } else {
+                    Ok(())
+                }
+            });
+            self.packs.get_mut(&pack_id).unwrap().total = total;
+            result?;
+
+            let location = BlockLocation {
+                pack: pack_id,
+                offset,
+                size: data.len() as u64,
+            };
+            entries.push(JournalEntry::Write { id: *id, location });
+        }
+
+        // The blocks are not written until their journal entries are persisted.
+        self.append_entries(&entries)?;
+
+        for entry in entries {
+            if let JournalEntry::Write { id, location } = entry {
+                self.packs.get_mut(&location.pack).unwrap().live += location.size;
+                if let Some(old_location) = self.index.insert(id, location) {
+                    self.release(old_location)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn read_block(&self, id: Uuid) -> io::Result<Option<Vec<u8>>> {
+        let location = match self.index.get(&id) {
+            Some(location) => *location,
+            None => return Ok(None),
+        };
+
+        let mut file = File::open(self.pack_path(location.pack))?;
+        file.seek(SeekFrom::Start(location.offset))?;
+        let mut buffer = vec![0u8; location.size as usize];
+        file.read_exact(&mut buffer)?;
+        Ok(Some(buffer))
+    }
+
+    fn remove_block(&mut self, id: Uuid) -> io::Result<()> {
+        self.remove_blocks(&[id])
+    }
+
+    fn remove_blocks(&mut self, ids: &[Uuid]) -> io::Result<()> {
+        let mut ids = ids
+            .iter()
+            .copied()
+            .filter(|id| self.index.contains_key(id))
+            .collect::<Vec<_>>();
+        ids.sort_unstable();
+        ids.dedup();
+        if ids.is_empty() {
+            return Ok(());
+        }
+
+        let entries = ids
+            .iter()
+            .map(|&id| JournalEntry::Remove { id })
+            .collect::<Vec<_>>();
+        self.append_entries(&entries)?;
+
+        for id in ids {
+            let location = self.index.remove(&id).unwrap();
+            self.release(location)?;
+        }
+
+        Ok(())
+    }
+
+    /// Return the pack files which have enough unused space that they should be compacted.
+    fn sparse_packs(&self) -> Vec<Uuid> {
+        let current_id = self.current_pack.as_ref().map(|(pack_id, _)| *pack_id);
+        self.packs
+            .iter()
+            .filter(|(pack_id, usage)| {
+                Some(**pack_id) != current_id && usage.live * 2 < usage.total
+            })
+            .map(|(pack_id, _)| *pack_id)
+            .collect()
+    }
+
+    /// Return whether the journal has enough superseded entries that it should be rewritten.
+    fn journal_is_sparse(&self) -> bool {
+        self.journal_entries > self.index.len() as u64 * 2 + JOURNAL_SLACK
+    }
+
+    /// Return whether there is anything to compact.
+    fn needs_compaction(&self) -> bool {
+        !self.sparse_packs().is_empty() || self.journal_is_sparse()
+    }
+
+    /// Copy the blocks in sparse pack files to the current pack file and remove them.
+    fn compact(&mut self) -> io::Result<()> {
+        while self.compact_next()? {}
+        Ok(())
+    }
+
+    /// Compact one sparse pack file or, if there are none, rewrite the journal if it is sparse.
+    ///
+    /// This returns whether there was anything to compact. Compacting one pack file at a time
+    /// lets the compaction thread release the lock on the store in between.
+    fn compact_next(&mut self) -> io::Result<bool> {
+        let pack_id = match self.sparse_packs().into_iter().next() {
+            Some(pack_id) => pack_id,
+            None if self.journal_is_sparse() => {
+                self.rewrite_journal()?;
+                return Ok(true);
+            }
+            None => return Ok(false),
+        };
+
+        let block_ids = self
+            .index
+            .iter()
+            .filter(|(_, location)| location.pack == pack_id)
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        let blocks = block_ids
+            .iter()
+            .map(|&id| Ok((id, self.read_block(id)?.unwrap())))
+            .collect::<io::Result<Vec<_>>>()?;
+
+        // Rewriting the blocks moves them to the current pack file, which is never a sparse one.
+        // The pack file is removed once the last block is moved out of it.
+        let blocks = blocks
+            .iter()
+            .map(|(id, data)| (*id, data.as_slice()))
+            .collect::<Vec<_>>();
+        self.write_blocks(&blocks)?;
+
+        Ok(true)
+    }
+
+    /// Replace the journal with one which only contains an entry for each block in the store.
+    fn rewrite_journal(&mut self) -> io::Result<()> {
+        let staging_path = self.path.join(STAGING_JOURNAL_FILE);
+        let journal_path = self.path.join(JOURNAL_FILE);
+
+        let mut staging_file = File::create(&staging_path)?;
+        for (id, location) in self.index.iter() {
+            let entry = JournalEntry::Write {
+                id: *id,
+                location: *location,
+            };
+            staging_file.write_all(&encode_entry(&entry))?;
+        }
+        staging_file.sync_all()?;
+        drop(staging_file);
+
+        // Atomically replace the old journal.
+        rename(&staging_path, &journal_path)?;
+        self.journal = OpenOptions::new().append(true).open(&journal_path)?;
+        self.journal_entries = self.index.len() as u64;
+
+        Ok(())
+    }
+
+    /// Remove all the blocks in the store.
+    fn truncate(&mut self) -> io::Result<()> {
+        // The empty journal is persisted before the pack files are removed so that the journal
+        // never references a missing pack file. If the store is interrupted before the pack files
+        // are removed, they are removed the next time it is opened because nothing uses them.
+        self.index.clear();
+        self.rewrite_journal()?;
+
+        self.current_pack = None;
+        self.packs.clear();
+        remove_dir_all(self.path.join(PACKS_DIRECTORY))?;
+        create_dir(self.path.join(PACKS_DIRECTORY))
+    }
+}
+
+/// Compute a checksum of a serialized journal entry.
+fn entry_checksum(data: &[u8]) -> [u8; CHECKSUM_SIZE] {
+    let mut hasher = VarBlake2b::new(CHECKSUM_SIZE).unwrap();
+    hasher.input(data);
+    let mut checksum = [0u8; CHECKSUM_SIZE];
+    hasher.variable_result(|result| checksum.copy_from_slice(result));
+    checksum
+}
+
+/// Serialize a journal `entry` along with its length and checksum.
+fn encode_entry(entry: &JournalEntry) -> Vec<u8> {
+    let serialized_entry = to_vec(entry).expect("Could not serialize journal entry.");
+    let mut buffer = Vec::with_capacity(ENTRY_HEADER_SIZE + serialized_entry.len());
+    buffer.extend_from_slice(&(serialized_entry.len() as u32).to_le_bytes());
+    buffer.extend_from_slice(&entry_checksum(&serialized_entry));
+    buffer.extend_from_slice(&serialized_entry);
+    buffer
+}
+
+/// Deserialize the journal entry at the start of `data` and return it along with its length.
+///
+/// This returns `None` if `data` does not start with a complete and valid entry.
+fn decode_entry(data: &[u8]) -> Option<(JournalEntry, usize)> {
+    if data.len() < ENTRY_HEADER_SIZE {
+        return None;
+    }
+    let entry_len = u32::from_le_bytes(data[..4].try_into().unwrap()) as usize;
+    let checksum = &data[4..ENTRY_HEADER_SIZE];
+    let serialized_entry = data.get(ENTRY_HEADER_SIZE..ENTRY_HEADER_SIZE + entry_len)?;
+    if entry_checksum(serialized_entry) != checksum {
+        return None;
+    }
+    let entry = from_read(serialized_entry).ok()?;
+    Some((entry, ENTRY_HEADER_SIZE + entry_len))
+}
+
+/// A `DataStore` which stores data in large pack files in a directory in the local file system.
+///
+/// Unlike `DirectoryStore`, which stores each block in its own file, this store appends blocks to
+/// pack files which can each hold many blocks. This avoids creating a large number of small files
+/// when blocks are small.
+///
+/// The location of each block is recorded in an append-only journal. A block is not written or
+/// removed until its journal entry has been persisted, which makes `write_block` and
+/// `remove_block` atomic. If the store is interrupted while a journal entry is being written, the
+/// incomplete entry is discarded the next time the store is opened.
+///
+/// Removing a block does not immediately free the space it used. When enough of a pack file is
+/// unused, a background thread copies the blocks which are still in use to another pack file and
+/// removes it. This can also be done on demand with `compact`.
+#[derive(Debug)]
+pub struct PackStore {
+    /// The state of the store, which is shared with the compaction thread.
+    state: Arc<Mutex<PackState>>,
+
+    /// A channel for waking the compaction thread and the thread itself.
+    compactor: Option<(SyncSender<()>, JoinHandle<()>)>,
+}
+
+impl PackStore {
+    /// Create a new `PackStore` at the path in `config`.
+    fn create_new(config: PackConfig) -> crate::Result<Self> {
+        // Create the directories in the data store.
+        create_dir_all(&config.path)?;
+        create_dir(config.path.join(PACKS_DIRECTORY))?;
+        File::create(config.path.join(JOURNAL_FILE))?;
+
+        // Write the version ID file.
+        let mut version_file = File::create(config.path.join(VERSION_FILE))?;
+        version_file.write_all(CURRENT_VERSION.as_bytes())?;
+
+        Self::open_existing(config)
+    }
+
+    /// Open an existing `PackStore` at the path in `config`.
+    fn open_existing(config: PackConfig) -> crate::Result<Self> {
+        // Read the version ID file.
+        let mut version_file = File::open(config.path.join(VERSION_FILE))?;
+        let mut version_id = String::new();
+        version_file.read_to_string(&mut version_id)?;
+
+        // Verify the version ID.
+        if version_id != CURRENT_VERSION {
+            return Err(crate::Error::UnsupportedFormat);
+        }
+
+        let state = Arc::new(Mutex::new(PackState::open(config.path, config.pack_size)?));
+
+        // Start the thread which compacts pack files. Signals are coalesced so that the thread
+        // isn't woken more than necessary.
+        let (sender, receiver) = sync_channel::<()>(1);
+        let thread_state = Arc::clone(&state);
+        let handle = thread::Builder::new()
+            .name(String::from("acid-store-pack-compactor"))
+            .spawn(move || {
+                for () in receiver {
+                    // The lock is released after each pack file so that the store can be used
+                    // while it is being compacted. Compaction is retried the next time the thread
+                    // is woken if it fails.
+                    while let Ok(mut state) = thread_state.lock() {
+                        match state.compact_next() {
+                            Ok(true) => continue,
+                            _ => break,
+                        }
+                    }
+                }
+            })?;
+
+        Ok(PackStore {
+            state,
+            compactor: Some((sender, handle)),
+        })
+    }
+
+    /// Return the path of the store's root directory.
+    pub fn path(&self) -> PathBuf {
+        self.state.lock().unwrap().path.clone()
+    }
+
+    /// Copy the blocks in sparse pack files to another pack file and remove them.
+    ///
+    /// This is normally done automatically in the background. A pack file is compacted when
+    /// less than half of it is in use.
+    ///
+    /// # Errors
+    /// - `Error::Io`: An I/O error occurred.
+    pub fn compact(&mut self) -> crate::Result<()> {
+        Ok(self.state.lock().unwrap().compact()?)
+    }
+
+    /// Wake the compaction thread if there is anything to compact.
+    fn schedule_compaction(&self, state: &PackState) {
+        if state.needs_compaction() {
+            if let Some((sender, _)) = &self.compactor {
+                // If the thread has already been woken, there's no need to wake it again.
+                sender.try_send(()).ok();
+            }
+        }
+    }
+}
+
+impl Drop for PackStore {
+    fn drop(&mut self) {
+        if let Some((sender, handle)) = self.compactor.take() {
+            // Dropping the sender stops the compaction thread once it's done.
+            drop(sender);
+            handle.join().ok();
+        }
+    }
+}
+
+impl OpenStore for PackStore {
+    type Config = PackConfig;
+
+    fn open(config: Self::Config, options: OpenOption) -> crate::Result<Self>
+    where
+        Self: Sized,
+    {
+        let path: &Path = &config.path;
+        let exists = path.is_file() || (path.is_dir() && path.read_dir()?.next().is_some());
+
+        if options.contains(OpenOption::CREATE_NEW) {
+            if exists {
+                Err(crate::Error::AlreadyExists)
+            } else {
+                Self::create_new(config)
+            }
+        } else if options.contains(OpenOption::CREATE) && !exists {
+            Self::create_new(config)
+        } else {
+            if !exists {
+                return Err(crate::Error::NotFound);
+            }
+
+            let store = Self::open_existing(config)?;
+
+            if options.contains(OpenOption::TRUNCATE) {
+                store.state.lock().unwrap().truncate()?;
+            }
+
+            Ok(store)
+        }
+    }
+}
+
+impl DataStore for PackStore {
+    type Error = io::Error;
+
+    fn write_block(&mut self, id: Uuid, data: &[u8]) -> Result<(), Self::Error> {
+        let mut state = self.state.lock().unwrap();
+        state.write_block(id, data)?;
+        self.schedule_compaction(&state);
+        Ok(())
+    }
+
+    fn read_block(&mut self, id: Uuid) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.state.lock().unwrap().read_block(id)
+    }
+
+    fn remove_block(&mut self, id: Uuid) -> Result<(), Self::Error> {
+        let mut state = self.state.lock().unwrap();
+        state.remove_block(id)?;
+        self.schedule_compaction(&state);
+        Ok(())
+    }
+
+    fn write_blocks(&mut self, blocks: &[(Uuid, &[u8])]) -> Result<(), Self::Error> {
+        let mut state = self.state.lock().unwrap();
+        state.write_blocks(blocks)?;
+        self.schedule_compaction(&state);
+        Ok(())
+    }
+
+    fn remove_blocks(&mut self, ids: &[Uuid]) -> Result<(), Self::Error> {
+        let mut state = self.state.lock().unwrap();
+        state.remove_blocks(ids)?;
+        self.schedule_compaction(&state);
+        Ok(())
+    }
+
+    fn list_blocks(&mut self) -> Result<Vec<Uuid>, Self::Error> {
+        Ok(self.state.lock().unwrap().index.keys().copied().collect())
+    }
+}
@@ -18,7 +18,7 @@
 
 use std::fmt::{self, Debug, Formatter};
 
-use redis::{Client, Commands, Connection, ConnectionInfo, RedisError};
+use redis::{Client, Commands, Connection, ConnectionInfo, PipelineCommands, RedisError};
 use uuid::Uuid;
 
 use crate::store::common::{DataStore, OpenOption, OpenStore};
@@ -112,4 +112,39 @@ impl DataStore for RedisStore {
             .collect();
         Ok(blocks)
     }
+
+    fn write_blocks(&mut self, blocks: &[(Uuid, &[u8])]) -> Result<(), Self::Error> {
+        let mut pipeline = redis::pipe();
+        for (id, data) in blocks {
+            let key_id = id.to_hyphenated().to_string();
+            pipeline.set(format!("block:{}", key_id), *data).ignore();
+        }
+        pipeline.query::<()>(&mut self.connection)
+    }
+
+    fn read_blocks(&mut self, ids: &[Uuid]) -> Result<Vec<Option<Vec<u8>>>, Self::Error> {
+        // `MGET` requires at least one key.
+        if ids.is_empty() {
+            return Ok(Vec::new());
+        }
+        let keys = ids
+            .iter()
+            .map(|id| format!("block:{}", id.to_hyphenated().to_string()))
+            .collect::<Vec<_>>();
+        redis::cmd("MGET")
+            .arg(keys)
+            .query::<Vec<Option<Vec<u8>>>>(&mut self.connection)
+    }
+
+    fn remove_blocks(&mut self, ids: &[Uuid]) -> Result<(), Self::Error> {
+        // `DEL` requires at least one key.
+        if ids.is_empty() {
+            return Ok(());
+        }
+        let keys = ids
+            .iter()
+            .map(|id| format!("block:{}", id.to_hyphenated().to_string()))
+            .collect::<Vec<_>>();
+        self.connection.del::<_, ()>(keys)
+    }
 }
@@ -37,6 +37,10 @@ const BINARY_CONTENT_TYPE: &str = "application/octet-stream";
 const NOT_FOUND_CODE: u16 = 404;
 
 /// A `DataStore` which stores data in an Amazon S3 bucket.
+///
+/// This store doesn't override the batch methods of `DataStore`. The version of `rust-s3` it uses
+/// doesn't support the multi-object delete API, so `remove_blocks` sends one request per block,
+/// like `write_blocks` and `read_blocks`.
 #[derive(Debug)]
 pub struct S3Store {
     bucket: Bucket,
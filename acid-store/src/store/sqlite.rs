@@ -189,4 +189,52 @@ impl DataStore for SqliteStore {
 
         Ok(result)
     }
+
+    fn write_blocks(&mut self, blocks: &[(Uuid, &[u8])]) -> Result<(), Self::Error> {
+        let transaction = self.connection.transaction()?;
+        {
+            let mut statement = transaction.prepare(
+                r#"
+                    REPLACE INTO Blocks (uuid, data)
+                    VALUES (?1, ?2);
+                "#,
+            )?;
+            for (id, data) in blocks {
+                statement.execute(params![&id.as_bytes()[..], *data])?;
+            }
+        }
+        transaction.commit()
+    }
+
+    fn read_blocks(&mut self, ids: &[Uuid]) -> Result<Vec<Option<Vec<u8>>>, Self::Error> {
+        let mut statement = self.connection.prepare(
+            r#"
+                SELECT data FROM Blocks
+                WHERE uuid = ?1;
+            "#,
+        )?;
+        ids.iter()
+            .map(|id| {
+                statement
+                    .query_row(params![&id.as_bytes()[..]], |row| row.get(0))
+                    .optional()
+            })
+            .collect()
+    }
+
+    fn remove_blocks(&mut self, ids: &[Uuid]) -> Result<(), Self::Error> {
+        let transaction = self.connection.transaction()?;
+        {
+            let mut statement = transaction.prepare(
+                r#"
+                    DELETE FROM Blocks
+                    WHERE uuid = ?1;
+                "#,
+            )?;
+            for id in ids {
+                statement.execute(params![&id.as_bytes()[..]])?;
+            }
+        }
+        transaction.commit()
+    }
 }
@@ -0,0 +1,170 @@
+/*
+ * Copyright 2019-2020 Wren Powell
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+#![cfg(all(feature = "encryption", feature = "compression"))]
+
+use std::fs::read_dir;
+use std::io::{Read, Write};
+
+use tempfile::tempdir;
+use uuid::Uuid;
+
+use acid_store::repo::{LockStrategy, ObjectRepository, OpenRepo};
+use acid_store::store::{CacheConfig, CachedStore, DataStore, MemoryStore};
+use common::{random_buffer, random_bytes, PASSWORD, REPO_CONFIG};
+
+mod common;
+
+#[test]
+fn repeated_reads_hit_cache() -> anyhow::Result<()> {
+    let mut backing_store = MemoryStore::new();
+    let id = Uuid::new_v4();
+    let expected_block = random_buffer();
+    backing_store.write_block(id, &expected_block)?;
+
+    let mut store = CachedStore::new(backing_store, CacheConfig::memory(1024 * 1024))?;
+    assert_eq!(store.read_block(id)?, Some(expected_block.clone()));
+    assert_eq!(store.read_block(id)?, Some(expected_block.clone()));
+    assert_eq!(store.read_block(id)?, Some(expected_block));
+
+    assert_eq!(store.stats().misses(), 1);
+    assert_eq!(store.stats().hits(), 2);
+
+    Ok(())
+}
+
+#[test]
+fn writes_are_written_through() -> anyhow::Result<()> {
+    let mut store = CachedStore::new(MemoryStore::new(), CacheConfig::memory(1024 * 1024))?;
+    let id = Uuid::new_v4();
+    let expected_block = random_buffer();
+    store.write_block(id, &expected_block)?;
+
+    assert_eq!(store.read_block(id)?, Some(expected_block.clone()));
+    assert_eq!(store.stats().hits(), 1);
+    assert_eq!(store.into_inner().read_block(id)?, Some(expected_block));
+
+    Ok(())
+}
+
+#[test]
+fn remove_invalidates_cache() -> anyhow::Result<()> {
+    let mut store = CachedStore::new(MemoryStore::new(), CacheConfig::memory(1024 * 1024))?;
+    let id = Uuid::new_v4();
+    store.write_block(id, &random_buffer())?;
+    store.remove_block(id)?;
+
+    assert_eq!(store.read_block(id)?, None);
+    assert_eq!(store.stats().size(), 0);
+
+    Ok(())
+}
+
+#[test]
+fn cache_size_is_bounded() -> anyhow::Result<()> {
+    let mut store = CachedStore::new(MemoryStore::new(), CacheConfig::memory(4096))?;
+    let blocks = (0..8)
+        .map(|_| (Uuid::new_v4(), random_bytes(1024)))
+        .collect::<Vec<_>>();
+    for (id, data) in blocks.iter() {
+        store.write_block(*id, data)?;
+    }
+
+    assert_eq!(store.stats().size(), 4096);
+
+    // Only the most recently written blocks should still be cached.
+    for (id, data) in blocks.iter().rev() {
+        assert_eq!(store.read_block(*id)?.as_ref(), Some(data));
+    }
+    assert_eq!(store.stats().hits(), 4);
+    assert_eq!(store.stats().misses(), 4);
+
+    // Blocks larger than the cache are never cached.
+    let large_id = Uuid::new_v4();
+    store.write_block(large_id, &random_bytes(8192))?;
+    store.read_block(large_id)?;
+    assert_eq!(store.stats().misses(), 5);
+
+    Ok(())
+}
+
+#[test]
+fn directory_cache_evicts_files() -> anyhow::Result<()> {
+    let temp_dir = tempdir()?;
+    let cache_path = temp_dir.as_ref().join("cache");
+    let mut store = CachedStore::new(
+        MemoryStore::new(),
+        CacheConfig::directory(cache_path.clone(), 4096),
+    )?;
+
+    for _ in 0..8 {
+        store.write_block(Uuid::new_v4(), &random_bytes(1024))?;
+    }
+    assert_eq!(read_dir(&cache_path)?.count(), 4);
+
+    store.clear_cache();
+    assert_eq!(read_dir(&cache_path)?.count(), 0);
+
+    Ok(())
+}
+
+#[test]
+fn directory_cache_is_cleared_when_created() -> anyhow::Result<()> {
+    let temp_dir = tempdir()?;
+    let cache_path = temp_dir.as_ref().join("cache");
+    let config = CacheConfig::directory(cache_path.clone(), 1024 * 1024);
+
+    let mut store = CachedStore::new(MemoryStore::new(), config.clone())?;
+    let id = Uuid::new_v4();
+    store.write_block(id, b"Stale")?;
+    let mut backing_store = store.inner().clone();
+    drop(store);
+
+    // A cache left over from an earlier store must not be trusted.
+    backing_store.write_block(id, b"Fresh")?;
+    let mut store = CachedStore::new(backing_store, config)?;
+    assert_eq!(store.read_block(id)?, Some(b"Fresh".to_vec()));
+    assert_eq!(store.stats().misses(), 1);
+
+    Ok(())
+}
+
+#[test]
+fn repository_reads_through_cache() -> anyhow::Result<()> {
+    let store = CachedStore::new(MemoryStore::new(), CacheConfig::memory(1024 * 1024))?;
+    let mut repository: ObjectRepository<String, _> =
+        ObjectRepository::new_repo(store, REPO_CONFIG.to_owned(), Some(PASSWORD))?;
+    let expected_data = random_buffer();
+    let mut object = repository.insert(String::from("Test"));
+    object.write_all(&expected_data)?;
+    object.flush()?;
+    drop(object);
+    repository.commit()?;
+
+    let repository: ObjectRepository<String, _> =
+        ObjectRepository::open_repo(repository.into_store(), LockStrategy::Abort, Some(PASSWORD))?;
+    let mut actual_data = Vec::new();
+    repository
+        .get("Test")
+        .unwrap()
+        .read_to_end(&mut actual_data)?;
+    assert_eq!(actual_data, expected_data);
+
+    let store = repository.into_store();
+    assert!(store.stats().hits() > 0);
+
+    Ok(())
+}
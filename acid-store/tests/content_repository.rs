@@ -116,3 +116,22 @@ fn verify_valid_repository_is_valid() -> anyhow::Result<()> {
     assert!(repository.verify()?.is_empty());
     Ok(())
 }
+
+#[test]
+fn rollback_restores_objects() -> anyhow::Result<()> {
+    let mut repository = create_repo()?;
+    let committed_hash = repository.put(random_buffer().as_slice())?;
+    repository.commit()?;
+
+    let savepoint = repository.savepoint();
+    let uncommitted_hash = repository.put(random_buffer().as_slice())?;
+    repository.rollback_to(savepoint)?;
+    assert!(!repository.contains(&uncommitted_hash));
+    assert!(repository.contains(&committed_hash));
+
+    repository.remove(&committed_hash);
+    repository.rollback()?;
+    assert!(repository.contains(&committed_hash));
+    assert!(repository.verify()?.is_empty());
+    Ok(())
+}
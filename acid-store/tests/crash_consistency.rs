@@ -0,0 +1,472 @@
+/*
+ * Copyright 2019-2020 Wren Powell
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+#![cfg(all(
+    feature = "encryption",
+    feature = "compression",
+    feature = "store-faulty",
+    feature = "store-pack"
+))]
+
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use rand::rngs::SmallRng;
+use rand::{Rng, RngCore, SeedableRng};
+use tempfile::{tempdir, TempDir};
+use uuid::Uuid;
+
+use acid_store::repo::{
+    Chunking, Encryption, HeaderMode, LockStrategy, ObjectRepository, OpenRepo, RepositoryConfig,
+};
+use acid_store::store::{
+    DataStore, FaultConfig, FaultyStore, MemoryStore, OpenOption, OpenStore, PackConfig, PackStore,
+};
+use common::REPO_CONFIG;
+
+mod common;
+
+// These tests run a random sequence of operations against a repository whose data store injects
+// faults. After every fault, the repository is reopened without faults to check that it contains
+// exactly what was last committed. Everything is seeded so that failures can be reproduced. Each
+// test is run with both header modes and with both a `MemoryStore` and a `PackStore`.
+
+/// The number of operations in each workload.
+const WORKLOAD_STEPS: usize = 48;
+
+/// The number of commits after which the repository compacts the journal of its header.
+const MAX_JOURNAL_LEN: usize = 32;
+
+/// The number of commits made before each workload.
+///
+/// This is just short of `MAX_JOURNAL_LEN`, so the journal is compacted during the workload.
+const SETUP_COMMITS: usize = MAX_JOURNAL_LEN - 4;
+
+/// The number of distinct keys the workloads write to.
+const WORKLOAD_KEYS: u32 = 4;
+
+/// The number of seeds to run each randomized test with.
+const SEEDS: u64 = 8;
+
+/// How many operations apart the crashes are when crashing a `PackStore` at each operation.
+const PACK_CRASH_STRIDE: usize = 8;
+
+/// The header modes which each test is run with.
+const HEADER_MODES: [HeaderMode; 2] = [HeaderMode::Memory, HeaderMode::Paged { cache_pages: 4 }];
+
+/// The objects in a repository and their contents.
+type Contents = HashMap<String, Vec<u8>>;
+
+/// A data store which can be shared so that its data outlives the repositories which use it.
+#[derive(Debug)]
+struct SharedStore<S> {
+    store: Arc<Mutex<S>>,
+
+    /// The directory the store is in, which is removed once the last copy is dropped.
+    _directory: Option<Arc<TempDir>>,
+}
+
+impl<S> Clone for SharedStore<S> {
+    fn clone(&self) -> Self {
+        SharedStore {
+            store: Arc::clone(&self.store),
+            _directory: self._directory.clone(),
+        }
+    }
+}
+
+impl<S: DataStore> DataStore for SharedStore<S> {
+    type Error = S::Error;
+
+    fn write_block(&mut self, id: Uuid, data: &[u8]) -> Result<(), Self::Error> {
+        self.store.lock().unwrap().write_block(id, data)
+    }
+
+    fn read_block(&mut self, id: Uuid) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.store.lock().unwrap().read_block(id)
+    }
+
+    fn remove_block(&mut self, id: Uuid) -> Result<(), Self::Error> {
+        self.store.lock().unwrap().remove_block(id)
+    }
+
+    fn list_blocks(&mut self) -> Result<Vec<Uuid>, Self::Error> {
+        self.store.lock().unwrap().list_blocks()
+    }
+
+    fn write_blocks(&mut self, blocks: &[(Uuid, &[u8])]) -> Result<(), Self::Error> {
+        self.store.lock().unwrap().write_blocks(blocks)
+    }
+
+    fn remove_blocks(&mut self, ids: &[Uuid]) -> Result<(), Self::Error> {
+        self.store.lock().unwrap().remove_blocks(ids)
+    }
+}
+
+/// Return a new empty `MemoryStore`.
+fn memory_store() -> SharedStore<MemoryStore> {
+    SharedStore {
+        store: Arc::new(Mutex::new(MemoryStore::new())),
+        _directory: None,
+    }
+}
+
+/// Return a new empty `PackStore` in a temporary directory.
+///
+/// The pack files are small so that the workloads fill several of them and compact them.
+fn pack_store() -> SharedStore<PackStore> {
+    let directory = tempdir().unwrap();
+    let mut config = PackConfig::new(directory.as_ref().join("store"));
+    config.pack_size = 16 * 1024;
+    let store = PackStore::open(config, OpenOption::CREATE_NEW).unwrap();
+    SharedStore {
+        store: Arc::new(Mutex::new(store)),
+        _directory: Some(Arc::new(directory)),
+    }
+}
+
+/// The result of running a workload until it finished or a fault was injected.
+struct Run {
+    /// The contents of the repository as of the last successful commit.
+    committed: Contents,
+
+    /// The contents the repository would have if a commit which failed had succeeded.
+    attempted: Option<Contents>,
+
+    /// The number of steps of the workload which were completed.
+    completed: usize,
+
+    /// The number of successful commits, including those made by reconfiguring.
+    commits: usize,
+
+    /// Whether a fault interrupted the workload.
+    faulted: bool,
+}
+
+/// Return the config for repositories in these tests.
+///
+/// Encryption is disabled because deriving a key each time the repository is reopened is slow.
+fn test_config(header_mode: HeaderMode) -> RepositoryConfig {
+    let mut config = REPO_CONFIG.to_owned();
+    config.encryption = Encryption::None;
+    config.header_mode = header_mode;
+    config
+}
+
+/// Return a random config to reconfigure the repository with.
+fn random_config(rng: &mut SmallRng) -> RepositoryConfig {
+    let mut config = test_config(HEADER_MODES[rng.gen_range(0, HEADER_MODES.len())]);
+    if rng.gen() {
+        config.chunking = Chunking::Fixed { size: 512 };
+    }
+    config
+}
+
+/// Create a new repository in `store` with no objects in it.
+///
+/// The repository is committed `SETUP_COMMITS` times so that its journal is almost full.
+fn create_repo<S: DataStore>(store: &SharedStore<S>, header_mode: HeaderMode) {
+    let mut repository =
+        ObjectRepository::<String, _>::new_repo(store.clone(), test_config(header_mode), None)
+            .unwrap();
+    for index in 0..SETUP_COMMITS {
+        let mut object = repository.insert(String::from("setup"));
+        object.write_all(&index.to_le_bytes()).unwrap();
+        object.flush().unwrap();
+        drop(object);
+        repository.commit().unwrap();
+    }
+    repository.remove("setup");
+    repository.commit().unwrap();
+}
+
+/// Read the contents of every object in `repository`.
+fn read_contents<S: DataStore>(
+    repository: &ObjectRepository<String, S>,
+) -> acid_store::Result<Contents> {
+    let mut contents = HashMap::new();
+    for key in repository.try_keys() {
+        let key = key?;
+        let mut data = Vec::new();
+        repository.try_get(&key)?.unwrap().read_to_end(&mut data)?;
+        contents.insert(key, data);
+    }
+    Ok(contents)
+}
+
+/// Open the repository in `store` without faults and return its contents.
+fn recover_contents<S: DataStore>(store: &SharedStore<S>) -> acid_store::Result<Contents> {
+    let repository: ObjectRepository<String, _> =
+        ObjectRepository::open_repo(store.clone(), LockStrategy::Abort, None)?;
+    assert!(repository.verify()?.is_empty());
+    read_contents(&repository)
+}
+
+/// Apply up to `steps` random operations to the repository in `store`.
+///
+/// This stops at the first operation which fails.
+fn run_workload<S: DataStore>(
+    store: &SharedStore<S>,
+    faults: FaultConfig,
+    rng: &mut SmallRng,
+    steps: usize,
+    committed: Contents,
+) -> Run {
+    let mut run = Run {
+        committed,
+        attempted: None,
+        completed: 0,
+        commits: 0,
+        faulted: true,
+    };
+
+    let faulty_store = FaultyStore::new(store.clone(), faults);
+    let mut repository: ObjectRepository<String, _> =
+        match ObjectRepository::open_repo(faulty_store, LockStrategy::Abort, None) {
+            Ok(repository) => repository,
+            Err(_) => return run,
+        };
+    let mut pending = run.committed.clone();
+
+    while run.completed < steps {
+        match rng.gen_range(0, 20) {
+            0..=7 => {
+                let key = format!("{}", rng.gen_range(0, WORKLOAD_KEYS));
+                let mut data = vec![0u8; rng.gen_range(0, 2048)];
+                rng.fill_bytes(&mut data);
+                let mut object = repository.insert(key.clone());
+                if object
+                    .write_all(&data)
+                    .and_then(|_| object.flush())
+                    .is_err()
+                {
+                    return run;
+                }
+                pending.insert(key, data);
+            }
+            8..=10 => {
+                let key = format!("{}", rng.gen_range(0, WORKLOAD_KEYS));
+                if repository.try_remove(&key).is_err() {
+                    return run;
+                }
+                pending.remove(&key);
+            }
+            11..=16 => {
+                if repository.commit().is_err() {
+                    run.attempted = Some(pending);
+                    return run;
+                }
+                run.committed = pending.clone();
+                run.commits += 1;
+            }
+            17..=18 => {
+                if repository.gc(None).is_err() {
+                    return run;
+                }
+            }
+            _ => {
+                // Reconfiguring commits first. A budget of zero leaves the reconfiguration in
+                // progress so that it is continued or abandoned by a later step.
+                let config = random_config(rng);
+                let budget = if rng.gen() {
+                    Some(Duration::from_secs(0))
+                } else {
+                    None
+                };
+                if repository.reconfigure(config, None, budget).is_err() {
+                    run.attempted = Some(pending);
+                    return run;
+                }
+                run.committed = pending.clone();
+                run.commits += 1;
+            }
+        }
+        run.completed += 1;
+    }
+
+    run.faulted = false;
+    run
+}
+
+/// Return whether `actual` is what the repository should contain after `run`.
+fn is_expected(actual: &Contents, run: &Run) -> bool {
+    *actual == run.committed || Some(actual) == run.attempted.as_ref()
+}
+
+/// Reopen the repository after `run` and assert that it contains what was last committed.
+fn assert_recovered<S: DataStore>(store: &SharedStore<S>, run: &Run, seed: u64) -> Contents {
+    let actual = recover_contents(store).unwrap();
+    assert!(
+        is_expected(&actual, run),
+        "Unexpected contents with seed {}.",
+        seed
+    );
+    actual
+}
+
+/// Crash the store after every `stride`th operation of a workload in turn.
+fn crash_at_operations<S: DataStore>(
+    new_store: fn() -> SharedStore<S>,
+    header_mode: HeaderMode,
+    stride: usize,
+) {
+    for crash_after in (0..).step_by(stride) {
+        let store = new_store();
+        create_repo(&store, header_mode);
+
+        let mut faults = FaultConfig::new(crash_after);
+        faults.crash_after = Some(crash_after);
+        let mut rng = SmallRng::seed_from_u64(0);
+        let run = run_workload(&store, faults, &mut rng, WORKLOAD_STEPS, Contents::new());
+
+        assert_recovered(&store, &run, crash_after);
+
+        // Stop once the workload finishes before the crash.
+        if !run.faulted {
+            assert_eq!(run.completed, WORKLOAD_STEPS);
+            assert!(SETUP_COMMITS + 1 + run.commits > MAX_JOURNAL_LEN);
+            break;
+        }
+    }
+}
+
+/// Inject random errors and reopen the repository after each one.
+fn random_errors<S: DataStore>(new_store: fn() -> SharedStore<S>, header_mode: HeaderMode) {
+    for seed in 0..SEEDS {
+        let store = new_store();
+        create_repo(&store, header_mode);
+
+        let mut rng = SmallRng::seed_from_u64(seed);
+        let mut remaining = WORKLOAD_STEPS * 4;
+        let mut committed = Contents::new();
+
+        // Reopen the repository after every fault and continue where the workload left off.
+        while remaining > 0 {
+            let mut faults = FaultConfig::new(rng.gen());
+            faults.error_probability = 0.02;
+            let run = run_workload(&store, faults, &mut rng, remaining, committed);
+            committed = assert_recovered(&store, &run, seed);
+            remaining -= run.completed;
+            if run.faulted && run.completed == 0 {
+                // Make progress even if the workload keeps failing immediately.
+                remaining -= 1;
+            }
+        }
+    }
+}
+
+/// Inject torn writes and return the number of seeds after which the repository could be opened.
+fn torn_writes<S: DataStore>(new_store: fn() -> SharedStore<S>, header_mode: HeaderMode) -> u64 {
+    let mut recovered = 0;
+
+    for seed in 0..SEEDS {
+        let store = new_store();
+        create_repo(&store, header_mode);
+
+        let mut rng = SmallRng::seed_from_u64(seed);
+        let mut faults = FaultConfig::new(seed);
+        faults.torn_write_probability = 0.05;
+        let run = run_workload(&store, faults, &mut rng, WORKLOAD_STEPS, Contents::new());
+
+        // A torn write breaks the guarantee that writes are atomic, so it can leave the repository
+        // corrupt. It must never appear to contain data which was not committed, though, and it
+        // must not fail in any other way.
+        match recover_contents(&store) {
+            Ok(actual) => {
+                assert!(
+                    is_expected(&actual, &run),
+                    "Unexpected contents with seed {}.",
+                    seed
+                );
+                recovered += 1;
+            }
+            Err(acid_store::Error::Corrupt) => {}
+            Err(error) => panic!("Unexpected error with seed {}: {}", seed, error),
+        }
+    }
+
+    recovered
+}
+
+#[test]
+fn crash_at_every_operation_preserves_last_commit() {
+    for header_mode in HEADER_MODES.iter() {
+        crash_at_operations(memory_store, *header_mode, 1);
+
+        // Every write to a `PackStore` is synced, so only some of its operations are tested.
+        crash_at_operations(pack_store, *header_mode, PACK_CRASH_STRIDE);
+    }
+}
+
+#[test]
+fn random_errors_preserve_last_commit() {
+    for header_mode in HEADER_MODES.iter() {
+        random_errors(memory_store, *header_mode);
+        random_errors(pack_store, *header_mode);
+    }
+}
+
+#[test]
+fn torn_writes_never_return_wrong_data() {
+    for header_mode in HEADER_MODES.iter() {
+        // Most torn writes are to blocks which are never referenced, so the repository should
+        // usually be recovered.
+        assert!(torn_writes(memory_store, *header_mode) > 0);
+        assert!(torn_writes(pack_store, *header_mode) > 0);
+    }
+}
+
+#[test]
+fn faults_are_reproducible() -> anyhow::Result<()> {
+    let mut faults = FaultConfig::new(42);
+    faults.error_probability = 0.5;
+
+    let outcomes = |faults| -> anyhow::Result<Vec<bool>> {
+        let mut store = FaultyStore::new(MemoryStore::new(), faults);
+        let mut outcomes = Vec::new();
+        for _ in 0..64 {
+            outcomes.push(store.write_block(Uuid::new_v4(), b"Data").is_ok());
+        }
+        Ok(outcomes)
+    };
+
+    assert_eq!(outcomes(faults)?, outcomes(faults)?);
+
+    Ok(())
+}
+
+#[test]
+fn crashed_store_fails_until_recovered() -> anyhow::Result<()> {
+    let mut faults = FaultConfig::new(0);
+    faults.crash_after = Some(1);
+    let mut store = FaultyStore::new(MemoryStore::new(), faults);
+
+    let id = Uuid::new_v4();
+    store.write_block(id, b"Data")?;
+
+    assert!(store.read_block(id).is_err());
+    assert!(store.is_crashed());
+    assert!(store.read_block(id).is_err());
+
+    store.recover();
+
+    assert_eq!(store.read_block(id)?, Some(b"Data".to_vec()));
+    assert_eq!(store.faults(), 1);
+
+    Ok(())
+}
@@ -22,15 +22,25 @@
     feature = "store-rclone"
 ))]
 use serial_test::serial;
+use std::fs::{read_dir, OpenOptions};
+use std::io::Write;
+
 use tempfile::tempdir;
 use uuid::Uuid;
 
 #[cfg(feature = "store-directory")]
 use acid_store::store::DirectoryStore;
+#[cfg(feature = "store-erasure")]
+use acid_store::store::ErasureStore;
 #[cfg(feature = "store-sqlite")]
 use acid_store::store::SqliteStore;
-use acid_store::store::{DataStore, MemoryStore, MultiStore, OpenOption, OpenStore};
-use common::{assert_contains_all, random_buffer};
+use acid_store::store::{
+    CacheConfig, CachedStore, DataStore, MemoryStore, MirrorStore, MultiStore, OpenOption,
+    OpenStore,
+};
+#[cfg(feature = "store-pack")]
+use acid_store::store::{PackConfig, PackStore};
+use common::{assert_contains_all, random_buffer, random_bytes};
 #[cfg(feature = "store-rclone")]
 use {acid_store::store::RcloneStore, common::RCLONE_REMOTE};
 #[cfg(feature = "store-redis")]
@@ -72,6 +82,16 @@ fn directory_read_block() -> anyhow::Result<()> {
     )?)
 }
 
+#[test]
+#[cfg(feature = "store-pack")]
+fn pack_read_block() -> anyhow::Result<()> {
+    let temp_dir = tempdir()?;
+    read_block(PackStore::open(
+        PackConfig::new(temp_dir.as_ref().join("store")),
+        OpenOption::CREATE_NEW,
+    )?)
+}
+
 #[test]
 #[cfg(feature = "store-sqlite")]
 fn sqlite_read_block() -> anyhow::Result<()> {
@@ -113,6 +133,26 @@ fn multi_read_block() -> anyhow::Result<()> {
     read_block(store)
 }
 
+#[test]
+fn mirror_read_block() -> anyhow::Result<()> {
+    read_block(MirrorStore::new(MemoryStore::new(), MemoryStore::new()))
+}
+
+#[test]
+fn cached_read_block() -> anyhow::Result<()> {
+    let temp_dir = tempdir()?;
+    read_block(CachedStore::new(
+        MemoryStore::new(),
+        CacheConfig::directory(temp_dir.as_ref().join("cache"), 1024 * 1024),
+    )?)
+}
+
+#[test]
+#[cfg(feature = "store-erasure")]
+fn erasure_read_block() -> anyhow::Result<()> {
+    read_block(ErasureStore::new(vec![MemoryStore::new(); 3], 2)?)
+}
+
 #[test]
 #[serial(rclone)]
 #[cfg(feature = "store-rclone")]
@@ -152,6 +192,16 @@ fn directory_overwrite_block() -> anyhow::Result<()> {
     )?)
 }
 
+#[test]
+#[cfg(feature = "store-pack")]
+fn pack_overwrite_block() -> anyhow::Result<()> {
+    let temp_dir = tempdir()?;
+    overwrite_block(PackStore::open(
+        PackConfig::new(temp_dir.as_ref().join("store")),
+        OpenOption::CREATE_NEW,
+    )?)
+}
+
 #[test]
 #[cfg(feature = "store-sqlite")]
 fn sqlite_overwrite_block() -> anyhow::Result<()> {
@@ -193,6 +243,26 @@ fn multi_overwrite_block() -> anyhow::Result<()> {
     overwrite_block(store)
 }
 
+#[test]
+fn mirror_overwrite_block() -> anyhow::Result<()> {
+    overwrite_block(MirrorStore::new(MemoryStore::new(), MemoryStore::new()))
+}
+
+#[test]
+fn cached_overwrite_block() -> anyhow::Result<()> {
+    let temp_dir = tempdir()?;
+    overwrite_block(CachedStore::new(
+        MemoryStore::new(),
+        CacheConfig::directory(temp_dir.as_ref().join("cache"), 1024 * 1024),
+    )?)
+}
+
+#[test]
+#[cfg(feature = "store-erasure")]
+fn erasure_overwrite_block() -> anyhow::Result<()> {
+    overwrite_block(ErasureStore::new(vec![MemoryStore::new(); 3], 2)?)
+}
+
 #[test]
 #[serial(rclone)]
 #[cfg(feature = "store-rclone")]
@@ -232,6 +302,16 @@ fn directory_remove_block() -> anyhow::Result<()> {
     )?)
 }
 
+#[test]
+#[cfg(feature = "store-pack")]
+fn pack_remove_block() -> anyhow::Result<()> {
+    let temp_dir = tempdir()?;
+    remove_block(PackStore::open(
+        PackConfig::new(temp_dir.as_ref().join("store")),
+        OpenOption::CREATE_NEW,
+    )?)
+}
+
 #[test]
 #[cfg(feature = "store-sqlite")]
 fn sqlite_remove_block() -> anyhow::Result<()> {
@@ -273,6 +353,26 @@ fn multi_remove_block() -> anyhow::Result<()> {
     remove_block(store)
 }
 
+#[test]
+fn mirror_remove_block() -> anyhow::Result<()> {
+    remove_block(MirrorStore::new(MemoryStore::new(), MemoryStore::new()))
+}
+
+#[test]
+fn cached_remove_block() -> anyhow::Result<()> {
+    let temp_dir = tempdir()?;
+    remove_block(CachedStore::new(
+        MemoryStore::new(),
+        CacheConfig::directory(temp_dir.as_ref().join("cache"), 1024 * 1024),
+    )?)
+}
+
+#[test]
+#[cfg(feature = "store-erasure")]
+fn erasure_remove_block() -> anyhow::Result<()> {
+    remove_block(ErasureStore::new(vec![MemoryStore::new(); 3], 2)?)
+}
+
 #[test]
 #[serial(rclone)]
 #[cfg(feature = "store-rclone")]
@@ -319,6 +419,16 @@ fn directory_list_blocks() -> anyhow::Result<()> {
     )?)
 }
 
+#[test]
+#[cfg(feature = "store-pack")]
+fn pack_list_blocks() -> anyhow::Result<()> {
+    let temp_dir = tempdir()?;
+    list_blocks(PackStore::open(
+        PackConfig::new(temp_dir.as_ref().join("store")),
+        OpenOption::CREATE_NEW,
+    )?)
+}
+
 #[test]
 #[cfg(feature = "store-sqlite")]
 fn sqlite_list_blocks() -> anyhow::Result<()> {
@@ -360,6 +470,26 @@ fn multi_list_block() -> anyhow::Result<()> {
     list_blocks(store)
 }
 
+#[test]
+fn mirror_list_blocks() -> anyhow::Result<()> {
+    list_blocks(MirrorStore::new(MemoryStore::new(), MemoryStore::new()))
+}
+
+#[test]
+fn cached_list_blocks() -> anyhow::Result<()> {
+    let temp_dir = tempdir()?;
+    list_blocks(CachedStore::new(
+        MemoryStore::new(),
+        CacheConfig::directory(temp_dir.as_ref().join("cache"), 1024 * 1024),
+    )?)
+}
+
+#[test]
+#[cfg(feature = "store-erasure")]
+fn erasure_list_blocks() -> anyhow::Result<()> {
+    list_blocks(ErasureStore::new(vec![MemoryStore::new(); 3], 2)?)
+}
+
 #[test]
 #[serial(rclone)]
 #[cfg(feature = "store-rclone")]
@@ -371,3 +501,297 @@ fn rclone_list_block() {
     .unwrap();
     list_blocks(store).unwrap();
 }
+
+fn batch_blocks(mut store: impl DataStore) -> anyhow::Result<()> {
+    let blocks = (0..8)
+        .map(|_| (Uuid::new_v4(), random_buffer()))
+        .collect::<Vec<_>>();
+    let block_refs = blocks
+        .iter()
+        .map(|(id, data)| (*id, data.as_slice()))
+        .collect::<Vec<_>>();
+    let ids = blocks.iter().map(|(id, _)| *id).collect::<Vec<_>>();
+    let missing_id = Uuid::new_v4();
+
+    store.write_blocks(&block_refs)?;
+
+    let mut read_ids = ids.clone();
+    read_ids.push(missing_id);
+    let mut expected_blocks = blocks
+        .iter()
+        .map(|(_, data)| Some(data.clone()))
+        .collect::<Vec<_>>();
+    expected_blocks.push(None);
+
+    assert_eq!(store.read_blocks(&read_ids)?, expected_blocks);
+    assert_contains_all(store.list_blocks()?, ids.clone());
+
+    store.remove_blocks(&ids[..4])?;
+
+    assert_eq!(store.read_blocks(&ids[..4])?, vec![None; 4]);
+    assert_eq!(
+        store.read_blocks(&ids[4..])?,
+        expected_blocks[4..8].to_vec()
+    );
+    assert_eq!(store.read_blocks(&[])?, Vec::<Option<Vec<u8>>>::new());
+    store.write_blocks(&[])?;
+    store.remove_blocks(&[])?;
+
+    Ok(())
+}
+
+#[test]
+fn memory_batch_blocks() -> anyhow::Result<()> {
+    batch_blocks(MemoryStore::new())
+}
+
+#[test]
+#[cfg(feature = "store-directory")]
+fn directory_batch_blocks() -> anyhow::Result<()> {
+    let temp_dir = tempdir()?;
+    batch_blocks(DirectoryStore::open(
+        temp_dir.as_ref().join("store"),
+        OpenOption::CREATE_NEW,
+    )?)
+}
+
+#[test]
+#[cfg(feature = "store-pack")]
+fn pack_batch_blocks() -> anyhow::Result<()> {
+    let temp_dir = tempdir()?;
+    batch_blocks(PackStore::open(
+        PackConfig::new(temp_dir.as_ref().join("store")),
+        OpenOption::CREATE_NEW,
+    )?)
+}
+
+#[test]
+#[cfg(feature = "store-sqlite")]
+fn sqlite_batch_blocks() -> anyhow::Result<()> {
+    let temp_dir = tempdir()?;
+    batch_blocks(SqliteStore::open(
+        temp_dir.as_ref().join("store.db"),
+        OpenOption::CREATE_NEW,
+    )?)
+}
+
+#[test]
+#[serial(redis)]
+#[cfg(feature = "store-redis")]
+fn redis_batch_blocks() {
+    let store = RedisStore::open(
+        REDIS_INFO.to_owned(),
+        OpenOption::CREATE | OpenOption::TRUNCATE,
+    )
+    .unwrap();
+    batch_blocks(store).unwrap();
+}
+
+#[test]
+fn multi_batch_blocks() -> anyhow::Result<()> {
+    let mut multi_store = MultiStore::new(MemoryStore::new())?;
+    let store = multi_store.insert(String::from("Test"))?;
+    batch_blocks(store)
+}
+
+#[test]
+fn cached_batch_blocks() -> anyhow::Result<()> {
+    let temp_dir = tempdir()?;
+    batch_blocks(CachedStore::new(
+        MemoryStore::new(),
+        CacheConfig::directory(temp_dir.as_ref().join("cache"), 1024 * 1024),
+    )?)
+}
+
+#[test]
+#[cfg(feature = "store-pack")]
+fn pack_batch_spanning_packs_persists_across_reopen() -> anyhow::Result<()> {
+    let temp_dir = tempdir()?;
+    let mut config = PackConfig::new(temp_dir.as_ref().join("store"));
+    config.pack_size = 4096;
+    let mut store = PackStore::open(config.clone(), OpenOption::CREATE_NEW)?;
+
+    let blocks = (0..16)
+        .map(|_| (Uuid::new_v4(), random_bytes(1024)))
+        .collect::<Vec<_>>();
+    let block_refs = blocks
+        .iter()
+        .map(|(id, data)| (*id, data.as_slice()))
+        .collect::<Vec<_>>();
+    store.write_blocks(&block_refs)?;
+    drop(store);
+
+    assert!(read_dir(config.path.join("packs"))?.count() > 1);
+
+    let mut store = PackStore::open(config, OpenOption::empty())?;
+    for (id, data) in blocks.iter() {
+        assert_eq!(store.read_block(*id)?.as_ref(), Some(data));
+    }
+
+    Ok(())
+}
+
+#[test]
+#[cfg(feature = "store-pack")]
+fn pack_blocks_persist_across_reopen() -> anyhow::Result<()> {
+    let temp_dir = tempdir()?;
+    let config = PackConfig::new(temp_dir.as_ref().join("store"));
+    let mut store = PackStore::open(config.clone(), OpenOption::CREATE_NEW)?;
+
+    let kept_id = Uuid::new_v4();
+    let removed_id = Uuid::new_v4();
+    let kept_block = random_buffer();
+    store.write_block(kept_id, kept_block.as_slice())?;
+    store.write_block(removed_id, random_buffer().as_slice())?;
+    store.remove_block(removed_id)?;
+    drop(store);
+
+    let mut store = PackStore::open(config, OpenOption::empty())?;
+
+    assert_eq!(store.read_block(kept_id)?, Some(kept_block));
+    assert_eq!(store.read_block(removed_id)?, None);
+    assert_eq!(store.list_blocks()?, vec![kept_id]);
+
+    Ok(())
+}
+
+#[test]
+#[cfg(feature = "store-pack")]
+fn pack_torn_journal_entry_is_discarded() -> anyhow::Result<()> {
+    let temp_dir = tempdir()?;
+    let config = PackConfig::new(temp_dir.as_ref().join("store"));
+    let mut store = PackStore::open(config.clone(), OpenOption::CREATE_NEW)?;
+
+    let id = Uuid::new_v4();
+    let expected_block = random_buffer();
+    store.write_block(id, expected_block.as_slice())?;
+    drop(store);
+
+    // Simulate being interrupted partway through writing a journal entry.
+    let mut journal = OpenOptions::new()
+        .append(true)
+        .open(config.path.join("journal"))?;
+    journal.write_all(&[0xff; 7])?;
+    drop(journal);
+
+    let mut store = PackStore::open(config.clone(), OpenOption::empty())?;
+    assert_eq!(store.read_block(id)?, Some(expected_block.clone()));
+
+    // New entries must not be lost behind the discarded one.
+    let new_id = Uuid::new_v4();
+    store.write_block(new_id, expected_block.as_slice())?;
+    drop(store);
+
+    let mut store = PackStore::open(config, OpenOption::empty())?;
+    assert_contains_all(store.list_blocks()?, vec![id, new_id]);
+
+    Ok(())
+}
+
+#[test]
+#[cfg(feature = "store-pack")]
+fn pack_compaction_removes_sparse_packs() -> anyhow::Result<()> {
+    let temp_dir = tempdir()?;
+    let mut config = PackConfig::new(temp_dir.as_ref().join("store"));
+    config.pack_size = 4096;
+    let mut store = PackStore::open(config.clone(), OpenOption::CREATE_NEW)?;
+
+    let blocks = (0..16)
+        .map(|_| (Uuid::new_v4(), random_bytes(1024)))
+        .collect::<Vec<_>>();
+    for (id, data) in blocks.iter() {
+        store.write_block(*id, data.as_slice())?;
+    }
+
+    let pack_count = || read_dir(config.path.join("packs")).unwrap().count();
+    let packs_before = pack_count();
+
+    // Leave one block in use in each pack.
+    for (index, (id, _)) in blocks.iter().enumerate() {
+        if index % 4 != 0 {
+            store.remove_block(*id)?;
+        }
+    }
+
+    store.compact()?;
+
+    assert!(pack_count() < packs_before);
+    for (id, data) in blocks.iter().step_by(4) {
+        assert_eq!(store.read_block(*id)?.as_ref(), Some(data));
+    }
+    drop(store);
+
+    let mut store = PackStore::open(config, OpenOption::empty())?;
+    assert_eq!(store.list_blocks()?.len(), 4);
+    for (id, data) in blocks.iter().step_by(4) {
+        assert_eq!(store.read_block(*id)?.as_ref(), Some(data));
+    }
+
+    Ok(())
+}
+
+#[test]
+#[cfg(feature = "store-pack")]
+fn pack_compaction_after_reopen_uses_new_pack() -> anyhow::Result<()> {
+    let temp_dir = tempdir()?;
+    let mut config = PackConfig::new(temp_dir.as_ref().join("store"));
+    config.pack_size = 4096;
+    let mut store = PackStore::open(config.clone(), OpenOption::CREATE_NEW)?;
+
+    let blocks = (0..3)
+        .map(|_| (Uuid::new_v4(), random_bytes(1024)))
+        .collect::<Vec<_>>();
+    for (id, data) in blocks.iter() {
+        store.write_block(*id, data.as_slice())?;
+    }
+    drop(store);
+
+    let pack_names = || {
+        read_dir(config.path.join("packs"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect::<Vec<_>>()
+    };
+    let old_packs = pack_names();
+    assert_eq!(old_packs.len(), 1);
+
+    // The pack file has room in it, but it must not be reused because it is sparse.
+    let mut store = PackStore::open(config.clone(), OpenOption::empty())?;
+    store.remove_blocks(&[blocks[1].0, blocks[2].0])?;
+    store.compact()?;
+
+    assert!(!pack_names().contains(&old_packs[0]));
+    assert_eq!(store.read_block(blocks[0].0)?.as_ref(), Some(&blocks[0].1));
+    drop(store);
+
+    let mut store = PackStore::open(config, OpenOption::empty())?;
+    assert_eq!(store.list_blocks()?, vec![blocks[0].0]);
+    assert_eq!(store.read_block(blocks[0].0)?.as_ref(), Some(&blocks[0].1));
+
+    Ok(())
+}
+
+#[test]
+#[cfg(feature = "store-pack")]
+fn pack_truncate_removes_blocks() -> anyhow::Result<()> {
+    let temp_dir = tempdir()?;
+    let config = PackConfig::new(temp_dir.as_ref().join("store"));
+    let mut store = PackStore::open(config.clone(), OpenOption::CREATE_NEW)?;
+    store.write_block(Uuid::new_v4(), random_buffer().as_slice())?;
+    drop(store);
+
+    let mut store = PackStore::open(config.clone(), OpenOption::TRUNCATE)?;
+    assert!(store.list_blocks()?.is_empty());
+    assert_eq!(read_dir(config.path.join("packs"))?.count(), 0);
+
+    let id = Uuid::new_v4();
+    let block = random_buffer();
+    store.write_block(id, block.as_slice())?;
+    drop(store);
+
+    let mut store = PackStore::open(config, OpenOption::empty())?;
+    assert_eq!(store.list_blocks()?, vec![id]);
+    assert_eq!(store.read_block(id)?, Some(block));
+
+    Ok(())
+}
@@ -0,0 +1,271 @@
+/*
+ * Copyright 2019-2020 Wren Powell
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+#![cfg(all(
+    feature = "encryption",
+    feature = "compression",
+    feature = "store-erasure"
+))]
+
+use std::io::{Read, Write};
+
+use uuid::Uuid;
+
+use acid_store::repo::{LockStrategy, ObjectRepository, OpenRepo};
+use acid_store::store::{DataStore, ErasureError, ErasureStore, MemoryStore};
+#[cfg(feature = "store-faulty")]
+use acid_store::store::{FaultConfig, FaultyStore};
+use common::{random_buffer, PASSWORD, REPO_CONFIG};
+
+mod common;
+
+/// Create an `ErasureStore` with four data shards and two parity shards.
+fn create_store() -> acid_store::Result<ErasureStore<MemoryStore>> {
+    ErasureStore::new(vec![MemoryStore::new(); 6], 4)
+}
+
+#[test]
+fn invalid_shard_counts_err() {
+    assert!(matches!(
+        ErasureStore::new(vec![MemoryStore::new(); 4], 0),
+        Err(acid_store::Error::InvalidConfig)
+    ));
+    assert!(matches!(
+        ErasureStore::new(vec![MemoryStore::new(); 4], 4),
+        Err(acid_store::Error::InvalidConfig)
+    ));
+    assert!(matches!(
+        ErasureStore::new(vec![MemoryStore::new(); 257], 200),
+        Err(acid_store::Error::InvalidConfig)
+    ));
+}
+
+#[test]
+fn read_reconstructs_missing_shards() -> anyhow::Result<()> {
+    let mut store = create_store()?;
+    let id = Uuid::new_v4();
+    let expected_block = random_buffer();
+    store.write_block(id, &expected_block)?;
+    assert!(store.degraded_blocks().is_empty());
+
+    store.stores_mut()[0].remove_block(id)?;
+    store.stores_mut()[5] = MemoryStore::new();
+
+    assert_eq!(store.read_block(id)?, Some(expected_block));
+    assert_eq!(store.degraded_blocks(), vec![id]);
+
+    Ok(())
+}
+
+#[test]
+fn corrupt_shards_are_treated_as_missing() -> anyhow::Result<()> {
+    let mut store = create_store()?;
+    let id = Uuid::new_v4();
+    let expected_block = random_buffer();
+    store.write_block(id, &expected_block)?;
+
+    store.stores_mut()[1].write_block(id, b"corrupt")?;
+    store.stores_mut()[2].write_block(id, b"corrupt")?;
+    assert_eq!(store.read_block(id)?, Some(expected_block));
+
+    store.stores_mut()[3].write_block(id, b"corrupt")?;
+    assert!(matches!(
+        store.read_block(id),
+        Err(ErasureError::Unrecoverable)
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn empty_blocks_round_trip() -> anyhow::Result<()> {
+    let mut store = create_store()?;
+    let id = Uuid::new_v4();
+    store.write_block(id, &[])?;
+    assert_eq!(store.read_block(id)?, Some(Vec::new()));
+
+    Ok(())
+}
+
+#[test]
+fn repair_restores_lost_store() -> anyhow::Result<()> {
+    let mut store = create_store()?;
+    let blocks = (0..8)
+        .map(|_| (Uuid::new_v4(), random_buffer()))
+        .collect::<Vec<_>>();
+    for (id, data) in blocks.iter() {
+        store.write_block(*id, data)?;
+    }
+
+    store.stores_mut()[2] = MemoryStore::new();
+    assert_eq!(store.scan()?.len(), blocks.len());
+
+    let stats = store.repair()?;
+    assert_eq!(stats.blocks(), blocks.len() as u64);
+    assert_eq!(stats.shards(), blocks.len() as u64);
+    assert_eq!(stats.unrecoverable(), 0);
+    assert!(store.scan()?.is_empty());
+
+    // The repaired shards should be usable in place of the others.
+    store.stores_mut()[0] = MemoryStore::new();
+    store.stores_mut()[1] = MemoryStore::new();
+    for (id, data) in blocks.iter() {
+        assert_eq!(store.read_block(*id)?.as_ref(), Some(data));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn interrupted_overwrite_reads_recoverable_version() -> anyhow::Result<()> {
+    let mut old_store = ErasureStore::new(vec![MemoryStore::new(); 4], 2)?;
+    let id = Uuid::new_v4();
+    let old_block = random_buffer();
+    let new_block = random_buffer();
+    old_store.write_block(id, &old_block)?;
+
+    let mut new_store = ErasureStore::new(old_store.stores().to_vec(), 2)?;
+    new_store.write_block(id, &new_block)?;
+
+    // Simulate an overwrite which only reached some of the stores.
+    let old_shards = old_store.into_inner();
+    let new_shards = new_store.into_inner();
+
+    let mut store = ErasureStore::new(
+        vec![
+            new_shards[0].clone(),
+            new_shards[1].clone(),
+            new_shards[2].clone(),
+            old_shards[3].clone(),
+        ],
+        2,
+    )?;
+    assert_eq!(store.read_block(id)?, Some(new_block));
+
+    let mut store = ErasureStore::new(
+        vec![
+            new_shards[0].clone(),
+            old_shards[1].clone(),
+            old_shards[2].clone(),
+            old_shards[3].clone(),
+        ],
+        2,
+    )?;
+    assert_eq!(store.read_block(id)?, Some(old_block));
+    assert_eq!(store.scan()?, vec![id]);
+
+    Ok(())
+}
+
+#[test]
+fn versions_increase_when_store_is_created_again() -> anyhow::Result<()> {
+    let id = Uuid::new_v4();
+    let old_block = random_buffer();
+    let new_block = random_buffer();
+    let mut store = ErasureStore::new(vec![MemoryStore::new(); 4], 2)?;
+    store.write_block(id, &old_block)?;
+    let old_shards = store.into_inner();
+
+    // Each new store continues from the counter saved by the last one.
+    let mut stores = old_shards.clone();
+    for _ in 0..3 {
+        let mut store = ErasureStore::new(stores, 2)?;
+        store.write_block(id, &new_block)?;
+        stores = store.into_inner();
+    }
+
+    // If the versions didn't increase, the old shards could be read instead of the new ones.
+    let mut store = ErasureStore::new(
+        vec![
+            stores[0].clone(),
+            stores[1].clone(),
+            old_shards[2].clone(),
+            old_shards[3].clone(),
+        ],
+        2,
+    )?;
+    assert_eq!(store.read_block(id)?, Some(new_block));
+
+    Ok(())
+}
+
+#[test]
+#[cfg(feature = "store-faulty")]
+fn interrupted_removal_reads_as_removed() -> anyhow::Result<()> {
+    let stores = (0..4)
+        .map(|_| FaultyStore::new(MemoryStore::new(), FaultConfig::new(0)))
+        .collect();
+    let mut store = ErasureStore::new(stores, 3)?;
+    let id = Uuid::new_v4();
+    store.write_block(id, &random_buffer())?;
+
+    // Only the shards in the first two stores are removed, which leaves too few to read the block.
+    for faulty_store in store.stores_mut()[2..].iter_mut() {
+        let mut faults = FaultConfig::new(0);
+        faults.crash_after = Some(0);
+        faulty_store.set_config(faults);
+    }
+    assert!(store.remove_block(id).is_err());
+    assert_eq!(store.read_block(id)?, None);
+    assert!(store.list_blocks()?.is_empty());
+
+    // The removal is finished once the stores have recovered, even if the store is created again.
+    let mut stores = store.into_inner();
+    for faulty_store in stores.iter_mut() {
+        faulty_store.recover();
+    }
+    let mut store = ErasureStore::new(stores, 3)?;
+    assert_eq!(store.read_block(id)?, None);
+    for faulty_store in store.stores_mut() {
+        assert_eq!(faulty_store.inner_mut().read_block(id)?, None);
+    }
+
+    // Writing the block again means it is no longer removed.
+    let new_block = random_buffer();
+    store.write_block(id, &new_block)?;
+    let mut store = ErasureStore::new(store.into_inner(), 3)?;
+    assert_eq!(store.read_block(id)?, Some(new_block));
+    assert_eq!(store.list_blocks()?, vec![id]);
+
+    Ok(())
+}
+
+#[test]
+fn repository_survives_losing_stores() -> anyhow::Result<()> {
+    let mut repository: ObjectRepository<String, _> =
+        ObjectRepository::new_repo(create_store()?, REPO_CONFIG.to_owned(), Some(PASSWORD))?;
+    let expected_data = random_buffer();
+    let mut object = repository.insert(String::from("Test"));
+    object.write_all(&expected_data)?;
+    object.flush()?;
+    drop(object);
+    repository.commit()?;
+
+    let mut store = repository.into_store();
+    store.stores_mut()[1] = MemoryStore::new();
+    store.stores_mut()[4] = MemoryStore::new();
+
+    let repository: ObjectRepository<String, _> =
+        ObjectRepository::open_repo(store, LockStrategy::Abort, Some(PASSWORD))?;
+    let mut actual_data = Vec::new();
+    repository
+        .get("Test")
+        .unwrap()
+        .read_to_end(&mut actual_data)?;
+    assert_eq!(actual_data, expected_data);
+
+    Ok(())
+}
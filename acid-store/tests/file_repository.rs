@@ -19,7 +19,7 @@
 use std::fs::{create_dir, File};
 use std::io::{Read, Write};
 
-use relative_path::RelativePath;
+use relative_path::RelativePathBuf;
 use tempfile::tempdir;
 
 use acid_store::repo::file::{Entry, FileRepository, NoMetadata};
@@ -304,8 +304,8 @@ fn list_children() -> anyhow::Result<()> {
 
     let actual = repository.list("root")?;
     let expected = vec![
-        RelativePath::new("root/child1"),
-        RelativePath::new("root/child2"),
+        RelativePathBuf::from("root/child1"),
+        RelativePathBuf::from("root/child2"),
     ];
 
     assert_contains_all(actual, expected);
@@ -331,9 +331,9 @@ fn walk_descendants() -> anyhow::Result<()> {
 
     let actual = repository.walk("root")?;
     let expected = vec![
-        RelativePath::new("root/child1"),
-        RelativePath::new("root/child2"),
-        RelativePath::new("root/child2/descendant"),
+        RelativePathBuf::from("root/child1"),
+        RelativePathBuf::from("root/child2"),
+        RelativePathBuf::from("root/child2/descendant"),
     ];
 
     assert_contains_all(actual, expected);
@@ -587,3 +587,28 @@ fn verify_valid_repository_is_valid() -> anyhow::Result<()> {
     assert!(repository.verify()?.is_empty());
     Ok(())
 }
+
+#[test]
+fn rollback_restores_tree() -> anyhow::Result<()> {
+    let mut repository = create_repo()?;
+    repository.create("committed", &Entry::file())?;
+    let mut object = repository.open_mut("committed")?;
+    object.write_all(b"Data")?;
+    object.flush()?;
+    drop(object);
+    repository.commit()?;
+
+    let savepoint = repository.savepoint();
+    repository.create_parents("uncommitted/file", &Entry::file())?;
+    repository.rollback_to(savepoint)?;
+    assert!(!repository.exists("uncommitted"));
+    assert!(!repository.exists("uncommitted/file"));
+
+    repository.remove("committed")?;
+    repository.rollback()?;
+    let mut object = repository.open("committed")?;
+    let mut actual_data = Vec::new();
+    object.read_to_end(&mut actual_data)?;
+    assert_eq!(actual_data, b"Data");
+    Ok(())
+}
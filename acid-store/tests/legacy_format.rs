@@ -0,0 +1,237 @@
+/*
+ * Copyright 2019-2020 Wren Powell
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+#![cfg(all(feature = "encryption", feature = "compression"))]
+
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use blake2::digest::{Input, VariableOutput};
+use blake2::VarBlake2b;
+use rmp_serde::to_vec;
+use serde::Serialize;
+use uuid::Uuid;
+
+use acid_store::repo::{
+    Chunking, Compression, Encryption, LockStrategy, ObjectRepository, OpenRepo, ReadMode,
+    ReadOnlyObjectRepository, ResourceLimit,
+};
+use acid_store::store::{DataStore, MemoryStore};
+use common::random_buffer;
+
+mod common;
+
+/// The block ID of the block which stores the repository metadata.
+const METADATA_BLOCK_ID: &str = "8691d360-29c6-11ea-8bc1-2fc8cfe66f33";
+
+/// The block ID of the block which stores the repository format version.
+const VERSION_BLOCK_ID: &str = "cbf28b1c-3550-11ea-8cb0-87d7a14efe10";
+
+/// The version ID of the legacy repository format.
+const LEGACY_VERSION_ID: &str = "036e2a8e-4b53-11ea-a6e9-57c2a822fccf";
+
+/// A chunk in the legacy format.
+#[derive(PartialEq, Eq, Hash, Serialize)]
+struct LegacyChunk {
+    size: usize,
+    hash: [u8; 32],
+}
+
+/// An object handle in the legacy format.
+#[derive(Serialize)]
+struct LegacyObjectHandle {
+    size: u64,
+    chunks: Vec<LegacyChunk>,
+}
+
+/// A repository header in the legacy format.
+#[derive(Serialize)]
+struct LegacyHeader {
+    chunks: HashMap<LegacyChunk, Uuid>,
+    objects: HashMap<String, LegacyObjectHandle>,
+}
+
+/// Repository metadata in the legacy format.
+#[derive(Serialize)]
+struct LegacyMetadata {
+    id: Uuid,
+    chunker_bits: u32,
+    compression: Compression,
+    encryption: Encryption,
+    memory_limit: ResourceLimit,
+    operations_limit: ResourceLimit,
+    master_key: Vec<u8>,
+    salt: Vec<u8>,
+    header: Uuid,
+    creation_time: SystemTime,
+}
+
+/// Return the BLAKE2 checksum of `data` which identifies a chunk.
+fn chunk_hash(data: &[u8]) -> [u8; 32] {
+    let mut hasher = VarBlake2b::new(32).unwrap();
+    hasher.input(data);
+    let mut checksum = [0u8; 32];
+    hasher.variable_result(|result| checksum.copy_from_slice(result));
+    checksum
+}
+
+/// Return a store containing an unencrypted legacy repository with an object "Test" with `data`.
+fn legacy_store(data: &[u8], creation_time: SystemTime) -> anyhow::Result<MemoryStore> {
+    let mut store = MemoryStore::new();
+
+    let chunk_id = Uuid::new_v4();
+    store.write_block(chunk_id, data)?;
+
+    let mut header = LegacyHeader {
+        chunks: HashMap::new(),
+        objects: HashMap::new(),
+    };
+    header.chunks.insert(
+        LegacyChunk {
+            size: data.len(),
+            hash: chunk_hash(data),
+        },
+        chunk_id,
+    );
+    header.objects.insert(
+        String::from("Test"),
+        LegacyObjectHandle {
+            size: data.len() as u64,
+            chunks: vec![LegacyChunk {
+                size: data.len(),
+                hash: chunk_hash(data),
+            }],
+        },
+    );
+    let header_id = Uuid::new_v4();
+    store.write_block(header_id, &to_vec(&header)?)?;
+
+    let metadata = LegacyMetadata {
+        id: Uuid::new_v4(),
+        chunker_bits: 8,
+        compression: Compression::None,
+        encryption: Encryption::None,
+        memory_limit: ResourceLimit::Interactive,
+        operations_limit: ResourceLimit::Interactive,
+        master_key: Vec::new(),
+        salt: Vec::new(),
+        header: header_id,
+        creation_time,
+    };
+    store.write_block(Uuid::parse_str(METADATA_BLOCK_ID)?, &to_vec(&metadata)?)?;
+    store.write_block(
+        Uuid::parse_str(VERSION_BLOCK_ID)?,
+        Uuid::parse_str(LEGACY_VERSION_ID)?.as_bytes(),
+    )?;
+
+    Ok(store)
+}
+
+/// Return the version ID which is stored in `store`.
+fn stored_version(store: &mut MemoryStore) -> anyhow::Result<Uuid> {
+    let serialized_version = store
+        .read_block(Uuid::parse_str(VERSION_BLOCK_ID)?)?
+        .unwrap();
+    Ok(Uuid::from_slice(&serialized_version)?)
+}
+
+#[test]
+fn legacy_info_can_be_peeked() -> anyhow::Result<()> {
+    let creation_time = SystemTime::now();
+    let mut store = legacy_store(b"data", creation_time)?;
+
+    let info = ObjectRepository::<String, _>::peek_info(&mut store)?;
+
+    assert_eq!(info.created(), creation_time);
+    assert_eq!(info.config().chunking, Chunking::Zpaq { bits: 8 });
+    Ok(())
+}
+
+#[test]
+fn legacy_repository_is_read_without_upgrading() -> anyhow::Result<()> {
+    let expected_data = random_buffer();
+    let store = legacy_store(&expected_data, SystemTime::now())?;
+
+    let repository = ReadOnlyObjectRepository::<String, _>::open(store, ReadMode::Snapshot, None)?;
+    let mut actual_data = Vec::new();
+    repository
+        .get("Test")
+        .unwrap()
+        .read_to_end(&mut actual_data)?;
+
+    assert_eq!(actual_data, expected_data);
+    let mut store = repository.into_store();
+    assert_eq!(
+        stored_version(&mut store)?,
+        Uuid::parse_str(LEGACY_VERSION_ID)?
+    );
+    Ok(())
+}
+
+#[test]
+fn legacy_repository_is_upgraded_when_opened() -> anyhow::Result<()> {
+    let expected_data = random_buffer();
+    let store = legacy_store(&expected_data, SystemTime::now())?;
+
+    let mut repository =
+        ObjectRepository::<String, _>::open_repo(store, LockStrategy::Abort, None)?;
+    let mut object = repository.get_mut("Test").unwrap();
+    let mut actual_data = Vec::new();
+    object.read_to_end(&mut actual_data)?;
+    assert_eq!(actual_data, expected_data);
+    assert_eq!(object.metadata()?.created(), UNIX_EPOCH);
+    drop(object);
+
+    let mut object = repository.insert(String::from("New"));
+    object.write_all(b"new data")?;
+    object.flush()?;
+    drop(object);
+    repository.commit()?;
+
+    let mut store = repository.into_store();
+    assert_ne!(
+        stored_version(&mut store)?,
+        Uuid::parse_str(LEGACY_VERSION_ID)?
+    );
+
+    let repository = ObjectRepository::<String, _>::open_repo(store, LockStrategy::Abort, None)?;
+    assert!(repository.contains("Test"));
+    assert!(repository.contains("New"));
+    Ok(())
+}
+
+#[test]
+fn interrupted_upgrade_is_finished() -> anyhow::Result<()> {
+    let store = legacy_store(b"data", SystemTime::now())?;
+    let repository = ObjectRepository::<String, _>::open_repo(store, LockStrategy::Abort, None)?;
+
+    // Restore the legacy version, as if the upgrade was interrupted after writing the metadata.
+    let mut store = repository.into_store();
+    store.write_block(
+        Uuid::parse_str(VERSION_BLOCK_ID)?,
+        Uuid::parse_str(LEGACY_VERSION_ID)?.as_bytes(),
+    )?;
+
+    let repository = ObjectRepository::<String, _>::open_repo(store, LockStrategy::Abort, None)?;
+    assert!(repository.contains("Test"));
+    let mut store = repository.into_store();
+    assert_ne!(
+        stored_version(&mut store)?,
+        Uuid::parse_str(LEGACY_VERSION_ID)?
+    );
+    Ok(())
+}
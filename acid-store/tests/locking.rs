@@ -0,0 +1,251 @@
+/*
+ * Copyright 2019-2020 Wren Powell
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+#![cfg(all(
+    feature = "encryption",
+    feature = "compression",
+    feature = "store-directory"
+))]
+
+use std::ffi::OsString;
+use std::fs::{read_dir, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use fs2::FileExt;
+use tempfile::tempdir;
+
+use acid_store::repo::{LockStrategy, ObjectRepository, OpenRepo};
+use acid_store::store::{DirectoryStore, LocalLockStore, OpenOption, OpenStore};
+use common::{PASSWORD, REPO_CONFIG};
+
+mod common;
+
+/// Create a repository in a `DirectoryStore` at `path` which keeps its lock files in `lock_dir`.
+fn create_repo_at(path: &Path, lock_dir: &Path) -> anyhow::Result<()> {
+    let store = DirectoryStore::open(path.to_owned(), OpenOption::CREATE_NEW)?;
+    let mut repository = ObjectRepository::<String, _>::new_repo(
+        LocalLockStore::new(store, lock_dir.to_owned()),
+        REPO_CONFIG.to_owned(),
+        Some(PASSWORD),
+    )?;
+    repository.commit()?;
+    Ok(())
+}
+
+/// Open the store at `path`, keeping lock files in `lock_dir`.
+fn open_store(path: &Path, lock_dir: &Path) -> anyhow::Result<LocalLockStore<DirectoryStore>> {
+    let store = DirectoryStore::open(path.to_owned(), OpenOption::empty())?;
+    Ok(LocalLockStore::new(store, lock_dir.to_owned()))
+}
+
+#[test]
+fn timeout_errs_if_lock_is_not_released() -> anyhow::Result<()> {
+    let temp_dir = tempdir()?;
+    let path = temp_dir.as_ref().join("store");
+    let lock_dir = temp_dir.as_ref().join("locks");
+    create_repo_at(&path, &lock_dir)?;
+
+    // Hold a file lock on the repository from another file handle, like another process would.
+    let lock_file = read_dir(&lock_dir)?.next().unwrap()?.path();
+    let holder = OpenOptions::new().write(true).open(&lock_file)?;
+    holder.lock_exclusive()?;
+
+    let timeout = Duration::from_millis(100);
+    let start = Instant::now();
+    let open_attempt = ObjectRepository::<String, _>::open_repo(
+        open_store(&path, &lock_dir)?,
+        LockStrategy::Timeout(timeout),
+        Some(PASSWORD),
+    );
+
+    assert!(matches!(
+        open_attempt.unwrap_err(),
+        acid_store::Error::Locked
+    ));
+    assert!(start.elapsed() >= timeout);
+    Ok(())
+}
+
+#[test]
+fn timeout_acquires_lock_once_released() -> anyhow::Result<()> {
+    let temp_dir = tempdir()?;
+    let path = temp_dir.as_ref().join("store");
+    let lock_dir = temp_dir.as_ref().join("locks");
+    create_repo_at(&path, &lock_dir)?;
+
+    // Hold a file lock on the repository from another file handle, like another process would.
+    let lock_file = read_dir(&lock_dir)?.next().unwrap()?.path();
+    let holder = OpenOptions::new().write(true).open(&lock_file)?;
+    holder.lock_exclusive()?;
+    let release = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(100));
+        FileExt::unlock(&holder).unwrap();
+    });
+
+    let repository = ObjectRepository::<String, _>::open_repo(
+        open_store(&path, &lock_dir)?,
+        LockStrategy::Timeout(Duration::from_secs(10)),
+        Some(PASSWORD),
+    );
+    release.join().unwrap();
+
+    assert!(repository.is_ok());
+    Ok(())
+}
+
+#[test]
+fn lock_files_are_kept_in_lock_dir() -> anyhow::Result<()> {
+    let temp_dir = tempdir()?;
+    let path = temp_dir.as_ref().join("store");
+    let lock_dir = temp_dir.as_ref().join("locks");
+    create_repo_at(&path, &lock_dir)?;
+
+    let mut store = open_store(&path, &lock_dir)?;
+    let id = ObjectRepository::<String, _>::peek_info(&mut store)?.id();
+    let _repository =
+        ObjectRepository::<String, _>::open_repo(store, LockStrategy::Abort, Some(PASSWORD))?;
+
+    let lock_files = read_dir(&lock_dir)?
+        .map(|entry| entry.map(|entry| entry.file_name()))
+        .collect::<Result<Vec<_>, _>>()?;
+    assert_eq!(
+        lock_files,
+        vec![OsString::from(format!("{}.lock", id.to_hyphenated()))]
+    );
+    Ok(())
+}
+
+#[test]
+fn lock_owner_is_current_process() -> anyhow::Result<()> {
+    let temp_dir = tempdir()?;
+    let path = temp_dir.as_ref().join("store");
+    let lock_dir = temp_dir.as_ref().join("locks");
+    create_repo_at(&path, &lock_dir)?;
+
+    let mut store = open_store(&path, &lock_dir)?;
+    assert_eq!(
+        ObjectRepository::<String, _>::peek_lock_owner(&mut store)?,
+        None
+    );
+
+    let before_lock = SystemTime::now() - Duration::from_secs(1);
+    let repository =
+        ObjectRepository::<String, _>::open_repo(store, LockStrategy::Abort, Some(PASSWORD))?;
+
+    let mut store = open_store(&path, &lock_dir)?;
+    let owner = ObjectRepository::<String, _>::peek_lock_owner(&mut store)?.unwrap();
+    assert_eq!(owner.pid(), std::process::id());
+    assert!(owner.time() >= before_lock);
+    if cfg!(unix) {
+        assert_eq!(owner.is_alive(), Some(true));
+    }
+
+    drop(repository);
+    assert_eq!(
+        ObjectRepository::<String, _>::peek_lock_owner(&mut store)?,
+        None
+    );
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn lock_held_for_dead_process_is_detected() -> anyhow::Result<()> {
+    let temp_dir = tempdir()?;
+    let path = temp_dir.as_ref().join("store");
+    let lock_dir = temp_dir.as_ref().join("locks");
+    create_repo_at(&path, &lock_dir)?;
+
+    // Find the hostname which is recorded in lock files.
+    let repository = ObjectRepository::<String, _>::open_repo(
+        open_store(&path, &lock_dir)?,
+        LockStrategy::Abort,
+        Some(PASSWORD),
+    )?;
+    let hostname =
+        ObjectRepository::<String, _>::peek_lock_owner(&mut open_store(&path, &lock_dir)?)?
+            .unwrap()
+            .hostname()
+            .to_owned();
+    drop(repository);
+
+    // Record a process which has exited as the owner of a lock which is still held, like a lock
+    // which was inherited by a child process.
+    let mut child = std::process::Command::new("true").spawn()?;
+    let dead_pid = child.id();
+    child.wait()?;
+    let lock_file = read_dir(&lock_dir)?.next().unwrap()?.path();
+    let mut holder = OpenOptions::new().write(true).open(&lock_file)?;
+    holder.lock_exclusive()?;
+    holder.set_len(0)?;
+    write!(holder, "{}\n{}\n{}\n", dead_pid, hostname, 0)?;
+
+    let owner = ObjectRepository::<String, _>::peek_lock_owner(&mut open_store(&path, &lock_dir)?)?
+        .unwrap();
+    assert_eq!(owner.pid(), dead_pid);
+    assert_eq!(owner.is_alive(), Some(false));
+
+    // Once the lock is released, the stale owner is replaced.
+    FileExt::unlock(&holder)?;
+    let mut store = open_store(&path, &lock_dir)?;
+    assert_eq!(
+        ObjectRepository::<String, _>::peek_lock_owner(&mut store)?,
+        None
+    );
+    let _repository =
+        ObjectRepository::<String, _>::open_repo(store, LockStrategy::Abort, Some(PASSWORD))?;
+    let owner = ObjectRepository::<String, _>::peek_lock_owner(&mut open_store(&path, &lock_dir)?)?
+        .unwrap();
+    assert_eq!(owner.pid(), std::process::id());
+    Ok(())
+}
+
+#[test]
+fn waiting_for_lock_does_not_block_other_repositories() -> anyhow::Result<()> {
+    let temp_dir = tempdir()?;
+    let locked_path = temp_dir.as_ref().join("locked");
+    let other_path = temp_dir.as_ref().join("other");
+    let lock_dir = temp_dir.as_ref().join("locks");
+    create_repo_at(&locked_path, &lock_dir)?;
+
+    // Hold a file lock on the repository from another file handle, like another process would.
+    let lock_file = read_dir(&lock_dir)?.next().unwrap()?.path();
+    let holder = OpenOptions::new().write(true).open(&lock_file)?;
+    holder.lock_exclusive()?;
+
+    let store = open_store(&locked_path, &lock_dir)?;
+    let waiter = thread::spawn(move || {
+        ObjectRepository::<String, _>::open_repo(
+            store,
+            LockStrategy::Timeout(Duration::from_secs(10)),
+            Some(PASSWORD),
+        )
+        .is_ok()
+    });
+    thread::sleep(Duration::from_millis(100));
+
+    // Another repository can be locked while the other thread is waiting.
+    let start = Instant::now();
+    create_repo_at(&other_path, &lock_dir)?;
+    assert!(start.elapsed() < Duration::from_secs(5));
+
+    FileExt::unlock(&holder)?;
+    assert!(waiter.join().unwrap());
+    Ok(())
+}
@@ -0,0 +1,261 @@
+/*
+ * Copyright 2019-2020 Wren Powell
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+#![cfg(all(feature = "encryption", feature = "compression"))]
+
+use std::io::{Read, Write};
+use std::path::PathBuf;
+
+use uuid::Uuid;
+
+use acid_store::repo::{LockStrategy, ObjectRepository, OpenRepo};
+use acid_store::store::{
+    DataStore, LocalLockStore, MemoryStore, MirrorError, MirrorSetStore, MirrorStore,
+};
+#[cfg(feature = "store-faulty")]
+use acid_store::store::{FaultConfig, FaultyStore};
+use common::{random_buffer, PASSWORD, REPO_CONFIG};
+
+mod common;
+
+#[test]
+fn read_falls_back_when_copy_is_missing() -> anyhow::Result<()> {
+    let mut store = MirrorStore::new(MemoryStore::new(), MemoryStore::new());
+    let id = Uuid::new_v4();
+    let expected_block = random_buffer();
+    store.write_block(id, &expected_block)?;
+
+    store.primary_mut().remove_block(id)?;
+
+    assert_eq!(store.read_block(id)?, Some(expected_block));
+
+    Ok(())
+}
+
+#[test]
+fn read_falls_back_when_copy_is_corrupt() -> anyhow::Result<()> {
+    let mut store = MirrorStore::new(MemoryStore::new(), MemoryStore::new());
+    let id = Uuid::new_v4();
+    let expected_block = random_buffer();
+    store.write_block(id, &expected_block)?;
+
+    store.secondary_mut().write_block(id, b"corrupt")?;
+    assert_eq!(store.read_block(id)?, Some(expected_block.clone()));
+
+    store.primary_mut().write_block(id, b"corrupt")?;
+    assert!(matches!(store.read_block(id), Err(MirrorError::Corrupt)));
+
+    Ok(())
+}
+
+#[test]
+fn resync_repairs_degraded_member() -> anyhow::Result<()> {
+    let mut store = MirrorStore::new(MemoryStore::new(), MemoryStore::new());
+    let missing_id = Uuid::new_v4();
+    let corrupt_id = Uuid::new_v4();
+    let missing_block = random_buffer();
+    let corrupt_block = random_buffer();
+    store.write_block(missing_id, &missing_block)?;
+    store.write_block(corrupt_id, &corrupt_block)?;
+
+    store.primary_mut().remove_block(missing_id)?;
+    store.secondary_mut().write_block(corrupt_id, b"corrupt")?;
+
+    let stats = store.resync()?;
+    assert_eq!(stats.repaired(), 2);
+    assert_eq!(stats.unrecoverable(), 0);
+
+    // Each member should have an intact copy of every block on its own.
+    let (primary, secondary) = store.into_inner();
+    let members_only = vec![
+        MirrorStore::new(primary, MemoryStore::new()),
+        MirrorStore::new(MemoryStore::new(), secondary),
+    ];
+    for mut store in members_only {
+        assert_eq!(store.read_block(missing_id)?, Some(missing_block.clone()));
+        assert_eq!(store.read_block(corrupt_id)?, Some(corrupt_block.clone()));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn resync_copies_blocks_to_new_member() -> anyhow::Result<()> {
+    let mut store = MirrorSetStore::new(vec![MemoryStore::new(), MemoryStore::new()]);
+    let id = Uuid::new_v4();
+    store.write_block(id, &random_buffer())?;
+
+    let mut members = store.into_inner();
+    members.push(MemoryStore::new());
+    let mut store = MirrorSetStore::new(members);
+
+    assert_eq!(store.resync()?.repaired(), 1);
+    assert_eq!(
+        store.members()[0].clone().list_blocks()?,
+        store.members()[2].clone().list_blocks()?
+    );
+
+    Ok(())
+}
+
+#[test]
+#[cfg(feature = "store-faulty")]
+fn failed_write_degrades_mirror() -> anyhow::Result<()> {
+    // The mirror reads the stale blocks recorded in each member before its first operation.
+    let mut faults = FaultConfig::new(0);
+    faults.crash_after = Some(2);
+    let mut store = MirrorStore::new(
+        MemoryStore::new(),
+        FaultyStore::new(MemoryStore::new(), faults),
+    );
+
+    let id = Uuid::new_v4();
+    let old_block = random_buffer();
+    let new_block = random_buffer();
+    store.write_block(id, &old_block)?;
+    assert!(!store.is_degraded());
+
+    // The secondary member still has the old copy of the block, which must not be read.
+    assert!(store.write_block(id, &new_block).is_err());
+    assert!(store.is_degraded());
+    assert_eq!(store.read_block(id)?, Some(new_block.clone()));
+
+    store.secondary_mut().recover();
+    assert_eq!(store.resync()?.repaired(), 1);
+    assert!(!store.is_degraded());
+
+    let (_, secondary) = store.into_inner();
+    let mut secondary_only = MirrorStore::new(MemoryStore::new(), secondary);
+    assert_eq!(secondary_only.read_block(id)?, Some(new_block));
+
+    Ok(())
+}
+
+#[test]
+#[cfg(feature = "store-faulty")]
+fn stale_copies_are_not_read_after_recreating_mirror() -> anyhow::Result<()> {
+    // The mirror reads the stale blocks recorded in each member before its first operation.
+    let mut faults = FaultConfig::new(0);
+    faults.crash_after = Some(2);
+    let mut store = MirrorSetStore::new(vec![
+        FaultyStore::new(MemoryStore::new(), faults),
+        FaultyStore::new(MemoryStore::new(), FaultConfig::new(0)),
+    ]);
+
+    let id = Uuid::new_v4();
+    let old_block = random_buffer();
+    let new_block = random_buffer();
+    store.write_block(id, &old_block)?;
+    assert!(store.write_block(id, &new_block).is_err());
+
+    // The first member still has the old copy and would be read from first.
+    let mut members = store.into_inner();
+    members[0].recover();
+    let mut store = MirrorSetStore::new(members);
+    assert_eq!(store.read_block(id)?, Some(new_block.clone()));
+    assert!(store.is_degraded());
+    assert_eq!(store.list_blocks()?, vec![id]);
+
+    assert_eq!(store.resync()?.repaired(), 1);
+    let mut members = store.into_inner();
+    members.swap(0, 1);
+    let mut store = MirrorSetStore::new(members);
+    assert!(!store.is_degraded());
+    assert_eq!(store.read_block(id)?, Some(new_block));
+
+    Ok(())
+}
+
+#[test]
+fn resync_uses_newest_copy() -> anyhow::Result<()> {
+    let mut store = MirrorStore::new(MemoryStore::new(), MemoryStore::new());
+    let id = Uuid::new_v4();
+    let new_block = random_buffer();
+    store.write_block(id, &random_buffer())?;
+    let old_copy = store.primary_mut().read_block(id)?.unwrap();
+    store.write_block(id, &new_block)?;
+
+    // Restore an old copy of the block in the primary member.
+    store.primary_mut().write_block(id, &old_copy)?;
+
+    assert_eq!(store.resync()?.repaired(), 1);
+    assert_eq!(store.read_block(id)?, Some(new_block.clone()));
+    let (primary, _) = store.into_inner();
+    let mut primary_only = MirrorStore::new(primary, MemoryStore::new());
+    assert_eq!(primary_only.read_block(id)?, Some(new_block));
+
+    Ok(())
+}
+
+#[test]
+fn repository_survives_losing_member() -> anyhow::Result<()> {
+    let store = MirrorSetStore::new(vec![
+        MemoryStore::new(),
+        MemoryStore::new(),
+        MemoryStore::new(),
+    ]);
+    let mut repository: ObjectRepository<String, _> =
+        ObjectRepository::new_repo(store, REPO_CONFIG.to_owned(), Some(PASSWORD))?;
+    let expected_data = random_buffer();
+    let mut object = repository.insert(String::from("Test"));
+    object.write_all(&expected_data)?;
+    object.flush()?;
+    drop(object);
+    repository.commit()?;
+
+    // Lose one member entirely and corrupt every block in another.
+    let mut store = repository.into_store();
+    store.members_mut()[0] = MemoryStore::new();
+    for id in store.members_mut()[1].list_blocks()? {
+        store.members_mut()[1].write_block(id, b"corrupt")?;
+    }
+
+    let repository: ObjectRepository<String, _> =
+        ObjectRepository::open_repo(store, LockStrategy::Abort, Some(PASSWORD))?;
+    let mut actual_data = Vec::new();
+    repository
+        .get("Test")
+        .unwrap()
+        .read_to_end(&mut actual_data)?;
+    assert_eq!(actual_data, expected_data);
+
+    let mut store = repository.into_store();
+    let stats = store.resync()?;
+    assert!(stats.repaired() > 0);
+    assert_eq!(stats.unrecoverable(), 0);
+
+    Ok(())
+}
+
+#[test]
+fn lock_dir_is_forwarded_from_members() {
+    let lock_dir = PathBuf::from("/tmp/acid-store-locks");
+
+    let store = MirrorStore::new(
+        MemoryStore::new(),
+        LocalLockStore::new(MemoryStore::new(), lock_dir.clone()),
+    );
+    assert_eq!(store.lock_dir(), Some(lock_dir.clone()));
+
+    let store = MirrorSetStore::new(vec![
+        LocalLockStore::new(MemoryStore::new(), lock_dir.clone()),
+        LocalLockStore::new(MemoryStore::new(), PathBuf::from("/tmp/other-locks")),
+    ]);
+    assert_eq!(store.lock_dir(), Some(lock_dir));
+
+    let store = MirrorStore::new(MemoryStore::new(), MemoryStore::new());
+    assert_eq!(store.lock_dir(), None);
+}
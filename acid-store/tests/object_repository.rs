@@ -16,13 +16,21 @@
 
 #![cfg(all(feature = "encryption", feature = "compression"))]
 
-use std::io::Write;
+use std::collections::HashSet;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::sync::{Arc, Mutex};
+use std::thread::sleep;
+use std::time::{Duration, SystemTime};
 
 use tempfile::tempdir;
+use uuid::Uuid;
 
-use acid_store::repo::{LockStrategy, ObjectRepository, OpenRepo, RepositoryConfig};
+use acid_store::repo::{
+    Chunking, Compression, Encryption, HeaderMode, KeySlotKind, LockStrategy, ObjectRepository,
+    OpenRepo, RepositoryConfig, ResourceLimit, SlotKey, DEFAULT_KEY_SLOT, RAW_KEY_SIZE,
+};
 use acid_store::store::{DataStore, DirectoryStore, MemoryStore, OpenOption, OpenStore};
-use common::{create_repo, random_buffer, PASSWORD, REPO_CONFIG};
+use common::{create_repo, random_buffer, random_bytes, PASSWORD, REPO_CONFIG};
 
 mod common;
 
@@ -258,6 +266,138 @@ fn committed_changes_are_persisted() -> anyhow::Result<()> {
     Ok(())
 }
 
+#[test]
+fn incremental_commits_are_persisted() -> anyhow::Result<()> {
+    let mut repository = create_repo()?;
+
+    // Commit enough small changes that the header journal is compacted at least once.
+    for i in 0..100 {
+        let mut object = repository.insert(format!("Test{}", i));
+        object.write_all(format!("Data{}", i).as_bytes())?;
+        object.flush()?;
+        drop(object);
+        if i % 3 == 0 {
+            repository.remove(&format!("Test{}", i));
+        }
+        repository.commit()?;
+    }
+
+    // Re-open the repository.
+    let repository = ObjectRepository::<String, _>::open_repo(
+        repository.into_store(),
+        LockStrategy::Abort,
+        Some(PASSWORD),
+    )?;
+
+    for i in 0..100 {
+        let key = format!("Test{}", i);
+        if i % 3 == 0 {
+            assert!(!repository.contains(&key));
+        } else {
+            let mut object = repository.get(&key).unwrap();
+            let mut actual_data = Vec::new();
+            object.read_to_end(&mut actual_data)?;
+            assert_eq!(actual_data, format!("Data{}", i).into_bytes());
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn paged_header_is_persisted() -> anyhow::Result<()> {
+    let mut config = REPO_CONFIG.to_owned();
+    config.header_mode = HeaderMode::Paged { cache_pages: 4 };
+    let mut repository = ObjectRepository::new_repo(MemoryStore::new(), config, Some(PASSWORD))?;
+
+    // Write enough objects that the B-tree pages are split, and remove some of them in a later
+    // commit so that pages are copied and freed.
+    for i in 0..300 {
+        let mut object = repository.insert(format!("Test{}", i));
+        object.write_all(format!("Data{}", i).as_bytes())?;
+        object.flush()?;
+    }
+    repository.commit()?;
+    for i in (0..300).step_by(3) {
+        repository.remove(&format!("Test{}", i));
+    }
+    repository.commit()?;
+
+    // Re-open the repository.
+    let repository = ObjectRepository::<String, _>::open_repo(
+        repository.into_store(),
+        LockStrategy::Abort,
+        Some(PASSWORD),
+    )?;
+
+    assert_eq!(repository.keys().count(), 200);
+    for i in 0..300 {
+        let key = format!("Test{}", i);
+        if i % 3 == 0 {
+            assert!(!repository.contains(&key));
+        } else {
+            let mut object = repository.get(&key).unwrap();
+            let mut actual_data = Vec::new();
+            object.read_to_end(&mut actual_data)?;
+            assert_eq!(actual_data, format!("Data{}", i).into_bytes());
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn zstd_dictionary_is_persisted() -> anyhow::Result<()> {
+    let mut config = REPO_CONFIG.to_owned();
+    config.compression = Compression::Zstd { level: 3 };
+    let mut repository = ObjectRepository::new_repo(MemoryStore::new(), config, Some(PASSWORD))?;
+
+    // Write data before the dictionary is trained so it is compressed without it.
+    let old_data = random_buffer();
+    let mut object = repository.insert(String::from("Old"));
+    object.write_all(old_data.as_slice())?;
+    object.flush()?;
+    drop(object);
+
+    let samples = (0..1_000)
+        .map(|i| {
+            format!(
+                "{{\"id\": {}, \"name\": \"user{}\", \"active\": true}}",
+                i,
+                i % 17
+            )
+        })
+        .collect::<Vec<_>>();
+    repository.train_dictionary(&samples, 1024)?;
+
+    let new_data = samples[..100].concat().into_bytes();
+    let mut object = repository.insert(String::from("New"));
+    object.write_all(new_data.as_slice())?;
+    object.flush()?;
+    drop(object);
+    repository.commit()?;
+
+    let store = repository.into_store();
+    let repository =
+        ObjectRepository::<String, _>::open_repo(store, LockStrategy::Abort, Some(PASSWORD))?;
+
+    let mut actual_data = Vec::new();
+    repository
+        .get("Old")
+        .unwrap()
+        .read_to_end(&mut actual_data)?;
+    assert_eq!(actual_data, old_data);
+
+    let mut actual_data = Vec::new();
+    repository
+        .get("New")
+        .unwrap()
+        .read_to_end(&mut actual_data)?;
+    assert_eq!(actual_data, new_data);
+
+    Ok(())
+}
+
 #[test]
 fn uncommitted_changes_are_not_persisted() -> anyhow::Result<()> {
     // Write data to the repository.
@@ -281,7 +421,83 @@ fn uncommitted_changes_are_not_persisted() -> anyhow::Result<()> {
 }
 
 #[test]
-fn unused_data_is_reclaimed_on_commit() -> anyhow::Result<()> {
+fn rollback_restores_committed_state() -> anyhow::Result<()> {
+    let mut repository = create_repo()?;
+    let expected_data = random_buffer();
+    let mut object = repository.insert("Committed".into());
+    object.write_all(expected_data.as_slice())?;
+    object.flush()?;
+    drop(object);
+    repository.commit()?;
+
+    let mut object = repository.insert("Uncommitted".into());
+    object.write_all(random_buffer().as_slice())?;
+    object.flush()?;
+    drop(object);
+    repository.remove("Committed");
+    repository.rollback()?;
+
+    assert!(repository.contains("Committed"));
+    assert!(!repository.contains("Uncommitted"));
+
+    let mut actual_data = Vec::new();
+    let mut object = repository.get("Committed").unwrap();
+    object.read_to_end(&mut actual_data)?;
+    assert_eq!(actual_data, expected_data);
+
+    // The data written since the last commit is reclaimed by `gc`.
+    assert!(repository.gc_dry_run().bytes() > 0);
+    Ok(())
+}
+
+#[test]
+fn rollback_to_savepoint() -> anyhow::Result<()> {
+    for header_mode in &[HeaderMode::Memory, HeaderMode::Paged { cache_pages: 4 }] {
+        let mut config = REPO_CONFIG.to_owned();
+        config.header_mode = *header_mode;
+        let mut repository =
+            ObjectRepository::new_repo(MemoryStore::new(), config, Some(PASSWORD))?;
+
+        let mut object = repository.insert(String::from("First"));
+        object.write_all(b"First")?;
+        object.flush()?;
+        drop(object);
+        let first_savepoint = repository.savepoint();
+
+        let mut object = repository.insert(String::from("Second"));
+        object.write_all(b"Second")?;
+        object.flush()?;
+        drop(object);
+        let second_savepoint = repository.savepoint();
+
+        repository.remove("First");
+        repository.rollback_to(second_savepoint)?;
+        assert!(repository.contains("First"));
+        assert!(repository.contains("Second"));
+
+        repository.rollback_to(first_savepoint)?;
+        assert!(repository.contains("First"));
+        assert!(!repository.contains("Second"));
+        assert_eq!(repository.gc_dry_run().bytes(), b"Second".len() as u64);
+
+        // Savepoints created after the one which was rolled back to are discarded.
+        assert!(matches!(
+            repository.rollback_to(second_savepoint),
+            Err(acid_store::Error::NotFound)
+        ));
+
+        // Savepoints are discarded when changes are committed.
+        repository.commit()?;
+        assert!(matches!(
+            repository.rollback_to(first_savepoint),
+            Err(acid_store::Error::NotFound)
+        ));
+    }
+    Ok(())
+}
+
+#[test]
+fn unused_data_is_reclaimed_by_gc() -> anyhow::Result<()> {
     let mut repository = create_repo()?;
     let mut object = repository.insert("Test".into());
     object.write_all(random_buffer().as_slice())?;
@@ -296,6 +512,7 @@ fn unused_data_is_reclaimed_on_commit() -> anyhow::Result<()> {
         ObjectRepository::<String, _>::open_repo(store, LockStrategy::Abort, Some(PASSWORD))?;
     repository.remove("Test");
     repository.commit()?;
+    repository.gc(None)?;
 
     let mut store = repository.into_store();
     let new_blocks = store.list_blocks()?.len();
@@ -304,6 +521,67 @@ fn unused_data_is_reclaimed_on_commit() -> anyhow::Result<()> {
     Ok(())
 }
 
+#[test]
+fn gc_removes_blocks_in_multiple_batches() -> anyhow::Result<()> {
+    let mut repository = create_repo()?;
+    for index in 0..300 {
+        let mut object = repository.insert(index.to_string());
+        object.write_all(random_bytes(64).as_slice())?;
+        object.flush()?;
+    }
+    repository.commit()?;
+
+    for index in 0..300 {
+        repository.remove(&index.to_string());
+    }
+    repository.commit()?;
+    let expected_stats = repository.gc_dry_run();
+    assert!(expected_stats.blocks() >= 300);
+
+    let stats = repository.gc(None)?;
+    assert_eq!(stats.blocks(), expected_stats.blocks());
+    assert_eq!(stats.remaining(), 0);
+    Ok(())
+}
+
+#[test]
+fn gc_dry_run_reports_reclaimable_space() -> anyhow::Result<()> {
+    let mut repository = create_repo()?;
+    let data = random_buffer();
+    let mut object = repository.insert("Test".into());
+    object.write_all(data.as_slice())?;
+    object.flush()?;
+    drop(object);
+    repository.commit()?;
+    assert_eq!(repository.gc_dry_run().bytes(), 0);
+
+    repository.remove("Test");
+    repository.commit()?;
+    let expected_stats = repository.gc_dry_run();
+    assert_eq!(expected_stats.bytes(), data.len() as u64);
+    assert!(expected_stats.blocks() > 1);
+
+    // The first call removes a single block before running out of time.
+    let first_stats = repository.gc(Some(Duration::from_secs(0)))?;
+    assert_eq!(first_stats.blocks(), 1);
+    assert_eq!(first_stats.remaining(), expected_stats.blocks() - 1);
+
+    // The remaining blocks are still tracked after the repository is reopened.
+    let store = repository.into_store();
+    let mut repository =
+        ObjectRepository::<String, _>::open_repo(store, LockStrategy::Abort, Some(PASSWORD))?;
+    let second_stats = repository.gc(None)?;
+
+    assert_eq!(second_stats.blocks(), expected_stats.blocks() - 1);
+    assert_eq!(
+        first_stats.bytes() + second_stats.bytes(),
+        expected_stats.bytes()
+    );
+    assert_eq!(second_stats.remaining(), 0);
+    assert_eq!(repository.gc_dry_run().blocks(), 0);
+    Ok(())
+}
+
 #[test]
 fn verify_valid_repository_is_valid() -> anyhow::Result<()> {
     let mut repository = create_repo()?;
@@ -321,7 +599,7 @@ fn verify_valid_repository_is_valid() -> anyhow::Result<()> {
 #[test]
 fn change_password() -> anyhow::Result<()> {
     let mut repository = create_repo()?;
-    repository.change_password(b"new password");
+    repository.change_password(b"new password")?;
     repository.commit()?;
 
     ObjectRepository::<String, _>::open_repo(
@@ -333,6 +611,176 @@ fn change_password() -> anyhow::Result<()> {
     Ok(())
 }
 
+#[test]
+fn open_with_any_key_slot() -> anyhow::Result<()> {
+    let mut repository = create_repo()?;
+    let raw_key = [7u8; RAW_KEY_SIZE];
+    repository.add_key_slot("recovery", SlotKey::Password(b"recovery password"))?;
+    repository.add_key_slot("service", SlotKey::Raw(&raw_key))?;
+    repository.commit()?;
+
+    let slots = repository.key_slots();
+    assert_eq!(
+        slots
+            .iter()
+            .map(|slot| (slot.name(), slot.kind()))
+            .collect::<Vec<_>>(),
+        vec![
+            (DEFAULT_KEY_SLOT, KeySlotKind::Password),
+            ("recovery", KeySlotKind::Password),
+            ("service", KeySlotKind::Raw),
+        ]
+    );
+
+    let repository = ObjectRepository::<String, _>::open_repo(
+        repository.into_store(),
+        LockStrategy::Abort,
+        Some(b"recovery password"),
+    )?;
+    let repository = ObjectRepository::<String, _>::open_repo(
+        repository.into_store(),
+        LockStrategy::Abort,
+        Some(PASSWORD),
+    )?;
+    let repository = ObjectRepository::<String, _>::open_repo_with_key(
+        repository.into_store(),
+        LockStrategy::Abort,
+        &raw_key,
+    )?;
+    let result = ObjectRepository::<String, _>::open_repo_with_key(
+        repository.into_store(),
+        LockStrategy::Abort,
+        &[8u8; RAW_KEY_SIZE],
+    );
+    assert!(matches!(result, Err(acid_store::Error::Password)));
+
+    Ok(())
+}
+
+#[test]
+fn removed_key_slot_cannot_open() -> anyhow::Result<()> {
+    let mut repository = create_repo()?;
+    repository.add_key_slot("recovery", SlotKey::Password(b"recovery password"))?;
+    assert!(repository.remove_key_slot(DEFAULT_KEY_SLOT));
+    assert!(!repository.remove_key_slot("nonexistent"));
+
+    // The last key slot can't be removed.
+    assert!(!repository.remove_key_slot("recovery"));
+    repository.commit()?;
+
+    let result = ObjectRepository::<String, _>::open_repo(
+        repository.into_store(),
+        LockStrategy::Abort,
+        Some(PASSWORD),
+    );
+    assert!(matches!(result, Err(acid_store::Error::Password)));
+
+    Ok(())
+}
+
+#[test]
+fn change_password_only_changes_unlocked_slot() -> anyhow::Result<()> {
+    let mut repository = create_repo()?;
+    repository.add_key_slot("recovery", SlotKey::Password(b"recovery password"))?;
+    repository.commit()?;
+    let mut repository = ObjectRepository::<String, _>::open_repo(
+        repository.into_store(),
+        LockStrategy::Abort,
+        Some(b"recovery password"),
+    )?;
+    repository.change_password(b"new password")?;
+    repository.commit()?;
+
+    let repository = ObjectRepository::<String, _>::open_repo(
+        repository.into_store(),
+        LockStrategy::Abort,
+        Some(PASSWORD),
+    )?;
+    let result = ObjectRepository::<String, _>::open_repo(
+        repository.into_store(),
+        LockStrategy::Abort,
+        Some(b"recovery password"),
+    );
+    assert!(matches!(result, Err(acid_store::Error::Password)));
+
+    Ok(())
+}
+
+#[test]
+fn open_with_named_key_slot() -> anyhow::Result<()> {
+    let mut repository = create_repo()?;
+    repository.add_key_slot("recovery", SlotKey::Password(b"recovery password"))?;
+    repository.commit()?;
+
+    let repository = ObjectRepository::<String, _>::open_repo_with_slot(
+        repository.into_store(),
+        LockStrategy::Abort,
+        "recovery",
+        SlotKey::Password(b"recovery password"),
+    )?;
+
+    // The key must unlock the named slot, even if it unlocks another one.
+    let result = ObjectRepository::<String, _>::open_repo_with_slot(
+        repository.into_store(),
+        LockStrategy::Abort,
+        "recovery",
+        SlotKey::Password(PASSWORD),
+    );
+    assert!(matches!(result, Err(acid_store::Error::Password)));
+
+    Ok(())
+}
+
+#[test]
+fn open_with_nonexistent_key_slot_errs() -> anyhow::Result<()> {
+    let repository = create_repo()?;
+    let result = ObjectRepository::<String, _>::open_repo_with_slot(
+        repository.into_store(),
+        LockStrategy::Abort,
+        "nonexistent",
+        SlotKey::Password(PASSWORD),
+    );
+    assert!(matches!(result, Err(acid_store::Error::Password)));
+    Ok(())
+}
+
+#[test]
+fn change_password_of_raw_key_slot_errs() -> anyhow::Result<()> {
+    let mut repository = create_repo()?;
+    let raw_key = [7u8; RAW_KEY_SIZE];
+    repository.add_key_slot("service", SlotKey::Raw(&raw_key))?;
+    repository.commit()?;
+    let mut repository = ObjectRepository::<String, _>::open_repo_with_key(
+        repository.into_store(),
+        LockStrategy::Abort,
+        &raw_key,
+    )?;
+
+    let result = repository.change_password(b"new password");
+    assert!(matches!(result, Err(acid_store::Error::Password)));
+    assert_eq!(
+        repository
+            .key_slots()
+            .iter()
+            .map(|slot| (slot.name(), slot.kind()))
+            .collect::<Vec<_>>(),
+        vec![
+            (DEFAULT_KEY_SLOT, KeySlotKind::Password),
+            ("service", KeySlotKind::Raw),
+        ]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn add_existing_key_slot_errs() -> anyhow::Result<()> {
+    let mut repository = create_repo()?;
+    let result = repository.add_key_slot(DEFAULT_KEY_SLOT, SlotKey::Password(b"password"));
+    assert!(matches!(result, Err(acid_store::Error::AlreadyExists)));
+    Ok(())
+}
+
 #[test]
 fn calculate_apparent_and_actual_size() -> anyhow::Result<()> {
     // Create a repository with compression and encryption disabled.
@@ -357,6 +805,64 @@ fn calculate_apparent_and_actual_size() -> anyhow::Result<()> {
     Ok(())
 }
 
+#[test]
+fn encoding_stats_count_bytes_read_and_written() -> anyhow::Result<()> {
+    let mut repository = create_repo()?;
+    let data = random_buffer();
+    let initial_stats = repository.encoding_stats();
+
+    let mut object = repository.insert("Test".to_string());
+    object.write_all(data.as_slice())?;
+    object.flush()?;
+    drop(object);
+
+    let written_stats = repository.encoding_stats();
+    assert!(written_stats.bytes_written() > initial_stats.bytes_written());
+    assert_eq!(written_stats.bytes_read(), initial_stats.bytes_read());
+
+    let mut object = repository.get("Test").unwrap();
+    let mut actual_data = Vec::new();
+    object.read_to_end(&mut actual_data)?;
+    drop(object);
+
+    let read_stats = repository.encoding_stats();
+    assert!(read_stats.bytes_read() > written_stats.bytes_read());
+    assert_eq!(read_stats.bytes_written(), written_stats.bytes_written());
+
+    Ok(())
+}
+
+#[test]
+fn object_encoding_stats_only_count_the_handle() -> anyhow::Result<()> {
+    let mut repository = create_repo()?;
+    let data = random_buffer();
+
+    let mut object = repository.insert("First".to_string());
+    object.write_all(data.as_slice())?;
+    object.flush()?;
+    let written_stats = object.encoding_stats();
+    assert!(written_stats.bytes_written() > 0);
+    assert_eq!(written_stats.bytes_read(), 0);
+    drop(object);
+
+    let mut object = repository.insert("Second".to_string());
+    object.write_all(data.as_slice())?;
+    object.flush()?;
+    drop(object);
+
+    let first = repository.get("First").unwrap();
+    let mut second = repository.get("Second").unwrap();
+    let mut actual_data = Vec::new();
+    second.read_to_end(&mut actual_data)?;
+
+    assert_eq!(first.encoding_stats(), Default::default());
+    assert!(second.encoding_stats().bytes_read() > 0);
+    assert_eq!(second.encoding_stats().bytes_written(), 0);
+    assert!(repository.encoding_stats().bytes_read() >= second.encoding_stats().bytes_read());
+
+    Ok(())
+}
+
 #[test]
 fn peek_info() -> anyhow::Result<()> {
     let repository = create_repo()?;
@@ -367,3 +873,875 @@ fn peek_info() -> anyhow::Result<()> {
     assert_eq!(actual_info, expected_info);
     Ok(())
 }
+
+#[test]
+fn each_cipher_round_trips() -> anyhow::Result<()> {
+    let data = random_buffer();
+
+    for encryption in &[
+        Encryption::XChaCha20Poly1305,
+        Encryption::Aes256Gcm,
+        Encryption::XChaCha20Poly1305Committing,
+        Encryption::Aes256GcmCommitting,
+        Encryption::Auto,
+    ] {
+        let mut config = REPO_CONFIG.to_owned();
+        config.encryption = *encryption;
+        let mut repository: ObjectRepository<String, _> =
+            match ObjectRepository::new_repo(MemoryStore::new(), config, Some(PASSWORD)) {
+                Err(acid_store::Error::Unsupported) => continue,
+                result => result?,
+            };
+
+        let info = repository.info();
+        if *encryption == Encryption::Auto {
+            assert_ne!(info.encryption(), Encryption::Auto);
+        } else {
+            assert_eq!(info.encryption(), *encryption);
+        }
+
+        let mut object = repository.insert(String::from("Test"));
+        object.write_all(data.as_slice())?;
+        object.flush()?;
+        drop(object);
+        repository.commit()?;
+
+        let store = repository.into_store();
+        let repository: ObjectRepository<String, _> =
+            ObjectRepository::open_repo(store, LockStrategy::Abort, Some(PASSWORD))?;
+        assert_eq!(repository.info(), info);
+
+        let mut object = repository.get("Test").unwrap();
+        let mut actual_data = Vec::new();
+        object.read_to_end(&mut actual_data)?;
+        assert_eq!(actual_data, data);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn gc_full_removes_untracked_blocks() -> anyhow::Result<()> {
+    for header_mode in [HeaderMode::Memory, HeaderMode::Paged { cache_pages: 4 }].iter() {
+        let mut config = REPO_CONFIG.to_owned();
+        config.header_mode = *header_mode;
+        let mut repository: ObjectRepository<String, _> =
+            ObjectRepository::new_repo(MemoryStore::new(), config, Some(PASSWORD))?;
+        let committed_data = random_buffer();
+        let mut object = repository.insert("Committed".into());
+        object.write_all(committed_data.as_slice())?;
+        object.flush()?;
+        drop(object);
+        repository.commit()?;
+
+        // A block which the repository never recorded, like one left by an interrupted process.
+        let mut store = repository.into_store();
+        let stray_id = Uuid::new_v4();
+        store.write_block(stray_id, b"stray")?;
+
+        let mut repository: ObjectRepository<String, _> =
+            ObjectRepository::open_repo(store, LockStrategy::Abort, Some(PASSWORD))?;
+        let uncommitted_data = random_buffer();
+        let mut object = repository.insert("Uncommitted".into());
+        object.write_all(uncommitted_data.as_slice())?;
+        object.flush()?;
+        drop(object);
+
+        // Blocks which were recorded as unused are removed along with the untracked one.
+        let tracked_blocks = repository.gc_dry_run().blocks();
+        let stats = repository.gc_full()?;
+        assert_eq!(stats.blocks(), tracked_blocks + 1);
+        assert_eq!(repository.gc_full()?.blocks(), 0);
+        repository.commit()?;
+
+        let mut store = repository.into_store();
+        assert!(store.read_block(stray_id)?.is_none());
+        let repository: ObjectRepository<String, _> =
+            ObjectRepository::open_repo(store, LockStrategy::Abort, Some(PASSWORD))?;
+        for (key, expected_data) in [
+            ("Committed", committed_data),
+            ("Uncommitted", uncommitted_data),
+        ]
+        .iter()
+        {
+            let mut object = repository.get(*key).unwrap();
+            let mut actual_data = Vec::new();
+            object.read_to_end(&mut actual_data)?;
+            assert_eq!(&actual_data, expected_data);
+        }
+        assert!(repository.verify()?.is_empty());
+    }
+    Ok(())
+}
+
+#[test]
+fn gc_full_removes_tracked_unused_blocks() -> anyhow::Result<()> {
+    let mut repository = create_repo()?;
+    let data = random_buffer();
+    let mut object = repository.insert("Test".into());
+    object.write_all(data.as_slice())?;
+    object.flush()?;
+    drop(object);
+    repository.commit()?;
+
+    repository.remove("Test");
+    repository.commit()?;
+    let expected_stats = repository.gc_dry_run();
+
+    let stats = repository.gc_full()?;
+    assert_eq!(stats.blocks(), expected_stats.blocks());
+    assert_eq!(stats.bytes(), expected_stats.bytes());
+    assert_eq!(repository.gc_dry_run().blocks(), 0);
+    Ok(())
+}
+
+#[test]
+fn gc_full_keeps_reconfiguration_in_progress() -> anyhow::Result<()> {
+    let mut repository = create_repo()?;
+    let keys = ["First", "Second", "Third"];
+    let data = keys.iter().map(|_| random_buffer()).collect::<Vec<_>>();
+    for (key, data) in keys.iter().zip(data.iter()) {
+        let mut object = repository.insert(key.to_string());
+        object.write_all(data.as_slice())?;
+        object.flush()?;
+    }
+    repository.commit()?;
+
+    let mut new_config = REPO_CONFIG.to_owned();
+    new_config.chunking = Chunking::Zpaq { bits: 10 };
+    new_config.encryption = Encryption::XChaCha20Poly1305Committing;
+    new_config.header_mode = HeaderMode::Paged { cache_pages: 4 };
+    repository.reconfigure(
+        new_config.clone(),
+        Some(SlotKey::Password(PASSWORD)),
+        Some(Duration::new(0, 0)),
+    )?;
+    repository.gc_full()?;
+
+    // The blocks of a reconfiguration which hasn't been resumed can't be read.
+    let store = repository.into_store();
+    let mut repository: ObjectRepository<String, _> =
+        ObjectRepository::open_repo(store, LockStrategy::Abort, Some(PASSWORD))?;
+    assert!(matches!(
+        repository.gc_full(),
+        Err(acid_store::Error::Password)
+    ));
+
+    let progress = repository.reconfigure(new_config, Some(SlotKey::Password(PASSWORD)), None)?;
+    assert!(progress.is_complete());
+    repository.gc_full()?;
+
+    for (key, expected_data) in keys.iter().zip(data.iter()) {
+        let mut object = repository.get(*key).unwrap();
+        let mut actual_data = Vec::new();
+        object.read_to_end(&mut actual_data)?;
+        assert_eq!(&actual_data, expected_data);
+    }
+    assert!(repository.verify()?.is_empty());
+    Ok(())
+}
+
+#[test]
+fn reconfigure_rewrites_objects() -> anyhow::Result<()> {
+    let mut repository = create_repo()?;
+    let keys = ["First", "Second", "Third"];
+    let data = keys.iter().map(|_| random_buffer()).collect::<Vec<_>>();
+    for (key, data) in keys.iter().zip(data.iter()) {
+        let mut object = repository.insert(key.to_string());
+        object.write_all(data.as_slice())?;
+        object.flush()?;
+    }
+    repository.commit()?;
+
+    let mut new_config = REPO_CONFIG.to_owned();
+    new_config.chunking = Chunking::Zpaq { bits: 10 };
+    new_config.compression = Compression::None;
+    new_config.encryption = Encryption::XChaCha20Poly1305Committing;
+    new_config.header_mode = HeaderMode::Paged { cache_pages: 4 };
+    let new_password = b"new password";
+
+    // A budget of zero rewrites one object per call.
+    for remaining in (0..keys.len() as u64).rev() {
+        let progress = repository.reconfigure(
+            new_config.clone(),
+            Some(SlotKey::Password(new_password)),
+            Some(Duration::new(0, 0)),
+        )?;
+        assert_eq!(progress.objects(), 1);
+        assert_eq!(progress.remaining(), remaining);
+    }
+    assert_eq!(repository.info().config(), &new_config);
+    assert!(repository.gc_dry_run().bytes() > 0);
+    repository.gc(None)?;
+
+    let store = repository.into_store();
+    let repository: ObjectRepository<String, _> =
+        ObjectRepository::open_repo(store, LockStrategy::Abort, Some(new_password))?;
+    for (key, expected_data) in keys.iter().zip(data.iter()) {
+        let mut object = repository.get(*key).unwrap();
+        let mut actual_data = Vec::new();
+        object.read_to_end(&mut actual_data)?;
+        assert_eq!(&actual_data, expected_data);
+    }
+    assert!(repository.verify()?.is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn reconfigure_resumes_after_reopen() -> anyhow::Result<()> {
+    let mut repository = create_repo()?;
+    let keys = ["First", "Second", "Third"];
+    for key in keys.iter() {
+        let mut object = repository.insert(key.to_string());
+        object.write_all(random_buffer().as_slice())?;
+        object.flush()?;
+    }
+    repository.commit()?;
+
+    let mut new_config = REPO_CONFIG.to_owned();
+    new_config.chunking = Chunking::Zpaq { bits: 10 };
+    let progress = repository.reconfigure(new_config.clone(), None, Some(Duration::new(0, 0)))?;
+    assert_eq!(progress.remaining(), 2);
+
+    // Change every object so that the one which was already rewritten is rewritten again.
+    let store = repository.into_store();
+    let mut repository: ObjectRepository<String, _> =
+        ObjectRepository::open_repo(store, LockStrategy::Abort, Some(PASSWORD))?;
+    let data = keys.iter().map(|_| random_buffer()).collect::<Vec<_>>();
+    for (key, data) in keys.iter().zip(data.iter()) {
+        let mut object = repository.insert(key.to_string());
+        object.write_all(data.as_slice())?;
+        object.flush()?;
+    }
+
+    let progress = repository.reconfigure(new_config.clone(), None, None)?;
+    assert!(progress.is_complete());
+    assert_eq!(progress.objects(), 3);
+    assert_eq!(repository.info().config(), &new_config);
+
+    for (key, expected_data) in keys.iter().zip(data.iter()) {
+        let mut object = repository.get(*key).unwrap();
+        let mut actual_data = Vec::new();
+        object.read_to_end(&mut actual_data)?;
+        assert_eq!(&actual_data, expected_data);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn reconfigure_keeps_key_slots_when_cipher_is_unchanged() -> anyhow::Result<()> {
+    let mut repository = create_repo()?;
+    let raw_key = [7u8; RAW_KEY_SIZE];
+    repository.add_key_slot("raw", SlotKey::Raw(&raw_key))?;
+    let data = random_buffer();
+    let mut object = repository.insert("Test".into());
+    object.write_all(data.as_slice())?;
+    object.flush()?;
+    drop(object);
+    repository.commit()?;
+    let key_slots = repository.key_slots();
+
+    let mut new_config = REPO_CONFIG.to_owned();
+    new_config.chunking = Chunking::Zpaq { bits: 10 };
+    new_config.memory_limit = ResourceLimit::Moderate;
+
+    // No key is needed because the master key doesn't change.
+    assert!(matches!(
+        repository.reconfigure(new_config.clone(), Some(SlotKey::Password(PASSWORD)), None),
+        Err(acid_store::Error::Password)
+    ));
+    let progress = repository.reconfigure(new_config.clone(), None, None)?;
+    assert!(progress.is_complete());
+    assert_eq!(repository.info().config(), &new_config);
+    assert_eq!(repository.key_slots(), key_slots);
+
+    // Every slot can still be unlocked, even though the key derivation limits have changed.
+    let store = repository.into_store();
+    let repository: ObjectRepository<String, _> =
+        ObjectRepository::open_repo(store, LockStrategy::Abort, Some(PASSWORD))?;
+    let store = repository.into_store();
+    let repository: ObjectRepository<String, _> =
+        ObjectRepository::open_repo_with_key(store, LockStrategy::Abort, &raw_key)?;
+
+    let mut object = repository.get("Test").unwrap();
+    let mut actual_data = Vec::new();
+    object.read_to_end(&mut actual_data)?;
+    assert_eq!(actual_data, data);
+    Ok(())
+}
+
+#[test]
+fn reconfigure_with_unneeded_password_errs() -> anyhow::Result<()> {
+    let mut repository = create_repo()?;
+    let mut new_config = REPO_CONFIG.to_owned();
+    new_config.encryption = Encryption::None;
+    assert!(matches!(
+        repository.reconfigure(new_config, Some(SlotKey::Password(PASSWORD)), None),
+        Err(acid_store::Error::Password)
+    ));
+    Ok(())
+}
+
+#[test]
+fn each_chunking_method_round_trips() -> anyhow::Result<()> {
+    let methods = [
+        Chunking::Zpaq { bits: 8 },
+        Chunking::FastCdc {
+            min_size: 64,
+            avg_size: 256,
+            max_size: 1024,
+        },
+        Chunking::Fixed { size: 256 },
+    ];
+
+    for &chunking in methods.iter() {
+        let mut config = REPO_CONFIG.to_owned();
+        config.chunking = chunking;
+        let mut repository: ObjectRepository<String, _> =
+            ObjectRepository::new_repo(MemoryStore::new(), config, Some(PASSWORD))?;
+        let expected_data = random_buffer();
+        let mut object = repository.insert(String::from("Test"));
+        object.write_all(expected_data.as_slice())?;
+        object.flush()?;
+        drop(object);
+        repository.commit()?;
+
+        let repository: ObjectRepository<String, _> = ObjectRepository::open_repo(
+            repository.into_store(),
+            LockStrategy::Abort,
+            Some(PASSWORD),
+        )?;
+        assert_eq!(repository.info().config().chunking, chunking);
+        let mut object = repository.get("Test").unwrap();
+        let mut actual_data = Vec::new();
+        object.read_to_end(&mut actual_data)?;
+        assert_eq!(actual_data, expected_data);
+    }
+
+    Ok(())
+}
+
+#[test]
+#[allow(deprecated)]
+fn chunker_bits_is_used_with_default_chunking() -> anyhow::Result<()> {
+    let mut config = RepositoryConfig::default();
+    config.chunker_bits = 12;
+    let repository: ObjectRepository<String, _> =
+        ObjectRepository::new_repo(MemoryStore::new(), config, None)?;
+    assert_eq!(
+        repository.info().config().chunking,
+        Chunking::Zpaq { bits: 12 }
+    );
+
+    let mut config = RepositoryConfig::default();
+    config.chunker_bits = 12;
+    config.chunking = Chunking::Fixed { size: 256 };
+    let repository: ObjectRepository<String, _> =
+        ObjectRepository::new_repo(MemoryStore::new(), config, None)?;
+    assert_eq!(
+        repository.info().config().chunking,
+        Chunking::Fixed { size: 256 }
+    );
+
+    let mut config = RepositoryConfig::default();
+    config.chunker_bits = 0;
+    assert!(matches!(
+        ObjectRepository::<String, _>::new_repo(MemoryStore::new(), config, None),
+        Err(acid_store::Error::InvalidConfig)
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn fixed_chunking_deduplicates_modified_data() -> anyhow::Result<()> {
+    let mut config = REPO_CONFIG.to_owned();
+    config.chunking = Chunking::Fixed { size: 256 };
+    let mut repository: ObjectRepository<String, _> =
+        ObjectRepository::new_repo(MemoryStore::new(), config, Some(PASSWORD))?;
+    let original_data = random_bytes(256 * 16);
+    let mut modified_data = original_data.clone();
+    modified_data[1000] = modified_data[1000].wrapping_add(1);
+
+    let mut object = repository.insert(String::from("Original"));
+    object.write_all(original_data.as_slice())?;
+    object.flush()?;
+    drop(object);
+    let mut object = repository.insert(String::from("Modified"));
+    object.write_all(modified_data.as_slice())?;
+    object.flush()?;
+    drop(object);
+
+    // Only the chunk containing the modified byte is stored again.
+    assert_eq!(repository.stats().actual_size(), 256 * 17);
+
+    Ok(())
+}
+
+#[test]
+fn fastcdc_chunking_deduplicates_shifted_data() -> anyhow::Result<()> {
+    let mut config = REPO_CONFIG.to_owned();
+    config.chunking = Chunking::FastCdc {
+        min_size: 256,
+        avg_size: 1024,
+        max_size: 4096,
+    };
+    let mut repository: ObjectRepository<String, _> =
+        ObjectRepository::new_repo(MemoryStore::new(), config, Some(PASSWORD))?;
+    let original_data = random_bytes(1024 * 64);
+    let mut shifted_data = vec![0u8];
+    shifted_data.extend_from_slice(original_data.as_slice());
+
+    let mut object = repository.insert(String::from("Original"));
+    object.write_all(original_data.as_slice())?;
+    object.flush()?;
+    drop(object);
+    let mut object = repository.insert(String::from("Shifted"));
+    object.write_all(shifted_data.as_slice())?;
+    object.flush()?;
+    drop(object);
+
+    // Inserting a byte at the start only changes the chunks near the start.
+    let stats = repository.stats();
+    assert!(stats.actual_size() < stats.apparent_size() * 3 / 4);
+
+    Ok(())
+}
+
+#[test]
+fn invalid_chunking_errs() {
+    let invalid_methods = [
+        Chunking::Zpaq { bits: 0 },
+        Chunking::FastCdc {
+            min_size: 1024,
+            avg_size: 256,
+            max_size: 4096,
+        },
+        Chunking::Fixed { size: 0 },
+    ];
+
+    for &chunking in invalid_methods.iter() {
+        let mut config = REPO_CONFIG.to_owned();
+        config.chunking = chunking;
+        let result =
+            ObjectRepository::<String, _>::new_repo(MemoryStore::new(), config, Some(PASSWORD));
+        assert!(matches!(result, Err(acid_store::Error::InvalidConfig)));
+    }
+}
+
+#[test]
+fn writes_ending_on_chunk_boundary_are_flushed() -> anyhow::Result<()> {
+    let mut config = REPO_CONFIG.to_owned();
+    config.chunking = Chunking::Fixed { size: 256 };
+    let mut repository: ObjectRepository<String, _> =
+        ObjectRepository::new_repo(MemoryStore::new(), config, Some(PASSWORD))?;
+    let first_data = random_bytes(512);
+    let second_data = random_bytes(256);
+
+    let mut object = repository.insert(String::from("Test"));
+    object.write_all(first_data.as_slice())?;
+    object.write_all(second_data.as_slice())?;
+    object.flush()?;
+    assert_eq!(object.size(), 768);
+
+    let mut actual_data = Vec::new();
+    object.seek(SeekFrom::Start(0))?;
+    object.read_to_end(&mut actual_data)?;
+    assert_eq!(&actual_data[..512], first_data.as_slice());
+    assert_eq!(&actual_data[512..], second_data.as_slice());
+
+    Ok(())
+}
+
+#[test]
+fn parallel_encoding_round_trips() -> anyhow::Result<()> {
+    let mut repository = create_repo()?;
+    repository.set_encoding_threads(4)?;
+    let mut expected_data = random_bytes(1024 * 64);
+    let replacement_data = random_bytes(1024 * 4);
+
+    let mut object = repository.insert(String::from("Test"));
+    for piece in expected_data.chunks(100) {
+        object.write_all(piece)?;
+    }
+    object.flush()?;
+
+    // Overwrite data in the middle of the object.
+    object.seek(SeekFrom::Start(1024 * 16))?;
+    object.write_all(replacement_data.as_slice())?;
+    object.flush()?;
+    expected_data[1024 * 16..1024 * 20].copy_from_slice(replacement_data.as_slice());
+    drop(object);
+    repository.commit()?;
+
+    // The number of encoding threads is not stored in the repository.
+    let mut repository: ObjectRepository<String, _> =
+        ObjectRepository::open_repo(repository.into_store(), LockStrategy::Abort, Some(PASSWORD))?;
+    assert_eq!(repository.encoding_threads(), 1);
+    repository.set_encoding_threads(4)?;
+    let mut object = repository.get("Test").unwrap();
+    let mut actual_data = Vec::new();
+    object.read_to_end(&mut actual_data)?;
+    assert_eq!(actual_data, expected_data);
+
+    // Read from the middle of the object.
+    let mut actual_data = Vec::new();
+    object.seek(SeekFrom::Start(1024 * 40))?;
+    object.read_to_end(&mut actual_data)?;
+    assert_eq!(actual_data.as_slice(), &expected_data[1024 * 40..]);
+    assert!(repository.verify()?.is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn parallel_encoding_deduplicates_within_batch() -> anyhow::Result<()> {
+    let mut config = REPO_CONFIG.to_owned();
+    config.chunking = Chunking::Fixed { size: 256 };
+    let mut repository: ObjectRepository<String, _> =
+        ObjectRepository::new_repo(MemoryStore::new(), config, Some(PASSWORD))?;
+    repository.set_encoding_threads(4)?;
+    let chunk_data = random_bytes(256);
+
+    let mut object = repository.insert(String::from("Test"));
+    for _ in 0..8 {
+        object.write_all(chunk_data.as_slice())?;
+    }
+    object.flush()?;
+    drop(object);
+
+    let stats = repository.stats();
+    assert_eq!(stats.apparent_size(), 256 * 8);
+    assert_eq!(stats.actual_size(), 256);
+
+    Ok(())
+}
+
+#[test]
+fn zero_encoding_threads_errs() -> anyhow::Result<()> {
+    let mut repository = create_repo()?;
+    assert!(matches!(
+        repository.set_encoding_threads(0),
+        Err(acid_store::Error::InvalidConfig)
+    ));
+    assert_eq!(repository.encoding_threads(), 1);
+    Ok(())
+}
+
+#[test]
+fn new_object_has_timestamps_and_no_tags() -> anyhow::Result<()> {
+    let mut repository = create_repo()?;
+    let before_insert = SystemTime::now();
+    let object = repository.insert("Test".into());
+    let metadata = object.metadata()?;
+    drop(object);
+
+    assert!(metadata.created() >= before_insert);
+    assert_eq!(metadata.created(), metadata.modified());
+    assert_eq!(metadata.tag_names().count(), 0);
+    assert_eq!(repository.metadata("Test")?, metadata);
+    Ok(())
+}
+
+#[test]
+fn writing_data_updates_modified_time() -> anyhow::Result<()> {
+    let mut repository = create_repo()?;
+    let created = repository.insert("Test".into()).metadata()?.created();
+    sleep(Duration::from_millis(10));
+
+    let mut object = repository.get_mut("Test").unwrap();
+    object.write_all(random_buffer().as_slice())?;
+    object.flush()?;
+    let metadata = object.metadata()?;
+
+    assert_eq!(metadata.created(), created);
+    assert!(metadata.modified() > created);
+
+    sleep(Duration::from_millis(10));
+    object.truncate(1)?;
+    assert!(object.metadata()?.modified() > metadata.modified());
+    Ok(())
+}
+
+#[test]
+fn tags_persist_without_rewriting_data() -> anyhow::Result<()> {
+    for header_mode in &[HeaderMode::Memory, HeaderMode::Paged { cache_pages: 4 }] {
+        let mut config = REPO_CONFIG.to_owned();
+        config.header_mode = *header_mode;
+        let mut repository =
+            ObjectRepository::new_repo(MemoryStore::new(), config, Some(PASSWORD))?;
+
+        let mut object = repository.insert(String::from("Test"));
+        object.write_all(random_buffer().as_slice())?;
+        object.flush()?;
+        let content_id = object.content_id();
+        let modified = object.metadata()?.modified();
+        drop(object);
+
+        repository.set_tag("Test", "content-type", "text/plain")?;
+        repository.set_tag("Test", "size-hint", &1024u64)?;
+        repository.commit()?;
+
+        let store = repository.into_store();
+        let repository =
+            ObjectRepository::<String, _>::open_repo(store, LockStrategy::Abort, Some(PASSWORD))?;
+        let metadata = repository.metadata("Test")?;
+        let object = repository.get("Test").unwrap();
+
+        assert_eq!(
+            metadata.tag_names().collect::<Vec<_>>(),
+            vec!["content-type", "size-hint"]
+        );
+        assert_eq!(
+            metadata.tag::<String>("content-type")?,
+            Some(String::from("text/plain"))
+        );
+        assert_eq!(metadata.tag::<u64>("size-hint")?, Some(1024));
+        assert_eq!(metadata.modified(), modified);
+        assert_eq!(object.metadata()?, metadata);
+        assert_eq!(object.content_id(), content_id);
+    }
+    Ok(())
+}
+
+#[test]
+fn copied_object_has_same_metadata() -> anyhow::Result<()> {
+    let mut repository = create_repo()?;
+    repository.insert("Source".into());
+    repository.set_tag("Source", "key", "value")?;
+
+    repository.copy("Source", "Dest".into())?;
+
+    assert_eq!(repository.metadata("Dest")?, repository.metadata("Source")?);
+    Ok(())
+}
+
+#[test]
+fn metadata_of_nonexistent_object_errs() -> anyhow::Result<()> {
+    let mut repository = create_repo()?;
+
+    assert!(matches!(
+        repository.metadata("Test"),
+        Err(acid_store::Error::NotFound)
+    ));
+    assert!(matches!(
+        repository.set_tag("Test", "key", "value"),
+        Err(acid_store::Error::NotFound)
+    ));
+    assert!(matches!(
+        repository.remove_tag("Test", "key"),
+        Err(acid_store::Error::NotFound)
+    ));
+    Ok(())
+}
+
+#[test]
+fn tags_can_be_removed() -> anyhow::Result<()> {
+    let mut repository = create_repo()?;
+    repository.insert("Test".into());
+    repository.set_tag("Test", "first", "value")?;
+    repository.set_tag("Test", "second", "value")?;
+
+    assert!(repository.remove_tag("Test", "first")?);
+    assert!(!repository.remove_tag("Test", "first")?);
+
+    let metadata = repository.metadata("Test")?;
+    assert_eq!(metadata.tag_names().collect::<Vec<_>>(), vec!["second"]);
+    assert_eq!(metadata.tag::<String>("first")?, None);
+    Ok(())
+}
+
+#[test]
+fn tag_with_wrong_type_errs() -> anyhow::Result<()> {
+    let mut repository = create_repo()?;
+    repository.insert("Test".into());
+    repository.set_tag("Test", "content-type", "text/plain")?;
+
+    assert!(matches!(
+        repository.metadata("Test")?.tag::<u64>("content-type"),
+        Err(acid_store::Error::Deserialize)
+    ));
+    Ok(())
+}
+
+#[test]
+fn insert_replaces_metadata() -> anyhow::Result<()> {
+    let mut repository = create_repo()?;
+    let created = repository.insert("Test".into()).metadata()?.created();
+    repository.set_tag("Test", "key", "value")?;
+    sleep(Duration::from_millis(10));
+
+    let metadata = repository.insert("Test".into()).metadata()?;
+
+    assert!(metadata.created() > created);
+    assert_eq!(metadata.tag_names().count(), 0);
+    Ok(())
+}
+
+/// A `MemoryStore` which can be told to fail a write or reads and which counts reads.
+#[derive(Debug, Clone, Default)]
+struct FlakyStore {
+    store: Arc<Mutex<MemoryStore>>,
+
+    /// The number of writes to allow before the next one fails.
+    fail_after: Arc<Mutex<Option<usize>>>,
+
+    /// The number of blocks which have been read.
+    reads: Arc<Mutex<usize>>,
+
+    /// Whether reads should fail.
+    fail_reads: Arc<Mutex<bool>>,
+}
+
+impl DataStore for FlakyStore {
+    type Error = io::Error;
+
+    fn write_block(&mut self, id: Uuid, data: &[u8]) -> Result<(), Self::Error> {
+        let mut fail_after = self.fail_after.lock().unwrap();
+        match *fail_after {
+            Some(0) => {
+                *fail_after = None;
+                return Err(io::Error::new(io::ErrorKind::Other, "Injected failure."));
+            }
+            Some(remaining) => *fail_after = Some(remaining - 1),
+            None => {}
+        }
+        self.store.lock().unwrap().write_block(id, data).unwrap();
+        Ok(())
+    }
+
+    fn read_block(&mut self, id: Uuid) -> Result<Option<Vec<u8>>, Self::Error> {
+        *self.reads.lock().unwrap() += 1;
+        if *self.fail_reads.lock().unwrap() {
+            return Err(io::Error::new(io::ErrorKind::Other, "Injected failure."));
+        }
+        Ok(self.store.lock().unwrap().read_block(id).unwrap())
+    }
+
+    fn remove_block(&mut self, id: Uuid) -> Result<(), Self::Error> {
+        self.store.lock().unwrap().remove_block(id).unwrap();
+        Ok(())
+    }
+
+    fn list_blocks(&mut self) -> Result<Vec<Uuid>, Self::Error> {
+        Ok(self.store.lock().unwrap().list_blocks().unwrap())
+    }
+}
+
+#[test]
+fn failed_memory_commit_can_be_retried() -> anyhow::Result<()> {
+    // Return the number of blocks left after removing an object, optionally failing the first
+    // attempt to write the metadata.
+    let blocks_after_removing = |fail_commit: bool| -> anyhow::Result<usize> {
+        let mut config = REPO_CONFIG.to_owned();
+        config.encryption = Encryption::None;
+        config.header_mode = HeaderMode::Memory;
+        let mut store = FlakyStore::default();
+        let mut repository: ObjectRepository<String, _> =
+            ObjectRepository::new_repo(store.clone(), config, None)?;
+
+        let mut object = repository.insert("Test".to_string());
+        object.write_all(random_buffer().as_slice())?;
+        object.flush()?;
+        drop(object);
+        repository.commit()?;
+
+        repository.remove("Test");
+        if fail_commit {
+            // The header block is written, but writing the metadata fails.
+            *store.fail_after.lock().unwrap() = Some(1);
+            assert!(repository.commit().is_err());
+        }
+        repository.commit()?;
+        repository.gc(None)?;
+        drop(repository);
+
+        let repository: ObjectRepository<String, _> =
+            ObjectRepository::open_repo(store.clone(), LockStrategy::Abort, None)?;
+        assert!(!repository.contains("Test"));
+        Ok(store.list_blocks()?.len())
+    };
+
+    // The failed commit can only leak the header block it wrote. The chunks it removed from the
+    // header and the blocks of the old header must still be removed by `gc`.
+    assert!(blocks_after_removing(true)? <= blocks_after_removing(false)? + 1);
+
+    Ok(())
+}
+
+#[test]
+fn paged_header_is_read_lazily() -> anyhow::Result<()> {
+    let mut config = REPO_CONFIG.to_owned();
+    config.encryption = Encryption::None;
+    config.header_mode = HeaderMode::Paged { cache_pages: 1 };
+    let store = FlakyStore::default();
+    let mut repository: ObjectRepository<String, _> =
+        ObjectRepository::new_repo(store.clone(), config, None)?;
+    for i in 0..1000 {
+        repository.insert(format!("Test{}", i));
+    }
+    repository.commit()?;
+    drop(repository);
+
+    *store.reads.lock().unwrap() = 0;
+    let mut repository: ObjectRepository<String, _> =
+        ObjectRepository::open_repo(store.clone(), LockStrategy::Abort, None)?;
+    let open_reads = *store.reads.lock().unwrap();
+
+    *store.reads.lock().unwrap() = 0;
+    assert_eq!(repository.keys().count(), 1000);
+    let keys_reads = *store.reads.lock().unwrap();
+
+    // Opening the repository reads the metadata and the root of the header, but only enough of the
+    // object index to check the key type.
+    assert!(open_reads * 2 < keys_reads);
+
+    // Uncommitted changes are reflected without reading the object index again.
+    repository.remove("Test0");
+    repository.insert(String::from("New"));
+    let keys = repository
+        .try_keys()
+        .collect::<acid_store::Result<HashSet<_>>>()?;
+    assert_eq!(keys.len(), 1000);
+    assert!(keys.contains("New"));
+    assert!(!keys.contains("Test0"));
+    assert!(repository.contains("Test1"));
+    assert!(!repository.contains("Test0"));
+
+    Ok(())
+}
+
+#[test]
+fn paged_header_read_errors_are_returned_by_try_methods() -> anyhow::Result<()> {
+    let mut config = REPO_CONFIG.to_owned();
+    config.encryption = Encryption::None;
+    config.header_mode = HeaderMode::Paged { cache_pages: 1 };
+    let store = FlakyStore::default();
+    let mut repository: ObjectRepository<String, _> =
+        ObjectRepository::new_repo(store.clone(), config, None)?;
+    for i in 0..1000 {
+        repository.insert(format!("Test{}", i));
+    }
+    repository.commit()?;
+    drop(repository);
+
+    let mut repository: ObjectRepository<String, _> =
+        ObjectRepository::open_repo(store.clone(), LockStrategy::Abort, None)?;
+    repository.insert(String::from("New"));
+    *store.fail_reads.lock().unwrap() = true;
+
+    assert!(repository.try_contains("Test500").is_err());
+    assert!(repository.try_get("Test500").is_err());
+    assert!(repository.try_remove("Test500").is_err());
+    assert!(repository.try_keys().any(|key| key.is_err()));
+
+    // Uncommitted objects don't need to be read from the data store.
+    assert!(repository.try_contains("New")?);
+    assert_eq!(repository.try_get("New")?.unwrap().try_size()?, 0);
+
+    *store.fail_reads.lock().unwrap() = false;
+    assert!(repository.try_contains("Test500")?);
+    Ok(())
+}
@@ -0,0 +1,183 @@
+/*
+ * Copyright 2019-2020 Wren Powell
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+#![cfg(all(
+    feature = "encryption",
+    feature = "compression",
+    feature = "store-directory"
+))]
+
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+use tempfile::tempdir;
+use uuid::Uuid;
+
+use acid_store::repo::{
+    LockStrategy, ObjectRepository, OpenRepo, ReadMode, ReadOnlyObjectRepository,
+};
+use acid_store::store::{DataStore, DirectoryStore, MemoryStore, OpenOption, OpenStore};
+use common::{create_repo, random_buffer, PASSWORD, REPO_CONFIG};
+
+mod common;
+
+/// A data store which fails every attempt to modify it, like one on read-only media.
+struct ReadOnlyStore(MemoryStore);
+
+impl DataStore for ReadOnlyStore {
+    type Error = io::Error;
+
+    fn write_block(&mut self, _id: Uuid, _data: &[u8]) -> Result<(), Self::Error> {
+        Err(io::Error::new(io::ErrorKind::PermissionDenied, "read-only"))
+    }
+
+    fn read_block(&mut self, id: Uuid) -> Result<Option<Vec<u8>>, Self::Error> {
+        Ok(self.0.read_block(id).unwrap())
+    }
+
+    fn remove_block(&mut self, _id: Uuid) -> Result<(), Self::Error> {
+        Err(io::Error::new(io::ErrorKind::PermissionDenied, "read-only"))
+    }
+
+    fn list_blocks(&mut self) -> Result<Vec<Uuid>, Self::Error> {
+        Ok(self.0.list_blocks().unwrap())
+    }
+}
+
+/// Create a repository in a `DirectoryStore` at `path` which contains an object.
+fn create_directory_repo(path: &Path, data: &[u8]) -> anyhow::Result<()> {
+    let store = DirectoryStore::open(path.to_owned(), OpenOption::CREATE_NEW)?;
+    let mut repository =
+        ObjectRepository::<String, _>::new_repo(store, REPO_CONFIG.to_owned(), Some(PASSWORD))?;
+    let mut object = repository.insert("Test".into());
+    object.write_all(data)?;
+    object.flush()?;
+    drop(object);
+    repository.commit()?;
+    Ok(())
+}
+
+/// Open the repository in the `DirectoryStore` at `path` for reading.
+fn open_read_only(
+    path: &Path,
+    mode: ReadMode,
+) -> acid_store::Result<ReadOnlyObjectRepository<String, DirectoryStore>> {
+    let store = DirectoryStore::open(path.to_owned(), OpenOption::empty())?;
+    ReadOnlyObjectRepository::open(store, mode, Some(PASSWORD))
+}
+
+#[test]
+fn read_only_repo_reads_committed_objects() -> anyhow::Result<()> {
+    let mut repository = create_repo()?;
+    let expected_data = random_buffer();
+    let mut object = repository.insert("Test".into());
+    object.write_all(expected_data.as_slice())?;
+    object.flush()?;
+    drop(object);
+    repository.commit()?;
+
+    let repository = ReadOnlyObjectRepository::<String, _>::open(
+        ReadOnlyStore(repository.into_store()),
+        ReadMode::Shared(LockStrategy::Abort),
+        Some(PASSWORD),
+    )?;
+
+    let mut actual_data = Vec::new();
+    repository
+        .get("Test")
+        .unwrap()
+        .read_to_end(&mut actual_data)?;
+
+    assert_eq!(actual_data, expected_data);
+    assert_eq!(repository.keys().collect::<Vec<_>>(), vec!["Test"]);
+    assert!(repository.verify()?.is_empty());
+    Ok(())
+}
+
+#[test]
+fn shared_locks_can_be_held_together() -> anyhow::Result<()> {
+    let temp_dir = tempdir()?;
+    let path = temp_dir.as_ref().join("store");
+    create_directory_repo(&path, random_buffer().as_slice())?;
+
+    let first = open_read_only(&path, ReadMode::Shared(LockStrategy::Abort))?;
+    let second = open_read_only(&path, ReadMode::Shared(LockStrategy::Abort))?;
+
+    assert_eq!(first.info().id(), second.info().id());
+    Ok(())
+}
+
+#[test]
+fn shared_lock_excludes_writers() -> anyhow::Result<()> {
+    let temp_dir = tempdir()?;
+    let path = temp_dir.as_ref().join("store");
+    create_directory_repo(&path, random_buffer().as_slice())?;
+
+    let reader = open_read_only(&path, ReadMode::Shared(LockStrategy::Abort))?;
+    let store = DirectoryStore::open(path.clone(), OpenOption::empty())?;
+    let writer =
+        ObjectRepository::<String, _>::open_repo(store, LockStrategy::Abort, Some(PASSWORD));
+    assert!(matches!(writer.unwrap_err(), acid_store::Error::Locked));
+
+    // The repository can be opened for writing once the reader is dropped.
+    drop(reader);
+    let store = DirectoryStore::open(path, OpenOption::empty())?;
+    ObjectRepository::<String, _>::open_repo(store, LockStrategy::Abort, Some(PASSWORD))?;
+    Ok(())
+}
+
+#[test]
+fn writer_excludes_shared_lock() -> anyhow::Result<()> {
+    let temp_dir = tempdir()?;
+    let path = temp_dir.as_ref().join("store");
+    create_directory_repo(&path, random_buffer().as_slice())?;
+
+    let store = DirectoryStore::open(path.clone(), OpenOption::empty())?;
+    let _writer =
+        ObjectRepository::<String, _>::open_repo(store, LockStrategy::Abort, Some(PASSWORD))?;
+    let reader = open_read_only(&path, ReadMode::Shared(LockStrategy::Abort));
+
+    assert!(matches!(reader.unwrap_err(), acid_store::Error::Locked));
+    Ok(())
+}
+
+#[test]
+fn snapshot_reads_while_writer_is_open() -> anyhow::Result<()> {
+    let temp_dir = tempdir()?;
+    let path = temp_dir.as_ref().join("store");
+    let expected_data = random_buffer();
+    create_directory_repo(&path, expected_data.as_slice())?;
+
+    let store = DirectoryStore::open(path.clone(), OpenOption::empty())?;
+    let mut writer =
+        ObjectRepository::<String, _>::open_repo(store, LockStrategy::Abort, Some(PASSWORD))?;
+    let reader = open_read_only(&path, ReadMode::Snapshot)?;
+
+    // Changes committed after the snapshot was taken are not visible.
+    let mut object = writer.insert("New".into());
+    object.write_all(random_buffer().as_slice())?;
+    object.flush()?;
+    drop(object);
+    writer.commit()?;
+
+    let mut actual_data = Vec::new();
+    reader.get("Test").unwrap().read_to_end(&mut actual_data)?;
+
+    assert_eq!(actual_data, expected_data);
+    assert!(!reader.contains("New"));
+    assert!(open_read_only(&path, ReadMode::Snapshot)?.contains("New"));
+    Ok(())
+}
@@ -95,3 +95,22 @@ fn verify_valid_repository_is_valid() -> anyhow::Result<()> {
     assert!(repository.verify()?.is_empty());
     Ok(())
 }
+
+#[test]
+fn rollback_restores_keys() -> anyhow::Result<()> {
+    let mut repository = create_repo()?;
+    repository.insert("Committed".into(), &SERIALIZABLE_VALUE)?;
+    repository.commit()?;
+
+    let savepoint = repository.savepoint();
+    repository.insert("Uncommitted".into(), &SERIALIZABLE_VALUE)?;
+    repository.rollback_to(savepoint)?;
+    assert!(!repository.contains("Uncommitted"));
+
+    repository.remove("Committed");
+    repository.rollback()?;
+    assert!(repository.contains("Committed"));
+    let actual: (bool, i32) = repository.get("Committed")?;
+    assert_eq!(actual, SERIALIZABLE_VALUE);
+    Ok(())
+}
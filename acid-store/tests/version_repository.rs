@@ -17,8 +17,9 @@
 #![cfg(all(feature = "encryption", feature = "compression"))]
 
 use std::io::{Read, Write};
+use std::time::UNIX_EPOCH;
 
-use acid_store::repo::version::VersionRepository;
+use acid_store::repo::version::{RetentionPolicy, Version, VersionRepository};
 use acid_store::repo::{LockStrategy, OpenRepo};
 use acid_store::store::MemoryStore;
 use common::{assert_contains_all, random_buffer, PASSWORD, REPO_CONFIG};
@@ -196,3 +197,217 @@ fn modifying_object_doesnt_modify_versions() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn default_retention_policy_keeps_all_versions() -> anyhow::Result<()> {
+    let mut repository = create_repo()?;
+    repository.insert("Key".into())?;
+    let version1 = repository.create_version("Key")?;
+    let version2 = repository.create_version("Key")?;
+    repository.commit()?;
+
+    let report = repository.prune(&RetentionPolicy::default())?;
+
+    assert!(report.removed().is_empty());
+    assert_contains_all(repository.list_versions("Key")?, vec![version1, version2]);
+    Ok(())
+}
+
+#[test]
+fn prune_keeps_last_versions() -> anyhow::Result<()> {
+    let mut repository = create_repo()?;
+    repository.insert("Key".into())?;
+    let mut versions = Vec::new();
+    for _ in 0..4 {
+        let mut object = repository.get_mut("Key").unwrap();
+        object.write_all(random_buffer().as_slice())?;
+        object.flush()?;
+        drop(object);
+        versions.push(repository.create_version("Key")?);
+    }
+    repository.commit()?;
+
+    let policy = RetentionPolicy {
+        keep_last: Some(2),
+        ..RetentionPolicy::default()
+    };
+    let report = repository.prune(&policy)?;
+
+    let removed = report
+        .removed()
+        .iter()
+        .map(|(key, version)| (key.as_str(), version.id()))
+        .collect::<Vec<_>>();
+    assert_contains_all(
+        removed,
+        vec![("Key", versions[0].id()), ("Key", versions[1].id())],
+    );
+    assert_contains_all(repository.list_versions("Key")?, versions[2..].to_vec());
+    assert!(repository.get_version("Key", versions[0].id()).is_none());
+    assert!(report.gc_stats().bytes() > 0);
+    Ok(())
+}
+
+#[test]
+fn prune_keeps_newest_version_per_period() -> anyhow::Result<()> {
+    let mut repository = create_repo()?;
+    repository.insert("Key".into())?;
+    let version1 = repository.create_version("Key")?;
+    let version2 = repository.create_version("Key")?;
+    let version3 = repository.create_version("Key")?;
+    repository.commit()?;
+
+    // The versions were created within the last week, but the day may have changed in between.
+    let policy = RetentionPolicy {
+        keep_daily: Some(7),
+        ..RetentionPolicy::default()
+    };
+    repository.prune(&policy)?;
+
+    let day = |version: &Version| {
+        version
+            .created()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            / (60 * 60 * 24)
+    };
+    let mut expected_versions = vec![version3.clone()];
+    if day(&version2) != day(&version3) {
+        expected_versions.push(version2.clone());
+    } else if day(&version1) != day(&version3) {
+        expected_versions.push(version1);
+    }
+    assert_contains_all(repository.list_versions("Key")?, expected_versions);
+    Ok(())
+}
+
+#[test]
+fn prune_applies_rules_to_each_key() -> anyhow::Result<()> {
+    let mut repository = create_repo()?;
+    repository.insert("Key1".into())?;
+    repository.insert("Key2".into())?;
+    repository.create_version("Key1")?;
+    let version1 = repository.create_version("Key1")?;
+    repository.create_version("Key2")?;
+    let version2 = repository.create_version("Key2")?;
+    repository.commit()?;
+
+    let policy = RetentionPolicy {
+        keep_last: Some(1),
+        ..RetentionPolicy::default()
+    };
+    let report = repository.prune(&policy)?;
+
+    assert_eq!(report.removed().len(), 2);
+    assert_eq!(repository.list_versions("Key1")?, vec![version1]);
+    assert_eq!(repository.list_versions("Key2")?, vec![version2]);
+    Ok(())
+}
+
+#[test]
+fn prune_removes_oldest_versions_over_max_size() -> anyhow::Result<()> {
+    let mut repository = create_repo()?;
+    let mut versions = Vec::new();
+    for key in &["Key1", "Key2"] {
+        let mut object = repository.insert(key.to_string())?;
+        object.write_all(random_buffer().as_slice())?;
+        object.flush()?;
+        drop(object);
+        versions.push(repository.create_version(*key)?);
+    }
+    repository.commit()?;
+
+    // Only the newest version fits.
+    let policy = RetentionPolicy {
+        max_size: Some(versions[1].size()),
+        ..RetentionPolicy::default()
+    };
+    let report = repository.prune(&policy)?;
+
+    assert_eq!(
+        report.removed(),
+        &[(String::from("Key1"), versions[0].clone())]
+    );
+    assert!(repository.list_versions("Key1")?.is_empty());
+    assert_eq!(repository.list_versions("Key2")?, vec![versions[1].clone()]);
+    Ok(())
+}
+
+#[test]
+fn prune_with_uncommitted_changes_errs() -> anyhow::Result<()> {
+    let mut repository = create_repo()?;
+    repository.insert("Key".into())?;
+    repository.create_version("Key")?;
+    repository.create_version("Key")?;
+
+    let policy = RetentionPolicy {
+        keep_last: Some(1),
+        ..RetentionPolicy::default()
+    };
+    assert!(matches!(
+        repository.prune(&policy),
+        Err(acid_store::Error::Uncommitted)
+    ));
+
+    // The uncommitted changes were not committed.
+    repository.rollback()?;
+    assert!(!repository.contains("Key"));
+    Ok(())
+}
+
+#[test]
+fn pruned_versions_stay_removed_after_reopen() -> anyhow::Result<()> {
+    let mut repository = create_repo()?;
+    repository.insert("Key".into())?;
+    repository.create_version("Key")?;
+    repository.create_version("Key")?;
+    let version = repository.create_version("Key")?;
+    repository.commit()?;
+
+    let policy = RetentionPolicy {
+        keep_last: Some(1),
+        ..RetentionPolicy::default()
+    };
+    repository.prune(&policy)?;
+
+    let store = repository.into_store();
+    let repository: VersionRepository<String, _> =
+        VersionRepository::open_repo(store, LockStrategy::Abort, Some(PASSWORD))?;
+    assert_eq!(repository.list_versions("Key")?, vec![version]);
+    Ok(())
+}
+
+#[test]
+fn rollback_restores_versions() -> anyhow::Result<()> {
+    let mut repository = create_repo()?;
+    let expected_data = random_buffer();
+    let mut object = repository.insert("Committed".into())?;
+    object.write_all(expected_data.as_slice())?;
+    object.flush()?;
+    drop(object);
+    let version = repository.create_version("Committed")?;
+    repository.commit()?;
+
+    let savepoint = repository.savepoint();
+    repository.insert("Uncommitted".into())?;
+    repository.create_version("Committed")?;
+    repository.rollback_to(savepoint)?;
+    assert!(!repository.contains("Uncommitted"));
+    assert_eq!(
+        repository.list_versions("Committed")?,
+        vec![version.clone()]
+    );
+
+    repository.remove_version("Committed", version.id())?;
+    repository.remove("Committed")?;
+    repository.rollback()?;
+    assert!(repository.contains("Committed"));
+    let mut object = repository
+        .get_version("Committed", version.id())
+        .ok_or(acid_store::Error::NotFound)?;
+    let mut actual_data = Vec::new();
+    object.read_to_end(&mut actual_data)?;
+    assert_eq!(actual_data, expected_data);
+    Ok(())
+}
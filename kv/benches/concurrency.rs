@@ -0,0 +1,103 @@
+// throughput in thousands of operations per second, measured with
+// `cargo bench --no-default-features --features acid_kv,sled_kv --bench concurrency -- --sample-size 10`
+// on a linux vm with 1 core (the bench prints the core count it runs on). a
+// single core only shows that readers don't contend with each other, not how
+// throughput scales with threads, so these numbers should be recorded again
+// on a machine with at least 8 cores. zbox isn't in the table because it
+// couldn't be built on that vm; the default features bench it as well
+//
+//   backend  workload  1 thread  2 threads  4 threads  8 threads
+//   acid     get           11.3       11.5       10.5       11.2
+//   acid     mixed         10.9       12.4       13.0       12.8
+//   sled     get          482        466        455        449
+//   sled     mixed        160        156        139        127
+//
+// the mixed workload inserts on one in every `WRITE_EVERY` operations
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use kv::*;
+use std::thread;
+use tempfile::tempdir;
+
+const KEYS: usize = 64;
+const VALUE_SIZE: usize = 16 * 1024;
+const OPS_PER_THREAD: usize = 256;
+// one in this many operations of the mixed workload is an insert
+const WRITE_EVERY: usize = 8;
+const THREADS: [usize; 4] = [1, 2, 4, 8];
+
+fn run_concurrently<B, E>(bucket: &B, threads: usize, write_every: Option<usize>)
+where
+    B: KVBucket<String, Vec<u8>, E> + Clone + Send + 'static,
+{
+    let handles: Vec<_> = (0..threads)
+        .map(|t| {
+            let bucket = bucket.clone();
+            thread::spawn(move || {
+                for i in 0..OPS_PER_THREAD {
+                    let key = format!("key{}", (t + i) % KEYS);
+                    match write_every {
+                        Some(every) if i % every == 0 => {
+                            assert!(bucket.insert(key, vec![i as u8; VALUE_SIZE]).is_ok())
+                        }
+                        _ => {
+                            black_box(bucket.get(key));
+                        }
+                    }
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+}
+
+fn bench_workloads<B, E>(c: &mut Criterion, name: &str, bucket: B)
+where
+    B: KVBucket<String, Vec<u8>, E> + Clone + Send + 'static,
+{
+    for i in 0..KEYS {
+        assert!(bucket
+            .insert(format!("key{}", i), vec![i as u8; VALUE_SIZE])
+            .is_ok());
+    }
+    for &(workload, write_every) in [("get", None), ("mixed", Some(WRITE_EVERY))].iter() {
+        let mut group = c.benchmark_group(format!("{}_concurrent_{}", name, workload));
+        for &threads in THREADS.iter() {
+            group.throughput(Throughput::Elements((threads * OPS_PER_THREAD) as u64));
+            group.bench_with_input(
+                BenchmarkId::from_parameter(threads),
+                &threads,
+                |b, &threads| b.iter(|| run_concurrently(&bucket, threads, write_every)),
+            );
+        }
+        group.finish();
+    }
+}
+
+fn concurrent_ops(c: &mut Criterion) {
+    kv_init();
+    // results from fewer cores than `THREADS` don't show how reads scale
+    let cores = thread::available_parallelism().map_or(1, |n| n.get());
+    println!("benchmarking on {} cores", cores);
+    let dir = tempdir().unwrap();
+    #[cfg(feature = "acid_kv")]
+    {
+        let db = AcidKV::new(dir.path().join("acid.db").display(), b"password").unwrap();
+        bench_workloads(c, "acid", db.get_bucket(String::from("bench")).unwrap());
+    }
+    #[cfg(feature = "zbox_kv")]
+    {
+        let db = ZboxKV::new(dir.path().join("zbox.db").display(), "password");
+        bench_workloads(c, "zbox", db.get_bucket(String::from("bench")).unwrap());
+    }
+    #[cfg(feature = "sled_kv")]
+    {
+        let db = SledKV::new(dir.path().join("sled.db").display());
+        bench_workloads(c, "sled", db.get_bucket(String::from("bench")).unwrap());
+    }
+}
+
+criterion_group!(benches, concurrent_ops);
+criterion_main!(benches);
@@ -1,9 +1,18 @@
 use super::*;
+use crate::kv::{
+    escape_component, index_names_path, index_path, index_prefix, merge_index_names,
+    remove_index_name, unescape_component,
+};
 use acid_store::{
-    repo::{Compression, Encryption, LockStrategy, ObjectRepository, OpenRepo, RepositoryConfig},
+    repo::{
+        Compression, EncodingStats, Encryption, LockStrategy, ObjectRepository, OpenRepo,
+        RepositoryConfig,
+    },
     store::{DataStore, OpenOption, OpenStore, SqliteStore},
     uuid::Uuid,
 };
+use std::collections::HashMap;
+use std::io;
 use std::marker::PhantomData;
 use std::str::FromStr;
 
@@ -37,89 +46,260 @@ type AcidDb<D> = ObjectRepository<Vec<u8>, SyncAcidStore<D>>;
 type AcidSqliteDb = AcidDb<SqliteStore>;
 type AcidSyncDb = Arc<RwLock<AcidSqliteDb>>;
 
+// writes hold the write lock, so the difference between two snapshots of the
+// repository's stats is exactly their own cost; reads use their handle's stats
+fn record_encoding(timer: &OpTimer, before: &EncodingStats, after: &EncodingStats) {
+    timer.encoding(
+        (after.compression_time() + after.decompression_time())
+            - (before.compression_time() + before.decompression_time()),
+        (after.encryption_time() + after.decryption_time())
+            - (before.encryption_time() + before.decryption_time()),
+    );
+}
+
+fn get_scope_path(scope: &str) -> String {
+    if !scope.is_empty() {
+        format!("/{}/", scope)
+    } else {
+        "/".into()
+    }
+}
+
 #[derive(Clone)]
 pub struct AcidKVBucket<K> {
     db: AcidSyncDb,
     scope: String,
+    options: BucketOptions,
+    indexes: HashMap<String, IndexExtractor>,
     _phantom: PhantomData<K>,
 }
 
 impl<K> AcidKVBucket<K> {
-    fn new<S: ToString>(db: Arc<RwLock<AcidSqliteDb>>, scope: S) -> Self {
+    fn new<S: ToString>(db: Arc<RwLock<AcidSqliteDb>>, scope: S, options: BucketOptions) -> Self {
         Self {
             db,
             scope: scope.to_string(),
+            options,
+            indexes: HashMap::new(),
             _phantom: PhantomData,
         }
     }
+    pub fn with_index<S, F>(mut self, name: S, extractor: F) -> Self
+    where
+        S: ToString,
+        F: Fn(&[u8]) -> Option<String> + Send + Sync + 'static,
+    {
+        self.indexes.insert(name.to_string(), Arc::new(extractor));
+        self
+    }
+    pub fn options(&self) -> BucketOptions {
+        self.options
+    }
+    pub fn index<S: ToString>(&self, name: S) -> AcidKVIndex<K> {
+        AcidKVIndex {
+            db: self.db.clone(),
+            prefix: index_prefix(&self.scope, &name.to_string()),
+            _phantom: PhantomData,
+        }
+    }
+    // removes the entries and the stored name of an index, so handles which
+    // don't register it can write to the bucket again
+    pub fn drop_index<S: ToString>(&mut self, name: S) -> Result<(), AcidError> {
+        let name = name.to_string();
+        self.indexes.remove(&name);
+        let mut db = self.db.write().unwrap();
+        let prefix = index_prefix(&self.scope, &name).into_bytes();
+        let entries = Self::keys_with_prefix(&db, &prefix)?;
+        for entry in entries.iter() {
+            db.try_remove(entry)?;
+        }
+        let names_path = index_names_path(&self.scope).into_bytes();
+        let stored = Self::read_object(&db, &names_path)?;
+        if let Some(names) = remove_index_name(stored.as_deref(), &name) {
+            Self::write_object(&mut db, names_path, &names)?;
+        }
+        Ok(())
+    }
     fn get_path<S: ToString>(&self, prefix: S) -> Vec<u8> {
-        format!(
-            "{}{}",
-            if !self.scope.is_empty() {
-                format!("/{}/", self.scope)
-            } else {
-                "/".into()
-            },
-            prefix.to_string()
-        )
-        .into_bytes()
+        format!("{}{}", get_scope_path(&self.scope), prefix.to_string()).into_bytes()
+    }
+    fn get_index_path(&self, name: &str, key: &str, primary: &str) -> Vec<u8> {
+        index_path(&self.scope, name, key, primary).into_bytes()
+    }
+    // keys are read from the object index one page at a time, so a failed
+    // read is returned instead of ending the listing early
+    fn keys_with_prefix(db: &AcidSqliteDb, prefix: &[u8]) -> Result<Vec<Vec<u8>>, AcidError> {
+        let mut keys = vec![];
+        for key in db.try_keys() {
+            let key = key?;
+            if key.starts_with(prefix) {
+                keys.push(key);
+            }
+        }
+        Ok(keys)
+    }
+    fn read_object(db: &AcidSqliteDb, path: &Vec<u8>) -> Result<Option<Vec<u8>>, AcidError> {
+        Ok(Self::read_object_with_stats(db, path)?.0)
+    }
+    // the handle only counts its own decoding, so the stats stay accurate
+    // while other readers share the repository
+    fn read_object_with_stats(
+        db: &AcidSqliteDb,
+        path: &Vec<u8>,
+    ) -> Result<(Option<Vec<u8>>, EncodingStats), AcidError> {
+        Ok(match db.try_get(path)? {
+            Some(mut obj) => {
+                let mut buf = vec![];
+                let value = obj.read_to_end(&mut buf).ok().map(|_| buf);
+                (value, obj.encoding_stats())
+            }
+            None => (None, EncodingStats::default()),
+        })
+    }
+    fn write_object(db: &mut AcidSqliteDb, path: Vec<u8>, data: &[u8]) -> Result<(), AcidError> {
+        let mut obj = db.insert(path);
+        obj.write_all(data)?;
+        obj.flush()?;
+        Ok(())
+    }
+    // runs before every write, even on handles without indexes, so a bucket
+    // with an index can't be written through a handle which doesn't update it
+    fn check_indexes(&self, db: &mut AcidSqliteDb) -> Result<(), AcidError> {
+        let names_path = index_names_path(&self.scope).into_bytes();
+        let stored = Self::read_object(db, &names_path)?;
+        if let Some(names) = merge_index_names(stored.as_deref(), &self.indexes)? {
+            Self::write_object(db, names_path, &names)?;
+        }
+        Ok(())
+    }
+    // index entries live in the same repository as the primary objects,
+    // so they are persisted by the same commit
+    fn update_indexes(
+        &self,
+        db: &mut AcidSqliteDb,
+        primary: &str,
+        old: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<(), AcidError> {
+        for (name, extractor) in self.indexes.iter() {
+            let old_key = old.and_then(|v| extractor(v));
+            let new_key = new.and_then(|v| extractor(v));
+            if old_key == new_key {
+                continue;
+            }
+            if let Some(key) = old_key {
+                db.try_remove(&self.get_index_path(name, &key, primary))?;
+            }
+            if let Some(key) = new_key {
+                db.insert(self.get_index_path(name, &key, primary));
+            }
+        }
+        Ok(())
     }
 }
 
 impl<K: ToString> KVBucket<K, Vec<u8>, AcidError> for AcidKVBucket<K> {
     fn exists(&self, k: K) -> Result<bool, AcidError> {
+        let timer = OpTimer::start("acid", &self.scope, "exists");
         let db = self.db.read().unwrap();
         let path = self.get_path(k);
-        Ok(db.contains(&path))
+        let exists = db.try_contains(&path)?;
+        timer.finish(0, 0);
+        Ok(exists)
     }
     fn get(&self, k: K) -> Option<Vec<u8>> {
-        let db = self.db.write().unwrap();
+        let timer = OpTimer::start("acid", &self.scope, "get");
+        // objects are decoded outside of the store lock, so readers only
+        // share the repository
+        let db = self.db.read().unwrap();
         let path = self.get_path(k);
-        if db.contains(&path) {
-            db.get(&path).and_then(|mut obj| {
-                let mut buf = vec![];
-                if obj.read_to_end(&mut buf).is_ok() {
-                    Some(buf)
-                } else {
-                    None
-                }
-            })
-        } else {
-            None
-        }
+        // a failed read drops the timer, so it is recorded as an error
+        let (value, stats) = Self::read_object_with_stats(&db, &path).ok()?;
+        record_encoding(&timer, &EncodingStats::default(), &stats);
+        timer.finish(0, value.as_ref().map(|v| v.len()).unwrap_or_default());
+        value
     }
-    fn insert(&self, k: K, mut v: Vec<u8>) -> Result<(), AcidError> {
+    fn insert(&self, k: K, v: Vec<u8>) -> Result<(), AcidError> {
+        let timer = OpTimer::start("acid", &self.scope, "insert");
         let mut db = self.db.write().unwrap();
-        let mut obj = db.insert(self.get_path(k));
-        obj.write_all(&mut v)?;
+        let stats = db.encoding_stats();
+        self.check_indexes(&mut db)?;
+        let key = k.to_string();
+        let path = self.get_path(&key);
+        let old = if !self.indexes.is_empty() {
+            Self::read_object(&db, &path)?
+        } else {
+            None
+        };
+        let mut obj = db.insert(path);
+        obj.write_all(&v)?;
         obj.flush()?;
+        drop(obj);
+        self.update_indexes(&mut db, &key, old.as_deref(), Some(&v))?;
+        record_encoding(&timer, &stats, &db.encoding_stats());
+        timer.finish(v.len(), 0);
         Ok(())
     }
     fn remove(&self, k: K) -> Result<(), AcidError> {
+        let timer = OpTimer::start("acid", &self.scope, "remove");
         let mut db = self.db.write().unwrap();
-        let path = self.get_path(k);
-        if db.contains(&path) {
-            db.remove(&path);
+        let stats = db.encoding_stats();
+        self.check_indexes(&mut db)?;
+        let key = k.to_string();
+        let path = self.get_path(&key);
+        if db.try_contains(&path)? {
+            if !self.indexes.is_empty() {
+                let old = Self::read_object(&db, &path)?;
+                self.update_indexes(&mut db, &key, old.as_deref(), None)?;
+            }
+            db.try_remove(&path)?;
         }
+        record_encoding(&timer, &stats, &db.encoding_stats());
+        timer.finish(0, 0);
         Ok(())
     }
     fn list(&self) -> Result<Vec<PathBuf>, AcidError> {
+        let timer = OpTimer::start("acid", &self.scope, "list");
         let db = self.db.read().unwrap();
         let prefix = PathBuf::from(String::from_utf8_lossy(&self.get_path("")).into_owned());
-        Ok(db
-            .keys()
+        let list: Vec<PathBuf> = Self::keys_with_prefix(&db, &[])?
+            .iter()
             .filter_map(|item| {
                 String::from_utf8(item.to_vec())
                     .ok()
                     .and_then(|path| PathBuf::from_str(&path).ok())
                     .and_then(|path| path.strip_prefix(&prefix).map(|path| path.into()).ok())
             })
+            .collect();
+        timer.finish(0, 0);
+        Ok(list)
+    }
+}
+
+#[derive(Clone)]
+pub struct AcidKVIndex<K> {
+    db: AcidSyncDb,
+    prefix: String,
+    _phantom: PhantomData<K>,
+}
+
+impl<K: ToString> KVIndex<K, AcidError> for AcidKVIndex<K> {
+    fn get(&self, k: K) -> Result<Vec<PathBuf>, AcidError> {
+        let db = self.db.read().unwrap();
+        let prefix = format!("{}{}/", self.prefix, escape_component(&k.to_string())).into_bytes();
+        Ok(AcidKVBucket::<K>::keys_with_prefix(&db, &prefix)?
+            .iter()
+            .filter_map(|item| String::from_utf8(item[prefix.len()..].to_vec()).ok())
+            .map(|primary| PathBuf::from(unescape_component(&primary)))
             .collect())
     }
 }
 
 pub struct AcidKV {
     db: AcidSyncDb,
+    name: String,
+    pass: Vec<u8>,
+    buckets: Arc<RwLock<HashMap<String, AcidSyncDb>>>,
 }
 
 impl AcidKV {
@@ -131,40 +311,173 @@ impl AcidKV {
         )?))
     }
 
-    fn get_config() -> RepositoryConfig {
+    fn get_config(compression: bool) -> RepositoryConfig {
         let mut config = RepositoryConfig::default();
-        config.compression = Compression::Lzma { level: 9 };
+        config.compression = if compression {
+            Compression::Lzma { level: 9 }
+        } else {
+            Compression::None
+        };
         config.encryption = Encryption::XChaCha20Poly1305;
         config
     }
 
-    pub fn new<N: ToString>(name: N, pass: &[u8]) -> Result<Self, AcidError> {
-        Ok(ObjectRepository::create_repo(
-            Self::get_store(&name)?,
-            Self::get_config(),
+    fn open_db<N: ToString>(
+        name: &N,
+        config: RepositoryConfig,
+        pass: &[u8],
+    ) -> Result<AcidSqliteDb, AcidError> {
+        ObjectRepository::create_repo(
+            Self::get_store(name)?,
+            config,
             LockStrategy::Abort,
             Some(pass),
         )
-        .map(|repo| Self {
-            db: Arc::new(RwLock::new(repo)),
-        })
         .or_else(|e| match e {
-            AcidError::AlreadyExists => ObjectRepository::open_repo(
-                Self::get_store(&name)?,
-                LockStrategy::Abort,
-                Some(pass),
-            )
-            .map(|repo| Self {
-                db: Arc::new(RwLock::new(repo)),
-            }),
+            AcidError::AlreadyExists => {
+                ObjectRepository::open_repo(Self::get_store(name)?, LockStrategy::Abort, Some(pass))
+            }
             e => Err(e),
-        })?)
+        })
+    }
+
+    pub fn new<N: ToString>(name: N, pass: &[u8]) -> Result<Self, AcidError> {
+        Ok(Self {
+            db: Arc::new(RwLock::new(Self::open_db(
+                &name,
+                Self::get_config(true),
+                pass,
+            )?)),
+            name: name.to_string(),
+            pass: pass.to_vec(),
+            buckets: Arc::new(RwLock::new(HashMap::new())),
+        })
+    }
+
+    fn get_options_path(scope: &str) -> Vec<u8> {
+        format!("#bucket/{}", escape_component(scope)).into_bytes()
+    }
+
+    fn get_options(&self, scope: &str) -> Result<BucketOptions, AcidError> {
+        let db = self.db.read().unwrap();
+        let mut obj = match db.try_get(&Self::get_options_path(scope))? {
+            Some(obj) => obj,
+            None => return Ok(BucketOptions::default()),
+        };
+        let mut buf = vec![];
+        obj.read_to_end(&mut buf)?;
+        BucketOptions::from_bytes(&buf).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "the bucket options are corrupt").into()
+        })
+    }
+
+    // acid deduplicates every object and keeps no older versions, so options
+    // which ask for anything else are rejected instead of silently ignored;
+    // compression can't change once the bucket has data, because the bucket
+    // would move to another repository and leave its data behind
+    fn set_options(&self, scope: &str, options: &BucketOptions) -> Result<(), AcidError> {
+        if options.version_limit.unwrap_or(1) != 1 || options.dedup == Some(false) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the acid backend always deduplicates and keeps a single version",
+            )
+            .into());
+        }
+        let stored = self.get_options(scope)?;
+        if stored.is_uncompressed() != options.is_uncompressed()
+            && Self::has_data(&self.get_db(scope, &stored)?, scope)?
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "compression of a bucket which has data can't be changed",
+            )
+            .into());
+        }
+        let mut db = self.db.write().unwrap();
+        let mut obj = db.insert(Self::get_options_path(scope));
+        obj.write_all(&options.to_bytes())?;
+        obj.flush()?;
+        Ok(())
+    }
+
+    fn has_data(db: &AcidSyncDb, scope: &str) -> Result<bool, AcidError> {
+        let prefix = get_scope_path(scope).into_bytes();
+        let db = db.read().unwrap();
+        for key in db.try_keys() {
+            if key?.starts_with(&prefix) {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    // compression is fixed per acid repository, so buckets which turn it off
+    // live in a repository of their own next to the main one
+    fn get_db(&self, scope: &str, options: &BucketOptions) -> Result<AcidSyncDb, AcidError> {
+        match options.compression {
+            Some(false) => {
+                let mut buckets = self.buckets.write().unwrap();
+                if let Some(db) = buckets.get(scope) {
+                    return Ok(db.clone());
+                }
+                let db = Arc::new(RwLock::new(Self::open_db(
+                    &format!("{}.{}", self.name, scope),
+                    Self::get_config(false),
+                    &self.pass,
+                )?));
+                buckets.insert(scope.into(), db.clone());
+                Ok(db)
+            }
+            _ => Ok(self.db.clone()),
+        }
+    }
+
+    fn commit_db(db: &AcidSyncDb, scope: &str) -> Result<(), AcidError> {
+        let timer = OpTimer::start("acid", scope, "commit");
+        let mut db = db.write().unwrap();
+        let stats = db.encoding_stats();
+        db.commit()?;
+        db.gc(None)?;
+        record_encoding(&timer, &stats, &db.encoding_stats());
+        timer.finish(0, 0);
+        Ok(())
+    }
+
+    pub fn commit(&self) -> Result<(), AcidError> {
+        for (scope, db) in self.buckets.read().unwrap().iter() {
+            Self::commit_db(db, scope)?;
+        }
+        Self::commit_db(&self.db, "")
     }
 }
 
 impl<S: ToString> KV<S, Vec<u8>, AcidError, AcidKVBucket<S>> for AcidKV {
     fn get_bucket(&self, name: S) -> Result<AcidKVBucket<S>, AcidError> {
-        Ok(AcidKVBucket::new(self.db.clone(), name))
+        let scope = name.to_string();
+        let options = self.get_options(&scope)?;
+        Ok(AcidKVBucket::new(
+            self.get_db(&scope, &options)?,
+            name,
+            options,
+        ))
+    }
+
+    fn get_bucket_with(
+        &self,
+        name: S,
+        options: BucketOptions,
+    ) -> Result<AcidKVBucket<S>, AcidError> {
+        let scope = name.to_string();
+        self.set_options(&scope, &options)?;
+        Ok(AcidKVBucket::new(
+            self.get_db(&scope, &options)?,
+            name,
+            options,
+        ))
+    }
+
+    fn commit(&self) -> Result<(), AcidError> {
+        AcidKV::commit(self)
     }
 }
 
@@ -204,3 +517,160 @@ pub fn transform_zbox_to_acid() -> Result<(), anyhow::Error> {
     println!("finash, {}ms", sw.elapsed().as_millis());
     Ok(())
 }
+
+#[cfg(test)]
+fn tag_extractor(v: &[u8]) -> Option<String> {
+    Some(String::from_utf8_lossy(v).into_owned())
+}
+
+#[test]
+fn acid_index_keys_with_separators() -> Result<(), anyhow::Error> {
+    let dir = tempfile::tempdir()?;
+    let db = AcidKV::new(dir.path().join("index.db").display(), b"password")?;
+    let bucket = db.get_bucket("items")?.with_index("tag", tag_extractor);
+    bucket.insert("one", b"a".to_vec())?;
+    bucket.insert("two", b"a/b".to_vec())?;
+    bucket.insert("a/three", b"a".to_vec())?;
+    let index = bucket.index("tag");
+    let mut found = index.get("a")?;
+    found.sort();
+    assert_eq!(found, vec![PathBuf::from("a/three"), PathBuf::from("one")]);
+    assert_eq!(index.get("a/b")?, vec![PathBuf::from("two")]);
+    assert!(index.get("a/three")?.is_empty());
+    Ok(())
+}
+
+#[test]
+fn acid_index_follows_updates() -> Result<(), anyhow::Error> {
+    let dir = tempfile::tempdir()?;
+    let db = AcidKV::new(dir.path().join("index.db").display(), b"password")?;
+    let bucket = db.get_bucket("items")?.with_index("tag", tag_extractor);
+    let index = bucket.index("tag");
+    bucket.insert("one", b"a".to_vec())?;
+    bucket.insert("one", b"b".to_vec())?;
+    assert!(index.get("a")?.is_empty());
+    assert_eq!(index.get("b")?, vec![PathBuf::from("one")]);
+    bucket.remove("one")?;
+    assert!(index.get("b")?.is_empty());
+    Ok(())
+}
+
+#[test]
+fn acid_index_must_be_registered_to_write() -> Result<(), anyhow::Error> {
+    let dir = tempfile::tempdir()?;
+    let db = AcidKV::new(dir.path().join("index.db").display(), b"password")?;
+    let mut indexed = db.get_bucket("items")?.with_index("tag", tag_extractor);
+    indexed.insert("one", b"a".to_vec())?;
+    let plain = db.get_bucket("items")?;
+    assert!(plain.insert("two", b"a".to_vec()).is_err());
+    assert!(plain.remove("one").is_err());
+    indexed.drop_index("tag")?;
+    plain.insert("two", b"a".to_vec())?;
+    assert!(indexed.index("tag").get("a")?.is_empty());
+    Ok(())
+}
+
+#[test]
+fn acid_unsupported_bucket_options_are_rejected() -> Result<(), anyhow::Error> {
+    let dir = tempfile::tempdir()?;
+    let db = AcidKV::new(dir.path().join("options.db").display(), b"password")?;
+    let versioned = BucketOptions {
+        version_limit: Some(3),
+        ..BucketOptions::default()
+    };
+    assert!(db.get_bucket_with("items", versioned).is_err());
+    let no_dedup = BucketOptions {
+        dedup: Some(false),
+        ..BucketOptions::default()
+    };
+    assert!(db.get_bucket_with("items", no_dedup).is_err());
+    let supported = BucketOptions {
+        version_limit: Some(1),
+        dedup: Some(true),
+        ..BucketOptions::default()
+    };
+    assert_eq!(db.get_bucket_with("items", supported)?.options(), supported);
+    Ok(())
+}
+
+#[test]
+fn acid_corrupt_bucket_options_are_errors() -> Result<(), anyhow::Error> {
+    let dir = tempfile::tempdir()?;
+    let db = AcidKV::new(dir.path().join("options.db").display(), b"password")?;
+    let supported = BucketOptions {
+        dedup: Some(true),
+        ..BucketOptions::default()
+    };
+    db.get_bucket_with("a/b", supported)?;
+    {
+        let mut repo = db.db.write().unwrap();
+        let mut obj = repo.insert(AcidKV::get_options_path("a/b"));
+        obj.write_all(b"corrupt")?;
+        obj.flush()?;
+    }
+    assert!(db.get_bucket("a/b").map(|b| b.options()).is_err());
+    Ok(())
+}
+
+#[test]
+fn acid_uncompressed_bucket_keeps_its_data() -> Result<(), anyhow::Error> {
+    let dir = tempfile::tempdir()?;
+    let name = dir.path().join("options.db");
+    let uncompressed = BucketOptions {
+        compression: Some(false),
+        ..BucketOptions::default()
+    };
+    {
+        let db = AcidKV::new(name.display(), b"password")?;
+        let bucket = db.get_bucket_with("raw", uncompressed)?;
+        bucket.insert("one", b"value".to_vec())?;
+        db.commit()?;
+        // moving the bucket back to the compressed repository would orphan its data
+        assert!(db.get_bucket_with("raw", BucketOptions::default()).is_err());
+    }
+    let db = AcidKV::new(name.display(), b"password")?;
+    let bucket = db.get_bucket("raw")?;
+    assert_eq!(bucket.options(), uncompressed);
+    assert_eq!(bucket.get("one"), Some(b"value".to_vec()));
+    Ok(())
+}
+
+#[test]
+fn acid_operations_are_recorded() -> Result<(), anyhow::Error> {
+    use crate::telemetry::recorder;
+
+    let dir = tempfile::tempdir()?;
+    let db = AcidKV::new(dir.path().join("telemetry.db").display(), b"password")?;
+    let indexed = db.get_bucket("items")?.with_index("tag", tag_extractor);
+    recorder::take();
+    indexed.insert("one", b"tag:a".to_vec())?;
+    assert_eq!(indexed.get("one"), Some(b"tag:a".to_vec()));
+    let plain = db.get_bucket("items")?;
+    assert!(plain.insert("two", b"a".to_vec()).is_err());
+
+    let (ops, encodings) = recorder::take();
+    let ops: Vec<_> = ops
+        .iter()
+        .map(|op| {
+            (
+                op.backend,
+                op.bucket.as_str(),
+                op.op,
+                op.status,
+                op.bytes_in,
+                op.bytes_out,
+            )
+        })
+        .collect();
+    assert_eq!(
+        ops,
+        vec![
+            ("acid", "items", "insert", "ok", 5, 0),
+            ("acid", "items", "get", "ok", 0, 5),
+            ("acid", "items", "insert", "error", 0, 0),
+        ]
+    );
+    let encoded: Vec<_> = encodings.iter().map(|encoding| encoding.op).collect();
+    assert_eq!(encoded, vec!["insert", "get"]);
+    Ok(())
+}
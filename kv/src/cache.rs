@@ -0,0 +1,637 @@
+use super::lru::{Lru, Meter, Pinnable};
+use super::*;
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, RwLockReadGuard};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CachePolicy {
+    // writes go to the backend first, the cache only keeps a copy
+    WriteThrough,
+    // writes stay in the cache until flush, commit or the cache is full
+    WriteBack,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CacheOptions {
+    pub capacity: usize,
+    pub max_entries: usize,
+    pub policy: CachePolicy,
+}
+
+impl Default for CacheOptions {
+    fn default() -> Self {
+        Self {
+            capacity: 64 * 1024 * 1024,
+            max_entries: 4096,
+            policy: CachePolicy::WriteThrough,
+        }
+    }
+}
+
+// `None` is a pending removal; `write` tells a flush whether the entry was
+// written again while its value was being sent to the backend
+struct CacheEntry {
+    value: Option<Vec<u8>>,
+    dirty: bool,
+    write: u64,
+}
+
+#[derive(Default)]
+struct ByteMeter;
+
+impl Meter<CacheEntry> for ByteMeter {
+    fn measure(&self, item: &CacheEntry) -> isize {
+        item.value.as_ref().map(|v| v.len()).unwrap_or_default() as isize
+    }
+}
+
+// dirty entries are pinned so eviction never drops unwritten data
+#[derive(Default)]
+struct DirtyChecker;
+
+impl Pinnable<CacheEntry> for DirtyChecker {
+    fn is_pinned(&self, item: &CacheEntry) -> bool {
+        item.dirty
+    }
+}
+
+// registers an index on a backend bucket, see `CachedKVBucket::with_index`
+type IndexRegistration<B> = Arc<dyn Fn(B) -> B + Send + Sync>;
+
+// the lru mutex is only held for in-memory work. backend i/o holds `io`
+// instead: reads which fill the cache share it, and writes to the backend
+// take it exclusively, so a read can't put back a value which a write
+// through or a flush has just replaced. `io` is always taken before `bucket`
+struct BucketCache<B> {
+    bucket: RwLock<B>,
+    // `None` when the bucket was opened without options
+    options: Mutex<Option<BucketOptions>>,
+    indexes: Mutex<Vec<IndexRegistration<B>>>,
+    lru: Mutex<Lru<String, CacheEntry, ByteMeter, DirtyChecker>>,
+    io: RwLock<()>,
+    writes: AtomicU64,
+}
+
+impl<B> BucketCache<B> {
+    fn new(bucket: B, bucket_options: Option<BucketOptions>, options: &CacheOptions) -> Self {
+        Self {
+            bucket: RwLock::new(bucket),
+            options: Mutex::new(bucket_options),
+            indexes: Mutex::new(vec![]),
+            lru: Mutex::new(Lru::new(options.capacity, options.max_entries)),
+            io: RwLock::new(()),
+            writes: AtomicU64::new(0),
+        }
+    }
+
+    fn backend(&self) -> RwLockReadGuard<'_, B> {
+        self.bucket.read().unwrap()
+    }
+
+    fn add_index(&self, register: IndexRegistration<B>)
+    where
+        B: Clone,
+    {
+        let _io = self.io.write().unwrap();
+        let mut bucket = self.bucket.write().unwrap();
+        *bucket = register(bucket.clone());
+        self.indexes.lock().unwrap().push(register);
+    }
+
+    // the bucket was opened again with other options: pending writes go to
+    // the old backend bucket, and the new one gets the registered indexes
+    fn reopen<E>(&self, bucket: B, options: BucketOptions) -> Result<(), E>
+    where
+        B: KVBucket<String, Vec<u8>, E>,
+    {
+        self.flush()?;
+        let _io = self.io.write().unwrap();
+        let bucket = self
+            .indexes
+            .lock()
+            .unwrap()
+            .iter()
+            .fold(bucket, |bucket, register| register(bucket));
+        *self.bucket.write().unwrap() = bucket;
+        *self.options.lock().unwrap() = Some(options);
+        drop(_io);
+        self.invalidate();
+        Ok(())
+    }
+
+    fn entry(&self, value: Option<Vec<u8>>, dirty: bool) -> CacheEntry {
+        CacheEntry {
+            value,
+            dirty,
+            write: self.writes.fetch_add(1, Ordering::Relaxed),
+        }
+    }
+
+    fn flush<E>(&self) -> Result<(), E>
+    where
+        B: KVBucket<String, Vec<u8>, E>,
+    {
+        let _io = self.io.write().unwrap();
+        let pending: Vec<_> = self
+            .lru
+            .lock()
+            .unwrap()
+            .iter_mut()
+            .filter(|(_, entry)| entry.dirty)
+            .map(|(key, entry)| (key.clone(), entry.value.clone(), entry.write))
+            .collect();
+        for (key, value, write) in pending {
+            match value {
+                Some(value) => self.backend().insert(key.clone(), value)?,
+                None => self.backend().remove(key.clone())?,
+            }
+            // entries written again during the flush stay dirty
+            let mut lru = self.lru.lock().unwrap();
+            let flushed = match lru.get_refresh(&key) {
+                Some(entry) if entry.write == write => {
+                    entry.dirty = false;
+                    entry.value.is_none()
+                }
+                _ => false,
+            };
+            if flushed {
+                lru.remove(&key);
+            }
+        }
+        Ok(())
+    }
+
+    // dirty entries written after the last flush are kept
+    fn invalidate(&self) {
+        let mut lru = self.lru.lock().unwrap();
+        let clean: Vec<_> = lru
+            .iter_mut()
+            .filter(|(_, entry)| !entry.dirty)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in clean {
+            lru.remove(&key);
+        }
+    }
+}
+
+pub struct CachedKVBucket<K, B> {
+    cache: Arc<BucketCache<B>>,
+    policy: CachePolicy,
+    _phantom: PhantomData<K>,
+}
+
+impl<K, B> Clone for CachedKVBucket<K, B> {
+    fn clone(&self) -> Self {
+        Self {
+            cache: self.cache.clone(),
+            policy: self.policy,
+            _phantom: PhantomData,
+        }
+    }
+}
+
+impl<K, B> CachedKVBucket<K, B> {
+    pub fn flush<E>(&self) -> Result<(), E>
+    where
+        B: KVBucket<String, Vec<u8>, E>,
+    {
+        self.cache.flush()
+    }
+
+    // the options the bucket was last opened with, `None` when it was
+    // opened without any
+    pub fn options(&self) -> Option<BucketOptions> {
+        *self.cache.options.lock().unwrap()
+    }
+
+    // registers an index on the backend bucket, e.g.
+    // `bucket.with_index(|b| b.with_index("tag", extractor))`. handles of the
+    // same bucket share one backend bucket, so they all get the index, and
+    // it's registered again when the bucket is opened with other options
+    pub fn with_index<F>(self, register: F) -> Self
+    where
+        B: Clone,
+        F: Fn(B) -> B + Send + Sync + 'static,
+    {
+        self.cache.add_index(Arc::new(register));
+        self
+    }
+
+    // opens an index of the backend bucket, e.g. `bucket.index(|b| b.index("tag"))`;
+    // indexes are only updated by the backend, so pending writes are
+    // flushed first
+    pub fn index<E, I, F>(&self, open: F) -> Result<I, E>
+    where
+        B: KVBucket<String, Vec<u8>, E>,
+        F: FnOnce(&B) -> I,
+    {
+        self.cache.flush()?;
+        Ok(open(&self.cache.backend()))
+    }
+
+    fn cache_write<E>(&self, key: String, value: Option<Vec<u8>>) -> Result<(), E>
+    where
+        B: KVBucket<String, Vec<u8>, E>,
+    {
+        let mut lru = self.cache.lru.lock().unwrap();
+        lru.insert(key, self.cache.entry(value, true));
+        if lru.is_overflow() {
+            drop(lru);
+            self.cache.flush()?;
+            // flushed entries are clean now and can be evicted
+            self.cache.lru.lock().unwrap().shrink();
+        }
+        Ok(())
+    }
+}
+
+impl<K: ToString, E, B: KVBucket<String, Vec<u8>, E>> KVBucket<K, Vec<u8>, E>
+    for CachedKVBucket<K, B>
+{
+    fn exists(&self, k: K) -> Result<bool, E> {
+        let key = k.to_string();
+        if let Some(entry) = self.cache.lru.lock().unwrap().get_refresh(&key) {
+            return Ok(entry.value.is_some());
+        }
+        self.cache.backend().exists(key)
+    }
+    fn get(&self, k: K) -> Option<Vec<u8>> {
+        let key = k.to_string();
+        if let Some(entry) = self.cache.lru.lock().unwrap().get_refresh(&key) {
+            return entry.value.clone();
+        }
+        let _io = self.cache.io.read().unwrap();
+        let value = self.cache.backend().get(key.clone())?;
+        let mut lru = self.cache.lru.lock().unwrap();
+        // a write back which raced with this read wins
+        if lru.get_refresh(&key).is_none() {
+            lru.insert(key, self.cache.entry(Some(value.clone()), false));
+        }
+        Some(value)
+    }
+    fn insert(&self, k: K, v: Vec<u8>) -> Result<(), E> {
+        let key = k.to_string();
+        match self.policy {
+            CachePolicy::WriteThrough => {
+                let _io = self.cache.io.write().unwrap();
+                self.cache.backend().insert(key.clone(), v.clone())?;
+                let entry = self.cache.entry(Some(v), false);
+                self.cache.lru.lock().unwrap().insert(key, entry);
+                Ok(())
+            }
+            CachePolicy::WriteBack => self.cache_write(key, Some(v)),
+        }
+    }
+    fn remove(&self, k: K) -> Result<(), E> {
+        let key = k.to_string();
+        match self.policy {
+            CachePolicy::WriteThrough => {
+                let _io = self.cache.io.write().unwrap();
+                self.cache.backend().remove(key.clone())?;
+                self.cache.lru.lock().unwrap().remove(&key);
+                Ok(())
+            }
+            CachePolicy::WriteBack => self.cache_write(key, None),
+        }
+    }
+    fn list(&self) -> Result<Vec<PathBuf>, E> {
+        self.cache.flush()?;
+        self.cache.backend().list()
+    }
+}
+
+pub struct CachedKV<D, B> {
+    db: D,
+    options: CacheOptions,
+    buckets: Arc<Mutex<HashMap<String, Arc<BucketCache<B>>>>>,
+}
+
+impl<D, B> CachedKV<D, B> {
+    pub fn new(db: D, options: CacheOptions) -> Self {
+        Self {
+            db,
+            options,
+            buckets: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn inner(&self) -> &D {
+        &self.db
+    }
+
+    // buckets with the same name share one cache, so handles stay coherent
+    fn get_cache<E, F>(&self, scope: String, open: F) -> Result<Arc<BucketCache<B>>, E>
+    where
+        F: FnOnce(String) -> Result<B, E>,
+    {
+        let mut buckets = self.buckets.lock().unwrap();
+        if let Some(cache) = buckets.get(&scope) {
+            return Ok(cache.clone());
+        }
+        let cache = Arc::new(BucketCache::new(open(scope.clone())?, None, &self.options));
+        buckets.insert(scope, cache.clone());
+        Ok(cache)
+    }
+
+    fn wrap<K>(&self, cache: Arc<BucketCache<B>>) -> CachedKVBucket<K, B> {
+        CachedKVBucket {
+            cache,
+            policy: self.options.policy,
+            _phantom: PhantomData,
+        }
+    }
+
+    // pending writes are flushed before the backend commits, and every
+    // cache is dropped afterwards so later reads see committed data
+    pub fn commit<E>(&self) -> Result<(), E>
+    where
+        B: KVBucket<String, Vec<u8>, E>,
+        D: KV<String, Vec<u8>, E, B>,
+    {
+        let caches: Vec<_> = self.buckets.lock().unwrap().values().cloned().collect();
+        for cache in caches.iter() {
+            cache.flush()?;
+        }
+        self.db.commit()?;
+        for cache in caches.iter() {
+            cache.invalidate();
+        }
+        Ok(())
+    }
+}
+
+impl<K, E, B, D> KV<K, Vec<u8>, E, CachedKVBucket<K, B>> for CachedKV<D, B>
+where
+    K: ToString,
+    B: KVBucket<String, Vec<u8>, E>,
+    D: KV<String, Vec<u8>, E, B>,
+{
+    fn get_bucket(&self, name: K) -> Result<CachedKVBucket<K, B>, E> {
+        let cache = self.get_cache(name.to_string(), |scope| self.db.get_bucket(scope))?;
+        Ok(self.wrap(cache))
+    }
+
+    fn get_bucket_with(&self, name: K, options: BucketOptions) -> Result<CachedKVBucket<K, B>, E> {
+        // the options are always forwarded, so the backend checks and stores
+        // them even when the bucket is cached already
+        let scope = name.to_string();
+        let bucket = self.db.get_bucket_with(scope.clone(), options)?;
+        let mut buckets = self.buckets.lock().unwrap();
+        let cache = match buckets.get(&scope) {
+            Some(cache) => {
+                if *cache.options.lock().unwrap() != Some(options) {
+                    cache.reopen(bucket, options)?;
+                }
+                cache.clone()
+            }
+            None => {
+                let cache = Arc::new(BucketCache::new(bucket, Some(options), &self.options));
+                buckets.insert(scope, cache.clone());
+                cache
+            }
+        };
+        drop(buckets);
+        Ok(self.wrap(cache))
+    }
+
+    fn commit(&self) -> Result<(), E> {
+        CachedKV::commit(self)
+    }
+}
+
+// counts every call which reaches the backend; clones share the data, so
+// the options and indexes belong to a single handle
+#[cfg(test)]
+#[derive(Clone, Default)]
+struct MemoryBucket {
+    data: Arc<Mutex<HashMap<String, Vec<u8>>>>,
+    calls: Arc<Mutex<Vec<(&'static str, String)>>>,
+    options: BucketOptions,
+    indexes: Vec<String>,
+}
+
+#[cfg(test)]
+impl MemoryBucket {
+    fn call(&self, op: &'static str, key: &str) {
+        self.calls.lock().unwrap().push((op, key.into()));
+    }
+    fn take_calls(&self) -> Vec<(&'static str, String)> {
+        self.calls.lock().unwrap().drain(..).collect()
+    }
+    fn with_index(mut self, name: &str) -> Self {
+        self.indexes.push(name.into());
+        self
+    }
+}
+
+#[cfg(test)]
+impl KVBucket<String, Vec<u8>, ()> for MemoryBucket {
+    fn exists(&self, k: String) -> Result<bool, ()> {
+        self.call("exists", &k);
+        Ok(self.data.lock().unwrap().contains_key(&k))
+    }
+    fn get(&self, k: String) -> Option<Vec<u8>> {
+        self.call("get", &k);
+        self.data.lock().unwrap().get(&k).cloned()
+    }
+    fn insert(&self, k: String, v: Vec<u8>) -> Result<(), ()> {
+        self.call("insert", &k);
+        self.data.lock().unwrap().insert(k, v);
+        Ok(())
+    }
+    fn remove(&self, k: String) -> Result<(), ()> {
+        self.call("remove", &k);
+        self.data.lock().unwrap().remove(&k);
+        Ok(())
+    }
+    fn list(&self) -> Result<Vec<PathBuf>, ()> {
+        Ok(self
+            .data
+            .lock()
+            .unwrap()
+            .keys()
+            .map(PathBuf::from)
+            .collect())
+    }
+}
+
+// relies on the default `commit`
+#[cfg(test)]
+#[derive(Default)]
+struct MemoryKV(MemoryBucket);
+
+#[cfg(test)]
+impl KV<String, Vec<u8>, (), MemoryBucket> for MemoryKV {
+    fn get_bucket(&self, _: String) -> Result<MemoryBucket, ()> {
+        Ok(self.0.clone())
+    }
+    fn get_bucket_with(&self, name: String, options: BucketOptions) -> Result<MemoryBucket, ()> {
+        self.0.call("open", &name);
+        Ok(MemoryBucket {
+            options,
+            ..self.0.clone()
+        })
+    }
+}
+
+#[cfg(test)]
+fn cached_memory_kv(
+    capacity: usize,
+    max_entries: usize,
+    policy: CachePolicy,
+) -> (CachedKV<MemoryKV, MemoryBucket>, MemoryBucket) {
+    let db = MemoryKV::default();
+    let backend = db.0.clone();
+    let options = CacheOptions {
+        capacity,
+        max_entries,
+        policy,
+    };
+    (CachedKV::new(db, options), backend)
+}
+
+#[test]
+fn cache_write_back_defers_writes() -> Result<(), ()> {
+    let (db, backend) = cached_memory_kv(1024, 16, CachePolicy::WriteBack);
+    let bucket = db.get_bucket("items")?;
+    bucket.insert("one", b"1".to_vec())?;
+    bucket.insert("two", b"2".to_vec())?;
+    bucket.remove("two")?;
+    assert_eq!(bucket.get("one"), Some(b"1".to_vec()));
+    assert_eq!(bucket.get("two"), None);
+    assert!(!bucket.exists("two")?);
+    assert!(backend.take_calls().is_empty());
+
+    db.commit()?;
+    let mut calls = backend.take_calls();
+    calls.sort();
+    assert_eq!(
+        calls,
+        vec![("insert", "one".into()), ("remove", "two".into())]
+    );
+    assert_eq!(backend.get("one".into()), Some(b"1".to_vec()));
+    Ok(())
+}
+
+#[test]
+fn cache_pins_dirty_entries_until_flushed() -> Result<(), ()> {
+    let (db, backend) = cached_memory_kv(4, 16, CachePolicy::WriteBack);
+    let bucket = db.get_bucket("items")?;
+    bucket.insert("one", b"11".to_vec())?;
+    bucket.insert("two", b"22".to_vec())?;
+    assert!(backend.take_calls().is_empty());
+
+    // the third entry overflows the cache, so everything is written out and
+    // the now clean entries can be evicted
+    bucket.insert("three", b"33".to_vec())?;
+    let mut calls = backend.take_calls();
+    calls.sort();
+    assert_eq!(
+        calls,
+        vec![
+            ("insert", "one".into()),
+            ("insert", "three".into()),
+            ("insert", "two".into()),
+        ]
+    );
+    for (key, value) in [("one", b"11"), ("two", b"22"), ("three", b"33")].iter() {
+        assert_eq!(bucket.get(*key), Some(value.to_vec()));
+    }
+    Ok(())
+}
+
+#[test]
+fn cache_evicts_least_recently_used() -> Result<(), ()> {
+    let (db, backend) = cached_memory_kv(1024, 2, CachePolicy::WriteThrough);
+    let bucket = db.get_bucket("items")?;
+    bucket.insert("one", b"1".to_vec())?;
+    bucket.insert("two", b"2".to_vec())?;
+    assert_eq!(bucket.get("one"), Some(b"1".to_vec()));
+    bucket.insert("three", b"3".to_vec())?;
+    backend.take_calls();
+
+    // "two" was the least recently used entry, so only it is read again
+    assert_eq!(bucket.get("one"), Some(b"1".to_vec()));
+    assert_eq!(bucket.get("three"), Some(b"3".to_vec()));
+    assert_eq!(bucket.get("two"), Some(b"2".to_vec()));
+    assert_eq!(backend.take_calls(), vec![("get", "two".into())]);
+    Ok(())
+}
+
+#[test]
+fn cache_write_through_remove_is_not_undone_by_reads() -> Result<(), ()> {
+    use std::thread;
+
+    let (db, _) = cached_memory_kv(1024, 16, CachePolicy::WriteThrough);
+    let bucket = db.get_bucket(String::from("items"))?;
+    for i in 0..200 {
+        let key = format!("key{}", i);
+        bucket.insert(key.clone(), b"value".to_vec())?;
+        // drop the cached copy so the reader has to go to the backend
+        db.commit()?;
+        let reader = {
+            let bucket = bucket.clone();
+            let key = key.clone();
+            thread::spawn(move || bucket.get(key))
+        };
+        bucket.remove(key.clone())?;
+        reader.join().unwrap();
+        assert_eq!(bucket.get(key), None);
+    }
+    Ok(())
+}
+
+#[test]
+fn cache_forwards_bucket_options() -> Result<(), ()> {
+    let (db, backend) = cached_memory_kv(1024, 16, CachePolicy::WriteBack);
+    let uncompressed = BucketOptions {
+        compression: Some(false),
+        ..Default::default()
+    };
+    let bucket = db.get_bucket_with("items", uncompressed)?;
+    bucket.insert("one", b"1".to_vec())?;
+    assert_eq!(backend.take_calls(), vec![("open", "items".into())]);
+
+    // the same options keep the cached bucket and its pending writes
+    let same = db.get_bucket_with("items", uncompressed)?;
+    assert_eq!(backend.take_calls(), vec![("open", "items".into())]);
+    assert_eq!(same.options(), Some(uncompressed));
+
+    // other options reach the backend too, and pending writes are flushed
+    // to the old bucket before the new one replaces it in every handle
+    let compressed = BucketOptions {
+        compression: Some(true),
+        ..Default::default()
+    };
+    db.get_bucket_with("items", compressed)?;
+    assert_eq!(
+        backend.take_calls(),
+        vec![("open", "items".into()), ("insert", "one".into())]
+    );
+    assert_eq!(bucket.options(), Some(compressed));
+    assert_eq!(bucket.index(|b| b.options), Ok(compressed));
+    Ok(())
+}
+
+#[test]
+fn cache_registers_indexes_on_the_backend() -> Result<(), ()> {
+    let (db, backend) = cached_memory_kv(1024, 16, CachePolicy::WriteBack);
+    let bucket = db
+        .get_bucket("items")?
+        .with_index(|b: MemoryBucket| b.with_index("tag"));
+    bucket.insert("one", b"1".to_vec())?;
+    assert!(backend.take_calls().is_empty());
+
+    // opening an index flushes pending writes, so the index sees them
+    let other = db.get_bucket("items")?;
+    assert_eq!(other.index(|b| b.indexes.clone()), Ok(vec!["tag".into()]));
+    assert_eq!(backend.take_calls(), vec![("insert", "one".into())]);
+
+    // indexes are registered again on a bucket opened with other options
+    db.get_bucket_with("items", BucketOptions::default())?;
+    assert_eq!(bucket.index(|b| b.indexes.clone()), Ok(vec!["tag".into()]));
+    Ok(())
+}
@@ -1,7 +1,176 @@
+use std::collections::HashMap;
+use std::io;
 use std::path::PathBuf;
+use std::sync::Arc;
+
+pub type IndexExtractor = Arc<dyn Fn(&[u8]) -> Option<String> + Send + Sync>;
+
+// index entries are stored under `#index/{scope}/{name}/{key}/{primary}`;
+// every component is escaped so a `/` inside a key or a primary key can't
+// make a prefix lookup match the entries of another key
+pub(crate) fn escape_component(component: &str) -> String {
+    let mut escaped = String::with_capacity(component.len());
+    for (i, c) in component.chars().enumerate() {
+        match c {
+            '%' => escaped.push_str("%25"),
+            '/' => escaped.push_str("%2F"),
+            '\n' => escaped.push_str("%0A"),
+            // keeps `.` and `..` from being read as paths by the zbox backend
+            '.' if i == 0 => escaped.push_str("%2E"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+pub(crate) fn unescape_component(component: &str) -> String {
+    let mut unescaped = String::with_capacity(component.len());
+    let mut rest = component;
+    while let Some(pos) = rest.find('%') {
+        unescaped.push_str(&rest[..pos]);
+        let code = rest
+            .get(pos + 1..pos + 3)
+            .and_then(|code| u8::from_str_radix(code, 16).ok());
+        match code {
+            Some(code) => {
+                unescaped.push(code as char);
+                rest = &rest[pos + 3..];
+            }
+            None => {
+                unescaped.push('%');
+                rest = &rest[pos + 1..];
+            }
+        }
+    }
+    unescaped.push_str(rest);
+    unescaped
+}
+
+pub(crate) fn index_prefix(scope: &str, name: &str) -> String {
+    format!(
+        "#index/{}/{}/",
+        escape_component(scope),
+        escape_component(name)
+    )
+}
+
+pub(crate) fn index_key_prefix(scope: &str, name: &str, key: &str) -> String {
+    format!("{}{}/", index_prefix(scope, name), escape_component(key))
+}
+
+pub(crate) fn index_path(scope: &str, name: &str, key: &str, primary: &str) -> String {
+    format!(
+        "{}{}",
+        index_key_prefix(scope, name, key),
+        escape_component(primary)
+    )
+}
+
+pub(crate) fn index_names_path(scope: &str) -> String {
+    format!("#indexes/{}", escape_component(scope))
+}
+
+fn decode_index_names(bytes: &[u8]) -> Vec<String> {
+    String::from_utf8_lossy(bytes)
+        .lines()
+        .filter(|name| !name.is_empty())
+        .map(unescape_component)
+        .collect()
+}
+
+fn encode_index_names(names: &[String]) -> Vec<u8> {
+    names
+        .iter()
+        .map(|name| escape_component(name))
+        .collect::<Vec<_>>()
+        .join("\n")
+        .into_bytes()
+}
+
+// extractors can't be stored, so only the index names of a bucket are kept;
+// a handle which writes to the bucket has to register every one of them, or
+// the index would silently miss the write. returns the names to store when
+// the handle registers new indexes
+pub(crate) fn merge_index_names(
+    stored: Option<&[u8]>,
+    indexes: &HashMap<String, IndexExtractor>,
+) -> io::Result<Option<Vec<u8>>> {
+    let mut names = stored.map(decode_index_names).unwrap_or_default();
+    if let Some(name) = names.iter().find(|name| !indexes.contains_key(*name)) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "index `{}` of this bucket is not registered with `with_index`",
+                name
+            ),
+        ));
+    }
+    if names.len() == indexes.len() {
+        return Ok(None);
+    }
+    names = indexes.keys().cloned().collect();
+    names.sort();
+    Ok(Some(encode_index_names(&names)))
+}
+
+// returns the stored names without `name`, or `None` if it isn't stored
+pub(crate) fn remove_index_name(stored: Option<&[u8]>, name: &str) -> Option<Vec<u8>> {
+    let mut names = stored.map(decode_index_names).unwrap_or_default();
+    let len = names.len();
+    names.retain(|stored| stored != name);
+    if names.len() == len {
+        None
+    } else {
+        Some(encode_index_names(&names))
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BucketOptions {
+    pub compression: Option<bool>,
+    pub version_limit: Option<u8>,
+    pub dedup: Option<bool>,
+}
+
+impl BucketOptions {
+    // buckets without compression are kept in a store of their own by the
+    // acid and zbox backends, since compression is fixed per store
+    pub(crate) fn is_uncompressed(&self) -> bool {
+        self.compression == Some(false)
+    }
+
+    pub(crate) fn to_bytes(self) -> Vec<u8> {
+        vec![
+            self.compression.is_some() as u8,
+            self.compression.unwrap_or_default() as u8,
+            self.version_limit.is_some() as u8,
+            self.version_limit.unwrap_or_default(),
+            self.dedup.is_some() as u8,
+            self.dedup.unwrap_or_default() as u8,
+        ]
+    }
+
+    pub(crate) fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        match bytes {
+            [has_compression, compression, has_version_limit, version_limit, has_dedup, dedup] => {
+                Some(Self {
+                    compression: Some(*compression != 0).filter(|_| *has_compression != 0),
+                    version_limit: Some(*version_limit).filter(|_| *has_version_limit != 0),
+                    dedup: Some(*dedup != 0).filter(|_| *has_dedup != 0),
+                })
+            }
+            _ => None,
+        }
+    }
+}
 
 pub trait KV<K, V, E, B: KVBucket<K, V, E>> {
     fn get_bucket(&self, name: K) -> Result<B, E>;
+    fn get_bucket_with(&self, name: K, options: BucketOptions) -> Result<B, E>;
+    // backends which persist every write as it happens have nothing to commit
+    fn commit(&self) -> Result<(), E> {
+        Ok(())
+    }
 }
 
 pub trait KVBucket<K, V, E> {
@@ -11,3 +180,16 @@ pub trait KVBucket<K, V, E> {
     fn remove(&self, k: K) -> Result<(), E>;
     fn list(&self) -> Result<Vec<PathBuf>, E>;
 }
+
+pub trait KVIndex<K, E> {
+    fn get(&self, k: K) -> Result<Vec<PathBuf>, E>;
+}
+
+#[test]
+fn escaped_components_round_trip() {
+    for component in ["", "a/b", "%2F", ".hidden", "..", "line\nbreak"].iter() {
+        let escaped = escape_component(component);
+        assert!(!escaped.contains('/') && !escaped.starts_with('.'));
+        assert_eq!(unescape_component(&escaped), *component);
+    }
+}
@@ -1,22 +1,27 @@
 #[cfg(feature = "acid_kv")]
 mod acid_impl;
+mod cache;
 mod kv;
+mod lru;
 #[cfg(feature = "sled_kv")]
 mod sled_impl;
+mod telemetry;
 #[cfg(feature = "zbox_kv")]
 mod zbox_impl;
 
 use std::io::{Read, Write};
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, RwLock};
+use telemetry::OpTimer;
 
-pub use crate::kv::{KVBucket, KV};
+pub use crate::kv::{BucketOptions, IndexExtractor, KVBucket, KVIndex, KV};
 #[cfg(feature = "acid_kv")]
-pub use acid_impl::{AcidError, AcidKV, AcidKVBucket};
+pub use acid_impl::{AcidError, AcidKV, AcidKVBucket, AcidKVIndex};
+pub use cache::{CacheOptions, CachePolicy, CachedKV, CachedKVBucket};
 #[cfg(feature = "sled_kv")]
-pub use sled_impl::{SledKV, SledKVBucket};
+pub use sled_impl::{SledKV, SledKVBucket, SledKVIndex};
 #[cfg(feature = "zbox_kv")]
-pub use zbox_impl::{Repo, ZboxError, ZboxKV, ZboxKVBucket};
+pub use zbox_impl::{Repo, ZboxError, ZboxKV, ZboxKVBucket, ZboxKVIndex};
 
 fn get_path_string<P: AsRef<Path>>(path: P) -> String {
     path.as_ref().to_str().unwrap_or_default().into()
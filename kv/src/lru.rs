@@ -0,0 +1,163 @@
+use linked_hash_map::{IterMut, LinkedHashMap};
+use std::borrow::Borrow;
+use std::hash::Hash;
+
+// same shape as zbox's `base::lru`, with an extra count limit next to the
+// metered capacity
+
+pub trait Meter<T> {
+    fn measure(&self, item: &T) -> isize;
+}
+
+pub trait Pinnable<T> {
+    fn is_pinned(&self, _: &T) -> bool {
+        false
+    }
+}
+
+pub struct Lru<K, V, M, P>
+where
+    K: Eq + Hash,
+    M: Meter<V> + Default,
+    P: Pinnable<V> + Default,
+{
+    capacity: usize,
+    max_entries: usize,
+    used: usize,
+    map: LinkedHashMap<K, V>,
+    meter: M,
+    pin_ckr: P,
+}
+
+impl<K, V, M, P> Lru<K, V, M, P>
+where
+    K: Eq + Hash,
+    M: Meter<V> + Default,
+    P: Pinnable<V> + Default,
+{
+    pub fn new(capacity: usize, max_entries: usize) -> Self {
+        Lru {
+            capacity,
+            max_entries,
+            used: 0,
+            map: LinkedHashMap::new(),
+            meter: M::default(),
+            pin_ckr: P::default(),
+        }
+    }
+
+    pub fn insert(&mut self, k: K, v: V) -> Option<V> {
+        let mut delta: isize = self.meter.measure(&v);
+        let mut ret: Option<V> = None;
+
+        if let Some(old_val) = self.map.insert(k, v) {
+            delta -= self.meter.measure(&old_val);
+            ret = Some(old_val);
+        }
+
+        self.used = (self.used as isize + delta) as usize;
+        self.shrink();
+
+        ret
+    }
+
+    // evict unpinned items until the cache fits its limits again
+    pub fn shrink(&mut self) {
+        while self.is_overflow() {
+            if self.remove_lru().is_none() {
+                break;
+            }
+        }
+    }
+
+    pub fn get_refresh<Q>(&mut self, k: &Q) -> Option<&mut V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        self.map.get_refresh(k)
+    }
+
+    pub fn iter_mut(&mut self) -> IterMut<'_, K, V> {
+        self.map.iter_mut()
+    }
+
+    pub fn remove<Q>(&mut self, k: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: ?Sized + Eq + Hash,
+    {
+        let v = self.map.remove(k)?;
+        self.used = (self.used as isize - self.meter.measure(&v)) as usize;
+        Some(v)
+    }
+
+    // true while pinned items keep the cache above its limits
+    pub fn is_overflow(&self) -> bool {
+        self.used > self.capacity || self.map.len() > self.max_entries
+    }
+
+    fn remove_lru(&mut self) -> Option<V> {
+        let pin_ckr = &self.pin_ckr;
+        let ret = self
+            .map
+            .entries()
+            .find(|ent| !pin_ckr.is_pinned(ent.get()))
+            .map(|ent| ent.remove());
+        if let Some(ref v) = ret {
+            self.used = (self.used as isize - self.meter.measure(v)) as usize;
+        }
+        ret
+    }
+}
+
+#[cfg(test)]
+#[derive(Default)]
+struct LenMeter;
+
+#[cfg(test)]
+impl Meter<(Vec<u8>, bool)> for LenMeter {
+    fn measure(&self, item: &(Vec<u8>, bool)) -> isize {
+        item.0.len() as isize
+    }
+}
+
+#[cfg(test)]
+#[derive(Default)]
+struct FlagPin;
+
+#[cfg(test)]
+impl Pinnable<(Vec<u8>, bool)> for FlagPin {
+    fn is_pinned(&self, item: &(Vec<u8>, bool)) -> bool {
+        item.1
+    }
+}
+
+#[test]
+fn lru_evicts_least_recent_unpinned_items() {
+    let mut lru: Lru<&str, (Vec<u8>, bool), LenMeter, FlagPin> = Lru::new(4, 3);
+    lru.insert("a", (vec![0; 2], false));
+    lru.insert("b", (vec![0; 2], false));
+    lru.get_refresh("a");
+    // over capacity, so the least recently used item goes
+    lru.insert("c", (vec![0; 2], false));
+    assert!(lru.get_refresh("b").is_none());
+    assert!(lru.get_refresh("a").is_some());
+    assert!(!lru.is_overflow());
+
+    // pinned items stay even when they keep the cache over its limits
+    lru.insert("d", (vec![0; 4], true));
+    lru.insert("e", (vec![0; 4], true));
+    assert!(lru.get_refresh("a").is_none() && lru.get_refresh("c").is_none());
+    assert!(lru.get_refresh("d").is_some() && lru.get_refresh("e").is_some());
+    assert!(lru.is_overflow());
+
+    // once unpinned they can be evicted again
+    for (_, item) in lru.iter_mut() {
+        item.1 = false;
+    }
+    lru.shrink();
+    assert!(!lru.is_overflow());
+    assert!(lru.remove("d").is_none());
+    assert!(lru.remove("e").is_some());
+}
@@ -1,11 +1,23 @@
 use super::*;
+use crate::kv::{
+    escape_component, index_names_path, index_path, index_prefix, merge_index_names,
+    remove_index_name, unescape_component,
+};
+use sled::transaction::{
+    ConflictableTransactionError, ConflictableTransactionResult, TransactionError,
+    TransactionalTree,
+};
 use sled::{Config, Db, Error as SledError};
+use std::collections::HashMap;
 use std::marker::PhantomData;
 use std::str::FromStr;
 
+#[derive(Clone)]
 pub struct SledKVBucket<K> {
     db: Arc<RwLock<Db>>,
     scope: String,
+    options: BucketOptions,
+    indexes: HashMap<String, IndexExtractor>,
     _phantom: PhantomData<K>,
 }
 
@@ -14,9 +26,45 @@ impl<K> SledKVBucket<K> {
         Self {
             db,
             scope: scope.to_string(),
+            options: BucketOptions::default(),
+            indexes: HashMap::new(),
             _phantom: PhantomData,
         }
     }
+    pub fn options(&self) -> BucketOptions {
+        self.options
+    }
+    pub fn with_index<S, F>(mut self, name: S, extractor: F) -> Self
+    where
+        S: ToString,
+        F: Fn(&[u8]) -> Option<String> + Send + Sync + 'static,
+    {
+        self.indexes.insert(name.to_string(), Arc::new(extractor));
+        self
+    }
+    pub fn index<S: ToString>(&self, name: S) -> SledKVIndex<K> {
+        SledKVIndex {
+            db: self.db.clone(),
+            prefix: index_prefix(&self.scope, &name.to_string()),
+            _phantom: PhantomData,
+        }
+    }
+    // removes the entries and the stored name of an index, so handles which
+    // don't register it can write to the bucket again
+    pub fn drop_index<S: ToString>(&mut self, name: S) -> Result<(), SledError> {
+        let name = name.to_string();
+        self.indexes.remove(&name);
+        let db = self.db.read().unwrap();
+        for key in db.scan_prefix(index_prefix(&self.scope, &name)).keys() {
+            db.remove(key?)?;
+        }
+        let names_path = index_names_path(&self.scope);
+        let stored = db.get(&names_path)?;
+        if let Some(names) = remove_index_name(stored.as_deref(), &name) {
+            db.insert(names_path, names)?;
+        }
+        Ok(())
+    }
     fn get_path<S: ToString>(&self, prefix: S) -> Vec<u8> {
         format!(
             "{}{}",
@@ -29,43 +77,102 @@ impl<K> SledKVBucket<K> {
         )
         .into_bytes()
     }
+    fn get_index_path(&self, name: &str, key: &str, primary: &str) -> Vec<u8> {
+        index_path(&self.scope, name, key, primary).into_bytes()
+    }
+    // runs before every write, even on handles without indexes, so a bucket
+    // with an index can't be written through a handle which doesn't update it;
+    // it's part of the write's transaction, so an index registered by another
+    // handle in between can't miss the write
+    fn check_indexes(
+        &self,
+        tx: &TransactionalTree,
+    ) -> ConflictableTransactionResult<(), SledError> {
+        let names_path = index_names_path(&self.scope);
+        let stored = tx.get(names_path.as_bytes())?;
+        let names = merge_index_names(stored.as_deref(), &self.indexes)
+            .map_err(|e| ConflictableTransactionError::Abort(e.into()))?;
+        if let Some(names) = names {
+            tx.insert(names_path.as_bytes(), names)?;
+        }
+        Ok(())
+    }
+    // primary and index entries are written in one sled transaction
+    fn write(&self, primary: &str, v: Option<&[u8]>) -> Result<(), SledError> {
+        let db = self.db.read().unwrap();
+        let path = self.get_path(primary);
+        db.transaction(
+            |tx: &TransactionalTree| -> ConflictableTransactionResult<(), SledError> {
+                self.check_indexes(tx)?;
+                let old = match v {
+                    Some(v) => tx.insert(path.as_slice(), v)?,
+                    None => tx.remove(path.as_slice())?,
+                };
+                for (name, extractor) in self.indexes.iter() {
+                    let old_key = old.as_ref().and_then(|v| extractor(&v[..]));
+                    let new_key = v.and_then(|v| extractor(v));
+                    if old_key == new_key {
+                        continue;
+                    }
+                    if let Some(key) = old_key {
+                        tx.remove(self.get_index_path(name, &key, primary))?;
+                    }
+                    if let Some(key) = new_key {
+                        tx.insert(self.get_index_path(name, &key, primary), Vec::<u8>::new())?;
+                    }
+                }
+                Ok(())
+            },
+        )
+        .map_err(|e| match e {
+            TransactionError::Abort(e) => e,
+            TransactionError::Storage(e) => e,
+        })
+    }
 }
 
 impl<K: ToString> KVBucket<K, Vec<u8>, SledError> for SledKVBucket<K> {
     fn exists(&self, k: K) -> Result<bool, SledError> {
+        let timer = OpTimer::start("sled", &self.scope, "exists");
         let db = self.db.read().unwrap();
         let path = self.get_path(k);
-        Ok(db.contains_key(&path)?)
+        let exists = db.contains_key(&path)?;
+        timer.finish(0, 0);
+        Ok(exists)
     }
     fn get(&self, k: K) -> Option<Vec<u8>> {
+        let timer = OpTimer::start("sled", &self.scope, "get");
         let db = self.db.read().unwrap();
         let path = self.get_path(k);
-        if db.contains_key(&path).unwrap_or(false) {
+        let value = if db.contains_key(&path).unwrap_or(false) {
             db.get(&path)
                 .ok()
                 .and_then(|iter| iter)
                 .map(|data| data.to_vec())
         } else {
             None
-        }
+        };
+        timer.finish(0, value.as_ref().map(|v| v.len()).unwrap_or_default());
+        value
     }
     fn insert(&self, k: K, v: Vec<u8>) -> Result<(), SledError> {
-        let db = self.db.read().unwrap();
-        db.insert(self.get_path(k), v)?;
+        let timer = OpTimer::start("sled", &self.scope, "insert");
+        let len = v.len();
+        self.write(&k.to_string(), Some(&v))?;
+        timer.finish(len, 0);
         Ok(())
     }
     fn remove(&self, k: K) -> Result<(), SledError> {
-        let db = self.db.read().unwrap();
-        let path = self.get_path(k);
-        if db.contains_key(&path)? {
-            db.remove(&path)?;
-        }
+        let timer = OpTimer::start("sled", &self.scope, "remove");
+        self.write(&k.to_string(), None)?;
+        timer.finish(0, 0);
         Ok(())
     }
     fn list(&self) -> Result<Vec<PathBuf>, SledError> {
+        let timer = OpTimer::start("sled", &self.scope, "list");
         let db = self.db.read().unwrap();
         let prefix = PathBuf::from(String::from_utf8_lossy(&self.get_path("")).into_owned());
-        Ok(db
+        let list: Vec<PathBuf> = db
             .iter()
             .filter_map(|item| {
                 item.ok()
@@ -73,7 +180,32 @@ impl<K: ToString> KVBucket<K, Vec<u8>, SledError> for SledKVBucket<K> {
                     .and_then(|path| PathBuf::from_str(&path).ok())
                     .and_then(|path| path.strip_prefix(&prefix).map(|path| path.into()).ok())
             })
-            .collect())
+            .collect();
+        timer.finish(0, 0);
+        Ok(list)
+    }
+}
+
+pub struct SledKVIndex<K> {
+    db: Arc<RwLock<Db>>,
+    prefix: String,
+    _phantom: PhantomData<K>,
+}
+
+impl<K: ToString> KVIndex<K, SledError> for SledKVIndex<K> {
+    fn get(&self, k: K) -> Result<Vec<PathBuf>, SledError> {
+        let db = self.db.read().unwrap();
+        let prefix = format!("{}{}/", self.prefix, escape_component(&k.to_string())).into_bytes();
+        db.scan_prefix(&prefix)
+            .keys()
+            .map(|item| {
+                item.map(|key| {
+                    PathBuf::from(unescape_component(&String::from_utf8_lossy(
+                        &key[prefix.len()..],
+                    )))
+                })
+            })
+            .collect()
     }
 }
 
@@ -92,11 +224,59 @@ impl SledKV {
             )),
         }
     }
+
+    fn get_options_path(scope: &str) -> Vec<u8> {
+        format!("#bucket/{}", scope).into_bytes()
+    }
+
+    pub fn commit(&self) -> Result<(), SledError> {
+        let timer = OpTimer::start("sled", "", "commit");
+        let bytes = self.db.read().unwrap().flush()?;
+        timer.finish(0, bytes);
+        Ok(())
+    }
 }
 
 impl<S: ToString> KV<S, Vec<u8>, SledError, SledKVBucket<S>> for SledKV {
     fn get_bucket(&self, name: S) -> Result<SledKVBucket<S>, SledError> {
-        Ok(SledKVBucket::new(self.db.clone(), name))
+        let db = self.db.read().unwrap();
+        let options = db
+            .get(Self::get_options_path(&name.to_string()))?
+            .and_then(|bytes| BucketOptions::from_bytes(&bytes))
+            .unwrap_or_default();
+        let mut bucket = SledKVBucket::new(self.db.clone(), name);
+        bucket.options = options;
+        Ok(bucket)
+    }
+
+    // sled keeps a single version of every value, doesn't deduplicate and
+    // is opened without compression, so options which ask for anything else
+    // are rejected instead of silently ignored
+    fn get_bucket_with(
+        &self,
+        name: S,
+        options: BucketOptions,
+    ) -> Result<SledKVBucket<S>, SledError> {
+        if options.compression == Some(true)
+            || options.version_limit.unwrap_or(1) != 1
+            || options.dedup == Some(true)
+        {
+            return Err(SledError::Unsupported(
+                "the sled backend doesn't compress, deduplicate or keep versions".into(),
+            ));
+        }
+        let db = self.db.read().unwrap();
+        db.insert(
+            Self::get_options_path(&name.to_string()),
+            options.to_bytes(),
+        )?;
+        let mut bucket = SledKVBucket::new(self.db.clone(), name);
+        bucket.options = options;
+        Ok(bucket)
+    }
+
+    fn commit(&self) -> Result<(), SledError> {
+        SledKV::commit(self)
     }
 }
 
@@ -133,3 +313,79 @@ fn transform_zbox_to_sled() -> Result<(), anyhow::Error> {
     println!("finash, {}ms", sw.elapsed().as_millis());
     Ok(())
 }
+
+#[cfg(test)]
+fn tag_extractor(v: &[u8]) -> Option<String> {
+    Some(String::from_utf8_lossy(v).into_owned())
+}
+
+#[test]
+fn sled_index_keys_with_separators() -> Result<(), anyhow::Error> {
+    let dir = tempfile::tempdir()?;
+    let db = SledKV::new(dir.path().join("index").display());
+    let bucket = db.get_bucket("items")?.with_index("tag", tag_extractor);
+    bucket.insert("one", b"a".to_vec())?;
+    bucket.insert("two", b"a/b".to_vec())?;
+    bucket.insert("a/three", b"a".to_vec())?;
+    let index = bucket.index("tag");
+    let mut found = index.get("a")?;
+    found.sort();
+    assert_eq!(found, vec![PathBuf::from("a/three"), PathBuf::from("one")]);
+    assert_eq!(index.get("a/b")?, vec![PathBuf::from("two")]);
+    assert!(index.get("a/three")?.is_empty());
+    Ok(())
+}
+
+#[test]
+fn sled_index_follows_updates() -> Result<(), anyhow::Error> {
+    let dir = tempfile::tempdir()?;
+    let db = SledKV::new(dir.path().join("index").display());
+    let bucket = db.get_bucket("items")?.with_index("tag", tag_extractor);
+    let index = bucket.index("tag");
+    bucket.insert("one", b"a".to_vec())?;
+    bucket.insert("one", b"b".to_vec())?;
+    assert!(index.get("a")?.is_empty());
+    assert_eq!(index.get("b")?, vec![PathBuf::from("one")]);
+    bucket.remove("one")?;
+    assert!(index.get("b")?.is_empty());
+    Ok(())
+}
+
+#[test]
+fn sled_index_must_be_registered_to_write() -> Result<(), anyhow::Error> {
+    let dir = tempfile::tempdir()?;
+    let db = SledKV::new(dir.path().join("index").display());
+    let mut indexed = db.get_bucket("items")?.with_index("tag", tag_extractor);
+    indexed.insert("one", b"a".to_vec())?;
+    let plain = db.get_bucket("items")?;
+    assert!(plain.insert("two", b"a".to_vec()).is_err());
+    assert!(plain.remove("one").is_err());
+    indexed.drop_index("tag")?;
+    plain.insert("two", b"a".to_vec())?;
+    assert!(indexed.index("tag").get("a")?.is_empty());
+    Ok(())
+}
+
+#[test]
+fn sled_bucket_options_are_checked_and_stored() -> Result<(), anyhow::Error> {
+    let dir = tempfile::tempdir()?;
+    let db = SledKV::new(dir.path().join("options").display());
+    let compressed = BucketOptions {
+        compression: Some(true),
+        ..BucketOptions::default()
+    };
+    assert!(db.get_bucket_with("items", compressed).is_err());
+    let versioned = BucketOptions {
+        version_limit: Some(3),
+        ..BucketOptions::default()
+    };
+    assert!(db.get_bucket_with("items", versioned).is_err());
+    let supported = BucketOptions {
+        compression: Some(false),
+        version_limit: Some(1),
+        dedup: Some(false),
+    };
+    db.get_bucket_with("items", supported)?;
+    assert_eq!(db.get_bucket("items")?.options(), supported);
+    Ok(())
+}
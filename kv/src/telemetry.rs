@@ -0,0 +1,245 @@
+use std::time::{Duration, Instant};
+
+// returns the cumulative compression and encryption time of the calling thread
+pub(crate) type EncodingSampler = fn() -> (Duration, Duration);
+
+// records one kv operation; everything is a no-op unless the `metrics` or
+// `tracing` feature is enabled. an operation which is dropped without
+// `finish`, e.g. because `?` returned early, is recorded as an error
+#[cfg_attr(not(any(feature = "metrics", feature = "tracing")), allow(dead_code))]
+pub(crate) struct OpTimer {
+    backend: &'static str,
+    bucket: String,
+    op: &'static str,
+    start: Instant,
+    sampler: Option<(EncodingSampler, (Duration, Duration))>,
+    finished: bool,
+}
+
+#[cfg_attr(
+    not(any(feature = "metrics", feature = "tracing")),
+    allow(unused_variables)
+)]
+impl OpTimer {
+    pub(crate) fn start(backend: &'static str, bucket: &str, op: &'static str) -> Self {
+        Self {
+            backend,
+            bucket: bucket.into(),
+            op,
+            start: Instant::now(),
+            sampler: None,
+            finished: false,
+        }
+    }
+
+    // for backends which encode on the calling thread, the encoding time is
+    // sampled when the operation starts and ends, on both the ok and error paths
+    #[cfg_attr(not(feature = "zbox_kv"), allow(dead_code))]
+    pub(crate) fn sample_encoding(mut self, sampler: EncodingSampler) -> Self {
+        self.sampler = Some((sampler, sampler()));
+        self
+    }
+
+    pub(crate) fn encoding(&self, compression: Duration, encryption: Duration) {
+        #[cfg(test)]
+        recorder::record_encoding(self, compression, encryption);
+        #[cfg(feature = "metrics")]
+        {
+            let labels = self.labels();
+            metrics::histogram!("kv_compression_seconds", compression, &labels);
+            metrics::histogram!("kv_encryption_seconds", encryption, &labels);
+        }
+        #[cfg(feature = "tracing")]
+        tracing::trace!(
+            backend = self.backend,
+            bucket = %self.bucket,
+            op = self.op,
+            compression_us = compression.as_micros() as u64,
+            encryption_us = encryption.as_micros() as u64,
+            "kv encoding"
+        );
+    }
+
+    pub(crate) fn finish(mut self, bytes_in: usize, bytes_out: usize) {
+        self.finished = true;
+        self.record("ok", bytes_in, bytes_out);
+    }
+
+    fn record(&self, status: &'static str, bytes_in: usize, bytes_out: usize) {
+        if let Some((sampler, (compression, encryption))) = self.sampler {
+            let (total_compression, total_encryption) = sampler();
+            self.encoding(
+                total_compression - compression,
+                total_encryption - encryption,
+            );
+        }
+        let elapsed = self.start.elapsed();
+        #[cfg(test)]
+        recorder::record_op(self, status, bytes_in, bytes_out);
+        #[cfg(feature = "metrics")]
+        {
+            let mut labels = self.labels();
+            labels.push(("status", status.into()));
+            metrics::counter!("kv_operations_total", 1, &labels);
+            metrics::histogram!("kv_operation_seconds", elapsed, &labels);
+            metrics::counter!("kv_bytes_in_total", bytes_in as u64, &labels);
+            metrics::counter!("kv_bytes_out_total", bytes_out as u64, &labels);
+        }
+        #[cfg(feature = "tracing")]
+        tracing::debug!(
+            backend = self.backend,
+            bucket = %self.bucket,
+            op = self.op,
+            status,
+            elapsed_us = elapsed.as_micros() as u64,
+            bytes_in,
+            bytes_out,
+            "kv operation"
+        );
+    }
+
+    #[cfg(feature = "metrics")]
+    fn labels(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("backend", self.backend.into()),
+            ("bucket", self.bucket.clone()),
+            ("op", self.op.into()),
+        ]
+    }
+}
+
+impl Drop for OpTimer {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.record("error", 0, 0);
+        }
+    }
+}
+
+// keeps what the calling thread recorded so tests can check it without
+// installing a metrics recorder or tracing subscriber
+#[cfg(test)]
+pub(crate) mod recorder {
+    use super::OpTimer;
+    use std::cell::RefCell;
+    use std::time::Duration;
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub(crate) struct Op {
+        pub backend: &'static str,
+        pub bucket: String,
+        pub op: &'static str,
+        pub status: &'static str,
+        pub bytes_in: usize,
+        pub bytes_out: usize,
+    }
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub(crate) struct Encoding {
+        pub op: &'static str,
+        pub compression: Duration,
+        pub encryption: Duration,
+    }
+
+    thread_local! {
+        static OPS: RefCell<Vec<Op>> = const { RefCell::new(Vec::new()) };
+        static ENCODINGS: RefCell<Vec<Encoding>> = const { RefCell::new(Vec::new()) };
+    }
+
+    pub(super) fn record_op(
+        timer: &OpTimer,
+        status: &'static str,
+        bytes_in: usize,
+        bytes_out: usize,
+    ) {
+        OPS.with(|ops| {
+            ops.borrow_mut().push(Op {
+                backend: timer.backend,
+                bucket: timer.bucket.clone(),
+                op: timer.op,
+                status,
+                bytes_in,
+                bytes_out,
+            })
+        });
+    }
+
+    pub(super) fn record_encoding(timer: &OpTimer, compression: Duration, encryption: Duration) {
+        ENCODINGS.with(|encodings| {
+            encodings.borrow_mut().push(Encoding {
+                op: timer.op,
+                compression,
+                encryption,
+            })
+        });
+    }
+
+    // returns and clears everything recorded on this thread
+    pub(crate) fn take() -> (Vec<Op>, Vec<Encoding>) {
+        (
+            OPS.with(|ops| ops.borrow_mut().drain(..).collect()),
+            ENCODINGS.with(|encodings| encodings.borrow_mut().drain(..).collect()),
+        )
+    }
+}
+
+#[test]
+fn dropped_timer_is_recorded_as_error() {
+    fn check(ok: bool) -> Result<(), ()> {
+        if ok {
+            Ok(())
+        } else {
+            Err(())
+        }
+    }
+    fn insert(ok: bool) -> Result<(), ()> {
+        let timer = OpTimer::start("test", "bucket", "insert");
+        check(ok)?;
+        timer.finish(1, 0);
+        Ok(())
+    }
+
+    recorder::take();
+    assert!(insert(false).is_err());
+    OpTimer::start("test", "bucket", "get").finish(0, 2);
+    let (ops, _) = recorder::take();
+    let ops: Vec<_> = ops
+        .iter()
+        .map(|op| (op.op, op.status, op.bytes_in, op.bytes_out))
+        .collect();
+    assert_eq!(ops, vec![("insert", "error", 0, 0), ("get", "ok", 0, 2)]);
+}
+
+#[test]
+fn sampled_encoding_is_recorded_on_both_paths() {
+    fn sampler() -> (Duration, Duration) {
+        thread_local!(static CALLS: std::cell::Cell<u64> = const { std::cell::Cell::new(0) });
+        let calls = CALLS.with(|calls| {
+            calls.set(calls.get() + 1);
+            calls.get()
+        });
+        (
+            Duration::from_millis(calls),
+            Duration::from_millis(2 * calls),
+        )
+    }
+
+    recorder::take();
+    OpTimer::start("test", "bucket", "get")
+        .sample_encoding(sampler)
+        .finish(0, 0);
+    drop(OpTimer::start("test", "bucket", "insert").sample_encoding(sampler));
+    let (ops, encodings) = recorder::take();
+    assert_eq!(ops.len(), 2);
+    let encodings: Vec<_> = encodings
+        .iter()
+        .map(|encoding| (encoding.op, encoding.compression, encoding.encryption))
+        .collect();
+    assert_eq!(
+        encodings,
+        vec![
+            ("get", Duration::from_millis(1), Duration::from_millis(2)),
+            ("insert", Duration::from_millis(1), Duration::from_millis(2)),
+        ]
+    );
+}
@@ -1,27 +1,186 @@
 use super::*;
+use crate::kv::{escape_component, merge_index_names, remove_index_name, unescape_component};
+use std::collections::HashMap;
 use std::marker::PhantomData;
-use zbox::RepoOpener;
+use std::time::Duration;
 pub use zbox::{Error as ZboxError, Repo};
+use zbox::{OpenOptions, RepoOpener};
+
+fn encoding_time() -> (Duration, Duration) {
+    let time = zbox::encoding_time();
+    (time.compression, time.encryption)
+}
 
 #[derive(Clone)]
 pub struct ZboxKVBucket<K> {
     db: Arc<RwLock<Repo>>,
     scope: PathBuf,
+    options: BucketOptions,
+    indexes: HashMap<String, IndexExtractor>,
     _phantom: PhantomData<K>,
 }
 
 impl<K> ZboxKVBucket<K> {
     pub fn new<S: ToString>(db: Arc<RwLock<Repo>>, scope: S) -> Result<Self, ZboxError> {
+        Self::new_with(db, scope, BucketOptions::default())
+    }
+    pub fn new_with<S: ToString>(
+        db: Arc<RwLock<Repo>>,
+        scope: S,
+        options: BucketOptions,
+    ) -> Result<Self, ZboxError> {
         let scope = Self::create_scope(db.clone(), scope.to_string())?;
         Ok(Self {
             db,
             scope,
+            options,
+            indexes: HashMap::new(),
             _phantom: PhantomData,
         })
     }
+    // unlike the acid and sled backends, index entries aren't written
+    // atomically with the value: zbox has no transaction across files. an
+    // interrupted write can leave entries which don't match the value, and
+    // `ZboxKVIndex::get` skips them, so lookups stay correct but the stale
+    // entries are left behind
+    pub fn with_index<S, F>(mut self, name: S, extractor: F) -> Self
+    where
+        S: ToString,
+        F: Fn(&[u8]) -> Option<String> + Send + Sync + 'static,
+    {
+        self.indexes.insert(name.to_string(), Arc::new(extractor));
+        self
+    }
+    pub fn options(&self) -> BucketOptions {
+        self.options
+    }
+    pub fn index<S: ToString>(&self, name: S) -> ZboxKVIndex<K> {
+        let name = name.to_string();
+        ZboxKVIndex {
+            db: self.db.clone(),
+            scope: self.scope.clone(),
+            path: self.get_index_dir(&name),
+            extractor: self.indexes.get(&name).cloned(),
+            _phantom: PhantomData,
+        }
+    }
+    // removes the entries and the stored name of an index, so handles which
+    // don't register it can write to the bucket again
+    pub fn drop_index<S: ToString>(&mut self, name: S) -> Result<(), ZboxError> {
+        let name = name.to_string();
+        self.indexes.remove(&name);
+        let mut db = self.db.write().unwrap();
+        let dir = self.get_index_dir(&name);
+        if db.is_dir(&dir)? {
+            db.remove_dir_all(&dir)?;
+        }
+        let names_path = self.get_index_names_path();
+        let stored = if db.is_file(&names_path)? {
+            read_file(&db, &names_path)
+        } else {
+            None
+        };
+        if let Some(names) = remove_index_name(stored.as_deref(), &name) {
+            write_file(&mut db, &names_path, &names)?;
+        }
+        Ok(())
+    }
     fn get_path<S: ToString>(&self, prefix: S) -> PathBuf {
         self.scope.join(prefix.to_string())
     }
+    // zbox compresses and encrypts on the calling thread and keeps a
+    // per-thread total, so concurrent readers don't skew each other's timings
+    fn start_timer(&self, op: &'static str) -> OpTimer {
+        OpTimer::start("zbox", &get_path_string(&self.scope), op).sample_encoding(encoding_time)
+    }
+    fn is_versioned(&self) -> bool {
+        self.options.version_limit.map_or(false, |limit| limit > 1)
+    }
+    fn get_open_options(&self) -> OpenOptions {
+        let mut opts = OpenOptions::new();
+        opts.create(true);
+        if let Some(version_limit) = self.options.version_limit {
+            opts.version_limit(version_limit);
+        }
+        if let Some(dedup) = self.options.dedup {
+            opts.dedup_chunk(dedup);
+        }
+        opts
+    }
+    // index components are escaped, so they never start with `.` and can't
+    // clash with the names file
+    fn get_index_dir(&self, name: &str) -> PathBuf {
+        self.scope.join(".index").join(escape_component(name))
+    }
+    fn get_index_path(&self, name: &str, key: &str) -> PathBuf {
+        self.get_index_dir(name).join(escape_component(key))
+    }
+    fn get_index_names_path(&self) -> PathBuf {
+        self.scope.join(".index").join(".names")
+    }
+    // runs before every write, even on handles without indexes, so a bucket
+    // with an index can't be written through a handle which doesn't update it
+    fn check_indexes(&self, db: &mut Repo) -> Result<(), ZboxError> {
+        let path = self.get_index_names_path();
+        let stored = if db.is_file(&path)? {
+            read_file(db, &path)
+        } else {
+            None
+        };
+        if let Some(names) = merge_index_names(stored.as_deref(), &self.indexes)? {
+            write_file(db, &path, &names)?;
+        }
+        Ok(())
+    }
+    // zbox commits every file operation on its own and has no transaction
+    // across files, so entries for the new value are added before the primary
+    // file is written and entries for the old value are removed after it. an
+    // interrupted write can only leave extra entries, which lookups skip
+    fn add_index_entries(
+        &self,
+        db: &mut Repo,
+        primary: &str,
+        old: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<(), ZboxError> {
+        for (name, extractor) in self.indexes.iter() {
+            let old_key = old.and_then(|v| extractor(v));
+            let new_key = new.and_then(|v| extractor(v));
+            if let Some(key) = new_key.filter(|key| Some(key) != old_key.as_ref()) {
+                let dir = self.get_index_path(name, &key);
+                if !db.is_dir(&dir)? {
+                    db.create_dir_all(&dir)?;
+                }
+                // a stale entry may already exist after an interrupted write
+                let path = dir.join(escape_component(primary));
+                if !db.is_file(&path)? {
+                    db.create_file(&path)?;
+                }
+            }
+        }
+        Ok(())
+    }
+    fn remove_index_entries(
+        &self,
+        db: &mut Repo,
+        primary: &str,
+        old: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<(), ZboxError> {
+        for (name, extractor) in self.indexes.iter() {
+            let old_key = old.and_then(|v| extractor(v));
+            let new_key = new.and_then(|v| extractor(v));
+            if let Some(key) = old_key.filter(|key| Some(key) != new_key.as_ref()) {
+                let path = self
+                    .get_index_path(name, &key)
+                    .join(escape_component(primary));
+                if db.is_file(&path)? {
+                    db.remove_file(&path)?;
+                }
+            }
+        }
+        Ok(())
+    }
     fn create_scope(db: Arc<RwLock<Repo>>, scope: String) -> Result<PathBuf, ZboxError> {
         let mut db = db.write().unwrap();
         let scope = PathBuf::from(if !scope.is_empty() {
@@ -41,46 +200,73 @@ impl<K> ZboxKVBucket<K> {
 
 impl<K: ToString> KVBucket<K, Vec<u8>, ZboxError> for ZboxKVBucket<K> {
     fn exists(&self, k: K) -> Result<bool, ZboxError> {
+        let timer = self.start_timer("exists");
         let db = self.db.read().unwrap();
         let path = self.get_path(k);
-        Ok(db.is_file(&path)?)
+        let exists = db.is_file(&path)?;
+        timer.finish(0, 0);
+        Ok(exists)
     }
     fn get(&self, k: K) -> Option<Vec<u8>> {
-        let mut db = self.db.write().unwrap();
+        let timer = self.start_timer("get");
+        let db = self.db.read().unwrap();
         let path = self.get_path(k);
-        if db.is_file(&path).unwrap_or(false) {
-            db.open_file(&path)
-                .and_then(|mut file| {
-                    let mut buf = vec![];
-                    file.read_to_end(&mut buf)?;
-                    Ok(buf)
-                })
-                .ok()
+        let value = if db.is_file(&path).unwrap_or(false) {
+            read_file(&db, &path)
         } else {
             None
-        }
+        };
+        timer.finish(0, value.as_ref().map(|v| v.len()).unwrap_or_default());
+        value
     }
     fn insert(&self, k: K, v: Vec<u8>) -> Result<(), ZboxError> {
+        let timer = self.start_timer("insert");
         let mut db = self.db.write().unwrap();
-        let path = self.get_path(k);
+        self.check_indexes(&mut db)?;
+        let key = k.to_string();
+        let path = self.get_path(&key);
+        let mut old = None;
         if db.is_file(&path)? {
-            db.remove_file(&path)?;
+            if !self.indexes.is_empty() {
+                old = read_file(&db, &path);
+            }
+            // versioned buckets keep the file so the old content stays in its history
+            if !self.is_versioned() {
+                db.remove_file(&path)?;
+            }
         }
-        db.create_file(&path)?.write_once(&v)?;
+        self.add_index_entries(&mut db, &key, old.as_deref(), Some(&v))?;
+        // replaces the content in a single version, so a versioned file never
+        // has a version which mixes the new value with the tail of the old one
+        let mut file = self.get_open_options().open(&mut db, &path)?;
+        file.replace_once(&v)?;
+        self.remove_index_entries(&mut db, &key, old.as_deref(), Some(&v))?;
+        timer.finish(v.len(), 0);
         Ok(())
     }
     fn remove(&self, k: K) -> Result<(), ZboxError> {
+        let timer = self.start_timer("remove");
         let mut db = self.db.write().unwrap();
-        let path = self.get_path(k);
+        self.check_indexes(&mut db)?;
+        let key = k.to_string();
+        let path = self.get_path(&key);
         if db.is_file(&path)? {
+            let old = if !self.indexes.is_empty() {
+                read_file(&db, &path)
+            } else {
+                None
+            };
             db.remove_file(&path)?;
+            self.remove_index_entries(&mut db, &key, old.as_deref(), None)?;
         }
+        timer.finish(0, 0);
         Ok(())
     }
     fn list(&self) -> Result<Vec<PathBuf>, ZboxError> {
+        let timer = self.start_timer("list");
         let db = self.db.read().unwrap();
         let prefix = self.get_path("");
-        Ok(db
+        let list: Vec<PathBuf> = db
             .read_dir(&prefix)?
             .iter()
             .filter(|entry| entry.metadata().is_file())
@@ -91,33 +277,187 @@ impl<K: ToString> KVBucket<K, Vec<u8>, ZboxError> for ZboxKVBucket<K> {
                     .ok()
                     .map(|path| path.into())
             })
-            .collect())
+            .collect();
+        timer.finish(0, 0);
+        Ok(list)
+    }
+}
+
+// shared open only needs a read lock, so readers don't block each other
+fn read_file(db: &Repo, path: &Path) -> Option<Vec<u8>> {
+    db.open_file_shared(path)
+        .and_then(|mut file| {
+            let mut buf = vec![];
+            file.read_to_end(&mut buf)?;
+            Ok(buf)
+        })
+        .ok()
+}
+
+fn write_file(db: &mut Repo, path: &Path, data: &[u8]) -> Result<(), ZboxError> {
+    if let Some(parent) = path.parent() {
+        if !db.is_dir(parent)? {
+            db.create_dir_all(parent)?;
+        }
+    }
+    if db.is_file(path)? {
+        db.remove_file(path)?;
+    }
+    db.create_file(path)?.write_once(data)
+}
+
+#[derive(Clone)]
+pub struct ZboxKVIndex<K> {
+    db: Arc<RwLock<Repo>>,
+    scope: PathBuf,
+    path: PathBuf,
+    extractor: Option<IndexExtractor>,
+    _phantom: PhantomData<K>,
+}
+
+impl<K: ToString> KVIndex<K, ZboxError> for ZboxKVIndex<K> {
+    // entries left behind by an interrupted write are skipped by checking
+    // them against the primary value, or only its existence when the index
+    // was opened from a handle which doesn't register it
+    fn get(&self, k: K) -> Result<Vec<PathBuf>, ZboxError> {
+        let db = self.db.read().unwrap();
+        let key = k.to_string();
+        let path = self.path.join(escape_component(&key));
+        if !db.is_dir(&path)? {
+            return Ok(vec![]);
+        }
+        let mut list = vec![];
+        for entry in db.read_dir(&path)?.iter() {
+            if !entry.metadata().is_file() {
+                continue;
+            }
+            let primary = unescape_component(entry.file_name());
+            let primary_path = self.scope.join(&primary);
+            let matches = match &self.extractor {
+                Some(extractor) => read_file(&db, &primary_path)
+                    .map_or(false, |v| extractor(&v).as_deref() == Some(key.as_str())),
+                None => db.is_file(&primary_path)?,
+            };
+            if matches {
+                list.push(PathBuf::from(primary));
+            }
+        }
+        Ok(list)
     }
 }
 
 pub struct ZboxKV {
     db: Arc<RwLock<Repo>>,
+    name: String,
+    pass: String,
+    buckets: Arc<RwLock<HashMap<String, Arc<RwLock<Repo>>>>>,
 }
 
 impl ZboxKV {
+    fn open_db(name: &str, pass: &str, compress: bool) -> Result<Repo, ZboxError> {
+        RepoOpener::new()
+            .create(true)
+            .compress(compress)
+            .dedup_chunk(true)
+            .force(true)
+            .open(&format!("sqlite://{}", name), pass)
+    }
+
     pub fn new<N: ToString, P: ToString>(name: N, pass: P) -> Self {
         Self {
             db: Arc::new(RwLock::new(
-                RepoOpener::new()
-                    .create(true)
-                    .compress(true)
-                    .dedup_chunk(true)
-                    .force(true)
-                    .open(&format!("sqlite://{}", name.to_string()), &pass.to_string())
+                Self::open_db(&name.to_string(), &pass.to_string(), true)
                     .expect("Fail to init database"),
             )),
+            name: name.to_string(),
+            pass: pass.to_string(),
+            buckets: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    fn get_options_path(scope: &str) -> PathBuf {
+        PathBuf::from("/.buckets").join(format!("{}.options", scope))
+    }
+
+    fn get_options(&self, scope: &str) -> Result<BucketOptions, ZboxError> {
+        let mut db = self.db.write().unwrap();
+        let path = Self::get_options_path(scope);
+        if !db.is_file(&path)? {
+            return Ok(BucketOptions::default());
+        }
+        let mut buf = vec![];
+        db.open_file(&path)?.read_to_end(&mut buf)?;
+        Ok(BucketOptions::from_bytes(&buf).unwrap_or_default())
+    }
+
+    // compression can't change once the bucket has data, because the bucket
+    // would move to another repository and leave its data behind
+    fn set_options(&self, scope: &str, options: &BucketOptions) -> Result<(), ZboxError> {
+        let stored = self.get_options(scope)?;
+        if stored.is_uncompressed() != options.is_uncompressed()
+            && Self::has_data(&self.get_db(scope, &stored)?, scope)?
+        {
+            return Err(ZboxError::InvalidArgument);
+        }
+        let mut db = self.db.write().unwrap();
+        let path = Self::get_options_path(scope);
+        if !db.is_dir("/.buckets")? {
+            db.create_dir_all("/.buckets")?;
+        }
+        if db.is_file(&path)? {
+            db.remove_file(&path)?;
+        }
+        db.create_file(&path)?.write_once(&options.to_bytes())?;
+        Ok(())
+    }
+
+    fn has_data(db: &Arc<RwLock<Repo>>, scope: &str) -> Result<bool, ZboxError> {
+        let db = db.read().unwrap();
+        let path = PathBuf::from(format!("/{}", scope));
+        Ok(db.is_dir(&path)?
+            && db
+                .read_dir(&path)?
+                .iter()
+                .any(|entry| entry.metadata().is_file()))
+    }
+
+    // compression is fixed per zbox repository, so buckets which change it
+    // live in a repository of their own next to the main one
+    fn get_db(&self, scope: &str, options: &BucketOptions) -> Result<Arc<RwLock<Repo>>, ZboxError> {
+        match options.compression {
+            Some(false) => {
+                let mut buckets = self.buckets.write().unwrap();
+                if let Some(db) = buckets.get(scope) {
+                    return Ok(db.clone());
+                }
+                let db = Arc::new(RwLock::new(Self::open_db(
+                    &format!("{}.{}", self.name, scope),
+                    &self.pass,
+                    false,
+                )?));
+                buckets.insert(scope.into(), db.clone());
+                Ok(db)
+            }
+            _ => Ok(self.db.clone()),
         }
     }
 }
 
 impl<S: ToString> KV<S, Vec<u8>, ZboxError, ZboxKVBucket<S>> for ZboxKV {
     fn get_bucket(&self, name: S) -> Result<ZboxKVBucket<S>, ZboxError> {
-        ZboxKVBucket::new(self.db.clone(), name)
+        let scope = name.to_string();
+        let options = self.get_options(&scope)?;
+        ZboxKVBucket::new_with(self.get_db(&scope, &options)?, name, options)
+    }
+
+    fn get_bucket_with(
+        &self,
+        name: S,
+        options: BucketOptions,
+    ) -> Result<ZboxKVBucket<S>, ZboxError> {
+        let scope = name.to_string();
+        self.set_options(&scope, &options)?;
+        ZboxKVBucket::new_with(self.get_db(&scope, &options)?, name, options)
     }
 }
 
@@ -154,3 +494,44 @@ fn transform_zbox() -> Result<(), anyhow::Error> {
     println!("finash, {}ms", sw.elapsed().as_millis());
     Ok(())
 }
+
+#[cfg(test)]
+fn tag_extractor(v: &[u8]) -> Option<String> {
+    Some(String::from_utf8_lossy(v).into_owned())
+}
+
+#[test]
+fn zbox_index_recovers_from_interrupted_writes() -> Result<(), anyhow::Error> {
+    ::zbox::init_env();
+    let dir = tempfile::tempdir()?;
+    let db = ZboxKV::new(dir.path().join("index.db").display(), "password");
+    let bucket = db.get_bucket("items")?.with_index("tag", tag_extractor);
+    let index = bucket.index("tag");
+    bucket.insert("one", b"red".to_vec())?;
+
+    // interrupted before the value was written: the entry for the new value
+    // is there, but the value is still the old one
+    bucket.add_index_entries(&mut db.db.write().unwrap(), "one", None, Some(b"blue"))?;
+    assert!(index.get("blue")?.is_empty());
+    assert_eq!(index.get("red")?, vec![PathBuf::from("one")]);
+
+    // interrupted after the value was written: the entry for the old value
+    // is still there
+    bucket.insert("one", b"blue".to_vec())?;
+    bucket.add_index_entries(&mut db.db.write().unwrap(), "one", None, Some(b"red"))?;
+    assert!(index.get("red")?.is_empty());
+    assert_eq!(index.get("blue")?, vec![PathBuf::from("one")]);
+
+    // a handle which doesn't register the index only checks that the value
+    // exists, so entries of removed values are skipped as well
+    bucket.remove("one")?;
+    bucket.add_index_entries(&mut db.db.write().unwrap(), "one", None, Some(b"blue"))?;
+    let unregistered: ZboxKVBucket<&str> = db.get_bucket("items")?;
+    assert!(unregistered.index("tag").get("blue")?.is_empty());
+
+    // writing the key again reuses the stale entry
+    bucket.insert("one", b"blue".to_vec())?;
+    assert_eq!(index.get("blue")?, vec![PathBuf::from("one")]);
+    assert!(index.get("red")?.is_empty());
+    Ok(())
+}
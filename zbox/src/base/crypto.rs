@@ -12,6 +12,7 @@ use serde::de::{self, Deserializer};
 use serde::ser::Serializer;
 use serde::{Deserialize, Serialize};
 
+use super::timing;
 use error::{Error, Result};
 
 extern "C" {
@@ -1036,6 +1037,16 @@ impl Crypto {
         msg: &[u8],
         key: &Key,
         ad: &[u8],
+    ) -> Result<usize> {
+        timing::time_encryption(|| self.encrypt_untimed(ctxt, msg, key, ad))
+    }
+
+    fn encrypt_untimed(
+        &self,
+        ctxt: &mut [u8],
+        msg: &[u8],
+        key: &Key,
+        ad: &[u8],
     ) -> Result<usize> {
         let nonce_size = self.nonce_size();
         let p_ctxt = ctxt.as_mut_ptr();
@@ -1126,6 +1137,16 @@ impl Crypto {
         ctxt: &[u8],
         key: &Key,
         ad: &[u8],
+    ) -> Result<usize> {
+        timing::time_encryption(|| self.decrypt_untimed(msg, ctxt, key, ad))
+    }
+
+    fn decrypt_untimed(
+        &self,
+        msg: &mut [u8],
+        ctxt: &[u8],
+        key: &Key,
+        ad: &[u8],
     ) -> Result<usize> {
         let mut msglen = msg.len() as u64;
         let nonce_size = self.nonce_size();
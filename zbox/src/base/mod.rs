@@ -6,6 +6,7 @@ pub(crate) mod lru;
 pub(crate) mod lz4;
 mod refcnt;
 mod time;
+pub(crate) mod timing;
 pub(crate) mod utils;
 pub(crate) mod version;
 pub(crate) mod vio;
@@ -0,0 +1,105 @@
+use std::cell::Cell;
+use std::time::Duration;
+
+#[cfg(not(target_arch = "wasm32"))]
+use std::time::Instant;
+
+/// Time spent compressing and encrypting data.
+///
+/// This is counted per thread, so the difference between two calls to
+/// [`encoding_time`] on the same thread is the time spent on that thread
+/// in between, even when other threads use the same repository.
+///
+/// [`encoding_time`]: fn.encoding_time.html
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct EncodingTime {
+    /// Time spent compressing and decompressing data.
+    pub compression: Duration,
+
+    /// Time spent encrypting and decrypting data.
+    pub encryption: Duration,
+}
+
+thread_local! {
+    static ENCODING_TIME: Cell<EncodingTime> = Cell::new(EncodingTime::default());
+}
+
+/// Get the total time this thread has spent compressing and encrypting data.
+///
+/// Timing is not available on `wasm32`, where this always returns zero.
+#[inline]
+pub fn encoding_time() -> EncodingTime {
+    ENCODING_TIME.with(|time| time.get())
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn timed<T>(f: impl FnOnce() -> T) -> (T, Duration) {
+    let start = Instant::now();
+    let result = f();
+    (result, start.elapsed())
+}
+
+#[cfg(target_arch = "wasm32")]
+fn timed<T>(f: impl FnOnce() -> T) -> (T, Duration) {
+    (f(), Duration::default())
+}
+
+// add the time spent in `f` to the compression time, excluding any
+// encryption which happens in the underlying storage while it runs
+pub(crate) fn time_compression<T>(f: impl FnOnce() -> T) -> T {
+    let before = encoding_time().encryption;
+    let (result, elapsed) = timed(f);
+    ENCODING_TIME.with(|time| {
+        let mut total = time.get();
+        let nested = total.encryption - before;
+        total.compression += elapsed.checked_sub(nested).unwrap_or_default();
+        time.set(total);
+    });
+    result
+}
+
+// add the time spent in `f` to the encryption time
+pub(crate) fn time_encryption<T>(f: impl FnOnce() -> T) -> T {
+    let (result, elapsed) = timed(f);
+    ENCODING_TIME.with(|time| {
+        let mut total = time.get();
+        total.encryption += elapsed;
+        time.set(total);
+    });
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn encoding_time_is_per_thread() {
+        let before = encoding_time();
+        time_encryption(|| thread::sleep(Duration::from_millis(5)));
+        let after = encoding_time();
+        assert!(
+            after.encryption - before.encryption >= Duration::from_millis(5)
+        );
+        assert_eq!(after.compression, before.compression);
+
+        let other = thread::spawn(encoding_time).join().unwrap();
+        assert_eq!(other, EncodingTime::default());
+    }
+
+    #[test]
+    fn nested_encryption_is_not_counted_as_compression() {
+        let before = encoding_time();
+        time_compression(|| {
+            time_encryption(|| thread::sleep(Duration::from_millis(20)))
+        });
+        let after = encoding_time();
+        assert!(
+            after.encryption - before.encryption >= Duration::from_millis(20)
+        );
+        assert!(
+            after.compression - before.compression < Duration::from_millis(20)
+        );
+    }
+}
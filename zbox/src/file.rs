@@ -480,6 +480,12 @@ impl File {
     /// [`Write`]: https://doc.rust-lang.org/std/io/trait.Write.html
     /// [`Error::NotWrite`]: enum.Error.html
     pub fn finish(&mut self) -> Result<()> {
+        self.finish_with(false)
+    }
+
+    // complete multi-part write, optionally dropping the content after the
+    // written data in the same version
+    fn finish_with(&mut self, truncate: bool) -> Result<()> {
         self.check_closed()?;
 
         match self.wtr.take() {
@@ -488,7 +494,11 @@ impl File {
                 let mut end_pos = 0;
 
                 tx_handle.run_all_exclusive(|| {
-                    end_pos = wtr.finish()?;
+                    end_pos = if truncate {
+                        wtr.finish_truncate()?
+                    } else {
+                        wtr.finish()?
+                    };
                     Ok(())
                 })?;
 
@@ -516,6 +526,29 @@ impl File {
     /// [`Write`]: https://doc.rust-lang.org/std/io/trait.Write.html
     /// [`finish`]: struct.File.html#method.finish
     pub fn write_once(&mut self, buf: &[u8]) -> Result<()> {
+        self.write_once_with(buf, false)
+    }
+
+    /// Single-part write which replaces the whole content of the file and
+    /// creates a new version.
+    ///
+    /// Unlike [`write_once`], which writes at the current position and keeps
+    /// any content after the written data, this method writes from the start
+    /// of the file and drops the rest of the old content in the same version.
+    ///
+    /// This method is atomic.
+    ///
+    /// [`write_once`]: struct.File.html#method.write_once
+    pub fn replace_once(&mut self, buf: &[u8]) -> Result<()> {
+        self.check_closed()?;
+        if self.wtr.is_some() {
+            return Err(Error::NotFinish);
+        }
+        self.pos = SeekFrom::Start(0);
+        self.write_once_with(buf, true)
+    }
+
+    fn write_once_with(&mut self, buf: &[u8], truncate: bool) -> Result<()> {
         self.check_closed()?;
         match self.wtr {
             Some(_) => Err(Error::NotFinish),
@@ -533,7 +566,7 @@ impl File {
                     },
                     None => unreachable!(),
                 }
-                self.finish()
+                self.finish_with(truncate)
             }
         }
     }
@@ -771,6 +771,16 @@ impl Writer {
     }
 
     pub fn finish(self) -> Result<usize> {
+        self.finish_with(false)
+    }
+
+    /// Finish writing and drop current content beyond the written data, so
+    /// the new version only contains what was written
+    pub fn finish_truncate(self) -> Result<usize> {
+        self.finish_with(true)
+    }
+
+    fn finish_with(self, truncate: bool) -> Result<usize> {
         let store = self.handle.store.upgrade().ok_or(Error::RepoClosed)?;
         let txmgr = self.handle.txmgr.upgrade().ok_or(Error::RepoClosed)?;
         let (stg_ctn, chk_map) = self.inner.finish()?;
@@ -782,6 +792,9 @@ impl Writer {
         let merged_ctn = {
             let mut ctn = fnode_cow.clone_current_content(&store)?;
             ctn.merge_from(&stg_ctn, &store)?;
+            if truncate && ctn.len() > stg_ctn.end_offset() {
+                ctn.truncate(stg_ctn.end_offset(), &store)?;
+            }
             ctn
         };
 
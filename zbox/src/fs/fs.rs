@@ -280,7 +280,7 @@ impl Fs {
     }
 
     /// Open fnode
-    pub fn open_fnode(&mut self, path: &Path) -> Result<Handle> {
+    pub fn open_fnode(&self, path: &Path) -> Result<Handle> {
         let fnode = self.resolve(path)?;
         Ok(Handle {
             fnode,
@@ -137,6 +137,7 @@ mod version;
 mod volume;
 
 pub use self::base::crypto::{Cipher, MemLimit, OpsLimit};
+pub use self::base::timing::{encoding_time, EncodingTime};
 pub use self::base::{init_env, zbox_version};
 pub use self::error::{Error, Result};
 pub use self::file::{File, VersionReader};
@@ -868,6 +868,43 @@ impl Repo {
         OpenOptions::new().open(self, path)
     }
 
+    /// Attempts to open a file in read-only mode through a shared reference.
+    ///
+    /// `path` must be an absolute path.
+    ///
+    /// Unlike [`open_file`], this method only borrows the repository
+    /// immutably, so threads sharing a repository behind a read lock can
+    /// open and read files concurrently.
+    ///
+    /// # Errors
+    /// This method will return an error if path does not already exist or
+    /// is a directory.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # #![allow(unused_mut, unused_variables, dead_code)]
+    /// # use zbox::{init_env, Result, RepoOpener};
+    /// # fn foo() -> Result<()> {
+    /// # init_env();
+    /// # let mut repo = RepoOpener::new()
+    /// #     .create(true)
+    /// #     .open("mem://foo", "pwd")?;
+    /// # repo.create_file("/foo.txt")?;
+    /// let f = repo.open_file_shared("/foo.txt")?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// [`open_file`]: #method.open_file
+    pub fn open_file_shared<P: AsRef<Path>>(&self, path: P) -> Result<File> {
+        let handle = self.fs.open_fnode(path.as_ref())?;
+        if handle.fnode.read().unwrap().is_dir() {
+            return Err(Error::IsDir);
+        }
+        Ok(File::new(handle, SeekFrom::Start(0), true, false))
+    }
+
     /// Creates a new, empty directory at the specified path.
     ///
     /// `path` must be an absolute path.
@@ -10,6 +10,7 @@ use base::lz4::{
     BlockMode, BlockSize, ContentChecksum, Decoder as Lz4Decoder,
     Encoder as Lz4Encoder, EncoderBuilder as Lz4EncoderBuilder,
 };
+use base::timing;
 use base::{IntoRef, Time, Version};
 use error::{Error, Result};
 use fs::Config;
@@ -226,6 +227,7 @@ impl Read for WalReader {
 /// Volume Reader
 pub struct Reader {
     inner: Box<dyn Read>,
+    compress: bool,
 }
 
 impl Reader {
@@ -235,10 +237,12 @@ impl Reader {
         if vol.info.compress {
             Ok(Reader {
                 inner: Box::new(Lz4Decoder::new(rdr).unwrap()),
+                compress: true,
             })
         } else {
             Ok(Reader {
                 inner: Box::new(rdr),
+                compress: false,
             })
         }
     }
@@ -247,7 +251,12 @@ impl Reader {
 impl Read for Reader {
     #[inline]
     fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
-        self.inner.read(buf)
+        if self.compress {
+            let inner = &mut self.inner;
+            timing::time_compression(|| inner.read(buf))
+        } else {
+            self.inner.read(buf)
+        }
     }
 }
 
@@ -327,14 +336,18 @@ impl Writer {
 impl Write for Writer {
     fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
         match self.inner {
-            InnerWriter::Compress(ref mut inner) => inner.write(buf),
+            InnerWriter::Compress(ref mut inner) => {
+                timing::time_compression(|| inner.write(buf))
+            }
             InnerWriter::NoCompress(ref mut inner) => inner.write(buf),
         }
     }
 
     fn flush(&mut self) -> IoResult<()> {
         match self.inner {
-            InnerWriter::Compress(ref mut inner) => inner.flush(),
+            InnerWriter::Compress(ref mut inner) => {
+                timing::time_compression(|| inner.flush())
+            }
             InnerWriter::NoCompress(ref mut inner) => inner.flush(),
         }
     }
@@ -344,7 +357,7 @@ impl Finish for Writer {
     fn finish(self) -> Result<()> {
         match self.inner {
             InnerWriter::Compress(inner) => {
-                let (wtr, result) = inner.finish();
+                let (wtr, result) = timing::time_compression(|| inner.finish());
                 result.map_err(Error::from)?;
                 wtr.finish()
             }